@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use gpu_processing::core::{
+    core::initialise_gpu_resources,
+    corrections::{
+        defect_correction::{
+            DefectCorrectionMode, DefectMapBufferResources, NormalizationPolicy,
+            DEFAULT_KERNEL_RADIUS,
+        },
+        defect_correction_texture::DefectMapTextureResources,
+        DEFAULT_LOCAL_SIZE,
+    },
+};
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, PrimaryAutoCommandBuffer,
+        RecordingCommandBuffer,
+    },
+    descriptor_set::allocator::StandardDescriptorSetAllocator,
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    sync::{self, GpuFuture},
+};
+
+const WIDTH: u32 = 4800;
+const HEIGHT: u32 = 5800;
+
+/// Roughly one defective pixel in a thousand, scattered with a cheap LCG so runs are
+/// reproducible.
+fn defect_map() -> Vec<u16> {
+    let mut state: u32 = 0x1234_5678;
+    (0..WIDTH * HEIGHT)
+        .map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state % 1000 == 0) as u16
+        })
+        .collect()
+}
+
+fn device_buffer(allocator: Arc<StandardMemoryAllocator>, data: Vec<u16>) -> Subbuffer<[u16]> {
+    Buffer::from_iter(
+        allocator,
+        BufferCreateInfo {
+            usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+            ..Default::default()
+        },
+        data,
+    )
+    .unwrap()
+}
+
+fn defect_correction(c: &mut Criterion) {
+    let (queue, device) = initialise_gpu_resources();
+    let memory_allocator = Arc::new(StandardMemoryAllocator::new_default(device.clone()));
+    let descriptor_set_allocator = Arc::new(StandardDescriptorSetAllocator::new(
+        device.clone(),
+        Default::default(),
+    ));
+    let command_buffer_allocator = Arc::new(StandardCommandBufferAllocator::new(
+        device.clone(),
+        Default::default(),
+    ));
+
+    let defect_map = defect_map();
+    let size = (WIDTH * HEIGHT) as usize;
+    let image_buffer = device_buffer(memory_allocator.clone(), vec![1000u16; size]);
+    let result_buffer = device_buffer(memory_allocator.clone(), vec![0u16; size]);
+
+    let buffer_resources = DefectMapBufferResources::new(
+        device.clone(),
+        queue.clone(),
+        command_buffer_allocator.clone(),
+        memory_allocator.clone(),
+        descriptor_set_allocator.clone(),
+        &defect_map,
+        NormalizationPolicy::default(),
+        DefectCorrectionMode::default(),
+        DEFAULT_KERNEL_RADIUS,
+        HEIGHT,
+        WIDTH,
+        DEFAULT_LOCAL_SIZE,
+    )
+    .unwrap();
+    let texture_resources = DefectMapTextureResources::new(
+        device.clone(),
+        queue.clone(),
+        command_buffer_allocator.clone(),
+        memory_allocator.clone(),
+        descriptor_set_allocator.clone(),
+        &defect_map,
+        NormalizationPolicy::default(),
+        HEIGHT,
+        WIDTH,
+    )
+    .unwrap();
+
+    let run = |record: &dyn Fn(&mut RecordingCommandBuffer<PrimaryAutoCommandBuffer>)| {
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator.clone(),
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        record(&mut builder);
+
+        sync::now(device.clone())
+            .then_execute(queue.clone(), builder.end().unwrap())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+    };
+
+    // Both variants fill the same defects from the same 5x5 window with the default
+    // normalisation, but the texture variant weights it separably rather than by Manhattan
+    // distance; `texture_and_buffer_variants_match` checks they agree where both are exact.
+    let mut group = c.benchmark_group("defect_correction");
+    group.throughput(Throughput::Elements(size as u64));
+
+    group.bench_function("buffer", |b| {
+        b.iter(|| {
+            run(&|builder| {
+                buffer_resources.apply_pipeline(
+                    builder,
+                    WIDTH,
+                    HEIGHT,
+                    image_buffer.clone(),
+                    result_buffer.clone(),
+                )
+            })
+        })
+    });
+
+    group.bench_function("texture", |b| {
+        b.iter(|| {
+            run(&|builder| {
+                texture_resources.apply_pipeline(
+                    builder,
+                    WIDTH,
+                    HEIGHT,
+                    image_buffer.clone(),
+                    result_buffer.clone(),
+                )
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, defect_correction);
+criterion_main!(benches);
@@ -0,0 +1,191 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use gpu_processing::core::core::{initialise_gpu_resources, Corrections};
+
+const WIDTH: u32 = 4800;
+const HEIGHT: u32 = 5800;
+const FRAMES: u32 = 12;
+
+/// Frames per second through the asynchronous path with dark correction enabled, keeping
+/// up to `buffer_count` frames in flight at once.
+fn frame_throughput(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _guard = runtime.enter();
+
+    let size = (WIDTH * HEIGHT) as usize;
+    let image = vec![1000u16; size];
+
+    let mut group = c.benchmark_group("frame_throughput");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    group.sample_size(10);
+
+    for buffer_count in [1, 3] {
+        let (queue, device) = initialise_gpu_resources();
+        let mut correction_context =
+            Corrections::new(device, queue, WIDTH, HEIGHT, buffer_count).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(buffer_count),
+            &buffer_count,
+            |b, &buffer_count| {
+                b.iter(|| {
+                    for _ in 0..FRAMES / buffer_count {
+                        for _ in 0..buffer_count {
+                            correction_context.upload_image(&image).unwrap();
+                            correction_context.process_image().unwrap();
+                        }
+                        correction_context.collect_results().unwrap();
+                    }
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// Frames per second with dark and gain correction enabled, each invocation of those passes
+/// correcting `items_per_invocation` pixels.
+fn items_per_invocation(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _guard = runtime.enter();
+
+    let size = (WIDTH * HEIGHT) as usize;
+    let image = vec![1000u16; size];
+
+    let mut group = c.benchmark_group("items_per_invocation");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    group.sample_size(10);
+
+    let (queue, device) = initialise_gpu_resources();
+    let mut correction_context = Corrections::new(device, queue, WIDTH, HEIGHT, 1).unwrap();
+    correction_context
+        .enable_dark_map_correction(&vec![100u16; size], 300)
+        .unwrap();
+    correction_context
+        .enable_gain_correction(&vec![1.5f32; size])
+        .unwrap();
+
+    for items_per_invocation in [1, 2, 4, 8] {
+        correction_context
+            .set_items_per_invocation(items_per_invocation)
+            .unwrap();
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(items_per_invocation),
+            &items_per_invocation,
+            |b, _| {
+                b.iter(|| {
+                    for _ in 0..FRAMES {
+                        correction_context.upload_image(&image).unwrap();
+                        correction_context.process_image().unwrap();
+                        correction_context.collect_results().unwrap();
+                    }
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// Megapixels per second of one frame at a time through each correction on its own and all
+/// three together.
+fn correction_throughput(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _guard = runtime.enter();
+
+    let size = (WIDTH * HEIGHT) as usize;
+    let image = vec![1000u16; size];
+    let dark_map = vec![100u16; size];
+    let gain_map = vec![1.5f32; size];
+    // Roughly one defective pixel in a thousand.
+    let defect_map: Vec<u16> = (0..size).map(|i| (i % 997 == 0) as u16).collect();
+
+    let mut group = c.benchmark_group("correction_throughput");
+    // Reported per pixel, so criterion shows megapixels per second as Melem/s.
+    group.throughput(Throughput::Elements(size as u64));
+    group.sample_size(10);
+
+    for case in ["dark", "gain", "defect", "all"] {
+        let (queue, device) = initialise_gpu_resources();
+        let mut correction_context = Corrections::new(device, queue, WIDTH, HEIGHT, 1).unwrap();
+        if matches!(case, "dark" | "all") {
+            correction_context
+                .enable_dark_map_correction(&dark_map, 300)
+                .unwrap();
+        }
+        if matches!(case, "gain" | "all") {
+            correction_context
+                .enable_gain_correction(&gain_map)
+                .unwrap();
+        }
+        if matches!(case, "defect" | "all") {
+            correction_context
+                .enable_defect_correction(&defect_map)
+                .unwrap();
+        }
+
+        group.bench_function(case, |b| {
+            b.iter(|| {
+                correction_context.upload_image(&image).unwrap();
+                correction_context.process_image().unwrap();
+                correction_context.collect_results().unwrap();
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// Frames per second of upload, dark correction and readback, staging each frame either
+/// from a slice or straight from a pointer into host memory as a camera SDK's frame ring
+/// would hand it over.
+fn host_ptr_upload(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _guard = runtime.enter();
+
+    let size = (WIDTH * HEIGHT) as usize;
+    let image = vec![1000u16; size];
+
+    let mut group = c.benchmark_group("host_ptr_upload");
+    group.throughput(Throughput::Elements(1));
+    group.sample_size(10);
+
+    for case in ["slice", "host_ptr"] {
+        let (queue, device) = initialise_gpu_resources();
+        let mut correction_context = Corrections::new(device, queue, WIDTH, HEIGHT, 1).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        group.bench_function(case, |b| {
+            b.iter(|| {
+                if case == "slice" {
+                    correction_context.upload_image(&image).unwrap();
+                    correction_context.process_image().unwrap();
+                } else {
+                    unsafe {
+                        correction_context
+                            .process_image_from_host_ptr(image.as_ptr(), image.len())
+                            .unwrap();
+                    }
+                }
+                correction_context.collect_results().unwrap();
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    frame_throughput,
+    items_per_invocation,
+    correction_throughput,
+    host_ptr_upload
+);
+criterion_main!(benches);
@@ -0,0 +1,236 @@
+use log::warn;
+
+use super::{
+    core::{initialise_gpu_resources_with, Corrections, GpuSelectionOptions},
+    corrections::defect_correction::{DefectCorrectionMode, NormalizationPolicy},
+    cpu_reference::CpuCorrections,
+    error::MyError,
+};
+
+/// Where [`create_corrector`] runs the corrections.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// A Vulkan device, falling back to `CpuReference` if none can be initialised.
+    #[default]
+    Gpu,
+    /// `CpuCorrections`, giving the same results without a GPU, only slower.
+    CpuReference,
+}
+
+/// The dark, gain and defect corrections every backend provides, so callers that must work
+/// with or without a GPU can hold either behind a `Box<dyn ImageCorrector>`.
+pub trait ImageCorrector {
+    /// The backend actually in use, `CpuReference` if a GPU was asked for but missing.
+    fn backend(&self) -> Backend;
+
+    /// Subtracts `dark_map` from every frame, adding `offset` back.
+    fn enable_dark(&mut self, dark_map: &[u16], offset: u32) -> Result<(), MyError>;
+
+    fn enable_gain(&mut self, gain_map: &[f32]) -> Result<(), MyError>;
+
+    /// Replaces the pixels flagged in `defect_map` from their valid neighbours, with the
+    /// default normalisation and mode.
+    fn enable_defect(&mut self, defect_map: &[u16]) -> Result<(), MyError>;
+
+    /// Turns the dark correction back off, a no-op if it isn't enabled.
+    fn disable_dark(&mut self);
+
+    fn disable_gain(&mut self);
+
+    fn disable_defect(&mut self);
+
+    /// Corrects `input`, waiting for the result.
+    fn process(&mut self, input: &[u16]) -> Result<Vec<u16>, MyError>;
+
+    /// The vulkano context behind a `Backend::Gpu` corrector, for the features only it has.
+    fn as_gpu(&mut self) -> Option<&mut Corrections> {
+        None
+    }
+
+    fn as_gpu_ref(&self) -> Option<&Corrections> {
+        None
+    }
+}
+
+impl ImageCorrector for Corrections {
+    fn backend(&self) -> Backend {
+        Backend::Gpu
+    }
+
+    fn enable_dark(&mut self, dark_map: &[u16], offset: u32) -> Result<(), MyError> {
+        self.enable_dark_map_correction(dark_map, offset)
+    }
+
+    fn enable_gain(&mut self, gain_map: &[f32]) -> Result<(), MyError> {
+        self.enable_gain_correction(gain_map)
+    }
+
+    fn enable_defect(&mut self, defect_map: &[u16]) -> Result<(), MyError> {
+        self.enable_defect_correction(defect_map)
+    }
+
+    fn disable_dark(&mut self) {
+        self.disable_dark_map_correction();
+    }
+
+    fn disable_gain(&mut self) {
+        self.disable_gain_correction();
+    }
+
+    fn disable_defect(&mut self) {
+        self.disable_defect_correction();
+    }
+
+    fn process(&mut self, input: &[u16]) -> Result<Vec<u16>, MyError> {
+        self.process_image_blocking(input)
+    }
+
+    fn as_gpu(&mut self) -> Option<&mut Corrections> {
+        Some(self)
+    }
+
+    fn as_gpu_ref(&self) -> Option<&Corrections> {
+        Some(self)
+    }
+}
+
+impl ImageCorrector for CpuCorrections {
+    fn backend(&self) -> Backend {
+        Backend::CpuReference
+    }
+
+    fn enable_dark(&mut self, dark_map: &[u16], offset: u32) -> Result<(), MyError> {
+        self.enable_dark_map_correction(dark_map, offset)
+    }
+
+    fn enable_gain(&mut self, gain_map: &[f32]) -> Result<(), MyError> {
+        self.enable_gain_correction(gain_map)
+    }
+
+    fn enable_defect(&mut self, defect_map: &[u16]) -> Result<(), MyError> {
+        self.enable_defect_correction_with(
+            defect_map,
+            NormalizationPolicy::default(),
+            DefectCorrectionMode::default(),
+        )
+    }
+
+    fn disable_dark(&mut self) {
+        self.disable_dark_map_correction();
+    }
+
+    fn disable_gain(&mut self) {
+        self.disable_gain_correction();
+    }
+
+    fn disable_defect(&mut self) {
+        self.disable_defect_correction();
+    }
+
+    fn process(&mut self, input: &[u16]) -> Result<Vec<u16>, MyError> {
+        self.process_image_blocking(input)
+    }
+}
+
+/// Creates a corrector for `image_width` by `image_height` frames on `backend`, a GPU one
+/// keeping `buffer_count` frames in flight. Asking for `Backend::Gpu` on a machine without
+/// a usable Vulkan device logs a warning and returns a `CpuReference` corrector rather
+/// than failing; other errors, such as frames too large for the device, are still
+/// returned.
+pub fn create_corrector(
+    backend: Backend,
+    image_width: u32,
+    image_height: u32,
+    buffer_count: u32,
+) -> Result<Box<dyn ImageCorrector>, MyError> {
+    if backend == Backend::Gpu {
+        match initialise_gpu_resources_with(GpuSelectionOptions::default()) {
+            Ok((queue, device)) => {
+                return Ok(Box::new(Corrections::new(
+                    device,
+                    queue,
+                    image_width,
+                    image_height,
+                    buffer_count,
+                )?));
+            }
+            Err(error @ (MyError::NoSuitableDevice | MyError::GpuInitialisationError(_))) => {
+                warn!("No GPU available ({error}), correcting frames on the CPU");
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(Box::new(CpuCorrections::new(image_width, image_height)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_corrector, Backend};
+
+    #[test]
+    fn backends_agree() {
+        let (width, height) = (64u32, 48u32);
+        let size = (width * height) as usize;
+
+        let image: Vec<u16> = (0..size).map(|i| 1000 + (i % 3000) as u16).collect();
+        let dark_map: Vec<u16> = (0..size).map(|i| (i % 200) as u16).collect();
+        let gain_map: Vec<f32> = (0..size).map(|i| 0.75 + (i % 7) as f32 / 8.0).collect();
+        let defect_map: Vec<u16> = (0..size).map(|i| (i % 101 == 0) as u16).collect();
+
+        let results: Vec<Vec<u16>> = [Backend::Gpu, Backend::CpuReference]
+            .into_iter()
+            .map(|backend| {
+                let mut corrector = create_corrector(backend, width, height, 1).unwrap();
+                corrector.enable_dark(&dark_map, 300).unwrap();
+                corrector.enable_gain(&gain_map).unwrap();
+                corrector.enable_defect(&defect_map).unwrap();
+                corrector.process(&image).unwrap()
+            })
+            .collect();
+
+        // The GPU may fuse multiplies and adds, so allow the odd count of difference.
+        assert!(results[0]
+            .iter()
+            .zip(&results[1])
+            .all(|(&gpu, &cpu)| gpu.abs_diff(cpu) <= 1));
+    }
+
+    #[test]
+    fn disabled_corrections_leave_frames_alone() {
+        let (width, height) = (16u32, 8u32);
+        let size = (width * height) as usize;
+        let image: Vec<u16> = (0..size).map(|i| 1000 + i as u16).collect();
+        let mut defect_map = vec![0u16; size];
+        defect_map[size / 2] = 1;
+
+        for backend in [Backend::Gpu, Backend::CpuReference] {
+            let mut corrector = create_corrector(backend, width, height, 1).unwrap();
+            corrector.enable_dark(&vec![100; size], 0).unwrap();
+            corrector.enable_gain(&vec![2.0; size]).unwrap();
+            corrector.enable_defect(&defect_map).unwrap();
+            assert_ne!(corrector.process(&image).unwrap(), image);
+
+            corrector.disable_dark();
+            corrector.disable_gain();
+            corrector.disable_defect();
+            assert_eq!(corrector.process(&image).unwrap(), image, "{backend:?}");
+            // Disabling twice is harmless.
+            corrector.disable_dark();
+        }
+    }
+
+    #[test]
+    fn only_the_gpu_backend_exposes_its_context() {
+        let mut corrector = create_corrector(Backend::CpuReference, 4, 4, 1).unwrap();
+        assert_eq!(corrector.backend(), Backend::CpuReference);
+        assert!(corrector.as_gpu().is_none());
+
+        let corrector = create_corrector(Backend::Gpu, 4, 4, 1).unwrap();
+        assert_eq!(
+            corrector.as_gpu_ref().is_some(),
+            corrector.backend() == Backend::Gpu
+        );
+    }
+}
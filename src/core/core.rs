@@ -1,44 +1,220 @@
 use std::{
-    io, mem,
-    os::windows::io::AsHandle,
-    sync::{Arc, Mutex, RwLock},
-    time::Instant,
+    collections::{HashMap, VecDeque},
+    ffi::c_char,
+    fs, io, mem, panic,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
-use futures::lock;
-use log::debug;
+use log::{debug, log, warn, Level};
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    task::{self, JoinHandle},
+};
 
 use vulkano::{
     buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
     command_buffer::{
-        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
+        allocator::StandardCommandBufferAllocator, BufferCopy, CommandBufferInheritanceInfo,
+        CommandBufferUsage, CopyBufferInfo, CopyBufferToImageInfo, RecordingCommandBuffer,
+        SecondaryAutoCommandBuffer,
     },
     descriptor_set::allocator::StandardDescriptorSetAllocator,
     device::{
-        physical::PhysicalDeviceType, Device, DeviceCreateInfo, DeviceExtensions, Features, Queue,
-        QueueCreateInfo, QueueFlags,
+        physical::{PhysicalDevice, PhysicalDeviceType},
+        Device, DeviceCreateInfo, DeviceExtensions, Features, Queue, QueueCreateInfo, QueueFlags,
+    },
+    format::Format,
+    image::{Image, ImageCreateInfo, ImageType, ImageUsage},
+    instance::{
+        debug::{
+            DebugUtilsMessageSeverity, DebugUtilsMessenger, DebugUtilsMessengerCallback,
+            DebugUtilsMessengerCreateInfo,
+        },
+        Instance, InstanceCreateFlags, InstanceCreateInfo, InstanceExtensions,
     },
-    instance::{Instance, InstanceCreateFlags, InstanceCreateInfo},
-    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    memory::{
+        allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+        MemoryHeapFlags,
+    },
+    pipeline::cache::{PipelineCache, PipelineCacheCreateInfo},
     sync::{self, GpuFuture},
-    Validated, VulkanError, VulkanLibrary,
+    VulkanLibrary,
 };
 
-use super::corrections::{
-    dark_correction::DarkMapBufferResources, defect_correction::DefectMapBufferResources,
-    gain_correction::GainMapBufferResources,
+use super::{
+    corrections::{
+        accumulate::{Accumulate, AccumulateResources},
+        auto_offset::AutoOffsetResources,
+        binning::{BinMode, BinningResources},
+        byte_swap::ByteSwapResources,
+        corner_dark::CornerDarkResources,
+        dark_correction::DarkMapBufferResources,
+        deadtime_correction::DeadtimeCorrectionResources,
+        defect_correction::{
+            DefectCorrectionMode, DefectMapBufferResources, NormalizationPolicy,
+            DEFAULT_KERNEL_RADIUS,
+        },
+        defect_detection::{DefectDetection, DefectDetectionResources},
+        defect_stats::{DefectStats, DefectStatsResources},
+        distortion::DistortionResources,
+        expression::ExpressionResources,
+        flat_field::FlatFieldBufferResources,
+        format_conversion::{FormatConversionResources, PixelFormat},
+        frame_len,
+        frame_quality::{FrameQuality, FrameQualityResources},
+        frame_sequence::FrameTurn,
+        gain_correction::{resample_gain_map, GainMapBufferResources},
+        histogram::HistogramResources,
+        image_stats::{ImageStats, ImageStatsResources},
+        lag_correction::LagCorrectionResources,
+        linear_transform::LinearTransformResources,
+        log_transform::LogTransformResources,
+        magnitude::{IqLayout, MagnitudeResources},
+        notch_filter::{NotchAxis, NotchFilterResources},
+        orientation::{Orientation, OrientationResources},
+        preview::{Interp, Preview, PreviewResources},
+        register_pipeline_cache,
+        temporal_ema::TemporalEmaResources,
+        unregister_pipeline_cache, validate_local_size, Rect, DEFAULT_LOCAL_SIZE,
+        LOCAL_SIZE_CANDIDATES, MAX_ITEMS_PER_INVOCATION,
+    },
+    error::MyError,
+    gpu_timing::{CorrectionTimings, TimedPass, TimestampQueries},
+    heartbeat::{self, Heartbeat},
+    latency::{LatencyHistogram, LatencyStats},
 };
 
+/// Default fraction of pixels `auto_offset` places at the target value.
+pub const AUTO_OFFSET_PERCENTILE: f32 = 0.01;
+/// Default value `auto_offset` shifts the chosen percentile of `image - dark` to.
+pub const AUTO_OFFSET_TARGET: i32 = 100;
+
+const VALIDATION_LAYER: &str = "VK_LAYER_KHRONOS_validation";
+
+/// Messengers forwarding validation output to `log`, kept alive for the process lifetime.
+static DEBUG_MESSENGERS: Mutex<Vec<DebugUtilsMessenger>> = Mutex::new(Vec::new());
+
+/// Which physical device [`initialise_gpu_resources_with`] picks.
+#[derive(Clone, Debug)]
+pub struct GpuSelectionOptions {
+    /// Only consider devices whose name contains this, ignoring case.
+    pub name_contains: Option<String>,
+    /// Device types in order of preference. Devices of other types are never picked.
+    pub device_types: Vec<PhysicalDeviceType>,
+    /// Features to enable on top of the ones the corrections need. Devices lacking any of
+    /// them are skipped.
+    pub required_features: Features,
+    /// Enables the Khronos validation layer, see [`initialise_gpu_resources_with_validation`].
+    pub with_validation: bool,
+    /// Runs the shaders on packed u32 words even if the device supports 16-bit storage,
+    /// as they would on devices that don't.
+    pub force_32_bit_storage: bool,
+}
+
+impl Default for GpuSelectionOptions {
+    fn default() -> Self {
+        GpuSelectionOptions {
+            name_contains: None,
+            device_types: vec![
+                PhysicalDeviceType::DiscreteGpu,
+                PhysicalDeviceType::IntegratedGpu,
+                PhysicalDeviceType::VirtualGpu,
+                PhysicalDeviceType::Cpu,
+                PhysicalDeviceType::Other,
+            ],
+            required_features: Features::empty(),
+            with_validation: false,
+            force_32_bit_storage: false,
+        }
+    }
+}
+
 pub fn initialise_gpu_resources() -> (Arc<Queue>, Arc<Device>) {
-    let library = VulkanLibrary::new().unwrap();
+    initialise_gpu_resources_with_validation(false)
+}
+
+/// Like [`initialise_gpu_resources`], optionally enabling the Khronos validation layer with
+/// its messages forwarded to `log`. Validation is expensive and meant for debugging only.
+pub fn initialise_gpu_resources_with_validation(
+    with_validation: bool,
+) -> (Arc<Queue>, Arc<Device>) {
+    initialise_gpu_resources_with(GpuSelectionOptions {
+        with_validation,
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+/// Creates a device and compute queue on the most preferred device matching `options`.
+/// Returns `MyError::NoSuitableDevice` if none match and `MyError::GpuInitialisationError`
+/// if Vulkan can't be set up.
+pub fn initialise_gpu_resources_with(
+    options: GpuSelectionOptions,
+) -> Result<(Arc<Queue>, Arc<Device>), MyError> {
+    let initialisation_error =
+        |e: &dyn std::fmt::Display| MyError::GpuInitialisationError(e.to_string());
+
+    let library = VulkanLibrary::new().map_err(|e| initialisation_error(&e))?;
+
+    let validation_available = library
+        .layer_properties()
+        .map_err(|e| initialisation_error(&e))?
+        .any(|layer| layer.name() == VALIDATION_LAYER);
+    if options.with_validation && !validation_available {
+        warn!("Validation requested but {VALIDATION_LAYER} is not installed");
+    }
+    let with_validation = options.with_validation && validation_available;
+
     let instance = Instance::new(
         library,
         InstanceCreateInfo {
             flags: InstanceCreateFlags::ENUMERATE_PORTABILITY,
+            enabled_layers: if with_validation {
+                vec![VALIDATION_LAYER.to_owned()]
+            } else {
+                Vec::new()
+            },
+            enabled_extensions: InstanceExtensions {
+                ext_debug_utils: with_validation,
+                ..InstanceExtensions::empty()
+            },
             ..Default::default()
         },
     )
-    .unwrap();
+    .map_err(|e| initialisation_error(&e))?;
+
+    if with_validation {
+        let messenger = DebugUtilsMessenger::new(
+            instance.clone(),
+            DebugUtilsMessengerCreateInfo::user_callback(unsafe {
+                DebugUtilsMessengerCallback::new(|severity, message_type, data| {
+                    let level = if severity.intersects(DebugUtilsMessageSeverity::ERROR) {
+                        Level::Error
+                    } else if severity.intersects(DebugUtilsMessageSeverity::WARNING) {
+                        Level::Warn
+                    } else if severity.intersects(DebugUtilsMessageSeverity::INFO) {
+                        Level::Info
+                    } else {
+                        Level::Debug
+                    };
+                    log!(
+                        level,
+                        "[{:?}] {}: {}",
+                        message_type,
+                        data.message_id_name.unwrap_or("unknown"),
+                        data.message
+                    );
+                })
+            }),
+        )
+        .map_err(|e| initialisation_error(&e))?;
+        DEBUG_MESSENGERS.lock().unwrap().push(messenger);
+    }
 
     // Choose which physical device to use.
     let device_extensions = DeviceExtensions {
@@ -46,25 +222,38 @@ pub fn initialise_gpu_resources() -> (Arc<Queue>, Arc<Device>) {
         ..DeviceExtensions::empty()
     };
 
+    // Shaders fall back to packed u32 words on devices without these, see
+    // `corrections::uses_pixel_words`.
+    let sixteen_bit_features = Features {
+        storage_buffer16_bit_access: true,
+        shader_int16: true,
+        ..Features::default()
+    };
+
+    let name_filter = options.name_contains.map(|name| name.to_lowercase());
+
     let (physical_device, queue_family_index) = instance
         .enumerate_physical_devices()
-        .unwrap()
+        .map_err(|e| initialisation_error(&e))?
         .filter(|p| p.supported_extensions().contains(&device_extensions))
+        .filter(|p| p.supported_features().contains(&options.required_features))
+        .filter(|p| match &name_filter {
+            Some(name) => p.properties().device_name.to_lowercase().contains(name),
+            None => true,
+        })
         .filter_map(|p| {
+            let preference = options
+                .device_types
+                .iter()
+                .position(|&device_type| device_type == p.properties().device_type)?;
             p.queue_family_properties()
                 .iter()
                 .position(|q| q.queue_flags.intersects(QueueFlags::COMPUTE))
-                .map(|i| (p, i as u32))
-        })
-        .min_by_key(|(p, _)| match p.properties().device_type {
-            PhysicalDeviceType::DiscreteGpu => 0,
-            PhysicalDeviceType::IntegratedGpu => 1,
-            PhysicalDeviceType::VirtualGpu => 2,
-            PhysicalDeviceType::Cpu => 3,
-            PhysicalDeviceType::Other => 4,
-            _ => 5,
+                .map(|i| (p, i as u32, preference))
         })
-        .unwrap();
+        .min_by_key(|&(_, _, preference)| preference)
+        .map(|(p, i, _)| (p, i))
+        .ok_or(MyError::NoSuitableDevice)?;
 
     debug!(
         "Using device: {} (type: {:?})",
@@ -72,6 +261,18 @@ pub fn initialise_gpu_resources() -> (Arc<Queue>, Arc<Device>) {
         physical_device.properties().device_type,
     );
 
+    let sixteen_bit_supported = physical_device
+        .supported_features()
+        .contains(&sixteen_bit_features);
+    if !sixteen_bit_supported {
+        warn!("Device lacks 16-bit storage, shaders will access pixels as 32-bit words");
+    }
+    let features = if sixteen_bit_supported && !options.force_32_bit_storage {
+        sixteen_bit_features.union(&options.required_features)
+    } else {
+        options.required_features
+    };
+
     for (index, heap) in physical_device
         .memory_properties()
         .memory_heaps
@@ -91,12 +292,6 @@ pub fn initialise_gpu_resources() -> (Arc<Queue>, Arc<Device>) {
         );
     }
 
-    let features = Features {
-        storage_buffer16_bit_access: true,
-        shader_int16: true,
-        ..Features::default()
-    };
-
     let (device, mut queues) = Device::new(
         physical_device,
         DeviceCreateInfo {
@@ -109,389 +304,6257 @@ pub fn initialise_gpu_resources() -> (Arc<Queue>, Arc<Device>) {
             ..Default::default()
         },
     )
-    .unwrap();
+    .map_err(|e| initialisation_error(&e))?;
 
     let queue = queues.next().unwrap();
 
-    (queue, device)
+    Ok((queue, device))
 }
 
-pub struct CorrectionsInner {
+/// Device, queue and allocators that several `Corrections` contexts can share, for
+/// example one per detector, rather than each creating its own, see
+/// [`Corrections::with_shared_resources`]. Cloning shares the same objects.
+#[derive(Clone)]
+pub struct SharedGpuResources {
     device: Arc<Device>,
     queue: Arc<Queue>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
     command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
-    image_buffers: Arc<Vec<Subbuffer<[u16]>>>,
-    result_buffer: Vec<Vec<u16>>,
-    width: u32,
-    height: u32,
-    dark_map_resources: Arc<Option<DarkMapBufferResources>>,
-    head_index: usize,
 }
 
-pub struct Corrections {
-    device: Arc<Device>,
-    queue: Arc<Queue>,
-    memory_allocator: Arc<StandardMemoryAllocator>,
-    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
-    result_buffer: Subbuffer<[u16]>,
-    readback_buffer: Subbuffer<[u16]>,
-    staging_buffers: Vec<Subbuffer<[u16]>>,
-    image_width: u32,
-    image_height: u32,
-    defect_buffer_resources: Option<DefectMapBufferResources>,
-    gain_map_resources: Option<GainMapBufferResources>,
-    inner: Arc<RwLock<CorrectionsInner>>,
+impl SharedGpuResources {
+    pub fn new(device: Arc<Device>, queue: Arc<Queue>) -> Self {
+        SharedGpuResources {
+            memory_allocator: Arc::new(StandardMemoryAllocator::new_default(device.clone())),
+            descriptor_set_allocator: Arc::new(StandardDescriptorSetAllocator::new(
+                device.clone(),
+                Default::default(),
+            )),
+            command_buffer_allocator: Arc::new(StandardCommandBufferAllocator::new(
+                device.clone(),
+                Default::default(),
+            )),
+            device,
+            queue,
+        }
+    }
+
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &Arc<Queue> {
+        &self.queue
+    }
 }
 
-impl Corrections {
-    pub fn new(
-        device: Arc<Device>,
-        queue: Arc<Queue>,
-        image_width: u32,
-        image_height: u32,
-        buffer_count: u32,
-    ) -> Self {
-        let memory_allocator = Arc::new(StandardMemoryAllocator::new_default(device.clone()));
-        let descriptor_set_allocator = Arc::new(StandardDescriptorSetAllocator::new(
-            device.clone(),
-            Default::default(),
-        ));
-        let command_buffer_allocator = Arc::new(StandardCommandBufferAllocator::new(
-            device.clone(),
-            Default::default(),
-        ));
+/// Uploads the `width` by `height` pixels of `data` into a new 2D image of `format`, for
+/// passes that gather 2D neighbourhoods through image loads. Blocks until the upload has
+/// finished.
+///
+/// `format` must hold a single 16-bit channel, such as `R16_UINT`, otherwise this is
+/// `MyError::InvalidParameter`. `data` that isn't `width * height` pixels is
+/// `MyError::InvalidTextureData`, and a device lost during the upload `MyError::DeviceLost`.
+pub fn create_image_texture(
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    data: &[u16],
+    format: Format,
+    width: u32,
+    height: u32,
+) -> Result<Arc<Image>, MyError> {
+    if format.components() != [16, 0, 0, 0] {
+        return Err(MyError::InvalidParameter);
+    }
+    if width == 0 || height == 0 || data.len() as u64 != width as u64 * height as u64 {
+        return Err(MyError::InvalidTextureData);
+    }
 
-        let readback_buffer = Buffer::from_iter(
+    let image = Image::new(
         memory_allocator.clone(),
-        BufferCreateInfo {
-            usage: BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER,
-            ..Default::default()
-        },
-        AllocationCreateInfo {
-            memory_type_filter: MemoryTypeFilter::PREFER_HOST | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+        ImageCreateInfo {
+            image_type: ImageType::Dim2d,
+            format,
+            extent: [width, height, 1],
+            usage: ImageUsage::STORAGE | ImageUsage::SAMPLED | ImageUsage::TRANSFER_DST,
             ..Default::default()
         },
-        vec![0u16; (image_width*image_height) as usize] /* number of elements, matching the image size */,
+        AllocationCreateInfo::default(),
     )
-    .unwrap();
+    .map_err(|_| MyError::TextureCreationError)?;
 
-        let result_buffer = Buffer::from_iter(
-        memory_allocator.clone(),
+    let staging_buffer = Buffer::from_iter(
+        memory_allocator,
         BufferCreateInfo {
-            usage: BufferUsage::STORAGE_BUFFER,
+            usage: BufferUsage::TRANSFER_SRC,
             ..Default::default()
         },
         AllocationCreateInfo {
-            memory_type_filter: MemoryTypeFilter::PREFER_HOST | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+            memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
             ..Default::default()
         },
-        vec![0u16; (image_width*image_height) as usize] /* number of elements, matching the image size */,
+        data.iter().copied(),
     )
-    .unwrap();
+    .map_err(|e| MyError::AllocationError("texture staging buffer", e.to_string()))?;
 
-        let mut staging_buffers = Vec::new();
-        let mut image_buffers = Vec::new();
+    let mut builder = RecordingCommandBuffer::primary(
+        command_buffer_allocator,
+        queue.queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )?;
+    builder.copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+        staging_buffer,
+        image.clone(),
+    ))?;
 
-        for i in 0..buffer_count {
-            staging_buffers.push(
-                Buffer::new_slice::<u16>(
-                    memory_allocator.clone(),
-                    BufferCreateInfo {
-                        usage: BufferUsage::TRANSFER_SRC | BufferUsage::STORAGE_BUFFER,
-                        ..Default::default()
-                    },
-                    AllocationCreateInfo {
-                        memory_type_filter: MemoryTypeFilter::PREFER_HOST
-                            | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
-                        ..Default::default()
-                    },
-                    (image_height * image_width) as u64,
-                )
-                .unwrap(),
-            );
+    sync::now(queue.device().clone())
+        .then_execute(queue.clone(), builder.end()?)
+        .map_err(|e| MyError::SubmissionError(e.to_string()))?
+        .then_signal_fence_and_flush()?
+        .wait(None)?;
 
-            image_buffers.push(
-                Buffer::new_slice::<u16>(
-                    memory_allocator.clone(),
-                    BufferCreateInfo {
-                        usage: BufferUsage::STORAGE_BUFFER
-                            | BufferUsage::TRANSFER_SRC
-                            | BufferUsage::TRANSFER_DST,
-                        ..Default::default()
-                    },
-                    AllocationCreateInfo {
-                        memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
-                            | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
-                        ..Default::default()
-                    },
-                    (image_height * image_width) as u64,
-                )
-                .unwrap(),
-            );
-        }
-        Corrections {
-            device: device.clone(),
-            queue: queue.clone(),
-            memory_allocator,
-            descriptor_set_allocator,
-            staging_buffers,
-            readback_buffer,
-            result_buffer,
-            image_width,
-            image_height,
-            defect_buffer_resources: None,
-            gain_map_resources: None,
-            inner: Arc::new(RwLock::new(CorrectionsInner {
-                queue: queue.clone(),
-                device: device.clone(),
-                image_buffers: Arc::new(image_buffers),
-                result_buffer: Vec::new(),
-                command_buffer_allocator,
-                width: image_width,
-                height: image_height,
-                dark_map_resources: Arc::new(None),
-                head_index: 0,
-            })),
-        }
-    }
+    Ok(image)
+}
 
-    pub fn enable_dark_map_correction(&mut self, dark_map: &[u16], offset: u32) {
-        let mut inner_lock = self.inner.write().unwrap();
-        inner_lock.dark_map_resources = Arc::new(Some(DarkMapBufferResources::new(
-            self.device.clone(),
-            self.queue.clone(),
-            inner_lock.command_buffer_allocator.clone(),
-            self.memory_allocator.clone(),
-            self.descriptor_set_allocator.clone(),
-            dark_map,
-            offset,
-            self.image_height,
-            self.image_width,
-        )));
-    }
+/// A single pass in the correction chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CorrectionStage {
+    Deadtime,
+    Dark,
+    CornerDark,
+    FlatField,
+    Notch,
+    Gain,
+    Defect,
+    Distortion,
+    Log,
+    Linear,
+    Expression,
+}
 
-    pub fn enable_gain_correction(&mut self, gain_map: &[f32]) {
-        let mut inner_lock = self.inner.write().unwrap();
+/// Pairs of stages where the first must run before the second, with the reason.
+const REQUIRED_STAGE_ORDERINGS: [(CorrectionStage, CorrectionStage, &str); 10] = [
+    (
+        CorrectionStage::Deadtime,
+        CorrectionStage::Gain,
+        "dead time depends on the measured count rate, not the flat-fielded one",
+    ),
+    (
+        CorrectionStage::Deadtime,
+        CorrectionStage::FlatField,
+        "dead time depends on the measured count rate, not the flat-fielded one",
+    ),
+    (
+        CorrectionStage::FlatField,
+        CorrectionStage::Log,
+        "the log transform expects flat-fielded intensities",
+    ),
+    (
+        CorrectionStage::Dark,
+        CorrectionStage::Gain,
+        "the gain map is calibrated on dark-subtracted frames",
+    ),
+    (
+        CorrectionStage::Dark,
+        CorrectionStage::Log,
+        "the log transform expects dark-subtracted intensities",
+    ),
+    (
+        CorrectionStage::CornerDark,
+        CorrectionStage::Gain,
+        "the gain map is calibrated on dark-subtracted frames",
+    ),
+    (
+        CorrectionStage::CornerDark,
+        CorrectionStage::Log,
+        "the log transform expects dark-subtracted intensities",
+    ),
+    (
+        CorrectionStage::Gain,
+        CorrectionStage::Log,
+        "gain is multiplicative in intensity, not in absorption",
+    ),
+    (
+        CorrectionStage::Gain,
+        CorrectionStage::Distortion,
+        "the gain map is calibrated in detector pixel coordinates",
+    ),
+    (
+        CorrectionStage::Defect,
+        CorrectionStage::Distortion,
+        "the defect map flags detector pixels, which resampling moves and blends",
+    ),
+];
 
-        self.gain_map_resources = Some(GainMapBufferResources::new(
-            self.device.clone(),
-            self.queue.clone(),
-            inner_lock.command_buffer_allocator.clone(),
-            self.memory_allocator.clone(),
-            self.descriptor_set_allocator.clone(),
-            &gain_map,
-            self.image_height,
-            self.image_width,
-        ));
-    }
+impl CorrectionStage {
+    /// Order stages are applied in unless changed with [`Corrections::set_stage_order`].
+    pub const DEFAULT_ORDER: [CorrectionStage; 11] = [
+        CorrectionStage::Deadtime,
+        CorrectionStage::Dark,
+        CorrectionStage::CornerDark,
+        CorrectionStage::FlatField,
+        CorrectionStage::Notch,
+        CorrectionStage::Gain,
+        CorrectionStage::Defect,
+        CorrectionStage::Distortion,
+        CorrectionStage::Log,
+        CorrectionStage::Linear,
+        CorrectionStage::Expression,
+    ];
 
-    pub fn enable_defect_correction(&mut self, defect_map: &[u16]) {
-        let mut inner_lock = self.inner.write().unwrap();
+    fn name(&self) -> &'static str {
+        match self {
+            CorrectionStage::Deadtime => "deadtime",
+            CorrectionStage::Dark => "dark",
+            CorrectionStage::CornerDark => "corner_dark",
+            CorrectionStage::FlatField => "flat_field",
+            CorrectionStage::Notch => "notch",
+            CorrectionStage::Gain => "gain",
+            CorrectionStage::Defect => "defect",
+            CorrectionStage::Distortion => "distortion",
+            CorrectionStage::Log => "log",
+            CorrectionStage::Linear => "linear",
+            CorrectionStage::Expression => "expression",
+        }
+    }
 
-        self.defect_buffer_resources = Some(DefectMapBufferResources::new(
-            self.device.clone(),
-            self.queue.clone(),
-            inner_lock.command_buffer_allocator.clone(),
-            self.memory_allocator.clone(),
-            self.descriptor_set_allocator.clone(),
-            defect_map,
-            self.image_height,
-            self.image_width,
-        ))
+    /// Whether the stage writes its result to the scratch buffer, to be copied back into the
+    /// image buffer, instead of working in place.
+    fn uses_scratch(&self) -> bool {
+        matches!(self, CorrectionStage::Defect | CorrectionStage::Distortion)
     }
+}
 
-    pub fn process_image(&mut self) {
-        let inner = self.inner.clone();
+/// Value of a single stage parameter, see [`Corrections::get_param`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamValue {
+    U32(u32),
+    F32(f32),
+    Bool(bool),
+}
 
-        tokio::spawn(async move {
-            let time = Instant::now();
-            println!("Running {:?}", time);
+/// Snapshot of the throughput counters of the asynchronous processing path.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CorrectionMetrics {
+    pub frames_submitted: u64,
+    pub frames_completed: u64,
+    /// Frames rejected because processing was paused.
+    pub frames_dropped: u64,
+    /// Largest number of submitted but not yet completed frames seen at submission.
+    pub max_in_flight: u64,
+}
 
-            let mut inner_lock = inner.write().unwrap();
-            let head_index = inner_lock.head_index;
-            inner_lock.head_index += 1;
+/// Kind of GPU a context runs on, see [`DeviceInfo`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GpuDeviceType {
+    Other,
+    IntegratedGpu,
+    DiscreteGpu,
+    VirtualGpu,
+    Cpu,
+}
 
-            let device = inner_lock.device.clone();
-            let queue = inner_lock.queue.clone();
-            let command_buffer_allocator = inner_lock.command_buffer_allocator.clone();
-            let image_buffers = inner_lock.image_buffers.clone();
-            let width = inner_lock.width;
-            let height = inner_lock.height;
-            let dark_map_resources = inner_lock.dark_map_resources.clone();
-            println!("Locking time {:?}", time.elapsed());
-            drop(inner_lock);
+impl From<PhysicalDeviceType> for GpuDeviceType {
+    fn from(device_type: PhysicalDeviceType) -> Self {
+        match device_type {
+            PhysicalDeviceType::IntegratedGpu => GpuDeviceType::IntegratedGpu,
+            PhysicalDeviceType::DiscreteGpu => GpuDeviceType::DiscreteGpu,
+            PhysicalDeviceType::VirtualGpu => GpuDeviceType::VirtualGpu,
+            PhysicalDeviceType::Cpu => GpuDeviceType::Cpu,
+            _ => GpuDeviceType::Other,
+        }
+    }
+}
 
-            image_buffers[head_index].write().unwrap();
+/// The GPU a context runs on, for hosts to log which one was picked.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// NUL-terminated UTF-8, truncated to fit.
+    pub name: [c_char; 256],
+    pub device_type: GpuDeviceType,
+    /// Total size of the device-local heaps.
+    pub vram_bytes: u64,
+    /// Encoded as the vendor's driver chooses.
+    pub driver_version: u32,
+}
 
-            let mut builder = RecordingCommandBuffer::primary(
-                command_buffer_allocator.clone(),
-                queue.queue_family_index(),
-                CommandBufferUsage::OneTimeSubmit,
-            )
-            .unwrap();
+impl DeviceInfo {
+    pub fn new(physical_device: &PhysicalDevice) -> Self {
+        let properties = physical_device.properties();
 
-            if let Some(dark_map_resources) = dark_map_resources.as_ref() {
-                println!("Applying dark correction");
-                dark_map_resources.apply_pipeline(
-                    &mut builder,
-                    width,
-                    height,
-                    image_buffers[head_index].clone(),
-                );
-            }
+        let mut name = [0; 256];
+        let mut len = properties.device_name.len().min(name.len() - 1);
+        while !properties.device_name.is_char_boundary(len) {
+            len -= 1;
+        }
+        for (dst, &src) in name
+            .iter_mut()
+            .zip(&properties.device_name.as_bytes()[..len])
+        {
+            *dst = src as c_char;
+        }
 
-            let command_buffer = builder.end().unwrap();
+        DeviceInfo {
+            name,
+            device_type: properties.device_type.into(),
+            vram_bytes: device_local_memory(physical_device),
+            driver_version: properties.driver_version,
+        }
+    }
 
-            let future = sync::now(device.clone())
-                .then_execute(queue.clone(), command_buffer)
-                .unwrap()
-                .then_signal_fence_and_flush();
+    /// `name` without its NUL terminator.
+    pub fn name(&self) -> String {
+        let bytes: Vec<u8> = self
+            .name
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
 
-            let time = Instant::now();
+/// Counters behind [`CorrectionMetrics`], shared with the frame tasks.
+#[derive(Default)]
+struct MetricCounters {
+    frames_submitted: AtomicU64,
+    frames_completed: AtomicU64,
+    frames_dropped: AtomicU64,
+    max_in_flight: AtomicU64,
+    /// From `process_image` to the corrected frame being read back.
+    latency: LatencyHistogram,
+}
 
-            match future.map_err(Validated::unwrap) {
-                Ok(future) => {
-                    future.wait(None).unwrap();
-                    println!(
-                        "Receiving data for head index {}, took time {:?}",
-                        head_index,
-                        time.elapsed()
-                    );
-                    let data = image_buffers[head_index].read().unwrap().to_vec();
-                    println!("Async task completed {:?}", time);
-                }
-                Err(e) => {}
-            }
-        });
+/// Frames handed to a tokio task that hasn't finished with them yet, including their
+/// completion callbacks, so [`Corrections::shutdown`] can wait for them.
+#[derive(Default)]
+struct OutstandingFrames {
+    count: Mutex<usize>,
+    finished: Condvar,
+}
 
-        /*
+impl OutstandingFrames {
+    fn claim(self: &Arc<Self>) -> OutstandingFrame {
+        *self.count.lock().unwrap() += 1;
+        OutstandingFrame(self.clone())
+    }
 
+    fn wait_all(&self) {
+        let count = self.count.lock().unwrap();
+        drop(self.finished.wait_while(count, |count| *count > 0).unwrap());
+    }
+}
 
-        /*
-        builder
-            .copy_buffer(CopyBufferInfo::buffers(
-                self.staging_buffer.clone(),
-                self.image_buffer.clone(),
-            ))
-            .unwrap();
-        */
+/// Held by a frame's task until it is done, or dropped with the task if it is cancelled.
+struct OutstandingFrame(Arc<OutstandingFrames>);
 
-        if let Some(dark_map_resources) = &self.dark_map_resources {
-            dark_map_resources.apply_pipeline(
-                &mut builder,
-                self.image_width,
-                self.image_height,
-                self.image_buffers[0]
-                .clone(),
-            );
-        }
+impl Drop for OutstandingFrame {
+    fn drop(&mut self) {
+        *self.0.count.lock().unwrap() -= 1;
+        self.0.finished.notify_all();
+    }
+}
 
-        if let Some(gain_map_resources) = &self.gain_map_resources {
-            gain_map_resources.apply_pipeline(
-                &mut builder,
-                self.image_width,
-                self.image_height,
-                self.image_buffers[0].clone(),
-                self.result_buffer.clone(),
-            );
-        }
+/// Which slots of a frame size hold a claimed frame that hasn't been read back yet. A slot's
+/// staging, image and readback buffers belong to that frame until it is, so staging another
+/// frame into the slot waits for it to be released.
+struct SlotStates {
+    busy: Mutex<Vec<bool>>,
+    released: Condvar,
+}
 
-        if let Some(defect_map_resources) = &self.defect_buffer_resources {
-            defect_map_resources.apply_pipeline(
-                &mut builder,
-                self.image_width,
-                self.image_height,
-                self.image_buffers[0].clone(),
-                self.result_buffer.clone(),
-            );
+impl SlotStates {
+    fn new(slot_count: usize) -> Self {
+        SlotStates {
+            busy: Mutex::new(vec![false; slot_count]),
+            released: Condvar::new(),
         }
+    }
 
-        if (self.defect_buffer_resources.is_some()) {
-            /*
-            builder
-            .copy_buffer(CopyBufferInfo::buffers(
-                self.result_buffer.clone(),
-                self.readback_buffer.clone(),
-            ))
-            .unwrap();
-            */
-        } else {
-            /*
-            builder
-            .copy_buffer(CopyBufferInfo::buffers(
-                self.image_buffer.clone(),
-                self.readback_buffer.clone(),
-            ))
-            .unwrap();
-            */
+    fn claim(self: &Arc<Self>, slot: usize) -> SlotClaim {
+        let mut busy = self.busy.lock().unwrap();
+        debug_assert!(!busy[slot], "slot {slot} claimed twice");
+        busy[slot] = true;
+        SlotClaim {
+            states: self.clone(),
+            slot,
         }
+    }
 
-        let command_buffer = builder.end().unwrap();
-
-        let future = sync::now(self.device.clone())
-            .then_execute(self.queue.clone(), command_buffer)
-            .unwrap()
-            .then_signal_fence_and_flush();
+    fn wait_until_free(&self, slot: usize) {
+        let busy = self.busy.lock().unwrap();
+        drop(self.released.wait_while(busy, |busy| busy[slot]).unwrap());
+    }
+}
 
-        let time = Instant::now();
+/// Releases its slot when the frame is done with it, or is dropped unrun.
+struct SlotClaim {
+    states: Arc<SlotStates>,
+    slot: usize,
+}
 
-        match future.map_err(Validated::unwrap) {
-            Ok(future) => {
-                future.wait(None).unwrap();
-                println!("Time to compute {:?}", time.elapsed());
-                let time = Instant::now();
-                image.copy_from_slice(&self.result_buffer.read().unwrap());
-                println!("Time to write {:?}", time.elapsed());
-            }
-            Err(e) => {
-                println!("failed to flush future: {e}");
-            }
-        }
-        */
+impl Drop for SlotClaim {
+    fn drop(&mut self) {
+        self.states.busy.lock().unwrap()[self.slot] = false;
+        self.states.released.notify_all();
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::time::Instant;
+/// A corrected frame as returned from the asynchronous processing path.
+pub struct ProcessedFrame {
+    pub data: Vec<u16>,
+    /// Present when frame quality metrics are enabled.
+    pub quality: Option<FrameQuality>,
+}
 
-    use super::{initialise_gpu_resources, Corrections};
+/// Calibration maps for [`process_single`], each enabling its correction when given.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CorrectionMaps<'a> {
+    /// Dark map and the offset added after subtracting it.
+    pub dark_map: Option<(&'a [u16], u32)>,
+    pub gain_map: Option<&'a [f32]>,
+    pub defect_map: Option<&'a [u16]>,
+}
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test() {
-        let gpu_resources = initialise_gpu_resources();
-        let image_width: u32 = 4800;
-        let image_height: u32 = 5800;
-        let offset = 300;
-        let buffer_count = 10;
+/// Corrects a single `width` by `height` frame with `maps`, synchronously, on the preferred
+/// GPU. Creates and tears down a whole context per call, so it's meant for scripts and
+/// one-off frames; streams should keep a [`Corrections`] around.
+///
+/// A map or input that isn't one frame long is `MyError::InvalidTextureData`.
+pub fn process_single(
+    input: &[u16],
+    width: u32,
+    height: u32,
+    maps: CorrectionMaps,
+) -> Result<Vec<u16>, MyError> {
+    let (queue, device) = initialise_gpu_resources_with(GpuSelectionOptions::default())?;
+    let mut correction_context = Corrections::new(device, queue, width, height, 1)?;
 
-        let mut correction_context = Corrections::new(
-            gpu_resources.1.clone(),
-            gpu_resources.0.clone(),
+    if let Some((dark_map, offset)) = maps.dark_map {
+        correction_context.enable_dark_map_correction(dark_map, offset)?;
+    }
+    if let Some(gain_map) = maps.gain_map {
+        correction_context.enable_gain_correction(gain_map)?;
+    }
+    if let Some(defect_map) = maps.defect_map {
+        correction_context.enable_defect_correction(defect_map)?;
+    }
+
+    correction_context.process_image_blocking(input)
+}
+
+/// Resources of every enabled correction. Cheap to clone so a frame can take a snapshot and
+/// release the lock before recording. Kept under the `inner` lock so `enable_*` calls from
+/// different threads don't race.
+#[derive(Clone)]
+struct CorrectionPasses {
+    deadtime_correction_resources: Arc<Option<DeadtimeCorrectionResources>>,
+    dark_map_resources: Arc<Option<DarkMapBufferResources>>,
+    corner_dark_resources: Arc<Option<CornerDarkResources>>,
+    flat_field_resources: Arc<Option<FlatFieldBufferResources>>,
+    gain_map_resources: Arc<Option<GainMapBufferResources>>,
+    defect_buffer_resources: Arc<Option<DefectMapBufferResources>>,
+    distortion_resources: Arc<Option<DistortionResources>>,
+    notch_filter_resources: Arc<Option<NotchFilterResources>>,
+    log_transform_resources: Arc<Option<LogTransformResources>>,
+    linear_transform_resources: Arc<Option<LinearTransformResources>>,
+    expression_resources: Arc<Option<ExpressionResources>>,
+    frame_quality_resources: Arc<Option<FrameQualityResources>>,
+    /// Runs in its own submission after the correction stages, see `LagCorrectionResources`.
+    lag_correction_resources: Arc<Option<LagCorrectionResources>>,
+    /// Runs in its own submission after lag correction, on the frame in sensor layout, see
+    /// `TemporalEmaResources`.
+    temporal_ema_resources: Arc<Option<TemporalEmaResources>>,
+    /// Flips or rotates the output after the quality metrics, before any byte swap.
+    orientation_resources: Arc<Option<OrientationResources>>,
+    /// Bins the output after any orientation, before any byte swap.
+    binning_resources: Arc<Option<BinningResources>>,
+    /// Swaps the output to the non-native byte order after all other passes.
+    byte_swap_resources: Arc<Option<ByteSwapResources>>,
+    /// Selected per frame by `process_image_with_calibration`.
+    calibrations: Arc<HashMap<String, CalibrationSet>>,
+    stage_order: Vec<CorrectionStage>,
+    /// Pixels each invocation of the dark and gain passes corrects.
+    items_per_invocation: u32,
+    /// Region the dark, gain and defect passes are limited to, the whole frame if `None`.
+    /// Set per frame size, as a region rarely makes sense for another size.
+    roi: Option<Rect>,
+}
+
+impl Default for CorrectionPasses {
+    fn default() -> Self {
+        CorrectionPasses {
+            deadtime_correction_resources: Arc::new(None),
+            dark_map_resources: Arc::new(None),
+            corner_dark_resources: Arc::new(None),
+            flat_field_resources: Arc::new(None),
+            gain_map_resources: Arc::new(None),
+            defect_buffer_resources: Arc::new(None),
+            distortion_resources: Arc::new(None),
+            notch_filter_resources: Arc::new(None),
+            log_transform_resources: Arc::new(None),
+            linear_transform_resources: Arc::new(None),
+            expression_resources: Arc::new(None),
+            frame_quality_resources: Arc::new(None),
+            lag_correction_resources: Arc::new(None),
+            temporal_ema_resources: Arc::new(None),
+            orientation_resources: Arc::new(None),
+            binning_resources: Arc::new(None),
+            byte_swap_resources: Arc::new(None),
+            calibrations: Arc::default(),
+            stage_order: CorrectionStage::DEFAULT_ORDER.to_vec(),
+            items_per_invocation: 1,
+            roi: None,
+        }
+    }
+}
+
+impl CorrectionPasses {
+    fn orientation(&self) -> Orientation {
+        self.orientation_resources
+            .as_ref()
+            .as_ref()
+            .map_or(Orientation::None, OrientationResources::orientation)
+    }
+
+    /// Width and height of a `width` by `height` frame once oriented and binned.
+    fn output_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        let (width, height) = self.orientation().output_dimensions(width, height);
+        match self.binning_resources.as_ref() {
+            Some(binning_resources) => binning_resources.output_dimensions(width, height),
+            None => (width, height),
+        }
+    }
+}
+
+/// Dark, gain and defect maps registered with [`Corrections::register_calibration`]. All
+/// sets stay resident on the GPU, so selecting one for a frame only rebinds its buffers.
+#[derive(Clone)]
+struct CalibrationSet {
+    dark_map_resources: Arc<Option<DarkMapBufferResources>>,
+    gain_map_resources: Arc<Option<GainMapBufferResources>>,
+    defect_buffer_resources: Arc<Option<DefectMapBufferResources>>,
+}
+
+/// Buffers and passes of a frame size that is not currently active, kept so alternating
+/// between panel sizes doesn't reallocate.
+struct FrameSet {
+    staging_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    image_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    scratch_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    readback_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    slot_states: Arc<SlotStates>,
+    staged: Vec<bool>,
+    passes: CorrectionPasses,
+    head_index: usize,
+}
+
+pub struct CorrectionsInner {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    staging_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    image_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    /// Per slot, for passes that can't work in place.
+    scratch_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    /// Per slot, host-cached copies of the corrected frames. A finished frame is read from
+    /// its own while the next slots compute.
+    readback_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    slot_states: Arc<SlotStates>,
+    staged: Vec<bool>,
+    width: u32,
+    height: u32,
+    passes: CorrectionPasses,
+    head_index: usize,
+    cached_frame_sets: HashMap<(u32, u32), FrameSet>,
+    /// Tail of the chain of frame submissions, shared by every frame size.
+    gpu_future: GpuFutureChain,
+}
+
+/// Most recent frame submission, which the next one is chained onto with `then_execute`
+/// so the queue is fed without waiting for the host in between. `None` once flushed.
+type GpuFutureChain = Arc<Mutex<Option<Box<dyn GpuFuture + Send + Sync>>>>;
+
+pub struct Corrections {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    image_width: u32,
+    image_height: u32,
+    buffer_count: u32,
+    /// Workgroup size the dark, gain, defect and flat-field passes are built with, see
+    /// [`Corrections::autotune`].
+    local_size: u32,
+    inner: Arc<RwLock<CorrectionsInner>>,
+    in_flight: VecDeque<JoinHandle<Result<ProcessedFrame, MyError>>>,
+    paused: bool,
+    metrics: Arc<MetricCounters>,
+    outstanding: Arc<OutstandingFrames>,
+    /// Readback buffer of the most recently completed frame.
+    last_result: Arc<Mutex<Option<Subbuffer<[u16]>>>>,
+    device_lost: Arc<AtomicBool>,
+    heartbeat: Option<Heartbeat>,
+    /// Rows of the frame being assembled by `push_rows` received so far.
+    rows_received: Vec<bool>,
+    pixel_format: PixelFormat,
+    /// Present unless `pixel_format` is `U16`.
+    format_conversion_resources: Option<Arc<FormatConversionResources>>,
+    /// Raw words of frames that bypass the staging buffers, the `process_image_u8` family's
+    /// frames in `pixel_format` and the I/Q samples of `process_iq_i16` and
+    /// `process_iq_f32`. Allocated on first use.
+    raw_buffer: Option<Subbuffer<[u32]>>,
+    /// Set by `enable_magnitude`.
+    magnitude_resources: Option<Arc<MagnitudeResources>>,
+    /// `None` if the queue can't write timestamps.
+    timestamp_queries: Option<Arc<TimestampQueries>>,
+    /// GPU timings of the most recently completed frame.
+    last_timings: Arc<Mutex<Option<CorrectionTimings>>>,
+    /// Set by `new_with_pipeline_cache`, saved back to its file on drop.
+    pipeline_cache: Option<(Arc<PipelineCache>, PathBuf)>,
+}
+
+impl Corrections {
+    /// Creates a context for 16-bit frames, see [`Corrections::new_with_pixel_format`].
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        image_width: u32,
+        image_height: u32,
+        buffer_count: u32,
+    ) -> Result<Self, MyError> {
+        Self::new_with_pixel_format(
+            device,
+            queue,
             image_width,
             image_height,
             buffer_count,
-        );
-        let mut image = vec![10u16; (image_height * image_width) as usize];
-        let mut defect_map = vec![1u16; (image_height * image_width) as usize];
-        let gain_map = vec![0.5f32; (image_height * image_width) as usize];
-        let dark_map = vec![1u16; (image_height * image_width) as usize];
+            PixelFormat::U16,
+        )
+    }
 
-        defect_map[0] = 1;
-        defect_map[1] = 0;
-        defect_map[2] = 0;
-        image[1] = 20;
-        image[2] = 10;
+    /// Creates a context for frames of `pixel_format`, passed to the matching
+    /// `process_image_u8`, `process_image_u32` or `process_image_f32`. The u16 entry points
+    /// keep working whatever the format.
+    pub fn new_with_pixel_format(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        image_width: u32,
+        image_height: u32,
+        buffer_count: u32,
+        pixel_format: PixelFormat,
+    ) -> Result<Self, MyError> {
+        Self::with_shared_resources(
+            SharedGpuResources::new(device, queue),
+            image_width,
+            image_height,
+            buffer_count,
+            pixel_format,
+        )
+    }
 
-        correction_context.enable_dark_map_correction(&dark_map, offset);
-        //correction_context.enable_gain_correction(&gain_map);
-        //correction_context.enable_defect_correction(&defect_map);
-        let time = Instant::now();
+    /// Like [`Corrections::new_with_pixel_format`], allocating from `resources` so that
+    /// contexts created from the same resources share one device and set of allocators.
+    ///
+    /// A zero width or height, or a frame too large for the device to bind as one storage
+    /// buffer, is `MyError::InvalidTextureData`.
+    pub fn with_shared_resources(
+        resources: SharedGpuResources,
+        image_width: u32,
+        image_height: u32,
+        buffer_count: u32,
+        pixel_format: PixelFormat,
+    ) -> Result<Self, MyError> {
+        let SharedGpuResources {
+            device,
+            queue,
+            memory_allocator,
+            descriptor_set_allocator,
+            command_buffer_allocator,
+        } = resources;
 
-        for i in 0..buffer_count {
-            correction_context.process_image();
+        validate_frame_dimensions(&device, image_width, image_height)?;
+        // A staging, image, scratch and readback buffer per slot.
+        check_memory_budget(
+            &device,
+            frame_buffers_size(image_width, image_height, buffer_count),
+        )?;
+
+        let (staging_buffers, image_buffers, scratch_buffers, readback_buffers) =
+            allocate_frame_buffers(&memory_allocator, image_width, image_height, buffer_count)?;
+
+        let format_conversion_resources = (pixel_format != PixelFormat::U16).then(|| {
+            Arc::new(FormatConversionResources::new(
+                device.clone(),
+                descriptor_set_allocator.clone(),
+                pixel_format,
+            ))
+        });
+
+        Ok(Corrections {
+            device: device.clone(),
+            queue: queue.clone(),
+            memory_allocator,
+            descriptor_set_allocator,
+            image_width,
+            image_height,
+            buffer_count,
+            local_size: DEFAULT_LOCAL_SIZE,
+            inner: Arc::new(RwLock::new(CorrectionsInner {
+                queue: queue.clone(),
+                device: device.clone(),
+                staged: vec![false; staging_buffers.len()],
+                slot_states: Arc::new(SlotStates::new(staging_buffers.len())),
+                staging_buffers: Arc::new(staging_buffers),
+                image_buffers: Arc::new(image_buffers),
+                scratch_buffers: Arc::new(scratch_buffers),
+                readback_buffers: Arc::new(readback_buffers),
+                command_buffer_allocator,
+                width: image_width,
+                height: image_height,
+                passes: CorrectionPasses::default(),
+                head_index: 0,
+                cached_frame_sets: HashMap::new(),
+                gpu_future: Arc::default(),
+            })),
+            in_flight: VecDeque::new(),
+            paused: false,
+            metrics: Arc::default(),
+            outstanding: Arc::default(),
+            last_result: Arc::default(),
+            device_lost: Arc::default(),
+            heartbeat: None,
+            rows_received: vec![false; image_height as usize],
+            pixel_format,
+            format_conversion_resources,
+            raw_buffer: None,
+            magnitude_resources: None,
+            timestamp_queries: TimestampQueries::new(&device, &queue, buffer_count).map(Arc::new),
+            last_timings: Arc::default(),
+            pipeline_cache: None,
+        })
+    }
+
+    /// Like [`Corrections::new`], building every pipeline of the device through a pipeline
+    /// cache loaded from `path`, and saving the cache back there when the context is
+    /// dropped. Later runs then skip most shader compilation. A missing or unreadable file
+    /// starts an empty cache, and the driver ignores one written by another device or
+    /// driver version.
+    pub fn new_with_pipeline_cache(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        image_width: u32,
+        image_height: u32,
+        buffer_count: u32,
+        path: impl Into<PathBuf>,
+    ) -> Result<Self, MyError> {
+        let path = path.into();
+        let initial_data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(error) => {
+                if error.kind() != io::ErrorKind::NotFound {
+                    warn!("ignoring pipeline cache {}: {error}", path.display());
+                }
+                Vec::new()
+            }
+        };
+        // SAFETY: the data was written by `get_data` on drop, and Vulkan checks its header
+        // and ignores data from another device or driver.
+        let pipeline_cache = unsafe {
+            PipelineCache::new(
+                device.clone(),
+                PipelineCacheCreateInfo {
+                    initial_data,
+                    ..Default::default()
+                },
+            )
         }
-        println!("Time to process image {:?}", time.elapsed() / buffer_count);
-        loop {}
+        .map_err(|e| MyError::AllocationError("pipeline cache", e.to_string()))?;
+        register_pipeline_cache(pipeline_cache.clone());
+
+        match Self::new(device, queue, image_width, image_height, buffer_count) {
+            Ok(mut corrections) => {
+                corrections.pipeline_cache = Some((pipeline_cache, path));
+                Ok(corrections)
+            }
+            Err(error) => {
+                unregister_pipeline_cache(&pipeline_cache);
+                Err(error)
+            }
+        }
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    /// Subtracts `dark_map` from every frame, adding `offset` back. A map that doesn't
+    /// match the frame is `MyError::InvalidTextureData` and leaves the current map in place.
+    pub fn enable_dark_map_correction(&self, dark_map: &[u16], offset: u32) -> Result<(), MyError> {
+        self.validate_frame_len(dark_map.len() as u64)?;
+
+        let mut inner_lock = self.inner.write().unwrap();
+        inner_lock.passes.dark_map_resources = Arc::new(Some(DarkMapBufferResources::new(
+            self.device.clone(),
+            self.queue.clone(),
+            inner_lock.command_buffer_allocator.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            dark_map,
+            offset,
+            self.image_height,
+            self.image_width,
+            self.local_size,
+        )?));
+
+        Ok(())
+    }
+
+    /// Replaces the enabled dark map's pixels without rebuilding its pipeline, which makes
+    /// periodic dark refreshes during acquisition cheap; see
+    /// [`DarkMapBufferResources::update_map`]. Waits for the frames already submitted, so
+    /// they are corrected with the old map and every later frame with the new one. Like
+    /// [`Corrections::shutdown`] it blocks the calling thread while they finish, so don't
+    /// call it from a current-thread runtime with frames outstanding.
+    ///
+    /// `MyError::DarkMapNotEnabled` if there is no dark map to update, and a map that
+    /// doesn't match the frame is `MyError::InvalidTextureData`.
+    pub fn update_dark_map(&self, dark_map: &[u16]) -> Result<(), MyError> {
+        self.validate_frame_len(dark_map.len() as u64)?;
+
+        // Holding the write lock keeps new frames from being submitted until the copy is done.
+        let inner_lock = self.inner.write().unwrap();
+        let Some(dark_map_resources) = inner_lock.passes.dark_map_resources.as_ref() else {
+            return Err(MyError::DarkMapNotEnabled);
+        };
+        self.outstanding.wait_all();
+        self.flush_chain(&inner_lock.gpu_future)?;
+        let updated = dark_map_resources.update_map(
+            self.queue.clone(),
+            inner_lock.command_buffer_allocator.clone(),
+            dark_map,
+        );
+        if matches!(updated, Err(MyError::DeviceLost)) {
+            self.device_lost.store(true, Ordering::Release);
+        }
+        updated
+    }
+
+    /// Stops subtracting the dark map. Frames already submitted hold on to the map they
+    /// started with and finish with it.
+    pub fn disable_dark_map_correction(&self) {
+        self.inner.write().unwrap().passes.dark_map_resources = Arc::new(None);
+    }
+
+    /// Multiplies every pixel by its gain in `gain_map`. A map that doesn't match the frame
+    /// is `MyError::InvalidTextureData` and leaves the current map in place.
+    pub fn enable_gain_correction(&self, gain_map: &[f32]) -> Result<(), MyError> {
+        self.validate_frame_len(gain_map.len() as u64)?;
+
+        let mut inner_lock = self.inner.write().unwrap();
+
+        inner_lock.passes.gain_map_resources = Arc::new(Some(GainMapBufferResources::new(
+            self.device.clone(),
+            self.queue.clone(),
+            inner_lock.command_buffer_allocator.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            gain_map,
+            self.image_height,
+            self.image_width,
+            self.local_size,
+        )?));
+
+        Ok(())
+    }
+
+    /// Like [`Corrections::enable_gain_correction`], normalising the map to its smallest
+    /// positive gain on the GPU, see [`GainMapBufferResources::normalise_to_minimum`].
+    /// Returns the factor the gains are scaled by. A map without a positive gain is
+    /// `MyError::InvalidParameter` and leaves the current map in place.
+    pub fn enable_gain_correction_normalised(&self, gain_map: &[f32]) -> Result<f32, MyError> {
+        self.validate_frame_len(gain_map.len() as u64)?;
+
+        let mut inner_lock = self.inner.write().unwrap();
+        let gain_map_resources = GainMapBufferResources::new(
+            self.device.clone(),
+            self.queue.clone(),
+            inner_lock.command_buffer_allocator.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            gain_map,
+            self.image_height,
+            self.image_width,
+            self.local_size,
+        )?;
+        let normalisation = gain_map_resources.normalise_to_minimum(
+            self.queue.clone(),
+            inner_lock.command_buffer_allocator.clone(),
+        )?;
+        inner_lock.passes.gain_map_resources = Arc::new(Some(gain_map_resources));
+
+        Ok(normalisation)
+    }
+
+    /// Enables gain correction with a map in unsigned fixed point with `frac_bits`
+    /// fractional bits, halving the map's memory and bandwidth against
+    /// [`Corrections::enable_gain_correction`] at some cost in precision, see
+    /// [`GainMapBufferResources::new_fixed`]. `quantize_gain_map` converts an f32 map, and
+    /// `DEFAULT_GAIN_FRAC_BITS` suits gains around 1.
+    ///
+    /// A map that doesn't match the frame is `MyError::InvalidTextureData`, more than
+    /// `MAX_GAIN_FRAC_BITS` fractional bits `MyError::InvalidParameter`.
+    pub fn enable_gain_correction_fixed(
+        &self,
+        gain_q: &[u16],
+        frac_bits: u32,
+    ) -> Result<(), MyError> {
+        self.validate_frame_len(gain_q.len() as u64)?;
+
+        let mut inner_lock = self.inner.write().unwrap();
+        let gain_map_resources = GainMapBufferResources::new_fixed(
+            self.device.clone(),
+            self.queue.clone(),
+            inner_lock.command_buffer_allocator.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            gain_q,
+            frac_bits,
+            self.local_size,
+        )?;
+
+        inner_lock.passes.gain_map_resources = Arc::new(Some(gain_map_resources));
+        Ok(())
+    }
+
+    /// Stops applying the gain map, however it was enabled. Frames already submitted
+    /// finish with the map they started with.
+    pub fn disable_gain_correction(&self) {
+        self.inner.write().unwrap().passes.gain_map_resources = Arc::new(None);
+    }
+
+    /// Enables gain correction with a map calibrated at `source_width` by `source_height`,
+    /// bilinearly resampled to the frame on the GPU first, e.g. after a binning change.
+    ///
+    /// A zero source dimension is `MyError::InvalidParameter`, a map that isn't
+    /// `source_width * source_height` long `MyError::InvalidTextureData`.
+    pub fn enable_gain_correction_resampled(
+        &self,
+        gain_map: &[f32],
+        source_width: u32,
+        source_height: u32,
+    ) -> Result<(), MyError> {
+        let command_buffer_allocator = self.inner.read().unwrap().command_buffer_allocator.clone();
+        let gain_map_buffer = resample_gain_map(
+            self.queue.clone(),
+            command_buffer_allocator,
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            gain_map,
+            (source_width, source_height),
+            (self.image_width, self.image_height),
+        )?;
+
+        self.enable_gain_correction_from_buffer(gain_map_buffer)
+    }
+
+    /// Dark-subtracts and flat-fields in a single pass, `(raw - dark) / (flat - dark)`
+    /// scaled by the flat's mean response so intensities stay in detector counts. An
+    /// alternative to separate dark and gain correction; pixels where `flat <= dark` are
+    /// passed through. The scale can be changed with [`Corrections::set_param`].
+    pub fn enable_flat_field_correction(
+        &self,
+        dark_map: &[u16],
+        flat_map: &[u16],
+    ) -> Result<(), MyError> {
+        let flat_field_resources = FlatFieldBufferResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            dark_map,
+            flat_map,
+            self.image_height,
+            self.image_width,
+            self.local_size,
+        )?;
+
+        self.inner.write().unwrap().passes.flat_field_resources =
+            Arc::new(Some(flat_field_resources));
+        Ok(())
+    }
+
+    /// Replaces pixels marked 1 in `defect_map` with a weighted mean of their good
+    /// neighbours. An empty map means no defects; any other length that doesn't match the
+    /// frame is `MyError::InvalidTextureData` and leaves the current map in place.
+    pub fn enable_defect_correction(&self, defect_map: &[u16]) -> Result<(), MyError> {
+        self.enable_defect_correction_with_policy(defect_map, NormalizationPolicy::default())
+    }
+
+    pub fn enable_defect_correction_with_policy(
+        &self,
+        defect_map: &[u16],
+        normalization: NormalizationPolicy,
+    ) -> Result<(), MyError> {
+        self.enable_defect_correction_with(
+            defect_map,
+            normalization,
+            DefectCorrectionMode::default(),
+        )
+    }
+
+    /// Like [`Corrections::enable_defect_correction`], estimating defective pixels with
+    /// `mode`. [`DefectCorrectionMode::Median`] holds up better against clusters of bad
+    /// pixels and hot neighbours.
+    pub fn enable_defect_correction_with_mode(
+        &self,
+        defect_map: &[u16],
+        mode: DefectCorrectionMode,
+    ) -> Result<(), MyError> {
+        self.enable_defect_correction_with(defect_map, NormalizationPolicy::default(), mode)
+    }
+
+    fn enable_defect_correction_with(
+        &self,
+        defect_map: &[u16],
+        normalization: NormalizationPolicy,
+        mode: DefectCorrectionMode,
+    ) -> Result<(), MyError> {
+        let mut inner_lock = self.inner.write().unwrap();
+
+        let defect_buffer_resources = DefectMapBufferResources::new(
+            self.device.clone(),
+            self.queue.clone(),
+            inner_lock.command_buffer_allocator.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            defect_map,
+            normalization,
+            mode,
+            DEFAULT_KERNEL_RADIUS,
+            self.image_height,
+            self.image_width,
+            self.local_size,
+        )?;
+
+        inner_lock.passes.defect_buffer_resources = Arc::new(Some(defect_buffer_resources));
+        Ok(())
+    }
+
+    /// Stops replacing defective pixels. Frames already submitted finish with the map they
+    /// started with.
+    pub fn disable_defect_correction(&self) {
+        self.inner.write().unwrap().passes.defect_buffer_resources = Arc::new(None);
+    }
+
+    /// Corrects photon-counting pile-up with the non-paralyzable dead-time model,
+    /// `measured / (1 - measured * tau)`, `tau` being the dead time in frames per count.
+    /// Pixels at or past the saturation rate `1 / tau` clamp to the u16 maximum instead of
+    /// dividing by zero. Applied first, on the measured counts.
+    pub fn enable_deadtime_correction(&self, tau: f32) -> Result<(), MyError> {
+        let deadtime_correction_resources = DeadtimeCorrectionResources::new(
+            self.device.clone(),
+            self.descriptor_set_allocator.clone(),
+            tau,
+        )?;
+
+        self.inner
+            .write()
+            .unwrap()
+            .passes
+            .deadtime_correction_resources = Arc::new(Some(deadtime_correction_resources));
+        Ok(())
+    }
+
+    /// Estimates each frame's dark level as the mean of `regions`, typically unexposed
+    /// corners, and subtracts it from every pixel, saturating at zero. Applied right after
+    /// dark correction, for detectors without a dark map or to track drift on top of one.
+    /// No regions, an empty region, one outside the frame or regions covering more than
+    /// `MAX_CORNER_PIXELS` together are `MyError::InvalidParameter`.
+    pub fn enable_corner_dark_estimate(&self, regions: &[Rect]) -> Result<(), MyError> {
+        let corner_dark_resources = CornerDarkResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            regions,
+            self.image_height,
+            self.image_width,
+        )?;
+
+        self.inner.write().unwrap().passes.corner_dark_resources =
+            Arc::new(Some(corner_dark_resources));
+        Ok(())
+    }
+
+    /// Notches the given spatial `frequencies` (cycles per line) out of every row or column
+    /// to remove periodic fixed-pattern stripes. Applied right after dark correction.
+    pub fn enable_notch_filter(&self, axis: NotchAxis, frequencies: &[u32]) -> Result<(), MyError> {
+        let notch_filter_resources = NotchFilterResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            axis,
+            frequencies,
+            self.image_height,
+            self.image_width,
+        )?;
+
+        self.inner.write().unwrap().passes.notch_filter_resources =
+            Arc::new(Some(notch_filter_resources));
+        Ok(())
+    }
+
+    /// Undoes geometric distortion: each output pixel `(x, y)` is the frame bilinearly
+    /// sampled at `(x + dx, y + dy)`, with `dx` and `dy` in pixels. Applied after defect
+    /// correction, as the maps of the earlier stages are in detector coordinates. Fields
+    /// that don't match the frame size are `MyError::InvalidTextureData`.
+    pub fn enable_distortion_correction(&self, dx: &[f32], dy: &[f32]) -> Result<(), MyError> {
+        let distortion_resources = DistortionResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            dx,
+            dy,
+            self.image_height,
+            self.image_width,
+        )?;
+
+        self.inner.write().unwrap().passes.distortion_resources =
+            Arc::new(Some(distortion_resources));
+        Ok(())
+    }
+
+    /// Converts corrected intensities to absorption, `scale * -ln(max(pixel, 1) / i0)`,
+    /// clamped to the u16 range. Applied after the other corrections, before any linear
+    /// transform.
+    pub fn enable_log_transform(&self, i0: f32, scale: f32) -> Result<(), MyError> {
+        let log_transform_resources = LogTransformResources::new(
+            self.device.clone(),
+            self.descriptor_set_allocator.clone(),
+            i0,
+            scale,
+        )?;
+
+        self.inner.write().unwrap().passes.log_transform_resources =
+            Arc::new(Some(log_transform_resources));
+        Ok(())
+    }
+
+    /// Remaps every pixel to `a * pixel + b`, clamped to the u16 range. Applied last by
+    /// default, use [`Corrections::set_stage_order`] to place it elsewhere in the chain.
+    pub fn enable_linear_transform(&self, a: f32, b: f32) -> Result<(), MyError> {
+        let linear_transform_resources = LinearTransformResources::new(
+            self.device.clone(),
+            self.descriptor_set_allocator.clone(),
+            a,
+            b,
+        )?;
+
+        self.inner
+            .write()
+            .unwrap()
+            .passes
+            .linear_transform_resources = Arc::new(Some(linear_transform_resources));
+        Ok(())
+    }
+
+    /// Applies a per-pixel arithmetic expression such as `sqrt(pixel) * gain + 10`, compiled
+    /// to a compute shader at runtime. Besides `pixel` and its `x` and `y` coordinates, any
+    /// other identifier is a uniform starting at zero, set with [`Corrections::set_param`] on
+    /// [`CorrectionStage::Expression`]. See `ExpressionResources` for the supported syntax;
+    /// anything else is rejected with `MyError::InvalidExpression`. Applied last by default.
+    pub fn enable_expression(&self, expression: &str) -> Result<(), MyError> {
+        let expression_resources = ExpressionResources::new(
+            self.device.clone(),
+            self.descriptor_set_allocator.clone(),
+            expression,
+        )?;
+
+        self.inner.write().unwrap().passes.expression_resources =
+            Arc::new(Some(expression_resources));
+        Ok(())
+    }
+
+    /// Subtracts detector afterglow modelled as `Σ wᵢ·prevᵢ` over the previous corrected
+    /// frames, `weights[0]` applying to the most recent. Applied after the correction stages,
+    /// before quality metrics and byte swapping. The history restarts from the next frame
+    /// processed. See `LagCorrectionResources::new` for the accepted weights.
+    pub fn enable_lag_correction(&self, weights: &[f32]) -> Result<(), MyError> {
+        let lag_correction_resources = LagCorrectionResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            weights,
+            self.image_height,
+            self.image_width,
+        )?;
+
+        self.inner.write().unwrap().passes.lag_correction_resources =
+            Arc::new(Some(lag_correction_resources));
+        Ok(())
+    }
+
+    /// Keeps an exponential moving average of the corrected frames, `acc = alpha * frame +
+    /// (1 - alpha) * acc`, for low-dose live preview. `alpha` must lie in (0, 1]. The
+    /// average restarts from the next frame processed. Frames are averaged after lag
+    /// correction, before any orientation, binning or byte swap, so the average keeps the
+    /// sensor's full resolution, layout and native byte order.
+    pub fn enable_temporal_ema(&self, alpha: f32) -> Result<(), MyError> {
+        let temporal_ema_resources = TemporalEmaResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            alpha,
+            self.image_height,
+            self.image_width,
+        )?;
+
+        self.inner.write().unwrap().passes.temporal_ema_resources =
+            Arc::new(Some(temporal_ema_resources));
+        Ok(())
+    }
+
+    /// Current moving average of the frames completed so far, see
+    /// [`Corrections::enable_temporal_ema`]. Returns `MyError::NoInput` before the first
+    /// frame has completed and `MyError::InvalidParameter` if the average isn't enabled.
+    pub fn read_ema(&self) -> Result<Vec<u16>, MyError> {
+        let temporal_ema_resources = self
+            .inner
+            .read()
+            .unwrap()
+            .passes
+            .temporal_ema_resources
+            .clone();
+        match temporal_ema_resources.as_ref() {
+            Some(temporal_ema_resources) => temporal_ema_resources.read().ok_or(MyError::NoInput),
+            None => Err(MyError::InvalidParameter),
+        }
+    }
+
+    /// Selects the byte order of the corrected frames, native by default. The swap runs on
+    /// the GPU after all corrections and quality metrics, for every frame size.
+    pub fn set_output_big_endian(&mut self, big_endian: bool) {
+        let byte_swap_resources =
+            Arc::new((big_endian != cfg!(target_endian = "big")).then(|| {
+                ByteSwapResources::new(self.device.clone(), self.descriptor_set_allocator.clone())
+            }));
+
+        let mut inner_lock = self.inner.write().unwrap();
+        for frame_set in inner_lock.cached_frame_sets.values_mut() {
+            frame_set.passes.byte_swap_resources = byte_swap_resources.clone();
+        }
+        inner_lock.passes.byte_swap_resources = byte_swap_resources;
+    }
+
+    /// Flips or rotates the corrected frames, for every frame size. Runs on the GPU after
+    /// all corrections and quality metrics, before any byte swap. Quarter turns swap the
+    /// width and height of the output, see [`Corrections::output_dimensions`].
+    pub fn set_orientation(&mut self, orientation: Orientation) -> Result<(), MyError> {
+        let orientation_resources = Arc::new(match orientation {
+            Orientation::None => None,
+            _ => Some(OrientationResources::new(
+                self.device.clone(),
+                self.descriptor_set_allocator.clone(),
+                orientation,
+            )?),
+        });
+
+        let mut inner_lock = self.inner.write().unwrap();
+        for frame_set in inner_lock.cached_frame_sets.values_mut() {
+            frame_set.passes.orientation_resources = orientation_resources.clone();
+        }
+        inner_lock.passes.orientation_resources = orientation_resources;
+        Ok(())
+    }
+
+    /// Width and height of the corrected frames, the frame dimensions unless a quarter turn
+    /// is set with [`Corrections::set_orientation`] or binning is enabled.
+    pub fn output_dimensions(&self) -> (u32, u32) {
+        self.inner
+            .read()
+            .unwrap()
+            .passes
+            .output_dimensions(self.image_width, self.image_height)
+    }
+
+    /// Sums or averages `factor` by `factor` blocks of every corrected frame of the current
+    /// size into one pixel, after all other passes but the byte swap. Frames come out
+    /// `(width / factor)` by `(height / factor)`, rows and columns past the last whole
+    /// block being cropped, see [`Corrections::output_dimensions`]. The in-place paths write
+    /// the binned frame to the start of the caller's buffer and leave the rest as it was.
+    ///
+    /// A factor of 0, above `MAX_BIN_FACTOR` or larger than the frame is
+    /// `MyError::InvalidParameter`.
+    pub fn enable_binning(&self, factor: u32, mode: BinMode) -> Result<(), MyError> {
+        let mut inner_lock = self.inner.write().unwrap();
+        let passes = &mut inner_lock.passes;
+        let (width, height) = passes
+            .orientation()
+            .output_dimensions(self.image_width, self.image_height);
+        if factor > width || factor > height {
+            return Err(MyError::InvalidParameter);
+        }
+
+        passes.binning_resources = Arc::new(Some(BinningResources::new(
+            self.device.clone(),
+            self.descriptor_set_allocator.clone(),
+            factor,
+            mode,
+        )?));
+        Ok(())
+    }
+
+    /// Computes `FrameQuality` for every processed frame. Pixels at or above
+    /// `saturation_level` count as saturated, those at or below `dark_level` as dark.
+    pub fn enable_frame_quality(&self, saturation_level: u16, dark_level: u16) {
+        let mut inner_lock = self.inner.write().unwrap();
+        inner_lock.passes.frame_quality_resources = Arc::new(Some(FrameQualityResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            saturation_level,
+            dark_level,
+        )));
+    }
+
+    /// Adopts a dark map that is already resident on the device instead of uploading one
+    /// from the host. The buffer must hold exactly one frame's worth of pixels.
+    pub fn enable_dark_map_from_buffer(
+        &self,
+        dark_map_buffer: Subbuffer<[u16]>,
+        offset: u32,
+    ) -> Result<(), MyError> {
+        self.validate_frame_len(dark_map_buffer.len())?;
+
+        let mut inner_lock = self.inner.write().unwrap();
+        inner_lock.passes.dark_map_resources = Arc::new(Some(DarkMapBufferResources::from_buffer(
+            self.device.clone(),
+            self.queue.clone(),
+            inner_lock.command_buffer_allocator.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            dark_map_buffer,
+            offset,
+            self.local_size,
+        )));
+
+        Ok(())
+    }
+
+    /// Adopts a device-resident gain map, see [`Corrections::enable_dark_map_from_buffer`].
+    pub fn enable_gain_correction_from_buffer(
+        &self,
+        gain_map_buffer: Subbuffer<[f32]>,
+    ) -> Result<(), MyError> {
+        self.validate_frame_len(gain_map_buffer.len())?;
+
+        let mut inner_lock = self.inner.write().unwrap();
+        inner_lock.passes.gain_map_resources = Arc::new(Some(GainMapBufferResources::from_buffer(
+            self.device.clone(),
+            self.queue.clone(),
+            inner_lock.command_buffer_allocator.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            gain_map_buffer,
+            self.local_size,
+        )));
+
+        Ok(())
+    }
+
+    /// Adopts a device-resident defect map, see [`Corrections::enable_dark_map_from_buffer`].
+    pub fn enable_defect_correction_from_buffer(
+        &self,
+        defect_map_buffer: Subbuffer<[u16]>,
+    ) -> Result<(), MyError> {
+        self.validate_frame_len(defect_map_buffer.len())?;
+
+        let mut inner_lock = self.inner.write().unwrap();
+        inner_lock.passes.defect_buffer_resources =
+            Arc::new(Some(DefectMapBufferResources::from_buffer(
+                self.device.clone(),
+                self.queue.clone(),
+                inner_lock.command_buffer_allocator.clone(),
+                self.memory_allocator.clone(),
+                self.descriptor_set_allocator.clone(),
+                defect_map_buffer,
+                NormalizationPolicy::default(),
+                DefectCorrectionMode::default(),
+                DEFAULT_KERNEL_RADIUS,
+                self.local_size,
+            )?));
+
+        Ok(())
+    }
+
+    /// Uploads a dark, gain and defect map under `calibration_id`, for scans that cycle
+    /// through several calibrations (one per filter, say) frame by frame. Frames processed
+    /// with [`Corrections::process_image_with_calibration`] use the set in place of the
+    /// maps enabled with `enable_dark_map_correction`, `enable_gain_correction` and
+    /// `enable_defect_correction`; every other stage is shared. Registering an existing id
+    /// replaces its set. The maps belong to the current frame size, and an empty defect map
+    /// means no defects.
+    pub fn register_calibration(
+        &self,
+        calibration_id: &str,
+        dark_map: &[u16],
+        offset: u32,
+        gain_map: &[f32],
+        defect_map: &[u16],
+    ) -> Result<(), MyError> {
+        self.validate_frame_len(dark_map.len() as u64)?;
+        self.validate_frame_len(gain_map.len() as u64)?;
+
+        let mut inner_lock = self.inner.write().unwrap();
+        let command_buffer_allocator = inner_lock.command_buffer_allocator.clone();
+
+        let calibration = CalibrationSet {
+            defect_buffer_resources: Arc::new(Some(DefectMapBufferResources::new(
+                self.device.clone(),
+                self.queue.clone(),
+                command_buffer_allocator.clone(),
+                self.memory_allocator.clone(),
+                self.descriptor_set_allocator.clone(),
+                defect_map,
+                NormalizationPolicy::default(),
+                DefectCorrectionMode::default(),
+                DEFAULT_KERNEL_RADIUS,
+                self.image_height,
+                self.image_width,
+                self.local_size,
+            )?)),
+            dark_map_resources: Arc::new(Some(DarkMapBufferResources::new(
+                self.device.clone(),
+                self.queue.clone(),
+                command_buffer_allocator.clone(),
+                self.memory_allocator.clone(),
+                self.descriptor_set_allocator.clone(),
+                dark_map,
+                offset,
+                self.image_height,
+                self.image_width,
+                self.local_size,
+            )?)),
+            gain_map_resources: Arc::new(Some(GainMapBufferResources::new(
+                self.device.clone(),
+                self.queue.clone(),
+                command_buffer_allocator,
+                self.memory_allocator.clone(),
+                self.descriptor_set_allocator.clone(),
+                gain_map,
+                self.image_height,
+                self.image_width,
+                self.local_size,
+            )?)),
+        };
+
+        Arc::make_mut(&mut inner_lock.passes.calibrations)
+            .insert(calibration_id.to_string(), calibration);
+        Ok(())
+    }
+
+    /// Picks a dark offset from a reference frame, see [`Corrections::auto_offset_with`],
+    /// using [`AUTO_OFFSET_PERCENTILE`] and [`AUTO_OFFSET_TARGET`].
+    pub fn auto_offset(&self, reference: &[u16]) -> Result<u32, MyError> {
+        self.auto_offset_with(reference, AUTO_OFFSET_PERCENTILE, AUTO_OFFSET_TARGET)
+    }
+
+    /// Computes the `percentile` of `reference - dark` on the GPU and returns the offset
+    /// that moves it to `target`, clamped at zero. The enabled dark map is used, so the
+    /// result can be passed straight back to [`Corrections::enable_dark_map_correction`].
+    pub fn auto_offset_with(
+        &self,
+        reference: &[u16],
+        percentile: f32,
+        target: i32,
+    ) -> Result<u32, MyError> {
+        self.validate_frame_len(reference.len() as u64)?;
+        if !(0.0..=1.0).contains(&percentile) {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let inner_lock = self.inner.read().unwrap();
+        let dark_map_buffer = match inner_lock.passes.dark_map_resources.as_ref() {
+            Some(dark_map_resources) => dark_map_resources.dark_map_buffer(),
+            None => return Err(MyError::DarkMapNotEnabled),
+        };
+
+        let reference_buffer = Buffer::from_iter(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            reference.iter().copied(),
+        )
+        .map_err(|e| MyError::AllocationError("auto offset reference buffer", e.to_string()))?;
+
+        let auto_offset_resources = AutoOffsetResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+        );
+
+        let mut builder = RecordingCommandBuffer::primary(
+            inner_lock.command_buffer_allocator.clone(),
+            self.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+
+        let histogram = auto_offset_resources.apply_pipeline(
+            &mut builder,
+            self.image_width,
+            self.image_height,
+            reference_buffer,
+            dark_map_buffer,
+        );
+
+        let command_buffer = builder.end()?;
+        drop(inner_lock);
+
+        let future = sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer)
+            .map_err(|e| MyError::SubmissionError(e.to_string()))?;
+        wait_for_submission(&self.device_lost, future)?;
+
+        Ok((target - histogram.percentile(percentile)).max(0) as u32)
+    }
+
+    /// Reads one parameter of an enabled stage:
+    ///
+    /// | stage        | name                        | value  |
+    /// |--------------|-----------------------------|--------|
+    /// | `Deadtime`   | `tau`                       | `F32`  |
+    /// | `Dark`       | `offset`                    | `U32`  |
+    /// | `FlatField`  | `scale`                     | `F32`  |
+    /// | `Defect`     | `full_kernel_normalization` | `Bool` |
+    /// | `Defect`     | `kernel_radius`             | `U32`  |
+    /// | `Log`        | `i0`, `scale`               | `F32`  |
+    /// | `Linear`     | `a`, `b`                    | `F32`  |
+    /// | `Expression` | each uniform it uses        | `F32`  |
+    ///
+    /// Returns `MyError::UnknownParameter` for any other name or a stage that isn't
+    /// enabled.
+    pub fn get_param(&self, stage: CorrectionStage, name: &str) -> Result<ParamValue, MyError> {
+        let inner_lock = self.inner.read().unwrap();
+        let passes = &inner_lock.passes;
+
+        let value = match (stage, name) {
+            (CorrectionStage::Deadtime, "tau") => passes
+                .deadtime_correction_resources
+                .as_ref()
+                .as_ref()
+                .map(|resources| ParamValue::F32(resources.tau())),
+            (CorrectionStage::Dark, "offset") => passes
+                .dark_map_resources
+                .as_ref()
+                .as_ref()
+                .map(|resources| ParamValue::U32(resources.offset())),
+            (CorrectionStage::FlatField, "scale") => passes
+                .flat_field_resources
+                .as_ref()
+                .as_ref()
+                .map(|resources| ParamValue::F32(resources.scale())),
+            (CorrectionStage::Defect, "full_kernel_normalization") => passes
+                .defect_buffer_resources
+                .as_ref()
+                .as_ref()
+                .map(|resources| {
+                    ParamValue::Bool(resources.normalization() == NormalizationPolicy::FullKernel)
+                }),
+            (CorrectionStage::Defect, "kernel_radius") => passes
+                .defect_buffer_resources
+                .as_ref()
+                .as_ref()
+                .map(|resources| ParamValue::U32(resources.kernel_radius())),
+            (CorrectionStage::Log, "i0") => passes
+                .log_transform_resources
+                .as_ref()
+                .as_ref()
+                .map(|resources| ParamValue::F32(resources.i0())),
+            (CorrectionStage::Log, "scale") => passes
+                .log_transform_resources
+                .as_ref()
+                .as_ref()
+                .map(|resources| ParamValue::F32(resources.scale())),
+            (CorrectionStage::Linear, "a") => passes
+                .linear_transform_resources
+                .as_ref()
+                .as_ref()
+                .map(|resources| ParamValue::F32(resources.a())),
+            (CorrectionStage::Linear, "b") => passes
+                .linear_transform_resources
+                .as_ref()
+                .as_ref()
+                .map(|resources| ParamValue::F32(resources.b())),
+            (CorrectionStage::Expression, name) => passes
+                .expression_resources
+                .as_ref()
+                .as_ref()
+                .and_then(|resources| resources.uniform(name))
+                .map(ParamValue::F32),
+            _ => None,
+        };
+
+        value.ok_or_else(|| MyError::UnknownParameter(stage.name(), name.to_string()))
+    }
+
+    /// Updates one parameter of an enabled stage, see [`Corrections::get_param`] for the
+    /// names. Push-constant parameters apply from the next frame recorded without touching
+    /// the pipeline; `full_kernel_normalization` and `kernel_radius` are specialization
+    /// constants and rebuild the defect pipeline, an out of range radius being
+    /// `MyError::InvalidParameter`. A value of the wrong type is `MyError::InvalidParameter`.
+    pub fn set_param(
+        &mut self,
+        stage: CorrectionStage,
+        name: &str,
+        value: ParamValue,
+    ) -> Result<(), MyError> {
+        // Checks the parameter exists on an enabled stage.
+        self.get_param(stage, name)?;
+
+        let mut inner_lock = self.inner.write().unwrap();
+        let passes = &inner_lock.passes;
+
+        match (stage, name, value) {
+            (CorrectionStage::Deadtime, "tau", ParamValue::F32(tau)) => {
+                if let Some(resources) = passes.deadtime_correction_resources.as_ref() {
+                    resources.set_tau(tau)?;
+                }
+            }
+            (CorrectionStage::Dark, "offset", ParamValue::U32(offset)) => {
+                if let Some(resources) = passes.dark_map_resources.as_ref() {
+                    resources.set_offset(offset);
+                }
+            }
+            (CorrectionStage::FlatField, "scale", ParamValue::F32(scale)) => {
+                if let Some(resources) = passes.flat_field_resources.as_ref() {
+                    resources.set_scale(scale)?;
+                }
+            }
+            (CorrectionStage::Defect, "full_kernel_normalization", ParamValue::Bool(full)) => {
+                let normalization = if full {
+                    NormalizationPolicy::FullKernel
+                } else {
+                    NormalizationPolicy::ValidNeighbours
+                };
+                let (defect_map_buffer, mode, kernel_radius) =
+                    match passes.defect_buffer_resources.as_ref() {
+                        Some(resources) if resources.normalization() != normalization => (
+                            resources.defect_map_buffer(),
+                            resources.mode(),
+                            resources.kernel_radius(),
+                        ),
+                        _ => return Ok(()),
+                    };
+
+                inner_lock.passes.defect_buffer_resources =
+                    Arc::new(Some(DefectMapBufferResources::from_buffer(
+                        self.device.clone(),
+                        self.queue.clone(),
+                        inner_lock.command_buffer_allocator.clone(),
+                        self.memory_allocator.clone(),
+                        self.descriptor_set_allocator.clone(),
+                        defect_map_buffer,
+                        normalization,
+                        mode,
+                        kernel_radius,
+                        self.local_size,
+                    )?));
+            }
+            (CorrectionStage::Defect, "kernel_radius", ParamValue::U32(kernel_radius)) => {
+                let (defect_map_buffer, normalization, mode) =
+                    match passes.defect_buffer_resources.as_ref() {
+                        Some(resources) if resources.kernel_radius() != kernel_radius => (
+                            resources.defect_map_buffer(),
+                            resources.normalization(),
+                            resources.mode(),
+                        ),
+                        _ => return Ok(()),
+                    };
+
+                inner_lock.passes.defect_buffer_resources =
+                    Arc::new(Some(DefectMapBufferResources::from_buffer(
+                        self.device.clone(),
+                        self.queue.clone(),
+                        inner_lock.command_buffer_allocator.clone(),
+                        self.memory_allocator.clone(),
+                        self.descriptor_set_allocator.clone(),
+                        defect_map_buffer,
+                        normalization,
+                        mode,
+                        kernel_radius,
+                        self.local_size,
+                    )?));
+            }
+            (CorrectionStage::Log, "i0", ParamValue::F32(i0)) => {
+                if let Some(resources) = passes.log_transform_resources.as_ref() {
+                    resources.set_i0(i0)?;
+                }
+            }
+            (CorrectionStage::Log, "scale", ParamValue::F32(scale)) => {
+                if let Some(resources) = passes.log_transform_resources.as_ref() {
+                    resources.set_scale(scale)?;
+                }
+            }
+            (CorrectionStage::Linear, "a", ParamValue::F32(a)) => {
+                if let Some(resources) = passes.linear_transform_resources.as_ref() {
+                    resources.set_a(a)?;
+                }
+            }
+            (CorrectionStage::Linear, "b", ParamValue::F32(b)) => {
+                if let Some(resources) = passes.linear_transform_resources.as_ref() {
+                    resources.set_b(b)?;
+                }
+            }
+            (CorrectionStage::Expression, name, ParamValue::F32(value)) => {
+                if let Some(resources) = passes.expression_resources.as_ref() {
+                    resources.set_uniform(name, value)?;
+                }
+            }
+            _ => return Err(MyError::InvalidParameter),
+        }
+
+        Ok(())
+    }
+
+    /// Counts and clusters the pixels flagged by the enabled defect map on the GPU.
+    pub fn defect_stats(&self) -> Result<DefectStats, MyError> {
+        let (defect_map_buffer, command_buffer_allocator) = {
+            let inner_lock = self.inner.read().unwrap();
+            let defect_map_buffer = match inner_lock.passes.defect_buffer_resources.as_ref() {
+                Some(defect_buffer_resources) => defect_buffer_resources.defect_map_buffer(),
+                None => return Err(MyError::DefectMapNotEnabled),
+            };
+            (
+                defect_map_buffer,
+                inner_lock.command_buffer_allocator.clone(),
+            )
+        };
+        let defect_stats_resources = DefectStatsResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+        );
+
+        Ok(defect_stats_resources.compute(
+            self.queue.clone(),
+            command_buffer_allocator,
+            self.image_width,
+            self.image_height,
+            defect_map_buffer,
+        ))
+    }
+
+    /// Histogram of the most recently completed frame in `bins` bins of equal width across
+    /// the full u16 range. See [`Corrections::compute_histogram_range`].
+    pub fn compute_histogram(&self, bins: u32) -> Result<Vec<u32>, MyError> {
+        self.compute_histogram_range(bins, 0, u16::MAX)
+    }
+
+    /// Histogram of the most recently completed frame in `bins` bins of equal width across
+    /// `[min, max]`, counted on the GPU. Pixels below `min` or above `max` are counted in the
+    /// first or last bin.
+    ///
+    /// `MyError::NoInput` if no frame has completed yet, `MyError::InvalidParameter` for 0
+    /// bins, more than
+    /// [`MAX_HISTOGRAM_BINS`](crate::core::corrections::histogram::MAX_HISTOGRAM_BINS) or `min >= max`. Counts the frame as read
+    /// back, so byte-swapped output gives the histogram of the swapped values.
+    pub fn compute_histogram_range(
+        &self,
+        bins: u32,
+        min: u16,
+        max: u16,
+    ) -> Result<Vec<u32>, MyError> {
+        if min >= max {
+            return Err(MyError::InvalidParameter);
+        }
+
+        // Holding the read lock blocks `process_image` from reusing the frame's slot.
+        let inner_lock = self.inner.read().unwrap();
+        let last_result = self
+            .last_result
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(MyError::NoInput)?;
+        let (output_width, output_height) = inner_lock
+            .passes
+            .output_dimensions(self.image_width, self.image_height);
+
+        let histogram_resources = HistogramResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+        )?;
+        histogram_resources.compute(
+            self.queue.clone(),
+            inner_lock.command_buffer_allocator.clone(),
+            output_width * output_height,
+            last_result,
+            bins,
+            min..=max,
+        )
+    }
+
+    /// Minimum, maximum and mean of the most recently completed frame, reduced on the GPU
+    /// in a single dispatch, e.g. to auto-scale display contrast.
+    ///
+    /// `MyError::NoInput` if no frame has completed yet. Like
+    /// [`Corrections::compute_histogram_range`] it reduces the frame as read back.
+    pub fn compute_stats(&self) -> Result<ImageStats, MyError> {
+        // Holding the read lock blocks `process_image` from reusing the frame's slot.
+        let inner_lock = self.inner.read().unwrap();
+        let last_result = self
+            .last_result
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(MyError::NoInput)?;
+        let (output_width, output_height) = inner_lock
+            .passes
+            .output_dimensions(self.image_width, self.image_height);
+
+        let image_stats_resources = ImageStatsResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+        )?;
+        image_stats_resources.compute(
+            self.queue.clone(),
+            inner_lock.command_buffer_allocator.clone(),
+            output_width * output_height,
+            last_result,
+        )
+    }
+
+    /// Reduces a stack of uncorrected frames to a single calibration frame on the GPU,
+    /// such as a master dark to pass straight to [`Corrections::enable_dark_map_correction`].
+    /// The frames must match the context's frame size; see
+    /// [`AccumulateResources::accumulate`] for the other errors.
+    pub fn accumulate_frames(
+        &self,
+        frames: &[&[u16]],
+        mode: Accumulate,
+    ) -> Result<Vec<u16>, MyError> {
+        let accumulate_resources = AccumulateResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            mode,
+        )?;
+        accumulate_resources.accumulate(
+            self.queue.clone(),
+            self.inner.read().unwrap().command_buffer_allocator.clone(),
+            self.image_width * self.image_height,
+            frames,
+        )
+    }
+
+    /// Builds a defect map from a flat exposure on the GPU, flagging pixels more than `k`
+    /// standard deviations from the median of their neighbourhood, ready to pass straight
+    /// to [`Corrections::enable_defect_correction`]. See
+    /// [`Corrections::detect_defects_with`] to change the neighbourhood.
+    pub fn detect_defects(&self, flat: &[u16], k: f32) -> Result<Vec<u16>, MyError> {
+        self.detect_defects_with(
+            flat,
+            DefectDetection {
+                threshold: k,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Like [`Corrections::detect_defects`] with both the neighbourhood radius and the
+    /// threshold of `detection`. The flat must match the context's frame size; see
+    /// [`DefectDetectionResources::detect`] for the other errors.
+    pub fn detect_defects_with(
+        &self,
+        flat: &[u16],
+        detection: DefectDetection,
+    ) -> Result<Vec<u16>, MyError> {
+        let defect_detection_resources = DefectDetectionResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+        )?;
+        defect_detection_resources.detect(
+            self.queue.clone(),
+            self.inner.read().unwrap().command_buffer_allocator.clone(),
+            self.image_width,
+            self.image_height,
+            flat,
+            detection,
+        )
+    }
+
+    /// Sets the order the correction stages are applied in, for every frame size. Stages left
+    /// out of `order` are skipped even when enabled.
+    ///
+    /// Orders that break the physics of the chain, such as gain before dark subtraction,
+    /// are rejected with `MyError::InvalidStageOrder` explaining why, unless
+    /// `allow_unusual_order` is set, in which case they are only logged.
+    pub fn set_stage_order(
+        &mut self,
+        order: Vec<CorrectionStage>,
+        allow_unusual_order: bool,
+    ) -> Result<(), MyError> {
+        for (i, stage) in order.iter().enumerate() {
+            if order[..i].contains(stage) {
+                return Err(MyError::InvalidStageOrder(format!(
+                    "{} appears more than once",
+                    stage.name()
+                )));
+            }
+        }
+
+        let position = |stage| order.iter().position(|&s| s == stage);
+        for (before, after, reason) in REQUIRED_STAGE_ORDERINGS {
+            if let (Some(b), Some(a)) = (position(before), position(after)) {
+                if a < b {
+                    let message = format!(
+                        "{} must run before {}: {reason}",
+                        before.name(),
+                        after.name()
+                    );
+                    if !allow_unusual_order {
+                        return Err(MyError::InvalidStageOrder(message));
+                    }
+                    warn!("Using unusual correction stage order, {message}");
+                }
+            }
+        }
+
+        let mut inner_lock = self.inner.write().unwrap();
+        for frame_set in inner_lock.cached_frame_sets.values_mut() {
+            frame_set.passes.stage_order = order.clone();
+        }
+        inner_lock.passes.stage_order = order;
+
+        Ok(())
+    }
+
+    /// Sets how many pixels each invocation of the dark and gain passes corrects, for
+    /// every frame size. Larger values dispatch fewer workgroups, which can help on GPUs
+    /// where the passes are bound by scheduling rather than memory bandwidth. Output is
+    /// identical for any value. Takes effect from the next recorded dispatch.
+    ///
+    /// Values of 0 or above `MAX_ITEMS_PER_INVOCATION` are `MyError::InvalidParameter`.
+    pub fn set_items_per_invocation(&self, items_per_invocation: u32) -> Result<(), MyError> {
+        if items_per_invocation == 0 || items_per_invocation > MAX_ITEMS_PER_INVOCATION {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let mut inner_lock = self.inner.write().unwrap();
+        for frame_set in inner_lock.cached_frame_sets.values_mut() {
+            frame_set.passes.items_per_invocation = items_per_invocation;
+        }
+        inner_lock.passes.items_per_invocation = items_per_invocation;
+
+        Ok(())
+    }
+
+    pub fn local_size(&self) -> u32 {
+        self.local_size
+    }
+
+    /// Sets the workgroup size the dark, gain, defect and flat-field passes enabled from
+    /// now on are built with. Passes already enabled keep theirs. Sizes the device can't
+    /// run are `MyError::InvalidParameter`.
+    pub fn set_local_size(&mut self, local_size: u32) -> Result<(), MyError> {
+        validate_local_size(&self.device, local_size)?;
+        self.local_size = local_size;
+        Ok(())
+    }
+
+    /// Times the dark pass on a dummy frame of the current size with each of
+    /// `LOCAL_SIZE_CANDIDATES` the device can run, and keeps the fastest as if by
+    /// [`Corrections::set_local_size`]. Call before enabling corrections, as passes already
+    /// enabled keep their size, and the current size is kept if the device can run none of
+    /// them. Waits for the frames in flight first so they don't skew the timings. Returns
+    /// the chosen size.
+    pub fn autotune(&mut self) -> Result<u32, MyError> {
+        // Enough back-to-back dispatches to swamp the submission overhead.
+        const DISPATCHES: u32 = 20;
+
+        self.flush()?;
+
+        let command_buffer_allocator = self.inner.read().unwrap().command_buffer_allocator.clone();
+        let pixel_count = self.image_width * self.image_height;
+        let dummy_map = vec![0u16; pixel_count as usize];
+        let dummy_frame = Buffer::new_slice::<u16>(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
+                ..Default::default()
+            },
+            pixel_count as u64,
+        )
+        .map_err(|e| MyError::AllocationError("autotune frame", e.to_string()))?;
+
+        let mut fastest = (Duration::MAX, self.local_size);
+        for local_size in LOCAL_SIZE_CANDIDATES {
+            if validate_local_size(&self.device, local_size).is_err() {
+                continue;
+            }
+
+            let resources = DarkMapBufferResources::new(
+                self.device.clone(),
+                self.queue.clone(),
+                command_buffer_allocator.clone(),
+                self.memory_allocator.clone(),
+                self.descriptor_set_allocator.clone(),
+                &dummy_map,
+                0,
+                self.image_height,
+                self.image_width,
+                local_size,
+            )?;
+            let run = || {
+                let mut builder = RecordingCommandBuffer::primary(
+                    command_buffer_allocator.clone(),
+                    self.queue.queue_family_index(),
+                    CommandBufferUsage::OneTimeSubmit,
+                )?;
+                for _ in 0..DISPATCHES {
+                    resources.apply_pipeline(
+                        &mut builder,
+                        self.image_width,
+                        self.image_height,
+                        dummy_frame.clone(),
+                    );
+                }
+                let command_buffer = builder.end()?;
+
+                let start = Instant::now();
+                let future = sync::now(self.device.clone())
+                    .then_execute(self.queue.clone(), command_buffer)
+                    .map_err(|e| MyError::SubmissionError(e.to_string()))?;
+                wait_for_submission(&self.device_lost, future)?;
+                Ok::<_, MyError>(start.elapsed())
+            };
+
+            // The first run pays for warming up the pipeline.
+            run()?;
+            let elapsed = run()?;
+            debug!("autotune: local size {local_size} took {elapsed:?}");
+            if elapsed < fastest.0 {
+                fastest = (elapsed, local_size);
+            }
+        }
+
+        let (_, local_size) = fastest;
+        self.local_size = local_size;
+        Ok(local_size)
+    }
+
+    /// Runs the enabled correction stages once on a dummy frame of the current size, so
+    /// the first real frame doesn't pay for it. Pipelines are built when their correction
+    /// is enabled, but drivers may defer compiling them until their first dispatch. Call
+    /// once the corrections are enabled.
+    pub fn warm_up(&self) -> Result<(), MyError> {
+        let (width, height, passes, command_buffer_allocator) = {
+            let inner_lock = self.inner.read().unwrap();
+            (
+                inner_lock.width,
+                inner_lock.height,
+                inner_lock.passes.clone(),
+                inner_lock.command_buffer_allocator.clone(),
+            )
+        };
+
+        let dummy_buffer = |name: &'static str| {
+            Buffer::new_slice::<u16>(
+                self.memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER
+                        | BufferUsage::TRANSFER_SRC
+                        | BufferUsage::TRANSFER_DST,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
+                    ..Default::default()
+                },
+                frame_len(width, height)?,
+            )
+            .map_err(|e| MyError::AllocationError(name, e.to_string()))
+        };
+        let image_buffer = dummy_buffer("warm-up frame")?;
+        let scratch_buffer = dummy_buffer("warm-up scratch")?;
+
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator,
+            self.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        record_corrections(
+            &mut builder,
+            &passes,
+            width,
+            height,
+            image_buffer,
+            scratch_buffer,
+            None,
+        );
+
+        let future = sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), builder.end()?)
+            .map_err(|e| MyError::SubmissionError(e.to_string()))?;
+        wait_for_submission(&self.device_lost, future)
+    }
+
+    /// Limits the dark, gain and defect passes to `roi` of frames of the current size, or
+    /// lifts the limit with `None`. Only the region's pixels are dispatched, the rest of the
+    /// frame is read back as uploaded, so results stay addressed by full-frame coordinates.
+    /// Defect correction still reads neighbours outside the region. Other stages process
+    /// the whole frame. Takes effect from the next recorded dispatch.
+    ///
+    /// A region that is empty or doesn't fit the frame is `MyError::InvalidParameter`.
+    pub fn set_roi(&self, roi: Option<Rect>) -> Result<(), MyError> {
+        if roi.is_some_and(|roi| !roi.fits(self.image_width, self.image_height)) {
+            return Err(MyError::InvalidParameter);
+        }
+
+        self.inner.write().unwrap().passes.roi = roi;
+
+        Ok(())
+    }
+
+    /// Enabled stages in the order they are applied to a frame.
+    fn stages(&self) -> Vec<CorrectionStage> {
+        let inner_lock = self.inner.read().unwrap();
+        let passes = &inner_lock.passes;
+
+        passes
+            .stage_order
+            .iter()
+            .copied()
+            .filter(|stage| match stage {
+                CorrectionStage::Deadtime => passes.deadtime_correction_resources.is_some(),
+                CorrectionStage::Dark => passes.dark_map_resources.is_some(),
+                CorrectionStage::CornerDark => passes.corner_dark_resources.is_some(),
+                CorrectionStage::FlatField => passes.flat_field_resources.is_some(),
+                CorrectionStage::Notch => passes.notch_filter_resources.is_some(),
+                CorrectionStage::Gain => passes.gain_map_resources.is_some(),
+                CorrectionStage::Defect => passes.defect_buffer_resources.is_some(),
+                CorrectionStage::Distortion => passes.distortion_resources.is_some(),
+                CorrectionStage::Log => passes.log_transform_resources.is_some(),
+                CorrectionStage::Linear => passes.linear_transform_resources.is_some(),
+                CorrectionStage::Expression => passes.expression_resources.is_some(),
+            })
+            .collect()
+    }
+
+    /// Renders the configured correction chain as a graphviz DOT graph, with the buffers
+    /// passed between stages as edge labels. Stages that write the scratch buffer lead into
+    /// a `copy` node back to the image buffer. Orientation and binning follow the stages;
+    /// lag correction, quality metrics and the output byte swap work in place and aren't
+    /// shown.
+    pub fn to_dot(&self) -> String {
+        let (oriented, binned) = {
+            let inner_lock = self.inner.read().unwrap();
+            (
+                inner_lock.passes.orientation_resources.is_some(),
+                inner_lock.passes.binning_resources.is_some(),
+            )
+        };
+        let mut passes: Vec<_> = self
+            .stages()
+            .iter()
+            .map(|stage| (stage.name(), stage.uses_scratch()))
+            .collect();
+        if oriented {
+            passes.push(("orientation", true));
+        }
+        if binned {
+            passes.push(("binning", true));
+        }
+
+        let mut dot = String::from("digraph corrections {\n    input [shape=box];\n");
+        let mut previous = "input".to_owned();
+        for (name, uses_scratch) in passes {
+            dot.push_str(&format!(
+                "    {previous} -> {name} [label=\"image_buffer\"];\n"
+            ));
+            previous = if uses_scratch {
+                let copy = format!("{name}_copy");
+                dot.push_str(&format!(
+                    "    {name} -> {copy} [label=\"scratch_buffer\"];\n"
+                ));
+                dot.push_str(&format!("    {copy} [label=\"copy\"];\n"));
+                copy
+            } else {
+                name.to_owned()
+            };
+        }
+
+        dot.push_str(&format!(
+            "    {previous} -> output [label=\"image_buffer\"];\n    output [shape=box];\n}}\n"
+        ));
+        dot
+    }
+
+    /// Switches the context to frames of `width` x `height`. Staging and image buffers and
+    /// the enabled passes are kept per size, so switching back to a previously used size
+    /// reuses them; a new size starts with fresh buffers and no passes enabled. The
+    /// `enable_*` methods configure the active size. None of the pipelines bake in the
+    /// frame size, so nothing is recompiled. Dimensions are checked as by
+    /// [`Corrections::with_shared_resources`].
+    pub fn set_frame_dimensions(&mut self, width: u32, height: u32) -> Result<(), MyError> {
+        validate_frame_dimensions(&self.device, width, height)?;
+        if (width, height) == (self.image_width, self.image_height) {
+            return Ok(());
+        }
+
+        let mut inner_lock = self.inner.write().unwrap();
+        let inner = &mut *inner_lock;
+
+        let next = match inner.cached_frame_sets.remove(&(width, height)) {
+            Some(frame_set) => frame_set,
+            None => {
+                check_memory_budget(
+                    &self.device,
+                    frame_buffers_size(width, height, self.buffer_count),
+                )?;
+                let (staging_buffers, image_buffers, scratch_buffers, readback_buffers) =
+                    allocate_frame_buffers(
+                        &self.memory_allocator,
+                        width,
+                        height,
+                        self.buffer_count,
+                    )?;
+                FrameSet {
+                    staged: vec![false; staging_buffers.len()],
+                    slot_states: Arc::new(SlotStates::new(staging_buffers.len())),
+                    staging_buffers: Arc::new(staging_buffers),
+                    image_buffers: Arc::new(image_buffers),
+                    scratch_buffers: Arc::new(scratch_buffers),
+                    readback_buffers: Arc::new(readback_buffers),
+                    passes: CorrectionPasses {
+                        stage_order: inner.passes.stage_order.clone(),
+                        items_per_invocation: inner.passes.items_per_invocation,
+                        orientation_resources: inner.passes.orientation_resources.clone(),
+                        byte_swap_resources: inner.passes.byte_swap_resources.clone(),
+                        ..Default::default()
+                    },
+                    head_index: 0,
+                }
+            }
+        };
+
+        let previous = FrameSet {
+            staging_buffers: mem::replace(&mut inner.staging_buffers, next.staging_buffers),
+            image_buffers: mem::replace(&mut inner.image_buffers, next.image_buffers),
+            scratch_buffers: mem::replace(&mut inner.scratch_buffers, next.scratch_buffers),
+            readback_buffers: mem::replace(&mut inner.readback_buffers, next.readback_buffers),
+            slot_states: mem::replace(&mut inner.slot_states, next.slot_states),
+            staged: mem::replace(&mut inner.staged, next.staged),
+            passes: mem::replace(&mut inner.passes, next.passes),
+            head_index: mem::replace(&mut inner.head_index, next.head_index),
+        };
+        inner
+            .cached_frame_sets
+            .insert((inner.width, inner.height), previous);
+
+        inner.width = width;
+        inner.height = height;
+        self.image_width = width;
+        self.image_height = height;
+        self.rows_received = vec![false; height as usize];
+
+        Ok(())
+    }
+
+    /// Stages a frame of `width` x `height`, switching sizes first if it differs from the
+    /// active one, see [`Corrections::set_frame_dimensions`].
+    pub fn upload_image_with_dimensions(
+        &mut self,
+        image: &[u16],
+        width: u32,
+        height: u32,
+    ) -> Result<(), MyError> {
+        self.set_frame_dimensions(width, height)?;
+        self.upload_image(image)
+    }
+
+    /// Blocks until the frame last claimed in the current slot has been read back, so its
+    /// buffers can be reused.
+    fn wait_for_slot(&self) {
+        let (slot_states, head_index) = {
+            let inner_lock = self.inner.read().unwrap();
+            (inner_lock.slot_states.clone(), inner_lock.head_index)
+        };
+        slot_states.wait_until_free(head_index);
+    }
+
+    fn validate_frame_len(&self, len: u64) -> Result<(), MyError> {
+        if len != (self.image_width * self.image_height) as u64 {
+            return Err(MyError::InvalidTextureData);
+        }
+        Ok(())
+    }
+
+    /// Stages `image` for the next `process_image` call.
+    ///
+    /// `image` must hold exactly one frame. A buffer holding several whole frames is
+    /// rejected with `MyError::MultipleFrames` rather than having only its first frame
+    /// processed; any other length is `MyError::InvalidTextureData`.
+    pub fn upload_image(&mut self, image: &[u16]) -> Result<(), MyError> {
+        let frame_len = (self.image_width * self.image_height) as usize;
+        if image.len() > frame_len && image.len() % frame_len == 0 {
+            return Err(MyError::MultipleFrames(image.len() / frame_len));
+        }
+        self.validate_frame_len(image.len() as u64)?;
+        self.wait_for_slot();
+
+        let mut inner_lock = self.inner.write().unwrap();
+        let head_index = inner_lock.head_index;
+        inner_lock.staging_buffers[head_index]
+            .write()
+            .unwrap()
+            .copy_from_slice(image);
+        inner_lock.staged[head_index] = true;
+
+        Ok(())
+    }
+
+    /// Stages a frame whose rows lie `row_stride_bytes` apart in `image`, as camera SDKs
+    /// deliver frames padded for alignment, skipping the padding while copying into the
+    /// staging buffer. Pixels are native-endian u16 and need not be aligned. A stride
+    /// shorter than a row, or an `image` too short to hold every row, is
+    /// `MyError::InvalidTextureData`; the last row needn't be padded.
+    pub fn upload_image_strided(
+        &mut self,
+        image: &[u8],
+        row_stride_bytes: usize,
+    ) -> Result<(), MyError> {
+        let row_bytes = self.image_width as usize * mem::size_of::<u16>();
+        let height = self.image_height as usize;
+        if row_stride_bytes < row_bytes
+            || image.len() < row_stride_bytes * height.saturating_sub(1) + row_bytes
+        {
+            return Err(MyError::InvalidTextureData);
+        }
+        self.wait_for_slot();
+
+        let mut inner_lock = self.inner.write().unwrap();
+        let head_index = inner_lock.head_index;
+        {
+            let mut staging = inner_lock.staging_buffers[head_index].write().unwrap();
+            let staging: &mut [u8] = bytemuck::cast_slice_mut(&mut staging[..]);
+            for (row, staged) in staging.chunks_exact_mut(row_bytes).enumerate() {
+                let start = row * row_stride_bytes;
+                staged.copy_from_slice(&image[start..start + row_bytes]);
+            }
+        }
+        inner_lock.staged[head_index] = true;
+
+        Ok(())
+    }
+
+    /// Copies the frame at `ptr` straight into the current slot's mapped staging buffer and
+    /// submits it as by `process_image`, for camera SDKs that hand out frames from their own
+    /// ring of pinned host memory. `len` is in pixels and must be exactly one frame,
+    /// otherwise `MyError::InvalidTextureData`. A null or misaligned `ptr` is
+    /// `MyError::InvalidParameter`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be aligned to `align_of::<u16>()` (2 bytes) and valid for reads of `len`
+    /// `u16`s for the duration of the call. The frame has been copied out when this returns,
+    /// so the SDK may hand the memory back to the camera straight away.
+    pub unsafe fn process_image_from_host_ptr(
+        &mut self,
+        ptr: *const u16,
+        len: usize,
+    ) -> Result<(), MyError> {
+        if ptr.is_null() || ptr as usize % mem::align_of::<u16>() != 0 {
+            return Err(MyError::InvalidParameter);
+        }
+        self.validate_frame_len(len as u64)?;
+        self.wait_for_slot();
+
+        {
+            let mut inner_lock = self.inner.write().unwrap();
+            let head_index = inner_lock.head_index;
+            {
+                let mut staging = inner_lock.staging_buffers[head_index].write().unwrap();
+                // The staging buffer is freshly mapped device memory, it can't overlap `ptr`.
+                unsafe { std::ptr::copy_nonoverlapping(ptr, staging.as_mut_ptr(), len) };
+            }
+            inner_lock.staged[head_index] = true;
+        }
+
+        self.process_image()
+    }
+
+    /// Writes `row_count` rows starting at `start_row` into the frame being assembled, for
+    /// detectors that read out a few rows at a time. Once every row of the frame has been
+    /// received the frame is submitted as by `process_image`, its result arriving through
+    /// `collect_results`, and `true` is returned.
+    ///
+    /// Rows may arrive in any order and in chunks of any size. A row pushed again before the
+    /// frame completes overwrites the earlier data. `rows` must hold exactly `row_count`
+    /// rows, and they must lie within the frame.
+    pub fn push_rows(
+        &mut self,
+        rows: &[u16],
+        start_row: u32,
+        row_count: u32,
+    ) -> Result<bool, MyError> {
+        let width = self.image_width as usize;
+        if rows.len() != row_count as usize * width {
+            return Err(MyError::InvalidTextureData);
+        }
+        if start_row as u64 + row_count as u64 > self.image_height as u64 {
+            return Err(MyError::InvalidParameter);
+        }
+
+        self.wait_for_slot();
+        {
+            let inner_lock = self.inner.read().unwrap();
+            let start = start_row as usize * width;
+            inner_lock.staging_buffers[inner_lock.head_index]
+                .write()
+                .unwrap()[start..start + rows.len()]
+                .copy_from_slice(rows);
+        }
+
+        let start = start_row as usize;
+        self.rows_received[start..start + row_count as usize].fill(true);
+        if !self.rows_received.iter().all(|&received| received) {
+            return Ok(false);
+        }
+
+        self.rows_received.fill(false);
+        {
+            let mut inner_lock = self.inner.write().unwrap();
+            let head_index = inner_lock.head_index;
+            inner_lock.staged[head_index] = true;
+        }
+        self.process_image()?;
+        Ok(true)
+    }
+
+    /// Stops accepting new frames, `process_image` returns `MyError::Paused` until
+    /// [`Corrections::resume`]. Frames already in flight still complete and the maps and
+    /// device resources stay alive.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Submits a trivial command buffer every `interval` from a background thread, so a
+    /// device lost while the context sits idle is noticed before the next frame, as frames
+    /// notice it when submitted. Once lost, `process_image` returns `MyError::DeviceLost`.
+    /// Replaces any running heartbeat.
+    pub fn enable_heartbeat(&mut self, interval: Duration) -> Result<(), MyError> {
+        let queue = self.queue.clone();
+        let command_buffer_allocator = self.inner.read().unwrap().command_buffer_allocator.clone();
+        let probe_buffer = Buffer::new_slice::<u32>(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
+                ..Default::default()
+            },
+            1,
+        )
+        .map_err(|e| MyError::AllocationError("heartbeat probe buffer", e.to_string()))?;
+
+        self.heartbeat = Some(Heartbeat::start(
+            interval,
+            self.device_lost.clone(),
+            move || heartbeat::submit_probe(&queue, &command_buffer_allocator, &probe_buffer),
+        ));
+        Ok(())
+    }
+
+    pub fn disable_heartbeat(&mut self) {
+        self.heartbeat = None;
+    }
+
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost.load(Ordering::Acquire)
+    }
+
+    /// Claims the staged frame of the current slot and snapshots what correcting it needs,
+    /// with the maps of the registered calibration `calibration_id` if given, leaving
+    /// recording and submission to [`FrameJob::run`].
+    fn prepare_frame(&mut self, calibration_id: Option<&str>) -> Result<FrameJob, MyError> {
+        if self.is_device_lost() {
+            return Err(MyError::DeviceLost);
+        }
+        if self.paused {
+            self.metrics.frames_dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(MyError::Paused);
+        }
+        self.wait_for_slot();
+
+        // Claim the slot and snapshot the active frame size's buffers and passes before
+        // running, so frames keep their submission order and a later size switch doesn't
+        // affect frames already submitted.
+        let (
+            head_index,
+            slot,
+            device,
+            queue,
+            command_buffer_allocator,
+            staging_buffers,
+            image_buffers,
+            scratch_buffers,
+            readback_buffers,
+            width,
+            height,
+            passes,
+            gpu_future,
+        ) = {
+            let mut inner_lock = self.inner.write().unwrap();
+            let head_index = inner_lock.head_index;
+            if !inner_lock.staged[head_index] {
+                return Err(MyError::NoInput);
+            }
+
+            let mut passes = inner_lock.passes.clone();
+            if let Some(calibration_id) = calibration_id {
+                let calibration = passes
+                    .calibrations
+                    .get(calibration_id)
+                    .cloned()
+                    .ok_or_else(|| MyError::UnknownCalibration(calibration_id.to_string()))?;
+                passes.dark_map_resources = calibration.dark_map_resources;
+                passes.gain_map_resources = calibration.gain_map_resources;
+                passes.defect_buffer_resources = calibration.defect_buffer_resources;
+            }
+
+            inner_lock.staged[head_index] = false;
+            inner_lock.head_index = (head_index + 1) % inner_lock.image_buffers.len();
+            (
+                head_index,
+                inner_lock.slot_states.claim(head_index),
+                inner_lock.device.clone(),
+                inner_lock.queue.clone(),
+                inner_lock.command_buffer_allocator.clone(),
+                inner_lock.staging_buffers.clone(),
+                inner_lock.image_buffers.clone(),
+                inner_lock.scratch_buffers.clone(),
+                inner_lock.readback_buffers.clone(),
+                inner_lock.width,
+                inner_lock.height,
+                passes,
+                inner_lock.gpu_future.clone(),
+            )
+        };
+
+        let submitted = self
+            .metrics
+            .frames_submitted
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        let in_flight = submitted - self.metrics.frames_completed.load(Ordering::Relaxed);
+        self.metrics
+            .max_in_flight
+            .fetch_max(in_flight, Ordering::Relaxed);
+        let metrics = self.metrics.clone();
+        let last_result = self.last_result.clone();
+        let last_timings = self.last_timings.clone();
+        let lag_frame = passes
+            .lag_correction_resources
+            .as_ref()
+            .as_ref()
+            .map(|lag_correction_resources| lag_correction_resources.claim_frame());
+        let ema_frame = passes
+            .temporal_ema_resources
+            .as_ref()
+            .as_ref()
+            .map(|temporal_ema_resources| temporal_ema_resources.claim_frame());
+
+        Ok(FrameJob {
+            head_index,
+            slot,
+            device,
+            queue,
+            command_buffer_allocator,
+            staging_buffers,
+            image_buffers,
+            scratch_buffers,
+            readback_buffers,
+            width,
+            height,
+            passes,
+            lag_frame,
+            ema_frame,
+            metrics,
+            last_result,
+            timestamp_queries: self.timestamp_queries.clone(),
+            last_timings,
+            submitted_at: Instant::now(),
+            conversion: None,
+            magnitude: None,
+            gpu_future,
+            device_lost: self.device_lost.clone(),
+        })
+    }
+
+    /// Corrects the frame staged by `upload_image` on a tokio task, returning
+    /// `MyError::NoInput` if nothing has been uploaded for the current slot.
+    pub fn process_image(&mut self) -> Result<(), MyError> {
+        self.spawn_frame(None)
+    }
+
+    /// Like [`Corrections::process_image`], correcting the frame with the dark, gain and
+    /// defect maps registered as `calibration_id`. An id that hasn't been registered is
+    /// `MyError::UnknownCalibration` and leaves the frame staged.
+    pub fn process_image_with_calibration(&mut self, calibration_id: &str) -> Result<(), MyError> {
+        self.spawn_frame(Some(calibration_id))
+    }
+
+    fn spawn_frame(&mut self, calibration_id: Option<&str>) -> Result<(), MyError> {
+        let job = self.prepare_frame(calibration_id)?;
+        let outstanding = self.outstanding.claim();
+        let handle = tokio::spawn(async move {
+            let frame = job.run();
+            drop(outstanding);
+            frame
+        });
+
+        self.in_flight.push_back(handle);
+
+        Ok(())
+    }
+
+    /// Waits until every frame submitted so far has finished on the GPU. Each frame's
+    /// submission is chained onto the previous one, so this waits on the tail of the chain
+    /// only.
+    pub fn flush(&self) -> Result<(), MyError> {
+        let gpu_future = self.inner.read().unwrap().gpu_future.clone();
+        self.flush_chain(&gpu_future)
+    }
+
+    /// Waits on the tail of `gpu_future`, for callers already holding the `inner` lock.
+    fn flush_chain(&self, gpu_future: &GpuFutureChain) -> Result<(), MyError> {
+        // Taken out so frames submitted while waiting start a new chain instead of blocking.
+        let Some(tail) = gpu_future.lock().unwrap().take() else {
+            return Ok(());
+        };
+
+        let flushed = tail
+            .then_signal_fence_and_flush()
+            .and_then(|future| future.wait(None))
+            .map_err(MyError::from);
+        if matches!(flushed, Err(MyError::DeviceLost)) {
+            self.device_lost.store(true, Ordering::Release);
+        }
+        flushed
+    }
+
+    /// Calls `f` with the corrected pixels of the most recently completed frame, read
+    /// straight from the mapped buffer without copying.
+    ///
+    /// New frames can't be submitted while `f` runs. Returns `None` if no frame has
+    /// completed yet, or if the frame's slot has since been reused by a frame still on the
+    /// GPU.
+    pub fn with_last_result<R>(&self, f: impl FnOnce(&[u16]) -> R) -> Option<R> {
+        // Holding the read lock blocks `process_image` from claiming a slot.
+        let _inner_lock = self.inner.read().unwrap();
+        let last_result = self.last_result.lock().unwrap().clone()?;
+        let data = last_result.read().ok()?;
+        Some(f(&data))
+    }
+
+    /// The GPU this context runs on.
+    pub fn device_info(&self) -> DeviceInfo {
+        DeviceInfo::new(self.device.physical_device())
+    }
+
+    pub fn metrics(&self) -> CorrectionMetrics {
+        CorrectionMetrics {
+            frames_submitted: self.metrics.frames_submitted.load(Ordering::Relaxed),
+            frames_completed: self.metrics.frames_completed.load(Ordering::Relaxed),
+            frames_dropped: self.metrics.frames_dropped.load(Ordering::Relaxed),
+            max_in_flight: self.metrics.max_in_flight.load(Ordering::Relaxed),
+        }
+    }
+
+    /// GPU timings of the dark, gain and defect passes of the most recently completed
+    /// frame. `None` before any frame completes, or if the device's queue can't write
+    /// timestamps.
+    pub fn last_frame_timings(&self) -> Option<CorrectionTimings> {
+        *self.last_timings.lock().unwrap()
+    }
+
+    /// Per-frame latency percentiles, from `process_image` to the corrected frame being
+    /// read back, over every frame completed since creation or the last
+    /// [`Corrections::reset_latency`].
+    pub fn latency_percentiles(&self) -> LatencyStats {
+        self.metrics.latency.stats()
+    }
+
+    pub fn reset_latency(&self) {
+        self.metrics.latency.reset();
+    }
+
+    /// Device buffer holding the frame of `slot`, for hosts recording their own commands
+    /// around [`Corrections::record_secondary`].
+    pub fn image_buffer(&self, slot: usize) -> Subbuffer<[u16]> {
+        self.inner.read().unwrap().image_buffers[slot].clone()
+    }
+
+    /// Records the enabled corrections for `slot` into a secondary command buffer without
+    /// submitting it, so a host can `execute_commands` it inside its own primary.
+    ///
+    /// The corrections read and write [`Corrections::image_buffer`] in place. Vulkano
+    /// inserts the barriers between the host's commands and the secondary when both are
+    /// recorded into the same primary, but the host must not submit the secondary while a
+    /// `process_image` call on the same slot is still in flight, nor read the buffer before
+    /// the primary's fence has signalled.
+    ///
+    /// Only the correction stages are recorded. Lag correction, the temporal average,
+    /// quality metrics, orientation, binning and the output byte swap are recorded around
+    /// them per frame, so with any of those enabled, or a `slot` past the context's buffers,
+    /// this is `MyError::InvalidParameter`.
+    pub fn record_secondary(
+        &self,
+        slot: usize,
+    ) -> Result<Arc<SecondaryAutoCommandBuffer>, MyError> {
+        let inner_lock = self.inner.read().unwrap();
+        let passes = &inner_lock.passes;
+        if slot >= inner_lock.image_buffers.len()
+            || passes.lag_correction_resources.is_some()
+            || passes.temporal_ema_resources.is_some()
+            || passes.frame_quality_resources.is_some()
+            || passes.orientation_resources.is_some()
+            || passes.binning_resources.is_some()
+            || passes.byte_swap_resources.is_some()
+        {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let mut builder = RecordingCommandBuffer::secondary(
+            inner_lock.command_buffer_allocator.clone(),
+            inner_lock.queue.queue_family_index(),
+            CommandBufferUsage::MultipleSubmit,
+            CommandBufferInheritanceInfo::default(),
+        )?;
+
+        record_corrections(
+            &mut builder,
+            &inner_lock.passes,
+            inner_lock.width,
+            inner_lock.height,
+            inner_lock.image_buffers[slot].clone(),
+            inner_lock.scratch_buffers[slot].clone(),
+            None,
+        );
+
+        Ok(builder.end()?)
+    }
+
+    /// Uploads `image`, corrects it with the enabled passes and writes the result back into
+    /// `image`. Synchronous: the corrected pixels are in place when this returns. Frames
+    /// submitted earlier through `process_image` stay in flight for `collect_results`.
+    pub fn process_image_in_place(&mut self, image: &mut [u16]) -> Result<(), MyError> {
+        self.upload_image(image)?;
+        self.correct_staged_into(image)
+    }
+
+    /// Like [`Corrections::process_image_blocking`], writing the corrected frame into `out`
+    /// instead of a new `Vec`, so streaming loops don't allocate per frame. `out` must be
+    /// one frame long, otherwise `MyError::InvalidTextureData`; a binned result only fills
+    /// its start.
+    pub fn process_image_into(&mut self, input: &[u16], out: &mut [u16]) -> Result<(), MyError> {
+        self.validate_frame_len(out.len() as u64)?;
+        self.upload_image(input)?;
+        self.correct_staged_into(out)
+    }
+
+    /// Corrects the staged frame, copying the result straight from the readback buffer into
+    /// the start of `out`.
+    fn correct_staged_into(&mut self, out: &mut [u16]) -> Result<(), MyError> {
+        let job = self.prepare_frame(None)?;
+        job.run_with(|corrected| out[..corrected.len()].copy_from_slice(corrected))?;
+        Ok(())
+    }
+
+    /// Like [`Corrections::process_image`], but hands the corrected frame to `on_complete`
+    /// on the runtime's worker thread once the GPU has finished, instead of queueing it for
+    /// `collect_results`. A frame that fails on the GPU hands over its error instead.
+    pub fn process_image_then(
+        &mut self,
+        on_complete: impl FnOnce(Result<ProcessedFrame, MyError>) + Send + 'static,
+    ) -> Result<(), MyError> {
+        let job = self.prepare_frame(None)?;
+        let outstanding = self.outstanding.claim();
+        tokio::spawn(async move {
+            on_complete(job.run());
+            drop(outstanding);
+        });
+        Ok(())
+    }
+
+    /// Like [`Corrections::process_image`], but returns the handle of the task correcting the
+    /// frame instead of queueing it for `collect_results`. Awaiting it gives the corrected
+    /// frame, or the error that kept the frame from being corrected, such as
+    /// `MyError::NoInput` or `MyError::DeviceLost`. A panic in the task surfaces as the handle's `JoinError`.
+    pub fn process_image_handle(&mut self) -> JoinHandle<Result<Vec<u16>, MyError>> {
+        match self.prepare_frame(None) {
+            Ok(job) => {
+                let outstanding = self.outstanding.claim();
+                tokio::spawn(async move {
+                    let data = job.run().map(|frame| frame.data);
+                    drop(outstanding);
+                    data
+                })
+            }
+            Err(error) => tokio::spawn(async move { Err(error) }),
+        }
+    }
+
+    /// Uploads `input`, corrects it and returns the corrected frame, blocking the calling
+    /// thread until the GPU has finished. Unlike `process_image` this needs no tokio runtime.
+    /// `input` must hold exactly one frame, otherwise `MyError::InvalidTextureData` is
+    /// returned.
+    pub fn process_image_blocking(&mut self, input: &[u16]) -> Result<Vec<u16>, MyError> {
+        self.validate_frame_len(input.len() as u64)?;
+        self.upload_image(input)?;
+        let job = self.prepare_frame(None)?;
+        job.run().map(|frame| frame.data)
+    }
+
+    /// Corrects a stack of frames with the dark, gain and defect passes, each pass recorded
+    /// once for many frames at a time, with the frames along the dispatch's z dimension,
+    /// rather than once per frame. Stacks too large for the device's memory or limits are
+    /// split into chunks of as many frames as fit.
+    ///
+    /// Only those three passes are batched: with any other stage, a region of interest or
+    /// an output pass enabled this is `MyError::InvalidParameter`, and the frames should
+    /// go through `process_image_blocking` instead.
+    pub fn process_batch(&mut self, frames: &[&[u16]]) -> Result<Vec<Vec<u16>>, MyError> {
+        if self.is_device_lost() {
+            return Err(MyError::DeviceLost);
+        }
+        if self.paused {
+            return Err(MyError::Paused);
+        }
+
+        let (width, height, passes, command_buffer_allocator) = {
+            let inner_lock = self.inner.read().unwrap();
+            (
+                inner_lock.width,
+                inner_lock.height,
+                inner_lock.passes.clone(),
+                inner_lock.command_buffer_allocator.clone(),
+            )
+        };
+        let frame_len = (width * height) as usize;
+        if frames.iter().any(|frame| frame.len() != frame_len) {
+            return Err(MyError::InvalidTextureData);
+        }
+
+        let batched = self.stages().iter().all(|stage| {
+            matches!(
+                stage,
+                CorrectionStage::Dark | CorrectionStage::Gain | CorrectionStage::Defect
+            )
+        });
+        if !batched
+            || passes.roi.is_some()
+            || passes.lag_correction_resources.is_some()
+            || passes.temporal_ema_resources.is_some()
+            || passes.frame_quality_resources.is_some()
+            || passes.orientation_resources.is_some()
+            || passes.binning_resources.is_some()
+            || passes.byte_swap_resources.is_some()
+        {
+            return Err(MyError::InvalidParameter);
+        }
+        if frames.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let batch_len = self.batch_len(width, height)?.min(frames.len());
+        let (staging_buffers, image_buffers, scratch_buffers, readback_buffers) =
+            allocate_frame_buffers(&self.memory_allocator, width, height * batch_len as u32, 1)?;
+
+        let mut corrected = Vec::with_capacity(frames.len());
+        for batch in frames.chunks(batch_len) {
+            let frame_count = batch.len() as u32;
+            let len = (batch.len() * frame_len) as u64;
+            for (staged, frame) in staging_buffers[0]
+                .write()
+                .unwrap()
+                .chunks_exact_mut(frame_len)
+                .zip(batch)
+            {
+                staged.copy_from_slice(frame);
+            }
+
+            let mut builder = RecordingCommandBuffer::primary(
+                command_buffer_allocator.clone(),
+                self.queue.queue_family_index(),
+                CommandBufferUsage::OneTimeSubmit,
+            )?;
+
+            builder.copy_buffer(CopyBufferInfo::buffers(
+                staging_buffers[0].clone().slice(..len),
+                image_buffers[0].clone().slice(..len),
+            ))?;
+
+            for stage in &passes.stage_order {
+                match stage {
+                    CorrectionStage::Dark => {
+                        if let Some(dark_map_resources) = passes.dark_map_resources.as_ref() {
+                            dark_map_resources.apply_pipeline_frames(
+                                &mut builder,
+                                width,
+                                height,
+                                frame_count,
+                                image_buffers[0].clone(),
+                            );
+                        }
+                    }
+                    CorrectionStage::Gain => {
+                        if let Some(gain_map_resources) = passes.gain_map_resources.as_ref() {
+                            gain_map_resources.apply_pipeline_frames(
+                                &mut builder,
+                                width,
+                                height,
+                                frame_count,
+                                image_buffers[0].clone(),
+                            );
+                        }
+                    }
+                    CorrectionStage::Defect => {
+                        if let Some(defect_buffer_resources) =
+                            passes.defect_buffer_resources.as_ref()
+                        {
+                            defect_buffer_resources.apply_pipeline_frames(
+                                &mut builder,
+                                width,
+                                height,
+                                frame_count,
+                                image_buffers[0].clone(),
+                                scratch_buffers[0].clone(),
+                            );
+                            builder.copy_buffer(CopyBufferInfo::buffers(
+                                scratch_buffers[0].clone().slice(..len),
+                                image_buffers[0].clone().slice(..len),
+                            ))?;
+                        }
+                    }
+                    // Rejected above if enabled.
+                    _ => {}
+                }
+            }
+
+            builder.copy_buffer(CopyBufferInfo::buffers(
+                image_buffers[0].clone().slice(..len),
+                readback_buffers[0].clone().slice(..len),
+            ))?;
+
+            let future = sync::now(self.device.clone())
+                .then_execute(self.queue.clone(), builder.end()?)
+                .map_err(|e| MyError::SubmissionError(e.to_string()))?;
+            wait_for_submission(&self.device_lost, future)?;
+
+            corrected.extend(
+                readback_buffers[0].read().unwrap()[..len as usize]
+                    .chunks_exact(frame_len)
+                    .map(<[u16]>::to_vec),
+            );
+        }
+
+        Ok(corrected)
+    }
+
+    /// Frames of `width` by `height` pixels `process_batch` corrects per submission. Its
+    /// buffers take at most half the device-local memory the context's own buffers leave,
+    /// and the stack must fit the device's dispatch and storage buffer limits.
+    fn batch_len(&self, width: u32, height: u32) -> Result<usize, MyError> {
+        let physical_device = self.device.physical_device();
+        let properties = physical_device.properties();
+
+        let per_frame = frame_buffers_size(width, height, 1);
+        let available = device_local_memory(physical_device).saturating_sub(frame_buffers_size(
+            self.image_width,
+            self.image_height,
+            self.buffer_count,
+        )) / 2;
+        if per_frame > available {
+            return Err(MyError::InsufficientMemory {
+                requested: per_frame,
+                available,
+            });
+        }
+
+        let limits = [
+            available / per_frame,
+            properties.max_compute_work_group_count[2] as u64,
+            properties.max_storage_buffer_range as u64 / frame_bytes(width, height),
+            // Buffer lengths are computed in u32 pixels.
+            u32::MAX as u64 / (width as u64 * height as u64),
+        ];
+        Ok(limits.into_iter().min().unwrap().max(1) as usize)
+    }
+
+    /// Corrects an 8-bit frame in place, for contexts created with `PixelFormat::U8`. Values
+    /// above 255 after correction saturate. Blocks until the corrected pixels are written back.
+    pub fn process_image_u8(&mut self, image: &mut [u8]) -> Result<(), MyError> {
+        self.process_converted(PixelFormat::U8, image)
+    }
+
+    /// Corrects a 32-bit frame in place, for contexts created with `PixelFormat::U32`. The
+    /// corrections run at 16 bits, so input values saturate at 65535.
+    pub fn process_image_u32(&mut self, image: &mut [u32]) -> Result<(), MyError> {
+        self.process_converted(PixelFormat::U32, bytemuck::cast_slice_mut(image))
+    }
+
+    /// Corrects a floating point frame in place, for contexts created with
+    /// `PixelFormat::F32`. Input values are rounded and clamped to `0..=65535`.
+    pub fn process_image_f32(&mut self, image: &mut [f32]) -> Result<(), MyError> {
+        self.process_converted(PixelFormat::F32, bytemuck::cast_slice_mut(image))
+    }
+
+    /// Runs a frame of `format`, given as its raw bytes, through the conversion passes and
+    /// the enabled corrections. A format other than the context's is
+    /// `MyError::InvalidParameter`.
+    fn process_converted(&mut self, format: PixelFormat, image: &mut [u8]) -> Result<(), MyError> {
+        let format_conversion_resources = match &self.format_conversion_resources {
+            Some(resources) if resources.format() == format => resources.clone(),
+            _ => return Err(MyError::InvalidParameter),
+        };
+        let pixel_count = self.image_width * self.image_height;
+        if image.len() != pixel_count as usize * format.bytes_per_pixel() {
+            return Err(MyError::InvalidTextureData);
+        }
+
+        let raw_buffer = self.raw_buffer_of(format.word_count(pixel_count))?;
+        bytemuck::cast_slice_mut::<u32, u8>(&mut raw_buffer.write().unwrap())[..image.len()]
+            .copy_from_slice(image);
+
+        let mut job = self.prepare_raw_frame()?;
+        job.conversion = Some((format_conversion_resources, raw_buffer.clone()));
+        let written = job.run()?.data.len() * format.bytes_per_pixel();
+
+        image[..written].copy_from_slice(
+            &bytemuck::cast_slice::<u32, u8>(&raw_buffer.read().unwrap())[..written],
+        );
+        Ok(())
+    }
+
+    /// Reads frames of interleaved I/Q samples from now on through `process_iq_i16` or
+    /// `process_iq_f32`, whichever matches `layout`. Each pixel's magnitude `sqrt(I² + Q²)`,
+    /// rounded and clamped to the u16 range, becomes the frame the corrections run on, so
+    /// the corrected frame has half as many values as the samples.
+    pub fn enable_magnitude(&mut self, layout: IqLayout) -> Result<(), MyError> {
+        self.magnitude_resources = Some(Arc::new(MagnitudeResources::new(
+            self.device.clone(),
+            self.descriptor_set_allocator.clone(),
+            layout,
+        )?));
+        Ok(())
+    }
+
+    /// Corrects the magnitude of a frame of interleaved 16-bit I/Q samples, see
+    /// [`Corrections::enable_magnitude`]. Blocks until the corrected frame is read back.
+    pub fn process_iq_i16(&mut self, samples: &[i16]) -> Result<Vec<u16>, MyError> {
+        self.process_iq(IqLayout::I16, bytemuck::cast_slice(samples))
+    }
+
+    /// Corrects the magnitude of a frame of interleaved floating point I/Q samples, see
+    /// [`Corrections::enable_magnitude`]. Blocks until the corrected frame is read back.
+    pub fn process_iq_f32(&mut self, samples: &[f32]) -> Result<Vec<u16>, MyError> {
+        self.process_iq(IqLayout::F32, bytemuck::cast_slice(samples))
+    }
+
+    /// Runs I/Q samples of `layout`, given as their raw bytes, through the magnitude pass
+    /// and the enabled corrections. Without magnitude enabled for `layout` this is
+    /// `MyError::InvalidParameter`; samples that aren't two per pixel are
+    /// `MyError::InvalidTextureData`.
+    fn process_iq(&mut self, layout: IqLayout, samples: &[u8]) -> Result<Vec<u16>, MyError> {
+        let magnitude_resources = match &self.magnitude_resources {
+            Some(resources) if resources.layout() == layout => resources.clone(),
+            _ => return Err(MyError::InvalidParameter),
+        };
+        let pixel_count = self.image_width * self.image_height;
+        if samples.len() != 2 * pixel_count as usize * layout.bytes_per_sample() {
+            return Err(MyError::InvalidTextureData);
+        }
+
+        let raw_buffer = self.raw_buffer_of(layout.word_count(pixel_count))?;
+        bytemuck::cast_slice_mut::<u32, u8>(&mut raw_buffer.write().unwrap())[..samples.len()]
+            .copy_from_slice(samples);
+
+        let mut job = self.prepare_raw_frame()?;
+        job.magnitude = Some((magnitude_resources, raw_buffer));
+        job.run().map(|frame| frame.data)
+    }
+
+    /// Claims the current slot for a frame read from the raw buffer instead of its staging
+    /// buffer.
+    fn prepare_raw_frame(&mut self) -> Result<FrameJob, MyError> {
+        let head_index = {
+            let mut inner_lock = self.inner.write().unwrap();
+            let head_index = inner_lock.head_index;
+            inner_lock.staged[head_index] = true;
+            head_index
+        };
+
+        self.prepare_frame(None).inspect_err(|_| {
+            self.inner.write().unwrap().staged[head_index] = false;
+        })
+    }
+
+    /// The raw buffer, reallocated unless it already holds `word_count` words.
+    fn raw_buffer_of(&mut self, word_count: u64) -> Result<Subbuffer<[u32]>, MyError> {
+        let raw_buffer = match &self.raw_buffer {
+            Some(raw_buffer) if raw_buffer.len() == word_count => raw_buffer.clone(),
+            _ => {
+                let raw_buffer = Buffer::new_slice::<u32>(
+                    self.memory_allocator.clone(),
+                    BufferCreateInfo {
+                        usage: BufferUsage::STORAGE_BUFFER,
+                        ..Default::default()
+                    },
+                    AllocationCreateInfo {
+                        memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                            | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                        ..Default::default()
+                    },
+                    word_count,
+                )
+                .map_err(|e| MyError::AllocationError("raw frame buffer", e.to_string()))?;
+                self.raw_buffer = Some(raw_buffer.clone());
+                raw_buffer
+            }
+        };
+        Ok(raw_buffer)
+    }
+
+    /// Corrects `input` and returns a display preview of it: binned down by `scale`,
+    /// averaging each block, then upscaled back with `interp`, all on the GPU. Rows and
+    /// columns that don't fill a whole block are dropped, so the preview can be slightly
+    /// smaller than the frame. Blocks until the preview is read back; like
+    /// `process_image_blocking` it needs no tokio runtime.
+    pub fn preview(
+        &mut self,
+        input: &[u16],
+        scale: u32,
+        interp: Interp,
+    ) -> Result<Preview, MyError> {
+        // The image buffer holds the frame oriented.
+        let (width, height) = self.output_dimensions();
+        if scale == 0 || scale > width || scale > height {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let slot = self.inner.read().unwrap().head_index;
+        self.process_image_blocking(input)?;
+
+        let (image_buffer, command_buffer_allocator) = {
+            let inner_lock = self.inner.read().unwrap();
+            (
+                inner_lock.image_buffers[slot].clone(),
+                inner_lock.command_buffer_allocator.clone(),
+            )
+        };
+        let preview_resources = PreviewResources::new(
+            self.device.clone(),
+            self.memory_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+        );
+
+        preview_resources.compute(
+            self.queue.clone(),
+            command_buffer_allocator,
+            width,
+            height,
+            image_buffer,
+            scale,
+            interp,
+        )
+    }
+
+    /// Like [`Corrections::process_image_in_place`], but leaves the corrected frame in a new
+    /// device buffer instead of copying it to the host, for callers that consume it on the
+    /// GPU. The buffer is host-visible so it can still be read back when needed, and stays
+    /// valid however many frames are processed after it.
+    pub fn process_image_to_buffer(&mut self, image: &[u16]) -> Result<Subbuffer<[u16]>, MyError> {
+        let slot = self.inner.read().unwrap().head_index;
+        self.upload_image(image)?;
+        let (output_len, _) = self.prepare_frame(None)?.run_with(<[u16]>::len)?;
+        let output_len = output_len as u64;
+
+        let result = Buffer::new_slice::<u16>(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER
+                    | BufferUsage::TRANSFER_SRC
+                    | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            output_len,
+        )
+        .map_err(|e| MyError::AllocationError("device result buffer", e.to_string()))?;
+
+        let inner_lock = self.inner.read().unwrap();
+        let mut builder = RecordingCommandBuffer::primary(
+            inner_lock.command_buffer_allocator.clone(),
+            self.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        builder.copy_buffer(CopyBufferInfo::buffers(
+            inner_lock.image_buffers[slot].clone().slice(..output_len),
+            result.clone(),
+        ))?;
+        let command_buffer = builder.end()?;
+        drop(inner_lock);
+
+        let copied = sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer)
+            .map_err(|e| MyError::SubmissionError(e.to_string()))
+            .and_then(|future| {
+                future
+                    .then_signal_fence_and_flush()
+                    .and_then(|future| future.wait(None))
+                    .map_err(MyError::from)
+            });
+        if matches!(copied, Err(MyError::DeviceLost)) {
+            self.device_lost.store(true, Ordering::Release);
+        }
+        copied?;
+
+        Ok(result)
+    }
+
+    /// Waits for every frame submitted through `process_image` and returns the corrected
+    /// data in submission order. If any frame failed, the first failure is returned once
+    /// they have all finished.
+    ///
+    /// Blocks the calling thread. On a multi-thread runtime's worker the worker's other tasks
+    /// are handed off meanwhile, so the frames can still finish, but it panics on a
+    /// current-thread runtime. Async code should await `process_image_handle` instead.
+    pub fn collect_results(&mut self) -> Result<Vec<Vec<u16>>, MyError> {
+        Ok(self
+            .collect_frames()?
+            .into_iter()
+            .map(|frame| frame.data)
+            .collect())
+    }
+
+    /// Like [`Corrections::collect_results`], but keeps each frame's quality metrics.
+    pub fn collect_frames(&mut self) -> Result<Vec<ProcessedFrame>, MyError> {
+        let handles: Vec<_> = self.in_flight.drain(..).collect();
+
+        // Moves the worker's other tasks to other workers while blocked, so the frames waited
+        // for can't be stuck behind it.
+        task::block_in_place(|| futures::executor::block_on(futures::future::join_all(handles)))
+            .into_iter()
+            // A task only fails to join by panicking, which is passed on as it is.
+            .map(|result| result.unwrap_or_else(|error| panic::resume_unwind(error.into_panic())))
+            .collect()
+    }
+
+    /// Tears down the context's outstanding work so its resources can be freed safely, and
+    /// is called on drop. Frames queued for `collect_results` that haven't started are
+    /// cancelled and the rest discarded. Frames of `process_image_then` and
+    /// `process_image_handle` are waited for, callbacks included, then the GPU is flushed.
+    ///
+    /// Blocks the calling thread, so the runtime's other workers must be free to finish the
+    /// frames: don't call it, or drop a context with frames outstanding, from a
+    /// current-thread runtime.
+    pub fn shutdown(&mut self) -> Result<(), MyError> {
+        for handle in self.in_flight.drain(..) {
+            handle.abort();
+        }
+        self.outstanding.wait_all();
+        self.flush()
+    }
+}
+
+impl Drop for Corrections {
+    fn drop(&mut self) {
+        if let Err(error) = self.shutdown() {
+            warn!("outstanding work failed while dropping corrections: {error}");
+        }
+
+        if let Some((pipeline_cache, path)) = self.pipeline_cache.take() {
+            unregister_pipeline_cache(&pipeline_cache);
+            let saved = pipeline_cache
+                .get_data()
+                .map_err(|e| e.to_string())
+                .and_then(|data| fs::write(&path, data).map_err(|e| e.to_string()));
+            if let Err(error) = saved {
+                warn!("failed to save pipeline cache {}: {error}", path.display());
+            }
+        }
+    }
+}
+
+/// Everything needed to correct one claimed frame, independent of the `Corrections` it came
+/// from so it can run on another thread.
+struct FrameJob {
+    head_index: usize,
+    /// Held until the frame is read back.
+    slot: SlotClaim,
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    staging_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    image_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    scratch_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    readback_buffers: Arc<Vec<Subbuffer<[u16]>>>,
+    width: u32,
+    height: u32,
+    passes: CorrectionPasses,
+    /// Given up if the job is dropped before lag correction, so later frames still get
+    /// their turn.
+    lag_frame: Option<FrameTurn>,
+    /// Likewise for the temporal average.
+    ema_frame: Option<FrameTurn>,
+    metrics: Arc<MetricCounters>,
+    last_result: Arc<Mutex<Option<Subbuffer<[u16]>>>>,
+    timestamp_queries: Option<Arc<TimestampQueries>>,
+    last_timings: Arc<Mutex<Option<CorrectionTimings>>>,
+    submitted_at: Instant,
+    /// Set for frames in another pixel format: they are unpacked from the raw buffer
+    /// instead of copied from staging, and packed back into it once corrected.
+    conversion: Option<(Arc<FormatConversionResources>, Subbuffer<[u32]>)>,
+    /// Set for I/Q frames: the magnitude of the samples in the raw buffer is the frame,
+    /// instead of a copy from staging.
+    magnitude: Option<(Arc<MagnitudeResources>, Subbuffer<[u32]>)>,
+    gpu_future: GpuFutureChain,
+    device_lost: Arc<AtomicBool>,
+}
+
+impl FrameJob {
+    /// Records and submits the frame's corrections and blocks until its result is read back.
+    /// A lost device also flags the context as lost, see `Corrections::is_device_lost`.
+    fn run(self) -> Result<ProcessedFrame, MyError> {
+        let (data, quality) = self.run_with(<[u16]>::to_vec)?;
+        Ok(ProcessedFrame { data, quality })
+    }
+
+    /// Like `run`, handing the corrected pixels to `read` straight from the mapped readback
+    /// buffer instead of copying them into a new `Vec`.
+    fn run_with<R>(
+        self,
+        read: impl FnOnce(&[u16]) -> R,
+    ) -> Result<(R, Option<FrameQuality>), MyError> {
+        let device_lost = self.device_lost.clone();
+        let result = self.submit_and_read(read);
+        if matches!(result, Err(MyError::DeviceLost)) {
+            device_lost.store(true, Ordering::Release);
+        }
+        result
+    }
+
+    /// `run_with`, leaving a lost device to the caller to flag.
+    fn submit_and_read<R>(
+        self,
+        read: impl FnOnce(&[u16]) -> R,
+    ) -> Result<(R, Option<FrameQuality>), MyError> {
+        let FrameJob {
+            head_index,
+            slot: _slot,
+            device,
+            queue,
+            command_buffer_allocator,
+            staging_buffers,
+            image_buffers,
+            scratch_buffers,
+            readback_buffers,
+            width,
+            height,
+            passes,
+            lag_frame,
+            ema_frame,
+            metrics,
+            last_result,
+            timestamp_queries,
+            last_timings,
+            submitted_at,
+            conversion,
+            magnitude,
+            gpu_future,
+            device_lost: _,
+        } = self;
+
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator.clone(),
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+
+        match (&magnitude, &conversion) {
+            (Some((magnitude_resources, samples_buffer)), _) => magnitude_resources.apply_pipeline(
+                &mut builder,
+                width,
+                height,
+                samples_buffer.clone(),
+                image_buffers[head_index].clone(),
+            ),
+            (None, Some((format_conversion_resources, raw_buffer))) => format_conversion_resources
+                .unpack(
+                    &mut builder,
+                    width,
+                    height,
+                    raw_buffer.clone(),
+                    image_buffers[head_index].clone(),
+                ),
+            (None, None) => {
+                builder.copy_buffer(CopyBufferInfo::buffers(
+                    staging_buffers[head_index].clone(),
+                    image_buffers[head_index].clone(),
+                ))?;
+            }
+        }
+
+        if let Some(timestamp_queries) = &timestamp_queries {
+            timestamp_queries.reset(&mut builder, head_index);
+        }
+        let timed = record_corrections(
+            &mut builder,
+            &passes,
+            width,
+            height,
+            image_buffers[head_index].clone(),
+            scratch_buffers[head_index].clone(),
+            timestamp_queries
+                .as_deref()
+                .map(|timestamp_queries| (timestamp_queries, head_index)),
+        );
+
+        // Lag correction needs the previous frames corrected first, and the temporal average
+        // the previous frames folded in, so each waits its turn in a submission of its own
+        // and the remaining passes follow in another. The average is taken here, while the
+        // frame still has the sensor's layout and byte order.
+        let lag = passes
+            .lag_correction_resources
+            .as_ref()
+            .as_ref()
+            .zip(lag_frame);
+        let ema = passes
+            .temporal_ema_resources
+            .as_ref()
+            .as_ref()
+            .zip(ema_frame);
+        if lag.is_some() || ema.is_some() {
+            sync::now(device.clone())
+                .then_execute(queue.clone(), builder.end()?)
+                .map_err(|e| MyError::SubmissionError(e.to_string()))?
+                .then_signal_fence_and_flush()?
+                .wait(None)?;
+
+            // Waiting for the turn blocks, possibly on frames queued behind this task.
+            if let Some((lag_correction_resources, lag_frame)) = lag {
+                blocking(|| {
+                    lag_correction_resources.apply(
+                        lag_frame,
+                        queue.clone(),
+                        command_buffer_allocator.clone(),
+                        width,
+                        height,
+                        image_buffers[head_index].clone(),
+                    )
+                })?;
+            }
+            if let Some((temporal_ema_resources, ema_frame)) = ema {
+                blocking(|| {
+                    temporal_ema_resources.update(
+                        ema_frame,
+                        queue.clone(),
+                        command_buffer_allocator.clone(),
+                        width,
+                        height,
+                        image_buffers[head_index].clone(),
+                    )
+                })?;
+            }
+
+            builder = RecordingCommandBuffer::primary(
+                command_buffer_allocator.clone(),
+                queue.queue_family_index(),
+                CommandBufferUsage::OneTimeSubmit,
+            )?;
+        }
+
+        let quality_readback = passes
+            .frame_quality_resources
+            .as_ref()
+            .as_ref()
+            .map(|resources| {
+                resources.apply_pipeline(
+                    &mut builder,
+                    width,
+                    height,
+                    image_buffers[head_index].clone(),
+                )
+            });
+
+        if let Some(orientation_resources) = passes.orientation_resources.as_ref() {
+            orientation_resources.apply_pipeline(
+                &mut builder,
+                width,
+                height,
+                image_buffers[head_index].clone(),
+                scratch_buffers[head_index].clone(),
+            );
+            builder.copy_buffer(CopyBufferInfo::buffers(
+                scratch_buffers[head_index].clone(),
+                image_buffers[head_index].clone(),
+            ))?;
+        }
+
+        // Only the start of the image buffer holds the frame once binned.
+        let (output_width, output_height) = passes.output_dimensions(width, height);
+        let output_len = (output_width * output_height) as usize;
+        if let Some(binning_resources) = passes.binning_resources.as_ref() {
+            let (oriented_width, oriented_height) =
+                passes.orientation().output_dimensions(width, height);
+            binning_resources.apply_pipeline(
+                &mut builder,
+                oriented_width,
+                oriented_height,
+                image_buffers[head_index].clone(),
+                scratch_buffers[head_index].clone(),
+            );
+            builder.copy_buffer(CopyBufferInfo::buffers(
+                scratch_buffers[head_index]
+                    .clone()
+                    .slice(..output_len as u64),
+                image_buffers[head_index].clone().slice(..output_len as u64),
+            ))?;
+        }
+
+        if let Some((format_conversion_resources, raw_buffer)) = &conversion {
+            format_conversion_resources.pack(
+                &mut builder,
+                output_width,
+                output_height,
+                image_buffers[head_index].clone(),
+                raw_buffer.clone(),
+            );
+        }
+
+        if let Some(byte_swap_resources) = passes.byte_swap_resources.as_ref() {
+            byte_swap_resources.apply_pipeline(
+                &mut builder,
+                output_width,
+                output_height,
+                image_buffers[head_index].clone(),
+            );
+        }
+
+        // Into the slot's host-cached buffer, so the frame is read back from there while
+        // the next slots compute.
+        builder.copy_buffer(CopyBufferInfo::buffers(
+            image_buffers[head_index].clone().slice(..output_len as u64),
+            readback_buffers[head_index]
+                .clone()
+                .slice(..output_len as u64),
+        ))?;
+
+        let command_buffer = builder.end()?;
+
+        // Chained onto the previous frame's submission rather than an idle `sync::now`,
+        // which becomes the new tail. Only this frame's own fence is waited on, for its
+        // readback.
+        let future = {
+            let mut tail = gpu_future.lock().unwrap();
+            let previous = match tail.take() {
+                Some(mut previous) => {
+                    // Releases the submissions that have finished, so the chain stays short.
+                    previous.cleanup_finished();
+                    previous
+                }
+                None => sync::now(device.clone()).boxed_send_sync(),
+            };
+            let future = previous
+                .then_execute(queue.clone(), command_buffer)
+                .map_err(|e| MyError::SubmissionError(e.to_string()))?
+                .then_signal_fence_and_flush()
+                .map(Arc::new)?;
+            *tail = Some(future.clone().boxed_send_sync());
+            future
+        };
+
+        future.wait(None)?;
+        drop(future);
+
+        let data = read(&readback_buffers[head_index].read().unwrap()[..output_len]);
+        metrics.frames_completed.fetch_add(1, Ordering::Relaxed);
+        metrics.latency.record(submitted_at.elapsed());
+        *last_result.lock().unwrap() = Some(readback_buffers[head_index].clone());
+        if let Some(timestamp_queries) = &timestamp_queries {
+            *last_timings.lock().unwrap() = Some(timestamp_queries.read(head_index, &timed));
+        }
+        Ok((data, quality_readback.map(|readback| readback.read())))
+    }
+}
+
+/// Runs `f`, which blocks, so a multi-thread runtime's worker hands its other tasks to other
+/// threads meanwhile. Elsewhere `f` just runs, as a current-thread runtime can't hand off.
+fn blocking<R>(f: impl FnOnce() -> R) -> R {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::CurrentThread => f(),
+        _ => task::block_in_place(f),
+    }
+}
+
+/// Flushes `future` and blocks until it has finished, flagging `device_lost` if the device
+/// was lost meanwhile.
+fn wait_for_submission(device_lost: &AtomicBool, future: impl GpuFuture) -> Result<(), MyError> {
+    let waited = future
+        .then_signal_fence_and_flush()
+        .and_then(|future| future.wait(None))
+        .map_err(MyError::from);
+    if matches!(waited, Err(MyError::DeviceLost)) {
+        device_lost.store(true, Ordering::Release);
+    }
+    waited
+}
+
+fn frame_bytes(image_width: u32, image_height: u32) -> u64 {
+    image_width as u64 * image_height as u64 * mem::size_of::<u16>() as u64
+}
+
+/// Returns `MyError::InvalidTextureData` unless frames of `width` by `height` have pixels and
+/// fit the storage buffers every pass binds them as, and `MyError::AllocationError` if their
+/// pixels overflow the shaders' 32-bit indices, see [`frame_len`].
+fn validate_frame_dimensions(device: &Device, width: u32, height: u32) -> Result<(), MyError> {
+    if width == 0 || height == 0 {
+        return Err(MyError::InvalidTextureData);
+    }
+    let frame_len = frame_len(width, height)?;
+    let max_storage_buffer_range = device
+        .physical_device()
+        .properties()
+        .max_storage_buffer_range as u64;
+    if frame_len * mem::size_of::<u16>() as u64 > max_storage_buffer_range {
+        return Err(MyError::InvalidTextureData);
+    }
+    Ok(())
+}
+
+/// Bytes `allocate_frame_buffers` allocates, saturating so an absurd buffer count fails the
+/// memory budget instead of wrapping around.
+fn frame_buffers_size(image_width: u32, image_height: u32, buffer_count: u32) -> u64 {
+    frame_bytes(image_width, image_height).saturating_mul(4 * buffer_count as u64)
+}
+
+/// Checks `requested` bytes fit in the device's device-local heaps before allocating, so
+/// a context that can never fit fails with `MyError::InsufficientMemory` instead of deep
+/// in the allocator. Host-visible staging memory is counted too, which errs on the safe
+/// side for GPUs with separate host memory.
+fn check_memory_budget(device: &Device, requested: u64) -> Result<(), MyError> {
+    let available = device_local_memory(device.physical_device());
+    if requested > available {
+        return Err(MyError::InsufficientMemory {
+            requested,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// Total size of the device-local heaps of `physical_device`.
+fn device_local_memory(physical_device: &PhysicalDevice) -> u64 {
+    physical_device
+        .memory_properties()
+        .memory_heaps
+        .iter()
+        .filter(|heap| heap.flags.intersects(MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|heap| heap.size)
+        .sum()
+}
+
+/// Buffers of one frame, one per slot.
+type SlotBuffers = Vec<Subbuffer<[u16]>>;
+
+/// Allocates `buffer_count` host staging buffers, device image buffers, device scratch
+/// buffers and host readback buffers of one frame.
+fn allocate_frame_buffers(
+    memory_allocator: &Arc<StandardMemoryAllocator>,
+    image_width: u32,
+    image_height: u32,
+    buffer_count: u32,
+) -> Result<(SlotBuffers, SlotBuffers, SlotBuffers, SlotBuffers), MyError> {
+    let mut staging_buffers = Vec::new();
+    let mut image_buffers = Vec::new();
+    let mut scratch_buffers = Vec::new();
+    let mut readback_buffers = Vec::new();
+
+    let frame_len = frame_len(image_width, image_height)?;
+    for _ in 0..buffer_count {
+        staging_buffers.push(
+            Buffer::new_slice::<u16>(
+                memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::TRANSFER_SRC | BufferUsage::STORAGE_BUFFER,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                        | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                    ..Default::default()
+                },
+                frame_len,
+            )
+            .map_err(|e| MyError::AllocationError("staging buffer", e.to_string()))?,
+        );
+
+        image_buffers.push(
+            Buffer::new_slice::<u16>(
+                memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER
+                        | BufferUsage::TRANSFER_SRC
+                        | BufferUsage::TRANSFER_DST,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                        | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                    ..Default::default()
+                },
+                frame_len,
+            )
+            .map_err(|e| MyError::AllocationError("image buffer", e.to_string()))?,
+        );
+
+        scratch_buffers.push(
+            Buffer::new_slice::<u16>(
+                memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
+                    ..Default::default()
+                },
+                frame_len,
+            )
+            .map_err(|e| MyError::AllocationError("scratch buffer", e.to_string()))?,
+        );
+
+        readback_buffers.push(
+            Buffer::new_slice::<u16>(
+                memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                        | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                    ..Default::default()
+                },
+                frame_len,
+            )
+            .map_err(|e| MyError::AllocationError("readback buffer", e.to_string()))?,
+        );
+    }
+
+    Ok((
+        staging_buffers,
+        image_buffers,
+        scratch_buffers,
+        readback_buffers,
+    ))
+}
+
+/// Copies of each row of `roi` between two frames `width` pixels wide, a single copy when
+/// the region spans whole rows.
+fn roi_copy_regions<C: FromIterator<BufferCopy>>(roi: Rect, width: u32) -> C {
+    let (rows, row_pixels) = if roi.w == width {
+        (roi.y..roi.y + 1, roi.pixel_count())
+    } else {
+        (roi.y..roi.y + roi.h, roi.w)
+    };
+    let bytes = |pixels: u32| pixels as u64 * mem::size_of::<u16>() as u64;
+
+    rows.map(|row| BufferCopy {
+        src_offset: bytes(row * width + roi.x),
+        dst_offset: bytes(row * width + roi.x),
+        size: bytes(row_pixels),
+        ..Default::default()
+    })
+    .collect()
+}
+
+/// Records the enabled correction passes over `image_buffer` into a single command buffer,
+/// in stage order. Vulkano inserts the barriers between consecutive dispatches. Defect and
+/// distortion correction can't work in place, so they write to `scratch_buffer` and are
+/// copied back.
+///
+/// With `timestamps`, the dark, gain and defect passes are timed in the given slot's
+/// queries, and the passes that were are returned.
+fn record_corrections<L>(
+    builder: &mut RecordingCommandBuffer<L>,
+    passes: &CorrectionPasses,
+    width: u32,
+    height: u32,
+    image_buffer: Subbuffer<[u16]>,
+    scratch_buffer: Subbuffer<[u16]>,
+    timestamps: Option<(&TimestampQueries, usize)>,
+) -> Vec<TimedPass> {
+    let roi = passes.roi.unwrap_or(Rect::full(width, height));
+    let mut timed = Vec::new();
+    let mut time = |builder: &mut RecordingCommandBuffer<L>,
+                    pass: TimedPass,
+                    record: &mut dyn FnMut(&mut RecordingCommandBuffer<L>)| {
+        match timestamps {
+            Some((timestamp_queries, slot)) => {
+                timestamp_queries.time(builder, slot, pass, record);
+                timed.push(pass);
+            }
+            None => record(builder),
+        }
+    };
+
+    for stage in &passes.stage_order {
+        match stage {
+            CorrectionStage::Deadtime => {
+                if let Some(deadtime_correction_resources) =
+                    passes.deadtime_correction_resources.as_ref()
+                {
+                    deadtime_correction_resources.apply_pipeline(
+                        builder,
+                        width,
+                        height,
+                        image_buffer.clone(),
+                    );
+                }
+            }
+            CorrectionStage::Dark => {
+                if let Some(dark_map_resources) = passes.dark_map_resources.as_ref() {
+                    time(builder, TimedPass::Dark, &mut |builder| {
+                        dark_map_resources.apply_pipeline_in(
+                            builder,
+                            width,
+                            height,
+                            roi,
+                            passes.items_per_invocation,
+                            image_buffer.clone(),
+                        )
+                    });
+                }
+            }
+            CorrectionStage::CornerDark => {
+                if let Some(corner_dark_resources) = passes.corner_dark_resources.as_ref() {
+                    corner_dark_resources.apply_pipeline(
+                        builder,
+                        width,
+                        height,
+                        image_buffer.clone(),
+                    );
+                }
+            }
+            CorrectionStage::FlatField => {
+                if let Some(flat_field_resources) = passes.flat_field_resources.as_ref() {
+                    flat_field_resources.apply_pipeline(
+                        builder,
+                        width,
+                        height,
+                        image_buffer.clone(),
+                    );
+                }
+            }
+            CorrectionStage::Notch => {
+                if let Some(notch_filter_resources) = passes.notch_filter_resources.as_ref() {
+                    notch_filter_resources.apply_pipeline(
+                        builder,
+                        width,
+                        height,
+                        image_buffer.clone(),
+                    );
+                }
+            }
+            CorrectionStage::Log => {
+                if let Some(log_transform_resources) = passes.log_transform_resources.as_ref() {
+                    log_transform_resources.apply_pipeline(
+                        builder,
+                        width,
+                        height,
+                        image_buffer.clone(),
+                    );
+                }
+            }
+            CorrectionStage::Linear => {
+                if let Some(linear_transform_resources) = passes.linear_transform_resources.as_ref()
+                {
+                    linear_transform_resources.apply_pipeline(
+                        builder,
+                        width,
+                        height,
+                        image_buffer.clone(),
+                    );
+                }
+            }
+            CorrectionStage::Expression => {
+                if let Some(expression_resources) = passes.expression_resources.as_ref() {
+                    expression_resources.apply_pipeline(
+                        builder,
+                        width,
+                        height,
+                        image_buffer.clone(),
+                    );
+                }
+            }
+            CorrectionStage::Gain => {
+                if let Some(gain_map_resources) = passes.gain_map_resources.as_ref() {
+                    time(builder, TimedPass::Gain, &mut |builder| {
+                        gain_map_resources.apply_pipeline_in(
+                            builder,
+                            width,
+                            height,
+                            roi,
+                            passes.items_per_invocation,
+                            image_buffer.clone(),
+                        )
+                    });
+                }
+            }
+            CorrectionStage::Defect => {
+                if let Some(defect_buffer_resources) = passes.defect_buffer_resources.as_ref() {
+                    time(builder, TimedPass::Defect, &mut |builder| {
+                        defect_buffer_resources.apply_pipeline_in(
+                            builder,
+                            width,
+                            height,
+                            roi,
+                            image_buffer.clone(),
+                            scratch_buffer.clone(),
+                        );
+                        builder
+                            .copy_buffer(CopyBufferInfo {
+                                regions: roi_copy_regions(roi, width),
+                                ..CopyBufferInfo::buffers(
+                                    scratch_buffer.clone(),
+                                    image_buffer.clone(),
+                                )
+                            })
+                            .unwrap();
+                    });
+                }
+            }
+            CorrectionStage::Distortion => {
+                if let Some(distortion_resources) = passes.distortion_resources.as_ref() {
+                    distortion_resources.apply_pipeline(
+                        builder,
+                        width,
+                        height,
+                        image_buffer.clone(),
+                        scratch_buffer.clone(),
+                    );
+                    builder
+                        .copy_buffer(CopyBufferInfo::buffers(
+                            scratch_buffer.clone(),
+                            image_buffer.clone(),
+                        ))
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    timed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    use vulkano::{
+        buffer::{Buffer, BufferCreateInfo, BufferUsage},
+        command_buffer::{CommandBufferUsage, CopyBufferInfo, RecordingCommandBuffer},
+        memory::allocator::{AllocationCreateInfo, MemoryTypeFilter},
+        sync::{self, GpuFuture},
+        VulkanLibrary,
+    };
+
+    use super::{
+        initialise_gpu_resources, initialise_gpu_resources_with,
+        initialise_gpu_resources_with_validation, CorrectionMetrics, CorrectionStage, Corrections,
+        GpuDeviceType, GpuSelectionOptions, MyError, ParamValue, SharedGpuResources,
+        DEBUG_MESSENGERS, VALIDATION_LAYER,
+    };
+    use crate::core::{
+        corrections::{
+            accumulate::Accumulate,
+            binning::BinMode,
+            defect_detection::DefectDetection,
+            format_conversion::PixelFormat,
+            gain_correction::{quantize_gain_map, DEFAULT_GAIN_FRAC_BITS, MAX_GAIN_FRAC_BITS},
+            magnitude::IqLayout,
+            notch_filter::NotchAxis,
+            orientation::Orientation,
+            preview::Interp,
+            uses_pixel_words, Rect, DEFAULT_LOCAL_SIZE, LOCAL_SIZE_CANDIDATES,
+            MAX_ITEMS_PER_INVOCATION,
+        },
+        latency::LatencyStats,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test() {
+        let gpu_resources = initialise_gpu_resources();
+        let image_width: u32 = 4800;
+        let image_height: u32 = 5800;
+        let offset = 300;
+        let buffer_count = 10;
+
+        let mut correction_context = Corrections::new(
+            gpu_resources.1.clone(),
+            gpu_resources.0.clone(),
+            image_width,
+            image_height,
+            buffer_count,
+        )
+        .unwrap();
+        let mut image = vec![10u16; (image_height * image_width) as usize];
+        let mut defect_map = vec![1u16; (image_height * image_width) as usize];
+        let gain_map = vec![0.5f32; (image_height * image_width) as usize];
+        let dark_map = vec![1u16; (image_height * image_width) as usize];
+
+        defect_map[0] = 1;
+        defect_map[1] = 0;
+        defect_map[2] = 0;
+        image[1] = 20;
+        image[2] = 10;
+
+        correction_context
+            .enable_dark_map_correction(&dark_map, offset)
+            .unwrap();
+        //correction_context.enable_gain_correction(&gain_map);
+        //correction_context.enable_defect_correction(&defect_map);
+        let time = Instant::now();
+
+        for i in 0..buffer_count {
+            correction_context.upload_image(&image).unwrap();
+            correction_context.process_image().unwrap();
+        }
+        println!("Time to process image {:?}", time.elapsed() / buffer_count);
+        correction_context.collect_results().unwrap();
+    }
+
+    #[test]
+    fn device_info_describes_the_device() {
+        let (queue, device) = initialise_gpu_resources();
+        let correction_context = Corrections::new(device.clone(), queue, 64, 64, 1).unwrap();
+        let info = correction_context.device_info();
+        let properties = device.physical_device().properties();
+
+        assert!(properties.device_name.starts_with(&info.name()));
+        assert!(!info.name().is_empty());
+        assert_eq!(
+            info.device_type,
+            GpuDeviceType::from(properties.device_type)
+        );
+        assert_eq!(info.driver_version, properties.driver_version);
+        assert!(info.vram_bytes > 0);
+    }
+
+    #[test]
+    fn degenerate_frame_sizes_are_rejected() {
+        let (queue, device) = initialise_gpu_resources();
+        for (width, height) in [(0, 0), (0, 16), (16, 0)] {
+            assert!(matches!(
+                Corrections::new(device.clone(), queue.clone(), width, height, 1),
+                Err(MyError::InvalidTextureData)
+            ));
+        }
+
+        let mut correction_context = Corrections::new(device, queue, 16, 16, 1).unwrap();
+        assert!(matches!(
+            correction_context.set_frame_dimensions(0, 16),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+
+    #[test]
+    fn frames_overflowing_32_bit_pixel_counts_are_rejected() {
+        let (queue, device) = initialise_gpu_resources();
+        // 70000 x 70000 wraps a u32 pixel count round to a few hundred megapixels, and
+        // 65536 x 65536 to none at all.
+        for (width, height) in [(70000, 70000), (65536, 65536)] {
+            assert!(matches!(
+                Corrections::new(device.clone(), queue.clone(), width, height, 1),
+                Err(MyError::AllocationError(..))
+            ));
+        }
+
+        let mut correction_context = Corrections::new(device, queue, 16, 16, 1).unwrap();
+        assert!(matches!(
+            correction_context.set_frame_dimensions(70000, 70000),
+            Err(MyError::AllocationError(..))
+        ));
+        assert_eq!(correction_context.image_width, 16);
+    }
+
+    #[test]
+    fn single_pixel_and_single_row_frames_are_corrected() {
+        let (queue, device) = initialise_gpu_resources();
+        for (width, height) in [(1u32, 1u32), (1, 37), (37, 1)] {
+            let size = (width * height) as usize;
+            let mut correction_context =
+                Corrections::new(device.clone(), queue.clone(), width, height, 1).unwrap();
+            correction_context
+                .enable_dark_map_correction(&vec![100u16; size], 300)
+                .unwrap();
+            let mut defect_map = vec![0u16; size];
+            defect_map[size / 2] = 1;
+            correction_context
+                .enable_defect_correction(&defect_map)
+                .unwrap();
+
+            let result = correction_context
+                .process_image_blocking(&vec![1000u16; size])
+                .unwrap();
+            // A lone pixel has no neighbours to take a value from and keeps its own.
+            assert!(
+                result.iter().all(|&pixel| pixel == 1000 - 100 + 300),
+                "{width}x{height}"
+            );
+        }
+    }
+
+    #[test]
+    fn contexts_sharing_resources_stay_independent() {
+        let (queue, device) = initialise_gpu_resources();
+        let resources = SharedGpuResources::new(device, queue);
+
+        let mut small =
+            Corrections::with_shared_resources(resources.clone(), 32, 32, 1, PixelFormat::U16)
+                .unwrap();
+        let mut large =
+            Corrections::with_shared_resources(resources, 64, 64, 2, PixelFormat::U16).unwrap();
+        small
+            .enable_dark_map_correction(&vec![100u16; 32 * 32], 0)
+            .unwrap();
+        large
+            .enable_dark_map_correction(&vec![200u16; 64 * 64], 300)
+            .unwrap();
+
+        let small_result = small
+            .process_image_blocking(&vec![1000u16; 32 * 32])
+            .unwrap();
+        let large_result = large
+            .process_image_blocking(&vec![1000u16; 64 * 64])
+            .unwrap();
+        assert!(small_result.iter().all(|&pixel| pixel == 1000 - 100));
+        assert!(large_result.iter().all(|&pixel| pixel == 1000 - 200 + 300));
+
+        // Dropping one context leaves the shared device usable by the other.
+        drop(small);
+        let large_result = large
+            .process_image_blocking(&vec![500u16; 64 * 64])
+            .unwrap();
+        assert!(large_result.iter().all(|&pixel| pixel == 500 - 200 + 300));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disabling_a_correction_spares_frames_in_flight() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 32 * 32;
+        let mut correction_context = Corrections::new(device, queue, 32, 32, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 0)
+            .unwrap();
+        correction_context
+            .enable_gain_correction(&vec![2.0f32; size])
+            .unwrap();
+        let mut defect_map = vec![0u16; size];
+        defect_map[5] = 1;
+        correction_context
+            .enable_defect_correction(&defect_map)
+            .unwrap();
+
+        let mut frame = vec![1000u16; size];
+        frame[5] = 60000;
+        correction_context.upload_image(&frame).unwrap();
+        correction_context.process_image().unwrap();
+
+        // The frame already submitted keeps every map it started with.
+        correction_context.disable_dark_map_correction();
+        correction_context.disable_gain_correction();
+        correction_context.disable_defect_correction();
+        assert_eq!(
+            correction_context.collect_results().unwrap(),
+            [vec![1800u16; size]]
+        );
+
+        assert_eq!(
+            correction_context.process_image_blocking(&frame).unwrap(),
+            frame
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn padded_rows_are_uploaded_without_their_padding() {
+        let (queue, device) = initialise_gpu_resources();
+        let (width, height) = (40usize, 24usize);
+        let stride = width + 16;
+        let mut correction_context =
+            Corrections::new(device, queue, width as u32, height as u32, 1).unwrap();
+
+        let frame: Vec<u16> = (0..width * height).map(|i| i as u16).collect();
+        // Rows padded with a value that would show up in the result if copied.
+        let mut padded = vec![u16::MAX; stride * (height - 1) + width];
+        for (row, pixels) in frame.chunks_exact(width).enumerate() {
+            padded[row * stride..row * stride + width].copy_from_slice(pixels);
+        }
+
+        correction_context
+            .upload_image_strided(bytemuck::cast_slice(&padded), stride * 2)
+            .unwrap();
+        correction_context.process_image().unwrap();
+        assert_eq!(correction_context.collect_results().unwrap(), [frame]);
+
+        assert!(matches!(
+            correction_context.upload_image_strided(bytemuck::cast_slice(&padded), width),
+            Err(MyError::InvalidTextureData)
+        ));
+        assert!(matches!(
+            correction_context.upload_image_strided(
+                bytemuck::cast_slice(&padded[..padded.len() - 1]),
+                stride * 2
+            ),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+
+    #[test]
+    fn pipeline_cache_is_saved_and_reused() {
+        let path = std::env::temp_dir().join(format!(
+            "gpu_processing_pipeline_cache_{}.bin",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let size = 32 * 32;
+
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            let (queue, device) = initialise_gpu_resources();
+            let mut correction_context =
+                Corrections::new_with_pipeline_cache(device, queue, 32, 32, 1, &path).unwrap();
+            correction_context
+                .enable_dark_map_correction(&vec![100u16; size], 300)
+                .unwrap();
+            correction_context
+                .enable_defect_correction(&vec![0u16; size])
+                .unwrap();
+            correction_context.warm_up().unwrap();
+            results.push(
+                correction_context
+                    .process_image_blocking(&vec![1000u16; size])
+                    .unwrap(),
+            );
+
+            // Saved on drop, and loaded by the second context.
+            drop(correction_context);
+            assert!(!std::fs::read(&path).unwrap().is_empty());
+        }
+
+        assert_eq!(results[0], results[1]);
+        assert!(results[0].iter().all(|&pixel| pixel == 1000 - 100 + 300));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn accumulated_darks_feed_dark_correction() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 32 * 32;
+        let mut correction_context = Corrections::new(device, queue, 32, 32, 1).unwrap();
+
+        let darks: Vec<Vec<u16>> = (0..4u16).map(|frame| vec![98 + frame; size]).collect();
+        let darks: Vec<&[u16]> = darks.iter().map(Vec::as_slice).collect();
+        let master_dark = correction_context
+            .accumulate_frames(&darks, Accumulate::Mean)
+            .unwrap();
+        // 398 / 4 rounds up.
+        assert!(master_dark.iter().all(|&pixel| pixel == 100));
+
+        correction_context
+            .enable_dark_map_correction(&master_dark, 0)
+            .unwrap();
+        let result = correction_context
+            .process_image_blocking(&vec![1000u16; size])
+            .unwrap();
+        assert!(result.iter().all(|&pixel| pixel == 900));
+
+        assert!(matches!(
+            correction_context.accumulate_frames(&[&[0u16; 16]], Accumulate::Median),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn updated_dark_maps_apply_from_the_next_frame() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 32 * 32;
+        let mut correction_context = Corrections::new(device, queue, 32, 32, 2).unwrap();
+        assert!(matches!(
+            correction_context.update_dark_map(&vec![0u16; size]),
+            Err(MyError::DarkMapNotEnabled)
+        ));
+
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 0)
+            .unwrap();
+        correction_context
+            .upload_image(&vec![1000u16; size])
+            .unwrap();
+        correction_context.process_image().unwrap();
+
+        // The frame already submitted is corrected with the old map.
+        correction_context
+            .update_dark_map(&vec![300u16; size])
+            .unwrap();
+        assert_eq!(
+            correction_context.collect_results().unwrap(),
+            [vec![900u16; size]]
+        );
+        assert_eq!(
+            correction_context
+                .process_image_blocking(&vec![1000u16; size])
+                .unwrap(),
+            vec![700u16; size]
+        );
+
+        assert!(matches!(
+            correction_context.update_dark_map(&vec![0u16; size / 2]),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+
+    #[test]
+    fn detected_defects_feed_defect_correction() {
+        let (queue, device) = initialise_gpu_resources();
+        let (width, height) = (32u32, 32u32);
+        let size = (width * height) as usize;
+        let mut correction_context = Corrections::new(device, queue, width, height, 1).unwrap();
+
+        let mut flat: Vec<u16> = (0..size).map(|i| 2000 + (i * 5 % 11) as u16).collect();
+        let (hot, cold) = (10 * width as usize + 10, 20 * width as usize + 3);
+        flat[hot] = 9000;
+        flat[cold] = 50;
+
+        let defect_map = correction_context.detect_defects(&flat, 6.0).unwrap();
+        let flagged: Vec<usize> = (0..size).filter(|&idx| defect_map[idx] == 1).collect();
+        assert_eq!(flagged, [hot, cold]);
+
+        correction_context
+            .enable_defect_correction(&defect_map)
+            .unwrap();
+        let mut image = vec![1000u16; size];
+        image[hot] = 9000;
+        image[cold] = 50;
+        let result = correction_context.process_image_blocking(&image).unwrap();
+        assert!(result.iter().all(|&pixel| pixel == 1000));
+
+        let wide = DefectDetection {
+            radius: 3,
+            threshold: 6.0,
+        };
+        assert_eq!(
+            correction_context.detect_defects_with(&flat, wide).unwrap(),
+            defect_map
+        );
+        assert!(matches!(
+            correction_context.detect_defects(&flat[..16], 6.0),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+
+    #[test]
+    fn frames_are_corrected_into_the_callers_buffer() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 32 * 32;
+        let mut correction_context = Corrections::new(device, queue, 32, 32, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        // Reused across frames, as a streaming loop would.
+        let mut out = vec![0u16; size];
+        for value in [1000u16, 2000, 3000] {
+            let input = vec![value; size];
+            correction_context
+                .process_image_into(&input, &mut out)
+                .unwrap();
+            assert_eq!(
+                out,
+                correction_context.process_image_blocking(&input).unwrap()
+            );
+        }
+
+        assert!(matches!(
+            correction_context.process_image_into(&vec![0u16; size], &mut out[..size - 1]),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+
+    #[test]
+    fn batches_match_frames_corrected_one_at_a_time() {
+        let (queue, device) = initialise_gpu_resources();
+        let (width, height) = (48u32, 32u32);
+        let size = (width * height) as usize;
+
+        let mut correction_context = Corrections::new(device, queue, width, height, 1).unwrap();
+        correction_context
+            .enable_dark_map_correction(
+                &(0..size).map(|i| (i % 50) as u16).collect::<Vec<_>>(),
+                300,
+            )
+            .unwrap();
+        correction_context
+            .enable_gain_correction(
+                &(0..size)
+                    .map(|i| 0.5 + (i % 5) as f32 / 4.0)
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+        correction_context
+            .enable_defect_correction(&(0..size).map(|i| (i % 37 == 0) as u16).collect::<Vec<_>>())
+            .unwrap();
+
+        let frames: Vec<Vec<u16>> = (0..5u16)
+            .map(|frame| {
+                (0..size)
+                    .map(|i| 1000 + frame * 100 + (i % 700) as u16)
+                    .collect()
+            })
+            .collect();
+        let batch: Vec<&[u16]> = frames.iter().map(Vec::as_slice).collect();
+
+        let batched = correction_context.process_batch(&batch).unwrap();
+        assert_eq!(batched.len(), frames.len());
+        for (frame, batched) in frames.iter().zip(&batched) {
+            assert_eq!(
+                &correction_context.process_image_blocking(frame).unwrap(),
+                batched
+            );
+        }
+    }
+
+    #[test]
+    fn batches_reject_passes_they_cannot_batch() {
+        let (queue, device) = initialise_gpu_resources();
+        let mut correction_context = Corrections::new(device, queue, 16, 16, 1).unwrap();
+        let frame = vec![1000u16; 16 * 16];
+
+        assert!(correction_context.process_batch(&[]).unwrap().is_empty());
+        assert!(matches!(
+            correction_context.process_batch(&[&frame[..8]]),
+            Err(MyError::InvalidTextureData)
+        ));
+
+        correction_context
+            .set_roi(Some(Rect {
+                x: 0,
+                y: 0,
+                w: 8,
+                h: 8,
+            }))
+            .unwrap();
+        assert!(matches!(
+            correction_context.process_batch(&[&frame]),
+            Err(MyError::InvalidParameter)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn collect_results_returns_frames_in_submission_order() {
+        let (queue, device) = initialise_gpu_resources();
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let buffer_count = 4;
+        let size = (image_width * image_height) as usize;
+
+        let mut correction_context =
+            Corrections::new(device, queue, image_width, image_height, buffer_count).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        for i in 0..buffer_count {
+            correction_context
+                .upload_image(&vec![1000 + i as u16; size])
+                .unwrap();
+            correction_context.process_image().unwrap();
+        }
+
+        let results = correction_context.collect_results().unwrap();
+        assert_eq!(results.len(), buffer_count as usize);
+        for (i, frame) in results.iter().enumerate() {
+            assert_eq!(frame.len(), size);
+            assert!(frame
+                .iter()
+                .all(|&pixel| pixel == 1000 + i as u16 - 100 + 300));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dark_map_from_device_buffer() {
+        let (queue, device) = initialise_gpu_resources();
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let mut correction_context =
+            Corrections::new(device.clone(), queue.clone(), image_width, image_height, 1).unwrap();
+
+        let dark_map_buffer = Buffer::new_slice::<u16>(
+            correction_context.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
+                ..Default::default()
+            },
+            size as u64,
+        )
+        .unwrap();
+
+        // Fill the map on the GPU, two u16 texels of 100 per u32 word.
+        let mut builder = RecordingCommandBuffer::primary(
+            correction_context
+                .inner
+                .read()
+                .unwrap()
+                .command_buffer_allocator
+                .clone(),
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        builder
+            .fill_buffer(dark_map_buffer.clone().reinterpret::<[u32]>(), 0x0064_0064)
+            .unwrap();
+        sync::now(device)
+            .then_execute(queue, builder.end().unwrap())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        let wrong_size = dark_map_buffer.clone().slice(0..size as u64 - 1);
+        assert!(correction_context
+            .enable_dark_map_from_buffer(wrong_size, 300)
+            .is_err());
+
+        correction_context
+            .enable_dark_map_from_buffer(dark_map_buffer, 300)
+            .unwrap();
+        correction_context.upload_image(&vec![1000; size]).unwrap();
+        correction_context.process_image().unwrap();
+        let results = correction_context.collect_results().unwrap();
+        assert!(results[0].iter().all(|&pixel| pixel == 1000 - 100 + 300));
+    }
+
+    #[test]
+    fn dot_graph_follows_stage_order() {
+        let (queue, device) = initialise_gpu_resources();
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let correction_context =
+            Corrections::new(device, queue, image_width, image_height, 1).unwrap();
+        assert!(correction_context
+            .to_dot()
+            .contains("input -> output [label=\"image_buffer\"]"));
+
+        correction_context
+            .enable_dark_map_correction(&vec![0u16; size], 300)
+            .unwrap();
+        correction_context
+            .enable_defect_correction(&vec![0u16; size])
+            .unwrap();
+
+        let dot = correction_context.to_dot();
+        let edges = [
+            "input -> dark [label=\"image_buffer\"]",
+            "dark -> defect [label=\"image_buffer\"]",
+            "defect -> defect_copy [label=\"scratch_buffer\"]",
+            "defect_copy -> output [label=\"image_buffer\"]",
+        ];
+        let positions: Vec<_> = edges.iter().map(|edge| dot.find(edge).unwrap()).collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(!dot.contains("gain"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn process_before_upload_is_rejected() {
+        let (queue, device) = initialise_gpu_resources();
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let mut correction_context =
+            Corrections::new(device, queue, image_width, image_height, 2).unwrap();
+
+        assert!(matches!(
+            correction_context.process_image(),
+            Err(MyError::NoInput)
+        ));
+        assert!(correction_context.upload_image(&vec![0; size - 1]).is_err());
+
+        correction_context.upload_image(&vec![0; size]).unwrap();
+        correction_context.process_image().unwrap();
+        assert!(matches!(
+            correction_context.process_image(),
+            Err(MyError::NoInput)
+        ));
+        assert_eq!(correction_context.collect_results().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn secondary_executes_inside_host_primary() {
+        let (queue, device) = initialise_gpu_resources();
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let mut correction_context =
+            Corrections::new(device.clone(), queue.clone(), image_width, image_height, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        let host_buffer = |data: Vec<u16>| {
+            Buffer::from_iter(
+                correction_context.memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::TRANSFER_SRC | BufferUsage::TRANSFER_DST,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                        | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                    ..Default::default()
+                },
+                data,
+            )
+            .unwrap()
+        };
+        let input = host_buffer(vec![1000; size]);
+        let output = host_buffer(vec![0; size]);
+
+        let slot = 1;
+        let secondary = correction_context.record_secondary(slot).unwrap();
+        let image_buffer = correction_context.image_buffer(slot);
+
+        let mut builder = RecordingCommandBuffer::primary(
+            correction_context
+                .inner
+                .read()
+                .unwrap()
+                .command_buffer_allocator
+                .clone(),
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        builder
+            .copy_buffer(CopyBufferInfo::buffers(input, image_buffer.clone()))
+            .unwrap()
+            .execute_commands(secondary)
+            .unwrap()
+            .copy_buffer(CopyBufferInfo::buffers(image_buffer, output.clone()))
+            .unwrap();
+
+        sync::now(device)
+            .then_execute(queue, builder.end().unwrap())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        assert!(output
+            .read()
+            .unwrap()
+            .iter()
+            .all(|&pixel| pixel == 1000 - 100 + 300));
+    }
+
+    #[test]
+    fn secondary_rejects_missing_slots_and_per_frame_passes() {
+        let (queue, device) = initialise_gpu_resources();
+        let correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+
+        assert!(matches!(
+            correction_context.record_secondary(2),
+            Err(MyError::InvalidParameter)
+        ));
+
+        correction_context
+            .enable_binning(2, BinMode::Average)
+            .unwrap();
+        assert!(matches!(
+            correction_context.record_secondary(0),
+            Err(MyError::InvalidParameter)
+        ));
+    }
+
+    #[test]
+    fn validation_installs_debug_messenger() {
+        let validation_available = VulkanLibrary::new()
+            .unwrap()
+            .layer_properties()
+            .unwrap()
+            .any(|layer| layer.name() == VALIDATION_LAYER);
+        if !validation_available {
+            return;
+        }
+
+        let messengers_before = DEBUG_MESSENGERS.lock().unwrap().len();
+        let (_queue, _device) = initialise_gpu_resources_with_validation(true);
+        assert_eq!(
+            DEBUG_MESSENGERS.lock().unwrap().len(),
+            messengers_before + 1
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn auto_offset_places_percentile_at_target() {
+        let (queue, device) = initialise_gpu_resources();
+        let image_width: u32 = 100;
+        let image_height: u32 = 100;
+        let size = (image_width * image_height) as usize;
+
+        let correction_context =
+            Corrections::new(device, queue, image_width, image_height, 2).unwrap();
+        assert!(matches!(
+            correction_context.auto_offset(&vec![0; size]),
+            Err(MyError::DarkMapNotEnabled)
+        ));
+
+        // `reference - dark` is uniform over -100..900, ten pixels per value, so the 1st
+        // percentile lands on -91.
+        let reference: Vec<u16> = (0..size).map(|i| 400 + (i % 1000) as u16).collect();
+        correction_context
+            .enable_dark_map_correction(&vec![500u16; size], 0)
+            .unwrap();
+
+        let offset = correction_context
+            .auto_offset_with(&reference, 0.01, 100)
+            .unwrap();
+        assert_eq!(offset, 191);
+        assert_eq!(
+            correction_context
+                .auto_offset_with(&reference, 0.5, 1000)
+                .unwrap(),
+            1000 - 399
+        );
+        assert_eq!(
+            correction_context
+                .auto_offset_with(&reference, 0.01, -200)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn frames_of_different_sizes_share_a_context() {
+        let (queue, device) = initialise_gpu_resources();
+        let (large_width, large_height) = (64u32, 64u32);
+        let (small_width, small_height) = (32u32, 16u32);
+        let large_size = (large_width * large_height) as usize;
+        let small_size = (small_width * small_height) as usize;
+
+        let mut correction_context =
+            Corrections::new(device, queue, large_width, large_height, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; large_size], 300)
+            .unwrap();
+
+        correction_context
+            .set_frame_dimensions(small_width, small_height)
+            .unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![50u16; small_size], 300)
+            .unwrap();
+
+        correction_context
+            .upload_image_with_dimensions(&vec![1000; large_size], large_width, large_height)
+            .unwrap();
+        correction_context.process_image().unwrap();
+        correction_context
+            .upload_image_with_dimensions(&vec![2000; small_size], small_width, small_height)
+            .unwrap();
+        correction_context.process_image().unwrap();
+
+        // A frame that doesn't match the active size is rejected.
+        assert!(matches!(
+            correction_context.upload_image(&vec![0; large_size]),
+            Err(MyError::InvalidTextureData)
+        ));
+
+        let results = correction_context.collect_results().unwrap();
+        assert_eq!(results[0].len(), large_size);
+        assert!(results[0].iter().all(|&pixel| pixel == 1000 - 100 + 300));
+        assert_eq!(results[1].len(), small_size);
+        assert!(results[1].iter().all(|&pixel| pixel == 2000 - 50 + 300));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn metrics_count_submitted_and_completed_frames() {
+        let (queue, device) = initialise_gpu_resources();
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let buffer_count = 4;
+        let size = (image_width * image_height) as usize;
+
+        let mut correction_context =
+            Corrections::new(device, queue, image_width, image_height, buffer_count).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+        assert_eq!(correction_context.metrics(), CorrectionMetrics::default());
+
+        for _ in 0..buffer_count - 1 {
+            correction_context.upload_image(&vec![1000; size]).unwrap();
+            correction_context.process_image().unwrap();
+        }
+        correction_context.pause();
+        correction_context.upload_image(&vec![1000; size]).unwrap();
+        assert!(correction_context.process_image().is_err());
+        correction_context.collect_results().unwrap();
+
+        let metrics = correction_context.metrics();
+        assert_eq!(metrics.frames_submitted, buffer_count as u64 - 1);
+        assert_eq!(metrics.frames_completed, metrics.frames_submitted);
+        assert_eq!(metrics.frames_dropped, 1);
+        assert!((1..=metrics.frames_submitted).contains(&metrics.max_in_flight));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn borrowed_result_matches_cloned_result() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+        assert!(correction_context.with_last_result(|_| ()).is_none());
+
+        let image: Vec<u16> = (0..size).map(|i| 1000 + (i % 500) as u16).collect();
+        correction_context.upload_image(&image).unwrap();
+        correction_context.process_image().unwrap();
+        let cloned = correction_context.collect_results().unwrap().pop().unwrap();
+
+        let borrowed_sum = correction_context
+            .with_last_result(|data| data.iter().map(|&pixel| pixel as u64).sum::<u64>())
+            .unwrap();
+        assert_eq!(
+            borrowed_sum,
+            cloned.iter().map(|&pixel| pixel as u64).sum::<u64>()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stats_match_the_last_frame() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        assert!(matches!(
+            correction_context.compute_stats(),
+            Err(MyError::NoInput)
+        ));
+
+        let image: Vec<u16> = (0..size).map(|i| 500 + (i % 3000) as u16).collect();
+        correction_context.upload_image(&image).unwrap();
+        correction_context.process_image().unwrap();
+        let result = correction_context.collect_results().unwrap().pop().unwrap();
+
+        let stats = correction_context.compute_stats().unwrap();
+        assert_eq!(stats.min, *result.iter().min().unwrap());
+        assert_eq!(stats.max, *result.iter().max().unwrap());
+        let mean = result.iter().map(|&pixel| pixel as f64).sum::<f64>() / size as f64;
+        assert!((stats.mean as f64 - mean).abs() < 1e-2);
+    }
+
+    #[test]
+    fn half_resolution_gain_map_is_upsampled() {
+        let (queue, device) = initialise_gpu_resources();
+        let (width, height) = (64u32, 32u32);
+        let mut correction_context = Corrections::new(device, queue, width, height, 2).unwrap();
+
+        // A gain ramp across the columns, calibrated with 2x2 binning.
+        let (source_width, source_height) = (width / 2, height / 2);
+        let source_gain = |x: f32| 1.0 + 0.02 * x.clamp(0.0, (source_width - 1) as f32);
+        let gain_map: Vec<f32> = (0..source_width * source_height)
+            .map(|i| source_gain((i % source_width) as f32))
+            .collect();
+        assert!(matches!(
+            correction_context.enable_gain_correction_resampled(&gain_map, 0, source_height),
+            Err(MyError::InvalidParameter)
+        ));
+        correction_context
+            .enable_gain_correction_resampled(&gain_map, source_width, source_height)
+            .unwrap();
+
+        let frame = vec![1000u16; (width * height) as usize];
+        let corrected = correction_context.process_image_blocking(&frame).unwrap();
+        for (i, &pixel) in corrected.iter().enumerate() {
+            // Frame pixel centres sit a quarter of a source pixel off the calibration's.
+            let x = (i as u32 % width) as f32;
+            let expected = 1000.0 * source_gain((x + 0.5) / 2.0 - 0.5);
+            assert!((pixel as f32 - expected).abs() <= 1.0, "pixel {i}: {pixel}");
+        }
+    }
+
+    #[test]
+    fn fixed_point_gain_matches_f32_gain() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        // Multiples of 2^-8, exact in both representations.
+        let gain_map: Vec<f32> = (0..size).map(|i| 0.5 + (i % 300) as f32 / 256.0).collect();
+        let image: Vec<u16> = (0..size).map(|i| (i % 4000) as u16 + 100).collect();
+
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 1).unwrap();
+        correction_context
+            .enable_gain_correction(&gain_map)
+            .unwrap();
+        let expected = correction_context.process_image_blocking(&image).unwrap();
+
+        let gain_q = quantize_gain_map(&gain_map, DEFAULT_GAIN_FRAC_BITS).unwrap();
+        correction_context
+            .enable_gain_correction_fixed(&gain_q, DEFAULT_GAIN_FRAC_BITS)
+            .unwrap();
+        assert_eq!(
+            correction_context.process_image_blocking(&image).unwrap(),
+            expected
+        );
+
+        assert!(matches!(
+            correction_context.enable_gain_correction_fixed(&gain_q[1..], DEFAULT_GAIN_FRAC_BITS),
+            Err(MyError::InvalidTextureData)
+        ));
+        assert!(matches!(
+            correction_context.enable_gain_correction_fixed(&gain_q, MAX_GAIN_FRAC_BITS + 1),
+            Err(MyError::InvalidParameter)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn autotuned_local_size_keeps_results() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let dark_map: Vec<u16> = (0..size).map(|i| (i % 97) as u16).collect();
+        let gain_map = vec![1.5f32; size];
+        let image: Vec<u16> = (0..size).map(|i| (i % 4000) as u16 + 100).collect();
+
+        let correct = |tune: bool| {
+            let mut correction_context =
+                Corrections::new(device.clone(), queue.clone(), 64, 64, 1).unwrap();
+            if tune {
+                let local_size = correction_context.autotune().unwrap();
+                assert!(LOCAL_SIZE_CANDIDATES.contains(&local_size));
+                assert_eq!(correction_context.local_size(), local_size);
+            }
+            correction_context
+                .enable_dark_map_correction(&dark_map, 300)
+                .unwrap();
+            correction_context
+                .enable_gain_correction(&gain_map)
+                .unwrap();
+            correction_context.process_image_blocking(&image).unwrap()
+        };
+        assert_eq!(correct(true), correct(false));
+
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 1).unwrap();
+        for local_size in [0, u32::MAX] {
+            assert!(matches!(
+                correction_context.set_local_size(local_size),
+                Err(MyError::InvalidParameter)
+            ));
+        }
+        assert_eq!(correction_context.local_size(), DEFAULT_LOCAL_SIZE);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn host_ptr_frames_match_uploaded_ones() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        let image: Vec<u16> = (0..size).map(|i| (i % 4000) as u16).collect();
+        correction_context.upload_image(&image).unwrap();
+        correction_context.process_image().unwrap();
+        unsafe {
+            correction_context
+                .process_image_from_host_ptr(image.as_ptr(), image.len())
+                .unwrap();
+        }
+        let results = correction_context.collect_results().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0], results[1]);
+
+        unsafe {
+            assert!(matches!(
+                correction_context.process_image_from_host_ptr(image.as_ptr(), size - 1),
+                Err(MyError::InvalidTextureData)
+            ));
+            assert!(matches!(
+                correction_context.process_image_from_host_ptr(std::ptr::null(), size),
+                Err(MyError::InvalidParameter)
+            ));
+            let misaligned = (image.as_ptr() as *const u8).add(1) as *const u16;
+            assert!(matches!(
+                correction_context.process_image_from_host_ptr(misaligned, size),
+                Err(MyError::InvalidParameter)
+            ));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn histogram_counts_the_last_frame() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        assert!(matches!(
+            correction_context.compute_histogram(16),
+            Err(MyError::NoInput)
+        ));
+
+        let image: Vec<u16> = (0..size).map(|i| (i % 1000) as u16).collect();
+        correction_context.upload_image(&image).unwrap();
+        correction_context.process_image().unwrap();
+        let result = correction_context.collect_results().unwrap().pop().unwrap();
+
+        let counts = correction_context.compute_histogram(256).unwrap();
+        assert_eq!(counts.iter().sum::<u32>(), size as u32);
+        assert_eq!(
+            counts[0],
+            result.iter().filter(|&&pixel| pixel < 256).count() as u32
+        );
+
+        // Everything from 500 up lands in the last bin.
+        let counts = correction_context
+            .compute_histogram_range(10, 0, 499)
+            .unwrap();
+        let above = result.iter().filter(|&&pixel| pixel >= 450).count() as u32;
+        assert_eq!(counts[9], above);
+
+        assert!(matches!(
+            correction_context.compute_histogram_range(10, 100, 100),
+            Err(MyError::InvalidParameter)
+        ));
+        assert!(matches!(
+            correction_context.compute_histogram(0),
+            Err(MyError::InvalidParameter)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lost_device_rejects_frames() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        correction_context
+            .enable_heartbeat(Duration::from_millis(1))
+            .unwrap();
+        correction_context.upload_image(&vec![0; size]).unwrap();
+
+        // Stand in for a heartbeat that saw the device disappear.
+        correction_context
+            .device_lost
+            .store(true, Ordering::Release);
+        assert!(correction_context.is_device_lost());
+        assert!(matches!(
+            correction_context.process_image(),
+            Err(MyError::DeviceLost)
+        ));
+        correction_context.disable_heartbeat();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn set_param_changes_offset_without_rebuild() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        let resources_before = correction_context
+            .inner
+            .read()
+            .unwrap()
+            .passes
+            .dark_map_resources
+            .clone();
+        assert_eq!(
+            correction_context
+                .get_param(CorrectionStage::Dark, "offset")
+                .unwrap(),
+            ParamValue::U32(300)
+        );
+
+        correction_context.upload_image(&vec![1000; size]).unwrap();
+        correction_context.process_image().unwrap();
+        correction_context
+            .set_param(CorrectionStage::Dark, "offset", ParamValue::U32(50))
+            .unwrap();
+        correction_context.upload_image(&vec![1000; size]).unwrap();
+        correction_context.process_image().unwrap();
+
+        let results = correction_context.collect_results().unwrap();
+        assert!(results[0].iter().all(|&pixel| pixel == 1000 - 100 + 300));
+        assert!(results[1].iter().all(|&pixel| pixel == 1000 - 100 + 50));
+
+        let resources_after = correction_context
+            .inner
+            .read()
+            .unwrap()
+            .passes
+            .dark_map_resources
+            .clone();
+        assert!(Arc::ptr_eq(&resources_before, &resources_after));
+
+        assert!(matches!(
+            correction_context.set_param(CorrectionStage::Dark, "offset", ParamValue::F32(1.0)),
+            Err(MyError::InvalidParameter)
+        ));
+        assert!(matches!(
+            correction_context.get_param(CorrectionStage::Dark, "radius"),
+            Err(MyError::UnknownParameter("dark", _))
+        ));
+        assert!(matches!(
+            correction_context.get_param(CorrectionStage::Log, "i0"),
+            Err(MyError::UnknownParameter(..))
+        ));
+    }
+
+    #[test]
+    fn device_selection_filters_by_name_and_type() {
+        let (_, default_device) = initialise_gpu_resources();
+        let name = default_device
+            .physical_device()
+            .properties()
+            .device_name
+            .clone();
+
+        let (_, device) = initialise_gpu_resources_with(GpuSelectionOptions {
+            name_contains: Some(name.to_uppercase()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(device.physical_device().properties().device_name, name);
+
+        assert!(matches!(
+            initialise_gpu_resources_with(GpuSelectionOptions {
+                name_contains: Some("no such device".to_string()),
+                ..Default::default()
+            }),
+            Err(MyError::NoSuitableDevice)
+        ));
+        assert!(matches!(
+            initialise_gpu_resources_with(GpuSelectionOptions {
+                device_types: Vec::new(),
+                ..Default::default()
+            }),
+            Err(MyError::NoSuitableDevice)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn forced_32_bit_storage_matches_16_bit_path() {
+        let (width, height) = (64u32, 48u32);
+        let size = (width * height) as usize;
+        let dark_map: Vec<u16> = (0..size).map(|i| 100 + (i % 7) as u16 * 50).collect();
+        let image: Vec<u16> = (0..size).map(|i| (i * 37 % 4000) as u16).collect();
+
+        let run = |options: GpuSelectionOptions| {
+            let (queue, device) = initialise_gpu_resources_with(options).unwrap();
+            let mut correction_context = Corrections::new(device, queue, width, height, 1).unwrap();
+            correction_context
+                .enable_dark_map_correction(&dark_map, 300)
+                .unwrap();
+            correction_context
+                .enable_notch_filter(NotchAxis::Rows, &[4])
+                .unwrap();
+            correction_context
+                .enable_linear_transform(1.5, 20.0)
+                .unwrap();
+            correction_context
+                .enable_expression("pixel + x - y")
+                .unwrap();
+
+            let mut result = image.clone();
+            correction_context
+                .process_image_in_place(&mut result)
+                .unwrap();
+            result
+        };
+
+        let (_, forced_device) = initialise_gpu_resources_with(GpuSelectionOptions {
+            force_32_bit_storage: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(uses_pixel_words(&forced_device));
+
+        let narrow = run(GpuSelectionOptions::default());
+        let wide = run(GpuSelectionOptions {
+            force_32_bit_storage: true,
+            ..Default::default()
+        });
+        assert_eq!(narrow, wide);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expression_uniform_is_a_stage_parameter() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        correction_context
+            .enable_expression("pixel * gain + 10")
+            .unwrap();
+        correction_context
+            .set_param(CorrectionStage::Expression, "gain", ParamValue::F32(2.0))
+            .unwrap();
+        assert!(matches!(
+            correction_context.get_param(CorrectionStage::Expression, "pixel"),
+            Err(MyError::UnknownParameter("expression", _))
+        ));
+
+        correction_context.upload_image(&vec![1000; size]).unwrap();
+        correction_context.process_image().unwrap();
+
+        let results = correction_context.collect_results().unwrap();
+        assert!(results[0].iter().all(|&pixel| pixel == 1000 * 2 + 10));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lag_correction_runs_in_frame_order() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 4).unwrap();
+        correction_context
+            .enable_lag_correction(&[0.5, 0.25])
+            .unwrap();
+
+        // All four frames are in flight together, so they only come out right if the
+        // correction runs in submission order.
+        for raw in [1000, 500, 250, 0] {
+            correction_context.upload_image(&vec![raw; size]).unwrap();
+            correction_context.process_image().unwrap();
+        }
+
+        let results = correction_context.collect_results().unwrap();
+        for (result, expected) in results.iter().zip([1000, 0, 0, 0]) {
+            assert!(result.iter().all(|&pixel| pixel == expected));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown_waits_for_outstanding_frames() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 3).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        let completed = Arc::new(AtomicU64::new(0));
+        correction_context
+            .upload_image(&vec![1000u16; size])
+            .unwrap();
+        correction_context.process_image().unwrap();
+        for _ in 0..2 {
+            correction_context
+                .upload_image(&vec![1000u16; size])
+                .unwrap();
+            let completed = completed.clone();
+            correction_context
+                .process_image_then(move |_| {
+                    completed.fetch_add(1, Ordering::Relaxed);
+                })
+                .unwrap();
+        }
+
+        correction_context.shutdown().unwrap();
+        assert_eq!(completed.load(Ordering::Relaxed), 2);
+        assert!(correction_context.collect_results().unwrap().is_empty());
+
+        // Still usable afterwards, and dropping with a frame in flight waits for it.
+        correction_context
+            .upload_image(&vec![1000u16; size])
+            .unwrap();
+        correction_context.process_image().unwrap();
+        drop(correction_context);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_submissions_keep_their_own_frames() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let correction_context = Arc::new(Mutex::new(
+            Corrections::new(device, queue, 64, 64, 3).unwrap(),
+        ));
+        correction_context
+            .lock()
+            .unwrap()
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        // Far more submitters than slots, each blocking its own thread while it waits for
+        // a free slot and then its frame.
+        let submissions: Vec<_> = (0..50u16)
+            .map(|frame| {
+                let correction_context = correction_context.clone();
+                tokio::task::spawn_blocking(move || {
+                    let handle = {
+                        let mut correction_context = correction_context.lock().unwrap();
+                        correction_context
+                            .upload_image(&vec![1000 + frame; size])
+                            .unwrap();
+                        correction_context.process_image_handle()
+                    };
+                    (frame, futures::executor::block_on(handle).unwrap().unwrap())
+                })
+            })
+            .collect();
+
+        for submission in submissions {
+            let (frame, result) = submission.await.unwrap();
+            assert!(
+                result
+                    .iter()
+                    .all(|&pixel| pixel == 1000 + frame - 100 + 300),
+                "frame {frame}"
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn buffer_slots_are_reused() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let buffer_count = 2;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, buffer_count).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        let mut results = Vec::new();
+        for frame in 0..3 * buffer_count as u16 {
+            correction_context
+                .upload_image(&vec![1000 + frame; size])
+                .unwrap();
+            correction_context.process_image().unwrap();
+            // A slot can only be restaged once its previous frame has completed.
+            if correction_context.in_flight.len() == buffer_count as usize {
+                results.extend(correction_context.collect_results().unwrap());
+            }
+        }
+
+        assert_eq!(results.len(), 3 * buffer_count as usize);
+        for (frame, result) in results.iter().enumerate() {
+            assert!(result
+                .iter()
+                .all(|&pixel| pixel == 1000 + frame as u16 - 100 + 300));
+        }
+    }
+
+    #[test]
+    fn concurrent_enables_all_land() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+
+        std::thread::scope(|scope| {
+            for round in 0..8u32 {
+                let correction_context = &correction_context;
+                scope.spawn(move || {
+                    correction_context
+                        .enable_dark_map_correction(&vec![100u16; size], round)
+                        .unwrap()
+                });
+                scope.spawn(move || {
+                    correction_context
+                        .enable_gain_correction(&vec![1.0; size])
+                        .unwrap()
+                });
+                scope.spawn(move || {
+                    correction_context
+                        .enable_defect_correction(&vec![0; size])
+                        .unwrap()
+                });
+            }
+        });
+
+        assert_eq!(
+            correction_context.stages(),
+            [
+                CorrectionStage::Dark,
+                CorrectionStage::Gain,
+                CorrectionStage::Defect
+            ]
+        );
+        assert!(matches!(
+            correction_context.get_param(CorrectionStage::Dark, "offset"),
+            Ok(ParamValue::U32(offset)) if offset < 8
+        ));
+        assert!(correction_context.defect_stats().is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn in_place_processing_returns_its_own_frame() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        correction_context.upload_image(&vec![2000; size]).unwrap();
+        correction_context.process_image().unwrap();
+
+        let mut image = vec![1000u16; size];
+        correction_context
+            .process_image_in_place(&mut image)
+            .unwrap();
+        assert!(image.iter().all(|&pixel| pixel == 1000 - 100 + 300));
+
+        // The earlier frame is still collected separately.
+        let results = correction_context.collect_results().unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].iter().all(|&pixel| pixel == 2000 - 100 + 300));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn frame_assembled_from_row_chunks() {
+        let (queue, device) = initialise_gpu_resources();
+        let (width, height) = (64u32, 48u32);
+        let size = (width * height) as usize;
+        let mut correction_context = Corrections::new(device, queue, width, height, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        let image: Vec<u16> = (0..size)
+            .map(|i| 1000 + (i / width as usize) as u16)
+            .collect();
+        let rows = |start: u32, count: u32| {
+            &image[(start * width) as usize..((start + count) * width) as usize]
+        };
+
+        // Out of order, with rows 20..24 pushed twice, the first time with stale data.
+        assert!(!correction_context.push_rows(rows(24, 24), 24, 24).unwrap());
+        assert!(!correction_context
+            .push_rows(&vec![0; 4 * width as usize], 20, 4)
+            .unwrap());
+        assert!(!correction_context.push_rows(rows(0, 12), 0, 12).unwrap());
+        assert!(correction_context.push_rows(rows(12, 12), 12, 12).unwrap());
+
+        let results = correction_context.collect_results().unwrap();
+        assert_eq!(results.len(), 1);
+        let expected: Vec<u16> = image.iter().map(|&pixel| pixel - 100 + 300).collect();
+        assert_eq!(results[0], expected);
+
+        assert!(matches!(
+            correction_context.push_rows(rows(0, 2), 0, 3),
+            Err(MyError::InvalidTextureData)
+        ));
+        assert!(matches!(
+            correction_context.push_rows(rows(40, 8), 44, 8),
+            Err(MyError::InvalidParameter)
+        ));
+    }
+
+    #[test]
+    fn latency_is_recorded_per_frame() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        assert_eq!(
+            correction_context.latency_percentiles(),
+            LatencyStats::default()
+        );
+
+        for _ in 0..5 {
+            correction_context
+                .process_image_blocking(&vec![1000; size])
+                .unwrap();
+        }
+        let stats = correction_context.latency_percentiles();
+        assert!(stats.max > Duration::ZERO);
+        assert!(stats.p50 <= stats.p95 && stats.p95 <= stats.p99 && stats.p99 <= stats.max);
+
+        correction_context.reset_latency();
+        assert_eq!(
+            correction_context.latency_percentiles(),
+            LatencyStats::default()
+        );
+    }
+
+    #[test]
+    fn blocking_processing_needs_no_runtime() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        for frame in 0..3u16 {
+            let result = correction_context
+                .process_image_blocking(&vec![1000 + frame; size])
+                .unwrap();
+            assert!(result
+                .iter()
+                .all(|&pixel| pixel == 1000 + frame - 100 + 300));
+        }
+
+        for len in [size - 1, 2 * size] {
+            assert!(matches!(
+                correction_context.process_image_blocking(&vec![0; len]),
+                Err(MyError::InvalidTextureData)
+            ));
+        }
+    }
+
+    #[test]
+    fn frames_in_other_pixel_formats_are_converted() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+
+        let mut u8_context = Corrections::new_with_pixel_format(
+            device.clone(),
+            queue.clone(),
+            64,
+            64,
+            1,
+            PixelFormat::U8,
+        )
+        .unwrap();
+        u8_context
+            .enable_dark_map_correction(&vec![50u16; size], 10)
+            .unwrap();
+        let mut image: Vec<u8> = (0..size).map(|i| (i % 256) as u8).collect();
+        let expected: Vec<u8> = image
+            .iter()
+            .map(|&pixel| {
+                (pixel as u16)
+                    .saturating_sub(50)
+                    .saturating_add(10)
+                    .min(255) as u8
+            })
+            .collect();
+        u8_context.process_image_u8(&mut image).unwrap();
+        assert_eq!(image, expected);
+
+        // The context only takes the format it was created for, or u16.
+        assert!(matches!(
+            u8_context.process_image_u32(&mut vec![0; size]),
+            Err(MyError::InvalidParameter)
+        ));
+        assert!(matches!(
+            u8_context.process_image_u8(&mut vec![0; size - 1]),
+            Err(MyError::InvalidTextureData)
+        ));
+        let mut image = vec![1000u16; size];
+        u8_context.process_image_in_place(&mut image).unwrap();
+        assert!(image.iter().all(|&pixel| pixel == 1000 - 50 + 10));
+
+        let mut u32_context =
+            Corrections::new_with_pixel_format(device, queue, 64, 64, 1, PixelFormat::U32).unwrap();
+        u32_context
+            .enable_dark_map_correction(&vec![50u16; size], 10)
+            .unwrap();
+        let mut image = vec![1000u32; size];
+        image[0] = 100_000;
+        u32_context.process_image_u32(&mut image).unwrap();
+        assert_eq!(image[0], 65535 - 50 + 10);
+        assert!(image[1..].iter().all(|&pixel| pixel == 1000 - 50 + 10));
+    }
+
+    #[test]
+    fn preview_is_corrected_binned_and_upscaled() {
+        let (queue, device) = initialise_gpu_resources();
+        let (width, height) = (64u32, 32u32);
+        let size = (width * height) as usize;
+        let mut correction_context = Corrections::new(device, queue, width, height, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 0)
+            .unwrap();
+
+        // Each 2x2 block is flat, stepping by 400 per block column.
+        let image: Vec<u16> = (0..size as u32)
+            .map(|i| (100 + 400 * ((i % width) / 2)) as u16)
+            .collect();
+
+        let nearest = correction_context
+            .preview(&image, 2, Interp::Nearest)
+            .unwrap();
+        assert_eq!((nearest.width, nearest.height), (width, height));
+        assert_eq!(nearest.data.len(), size);
+        assert_eq!(&nearest.data[..4], [0, 0, 400, 400]);
+
+        let bilinear = correction_context
+            .preview(&image, 2, Interp::Bilinear)
+            .unwrap();
+        assert_eq!((bilinear.width, bilinear.height), (width, height));
+        assert_eq!(&bilinear.data[..4], [0, 100, 300, 500]);
+
+        assert!(matches!(
+            correction_context.preview(&image, 0, Interp::Nearest),
+            Err(MyError::InvalidParameter)
+        ));
+    }
+
+    #[test]
+    fn multi_frame_upload_is_rejected() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 1).unwrap();
+
+        assert!(matches!(
+            correction_context.upload_image(&vec![0; 3 * size]),
+            Err(MyError::MultipleFrames(3))
+        ));
+        assert!(matches!(
+            correction_context.upload_image(&vec![0; 3 * size + 1]),
+            Err(MyError::InvalidTextureData)
+        ));
+        assert!(matches!(
+            correction_context.process_image(),
+            Err(MyError::NoInput)
+        ));
+    }
+
+    #[test]
+    fn maps_for_another_resolution_are_rejected() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 1).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        assert!(matches!(
+            correction_context.enable_dark_map_correction(&vec![0u16; 32 * 32], 300),
+            Err(MyError::InvalidTextureData)
+        ));
+        assert!(matches!(
+            correction_context.enable_gain_correction(&vec![2.0f32; size + 1]),
+            Err(MyError::InvalidTextureData)
+        ));
+        assert!(matches!(
+            correction_context.enable_defect_correction(&vec![0u16; size - 1]),
+            Err(MyError::InvalidTextureData)
+        ));
+
+        // The first dark map is still in place, and no gain map was enabled.
+        let result = correction_context
+            .process_image_blocking(&vec![1000u16; size])
+            .unwrap();
+        assert!(result.iter().all(|&pixel| pixel == 1000 - 100 + 300));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dark_gain_and_defect_run_in_one_submission() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let defective = 64 * 20 + 20;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+
+        let mut defect_map = vec![0u16; size];
+        defect_map[defective] = 1;
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+        correction_context
+            .enable_gain_correction(&vec![0.5f32; size])
+            .unwrap();
+        correction_context
+            .enable_defect_correction(&defect_map)
+            .unwrap();
+
+        let mut image = vec![1000u16; size];
+        image[defective] = 60000;
+        let mut corrected = image.clone();
+        correction_context
+            .process_image_in_place(&mut corrected)
+            .unwrap();
+        assert!(corrected
+            .iter()
+            .all(|&pixel| pixel == (1000 - 100 + 300) / 2));
+
+        // Dropping defect correction from the order leaves the defective pixel alone.
+        correction_context
+            .set_stage_order(vec![CorrectionStage::Dark, CorrectionStage::Gain], false)
+            .unwrap();
+        let mut corrected = image.clone();
+        correction_context
+            .process_image_in_place(&mut corrected)
+            .unwrap();
+        assert_eq!(corrected[defective], (60000 - 100 + 300) / 2);
+        assert_eq!(corrected[0], (1000 - 100 + 300) / 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn gain_only_is_applied_by_the_async_path() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 1).unwrap();
+
+        let gain_map: Vec<f32> = (0..size).map(|i| 0.5 + (i % 4) as f32 * 0.25).collect();
+        let image: Vec<u16> = (0..size).map(|i| (i % 1000) as u16 * 10).collect();
+        correction_context
+            .enable_gain_correction(&gain_map)
+            .unwrap();
+
+        correction_context.upload_image(&image).unwrap();
+        correction_context.process_image().unwrap();
+        let results = correction_context.collect_results().unwrap();
+
+        let expected: Vec<u16> = image
+            .iter()
+            .zip(&gain_map)
+            .map(|(&pixel, gain)| (pixel as f32 * gain) as u16)
+            .collect();
+        assert_eq!(results[0], expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn flat_field_runs_as_one_stage() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 1).unwrap();
+
+        let mut flat_map = vec![1100u16; size];
+        flat_map[1] = 100;
+        correction_context
+            .enable_flat_field_correction(&vec![100u16; size], &flat_map)
+            .unwrap();
+        assert_eq!(correction_context.stages(), [CorrectionStage::FlatField]);
+        assert_eq!(
+            correction_context
+                .get_param(CorrectionStage::FlatField, "scale")
+                .unwrap(),
+            ParamValue::F32(1000.0)
+        );
+
+        let mut image = vec![600u16; size];
+        correction_context
+            .process_image_in_place(&mut image)
+            .unwrap();
+        assert_eq!(image[0], 500);
+        // flat == dark, passed through.
+        assert_eq!(image[1], 600);
+        assert!(image[2..].iter().all(|&pixel| pixel == 500));
+
+        assert!(matches!(
+            correction_context.enable_flat_field_correction(&vec![100u16; size], &[]),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+
+    #[test]
+    fn gain_before_dark_requires_override() {
+        let (queue, device) = initialise_gpu_resources();
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 1).unwrap();
+        let order = vec![
+            CorrectionStage::Gain,
+            CorrectionStage::Dark,
+            CorrectionStage::Defect,
+        ];
+
+        match correction_context.set_stage_order(order.clone(), false) {
+            Err(MyError::InvalidStageOrder(message)) => {
+                assert!(message.starts_with("dark must run before gain"))
+            }
+            _ => panic!("gain before dark was accepted"),
+        }
+        assert!(correction_context
+            .set_stage_order(vec![CorrectionStage::Dark, CorrectionStage::Dark], true)
+            .is_err());
+
+        correction_context.set_stage_order(order, true).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![0u16; 64 * 64], 300)
+            .unwrap();
+        correction_context
+            .enable_gain_correction(&vec![1.0f32; 64 * 64])
+            .unwrap();
+        assert_eq!(
+            correction_context.stages(),
+            [CorrectionStage::Gain, CorrectionStage::Dark]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn awaiting_the_handle_gives_the_corrected_frame() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        assert!(matches!(
+            correction_context.process_image_handle().await.unwrap(),
+            Err(MyError::NoInput)
+        ));
+
+        correction_context
+            .upload_image(&vec![1000u16; size])
+            .unwrap();
+        let corrected = correction_context
+            .process_image_handle()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(corrected, vec![1200u16; size]);
+        assert!(correction_context.collect_results().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn binning_averages_to_a_smaller_frame() {
+        let (queue, device) = initialise_gpu_resources();
+        // 65 is cropped to 32 binned columns.
+        let (width, height) = (65u32, 32u32);
+        let size = (width * height) as usize;
+        let mut correction_context = Corrections::new(device, queue, width, height, 1).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        assert!(matches!(
+            correction_context.enable_binning(33, BinMode::Average),
+            Err(MyError::InvalidParameter)
+        ));
+        correction_context
+            .enable_binning(2, BinMode::Average)
+            .unwrap();
+        assert_eq!(correction_context.output_dimensions(), (32, 16));
+
+        let binned = correction_context
+            .process_image_blocking(&vec![1000u16; size])
+            .unwrap();
+        assert_eq!(binned, vec![1200u16; 32 * 16]);
+
+        let mut image = vec![1000u16; size];
+        correction_context
+            .process_image_in_place(&mut image)
+            .unwrap();
+        assert!(image[..32 * 16].iter().all(|&pixel| pixel == 1200));
+        assert!(image[32 * 16..].iter().all(|&pixel| pixel == 1000));
+    }
+
+    #[test]
+    fn rotation_swaps_output_dimensions() {
+        let (queue, device) = initialise_gpu_resources();
+        let (width, height) = (64u32, 32u32);
+        let size = (width * height) as usize;
+        let mut correction_context = Corrections::new(device, queue, width, height, 1).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+        let image: Vec<u16> = (0..size as u16).map(|i| 1000 + i).collect();
+
+        correction_context
+            .set_orientation(Orientation::Rot90)
+            .unwrap();
+        assert_eq!(correction_context.output_dimensions(), (height, width));
+        let rotated = correction_context.process_image_blocking(&image).unwrap();
+        for y in 0..height {
+            for x in 0..width {
+                // Clockwise, so the source's last row becomes the output's first column.
+                let output = (x * height + height - 1 - y) as usize;
+                assert_eq!(rotated[output], image[(y * width + x) as usize] - 100 + 300);
+            }
+        }
+
+        correction_context
+            .set_orientation(Orientation::None)
+            .unwrap();
+        assert_eq!(correction_context.output_dimensions(), (width, height));
+        let unrotated = correction_context.process_image_blocking(&image).unwrap();
+        assert_eq!(unrotated[0], image[0] - 100 + 300);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn corner_dark_level_is_subtracted() {
+        let (queue, device) = initialise_gpu_resources();
+        let (width, height) = (128u32, 64u32);
+        let mut correction_context = Corrections::new(device, queue, width, height, 1).unwrap();
+        let corners = [
+            Rect {
+                x: 0,
+                y: 0,
+                w: 16,
+                h: 16,
+            },
+            Rect {
+                x: width - 16,
+                y: height - 16,
+                w: 16,
+                h: 16,
+            },
+        ];
+        correction_context
+            .enable_corner_dark_estimate(&corners)
+            .unwrap();
+
+        let image: Vec<u16> = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                let in_corner = corners.iter().any(|corner| {
+                    (corner.x..corner.x + corner.w).contains(&x)
+                        && (corner.y..corner.y + corner.h).contains(&y)
+                });
+                if in_corner {
+                    250
+                } else {
+                    1000 + (i % 7) as u16
+                }
+            })
+            .collect();
+        let corrected = correction_context.process_image_blocking(&image).unwrap();
+
+        for (&before, &after) in image.iter().zip(&corrected) {
+            assert_eq!(after, before - 250);
+        }
+    }
+
+    #[test]
+    fn distortion_fields_must_match_the_frame() {
+        let (queue, device) = initialise_gpu_resources();
+        let correction_context = Corrections::new(device, queue, 64, 64, 1).unwrap();
+
+        assert!(matches!(
+            correction_context.enable_distortion_correction(&[0.0; 64 * 64], &[0.0; 64]),
+            Err(MyError::InvalidTextureData)
+        ));
+        assert!(correction_context.stages().is_empty());
+
+        correction_context
+            .enable_distortion_correction(&[0.5; 64 * 64], &[0.0; 64 * 64])
+            .unwrap();
+        assert_eq!(correction_context.stages(), [CorrectionStage::Distortion]);
+    }
+
+    #[test]
+    fn items_per_invocation_out_of_range_is_rejected() {
+        let (queue, device) = initialise_gpu_resources();
+        let correction_context = Corrections::new(device, queue, 64, 64, 1).unwrap();
+
+        for items_per_invocation in [0, MAX_ITEMS_PER_INVOCATION + 1] {
+            assert!(matches!(
+                correction_context.set_items_per_invocation(items_per_invocation),
+                Err(MyError::InvalidParameter)
+            ));
+        }
+        correction_context
+            .set_items_per_invocation(MAX_ITEMS_PER_INVOCATION)
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paused_context_rejects_frames_until_resumed() {
+        let (queue, device) = initialise_gpu_resources();
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let mut correction_context =
+            Corrections::new(device, queue, image_width, image_height, 4).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        correction_context.upload_image(&vec![1000; size]).unwrap();
+        correction_context.process_image().unwrap();
+
+        correction_context.pause();
+        correction_context.upload_image(&vec![2000; size]).unwrap();
+        assert!(matches!(
+            correction_context.process_image(),
+            Err(MyError::Paused)
+        ));
+
+        // The frame submitted before pausing still completes.
+        let results = correction_context.collect_results().unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].iter().all(|&pixel| pixel == 1000 - 100 + 300));
+
+        correction_context.resume();
+        correction_context.process_image().unwrap();
+        let results = correction_context.collect_results().unwrap();
+        assert!(results[0].iter().all(|&pixel| pixel == 2000 - 100 + 300));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn frames_alternate_between_registered_calibrations() {
+        let (queue, device) = initialise_gpu_resources();
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+        let defective = 64 * 10 + 10;
+
+        let mut correction_context =
+            Corrections::new(device, queue, image_width, image_height, 4).unwrap();
+        let mut defect_map = vec![0u16; size];
+        defect_map[defective] = 1;
+        correction_context
+            .register_calibration("open", &vec![100u16; size], 300, &vec![1.0f32; size], &[])
+            .unwrap();
+        correction_context
+            .register_calibration(
+                "filter",
+                &vec![200u16; size],
+                0,
+                &vec![2.0f32; size],
+                &defect_map,
+            )
+            .unwrap();
+
+        let mut image = vec![1000u16; size];
+        image[defective] = 60000;
+        let ids = ["open", "filter", "open", "filter"];
+        for id in ids {
+            correction_context.upload_image(&image).unwrap();
+            correction_context
+                .process_image_with_calibration(id)
+                .unwrap();
+        }
+
+        let results = correction_context.collect_results().unwrap();
+        assert_eq!(results.len(), ids.len());
+        for (id, frame) in ids.iter().zip(&results) {
+            match *id {
+                "open" => {
+                    assert_eq!(frame[0], 1000 - 100 + 300);
+                    assert_eq!(frame[defective], 60000 - 100 + 300);
+                }
+                _ => assert!(frame.iter().all(|&pixel| pixel == (1000 - 200) * 2)),
+            }
+        }
+
+        correction_context.upload_image(&image).unwrap();
+        assert!(matches!(
+            correction_context.process_image_with_calibration("missing"),
+            Err(MyError::UnknownCalibration(id)) if id == "missing"
+        ));
+        // The frame stays staged for a valid id.
+        correction_context
+            .process_image_with_calibration("open")
+            .unwrap();
+        assert_eq!(correction_context.collect_results().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn impossible_buffer_count_fails_before_allocating() {
+        let (queue, device) = initialise_gpu_resources();
+
+        let result = Corrections::new(device, queue, 4096, 4096, u32::MAX);
+        match result {
+            Err(MyError::InsufficientMemory {
+                requested,
+                available,
+            }) => {
+                assert_eq!(requested, 4 * u32::MAX as u64 * 4096 * 4096 * 2);
+                assert!(requested > available);
+            }
+            Err(e) => panic!("expected InsufficientMemory, got {e}"),
+            Ok(_) => panic!("expected InsufficientMemory"),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn flush_waits_for_chained_frames() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 4).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        // Nothing submitted yet.
+        correction_context.flush().unwrap();
+
+        for i in 0..4u16 {
+            correction_context
+                .upload_image(&vec![1000 + i; size])
+                .unwrap();
+            correction_context.process_image().unwrap();
+        }
+        correction_context.flush().unwrap();
+
+        let results = correction_context.collect_results().unwrap();
+        assert_eq!(results.len(), 4);
+        for (i, frame) in results.iter().enumerate() {
+            assert!(frame
+                .iter()
+                .all(|&pixel| pixel == 1000 + i as u16 - 100 + 300));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn roi_leaves_pixels_outside_untouched() {
+        let (queue, device) = initialise_gpu_resources();
+        let (width, height) = (4800u32, 5800u32);
+        let size = (width * height) as usize;
+        let mut correction_context = Corrections::new(device, queue, width, height, 1).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+        correction_context
+            .enable_gain_correction(&vec![2.0f32; size])
+            .unwrap();
+
+        let roi = Rect {
+            x: 1000,
+            y: 2000,
+            w: 100,
+            h: 100,
+        };
+        let inside_defect = ((roi.y + 50) * width + roi.x + 50) as usize;
+        let outside_defect = (10 * width + 10) as usize;
+        let mut defect_map = vec![0u16; size];
+        defect_map[inside_defect] = 1;
+        defect_map[outside_defect] = 1;
+        correction_context
+            .enable_defect_correction(&defect_map)
+            .unwrap();
+
+        assert!(matches!(
+            correction_context.set_roi(Some(Rect { x: 4750, ..roi })),
+            Err(MyError::InvalidParameter)
+        ));
+        assert!(matches!(
+            correction_context.set_roi(Some(Rect { w: 0, ..roi })),
+            Err(MyError::InvalidParameter)
+        ));
+        correction_context.set_roi(Some(roi)).unwrap();
+
+        let mut image = vec![1000u16; size];
+        image[inside_defect] = 5000;
+        image[outside_defect] = 5000;
+        correction_context.upload_image(&image).unwrap();
+        correction_context.process_image().unwrap();
+        let corrected = correction_context.collect_results().unwrap().pop().unwrap();
+
+        for y in 0..height {
+            for x in 0..width {
+                let index = (y * width + x) as usize;
+                let inside =
+                    (roi.x..roi.x + roi.w).contains(&x) && (roi.y..roi.y + roi.h).contains(&y);
+                let expected = if inside {
+                    (1000 - 100 + 300) * 2
+                } else {
+                    image[index]
+                };
+                assert_eq!(corrected[index], expected, "pixel ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn iq_frames_are_corrected_as_magnitudes() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+
+        let samples: Vec<i16> = (0..size).flat_map(|_| [300, -400]).collect();
+        assert!(matches!(
+            correction_context.process_iq_i16(&samples),
+            Err(MyError::InvalidParameter)
+        ));
+
+        correction_context.enable_magnitude(IqLayout::I16).unwrap();
+        let corrected = correction_context.process_iq_i16(&samples).unwrap();
+        assert_eq!(corrected.len(), size);
+        assert!(corrected.iter().all(|&pixel| pixel == 500 - 100 + 300));
+
+        assert!(matches!(
+            correction_context.process_iq_i16(&samples[..size]),
+            Err(MyError::InvalidTextureData)
+        ));
+        assert!(matches!(
+            correction_context.process_iq_f32(&vec![0.0; 2 * size]),
+            Err(MyError::InvalidParameter)
+        ));
+    }
+
+    #[test]
+    fn gpu_timings_cover_the_passes_that_ran() {
+        let (queue, device) = initialise_gpu_resources();
+        let size = 64 * 64;
+        let mut correction_context = Corrections::new(device, queue, 64, 64, 2).unwrap();
+        assert!(correction_context.last_frame_timings().is_none());
+
+        correction_context
+            .enable_dark_map_correction(&vec![100u16; size], 300)
+            .unwrap();
+        correction_context
+            .enable_gain_correction(&vec![0.5f32; size])
+            .unwrap();
+        correction_context
+            .process_image_blocking(&vec![1000u16; size])
+            .unwrap();
+
+        // Devices whose queue can't write timestamps report no timings at all.
+        let Some(timings) = correction_context.last_frame_timings() else {
+            return;
+        };
+        assert!(timings.dark_us.is_some_and(|micros| micros >= 0.0));
+        assert!(timings.gain_us.is_some_and(|micros| micros >= 0.0));
+        assert_eq!(timings.defect_us, None);
     }
 }
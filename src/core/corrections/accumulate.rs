@@ -0,0 +1,351 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage},
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
+    },
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, Queue},
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    shader::SpecializationConstant,
+    sync::{self, GpuFuture},
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+mod accumulate_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(constant_id = 0) const bool MEDIAN = false;
+
+                // See `MAX_MEDIAN_FRAMES`.
+                #define MAX_MEDIAN_FRAMES 64
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                    uint frame_count;
+                };
+
+                // The frames one after another.
+                PIXEL_BUFFER(0, frames)
+                PIXEL_BUFFER(1, result)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    if (MEDIAN) {
+                        // Insertion sort of the pixel's values as they are read.
+                        uint values[MAX_MEDIAN_FRAMES];
+                        for (uint frame = 0; frame < frame_count; ++frame) {
+                            uint value = load_frames(frame * pixel_count + idx);
+                            uint i = frame;
+                            while (i > 0 && values[i - 1] > value) {
+                                values[i] = values[i - 1];
+                                --i;
+                            }
+                            values[i] = value;
+                        }
+                        store_result(idx, (values[(frame_count - 1) / 2] + values[frame_count / 2]) / 2);
+                    } else {
+                        uint sum = 0;
+                        for (uint frame = 0; frame < frame_count; ++frame) {
+                            sum += load_frames(frame * pixel_count + idx);
+                        }
+                        // Rounded to the nearest count.
+                        store_result(idx, (sum + frame_count / 2) / frame_count);
+                    }
+                }
+            "
+    );
+}
+
+/// How [`AccumulateResources`] reduces a stack of frames to one, such as a master dark or
+/// flat.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Accumulate {
+    /// Mean of each pixel, rounded to the nearest count.
+    #[default]
+    Mean,
+    /// Median of each pixel, ignoring cosmic rays and other outliers of single frames. Of
+    /// an even number of frames, the mean of the middle two, rounded down.
+    Median,
+}
+
+/// Most frames `Accumulate::Mean` reduces, each pixel's sum being kept in 32 bits.
+pub const MAX_MEAN_FRAMES: usize = 65536;
+
+/// Most frames `Accumulate::Median` reduces, each invocation sorting its pixel's values in
+/// registers.
+pub const MAX_MEDIAN_FRAMES: usize = 64;
+
+impl Accumulate {
+    pub fn max_frames(self) -> usize {
+        match self {
+            Accumulate::Mean => MAX_MEAN_FRAMES,
+            Accumulate::Median => MAX_MEDIAN_FRAMES,
+        }
+    }
+}
+
+/// Reduces a stack of frames to one per pixel in a single dispatch, each invocation
+/// reading its pixel from every frame.
+pub struct AccumulateResources {
+    pipeline: Arc<ComputePipeline>,
+    mode: Accumulate,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+impl AccumulateResources {
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        mode: Accumulate,
+    ) -> Result<Self, MyError> {
+        let pipeline = {
+            let cs = accumulate_shader::load(device.clone())
+                .unwrap()
+                .specialize(
+                    [(0, SpecializationConstant::Bool(mode == Accumulate::Median))]
+                        .into_iter()
+                        .collect(),
+                )
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        Ok(AccumulateResources {
+            pipeline,
+            mode,
+            memory_allocator,
+            descriptor_set_allocator,
+        })
+    }
+
+    /// Reduces `frames` of `pixel_count` pixels each, blocking until the result is read
+    /// back. An empty stack or one of more than `max_frames` frames is
+    /// `MyError::InvalidParameter`, a frame of another length `MyError::InvalidTextureData`
+    /// and a stack too large to bind as one storage buffer `MyError::InsufficientMemory`.
+    pub fn accumulate(
+        &self,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        pixel_count: u32,
+        frames: &[&[u16]],
+    ) -> Result<Vec<u16>, MyError> {
+        if frames.is_empty() || frames.len() > self.mode.max_frames() || pixel_count == 0 {
+            return Err(MyError::InvalidParameter);
+        }
+        if frames
+            .iter()
+            .any(|frame| frame.len() != pixel_count as usize)
+        {
+            return Err(MyError::InvalidTextureData);
+        }
+
+        let stack_len = frames.len() as u64 * pixel_count as u64;
+        let requested = stack_len * std::mem::size_of::<u16>() as u64;
+        let available = queue
+            .device()
+            .physical_device()
+            .properties()
+            .max_storage_buffer_range as u64;
+        if requested > available {
+            return Err(MyError::InsufficientMemory {
+                requested,
+                available,
+            });
+        }
+
+        let stack = Buffer::new_slice::<u16>(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            stack_len,
+        )
+        .map_err(|e| MyError::AllocationError("frame stack", e.to_string()))?;
+        for (staged, frame) in stack
+            .write()
+            .unwrap()
+            .chunks_exact_mut(pixel_count as usize)
+            .zip(frames)
+        {
+            staged.copy_from_slice(frame);
+        }
+
+        let result = Buffer::new_slice::<u16>(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            pixel_count as u64,
+        )
+        .map_err(|e| MyError::AllocationError("accumulated frame", e.to_string()))?;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, stack),
+                WriteDescriptorSet::buffer(1, result.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                accumulate_shader::Params {
+                    pixel_count,
+                    frame_count: frames.len() as u32,
+                },
+            )
+            .unwrap()
+            .dispatch([pixel_count.div_ceil(64), 1, 1])
+            .unwrap();
+
+        sync::now(queue.device().clone())
+            .then_execute(queue.clone(), builder.end().unwrap())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        let result = result.read().unwrap().to_vec();
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Accumulate, AccumulateResources, MAX_MEDIAN_FRAMES};
+    use crate::core::{error::MyError, test_utils::TestContext};
+
+    fn accumulate(frames: &[Vec<u16>], mode: Accumulate) -> Result<Vec<u16>, MyError> {
+        let context = TestContext::new();
+        let resources = AccumulateResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            mode,
+        )
+        .unwrap();
+
+        let frames: Vec<&[u16]> = frames.iter().map(Vec::as_slice).collect();
+        resources.accumulate(
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            frames.first().map_or(1, |frame| frame.len() as u32),
+            &frames,
+        )
+    }
+
+    #[test]
+    fn mean_rounds_to_the_nearest_count() {
+        let frames = vec![
+            vec![100, 0, 65535],
+            vec![100, 1, 65535],
+            vec![101, 1, 65535],
+        ];
+        // 301 / 3 rounds down, 2 / 3 up, and a saturated stack stays saturated.
+        assert_eq!(
+            accumulate(&frames, Accumulate::Mean).unwrap(),
+            [100, 1, 65535]
+        );
+    }
+
+    #[test]
+    fn median_ignores_outliers() {
+        // Not a multiple of the workgroup size, so the last workgroup is partly idle.
+        let mut frames: Vec<Vec<u16>> = (0..5).map(|frame| vec![1000 + frame; 100]).collect();
+        frames[3][7] = 60000;
+        frames[1][42] = 0;
+
+        let result = accumulate(&frames, Accumulate::Median).unwrap();
+        assert!(result.iter().all(|&pixel| pixel == 1002));
+
+        // Of an even number of frames, the mean of the middle two.
+        let frames = vec![vec![10], vec![20], vec![31], vec![1000]];
+        assert_eq!(accumulate(&frames, Accumulate::Median).unwrap(), [25]);
+    }
+
+    #[test]
+    fn invalid_stacks_are_rejected() {
+        assert!(matches!(
+            accumulate(&[], Accumulate::Mean),
+            Err(MyError::InvalidParameter)
+        ));
+        assert!(matches!(
+            accumulate(&vec![vec![0; 4]; MAX_MEDIAN_FRAMES + 1], Accumulate::Median),
+            Err(MyError::InvalidParameter)
+        ));
+        assert!(matches!(
+            accumulate(&[vec![0; 4], vec![0; 3]], Accumulate::Mean),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+}
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+
+/// One bin per possible difference of two u16 values, -65535 to 65535.
+const BIN_COUNT: u32 = 2 * u16::MAX as u32 + 1;
+
+mod difference_histogram_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                };
+
+                PIXEL_BUFFER(0, reference)
+                PIXEL_BUFFER(1, darkMap)
+                layout(set = 0, binding = 2) buffer Histogram {
+                    uint bins[];
+                };
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    int difference = int(load_reference(idx)) - int(load_darkMap(idx));
+                    atomicAdd(bins[difference + 65535], 1);
+                }
+            "
+    );
+}
+
+/// Histogram of `reference - dark` used to pick a dark offset that keeps the corrected
+/// histogram positive.
+pub struct AutoOffsetResources {
+    pipeline: Arc<ComputePipeline>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+/// Histogram readable once the recorded pass has completed.
+pub struct DifferenceHistogram {
+    bins: Subbuffer<[u32]>,
+}
+
+impl DifferenceHistogram {
+    /// Smallest difference at or below which at least `percentile` of the pixels fall.
+    pub fn percentile(&self, percentile: f32) -> i32 {
+        let bins = self.bins.read().unwrap();
+        let total: u64 = bins.iter().map(|&count| count as u64).sum();
+        let threshold = (percentile as f64 * total as f64).ceil().max(1.0) as u64;
+
+        let mut cumulative = 0u64;
+        for (bin, &count) in bins.iter().enumerate() {
+            cumulative += count as u64;
+            if cumulative >= threshold {
+                return bin as i32 - u16::MAX as i32;
+            }
+        }
+        u16::MAX as i32
+    }
+}
+
+impl AutoOffsetResources {
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    ) -> Self {
+        let pipeline = {
+            let cs = difference_histogram_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)
+                .unwrap();
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        AutoOffsetResources {
+            pipeline,
+            memory_allocator,
+            descriptor_set_allocator,
+        }
+    }
+
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        reference_buffer: Subbuffer<[u16]>,
+        dark_map_buffer: Subbuffer<[u16]>,
+    ) -> DifferenceHistogram {
+        let local_size_x = 64;
+
+        let pixel_count = image_width * image_height;
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        let bins = Buffer::from_iter(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            vec![0u32; BIN_COUNT as usize],
+        )
+        .unwrap();
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, reference_buffer),
+                WriteDescriptorSet::buffer(1, dark_map_buffer),
+                WriteDescriptorSet::buffer(2, bins.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                difference_histogram_shader::Params { pixel_count },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+
+        DifferenceHistogram { bins }
+    }
+}
@@ -0,0 +1,268 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::Subbuffer,
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    shader::SpecializationConstant,
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+/// Largest supported bin size. The sum of a 16x16 block of u16 pixels still fits a u32.
+pub const MAX_BIN_FACTOR: u32 = 16;
+
+/// How the pixels of a block are combined.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BinMode {
+    /// Sum of the block, saturating at 65535.
+    Sum,
+    /// Mean of the block, rounded to the nearest count.
+    #[default]
+    Average,
+}
+
+mod binning_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(constant_id = 0) const bool AVERAGE = true;
+
+                layout(push_constant) uniform Params {
+                    uint image_width;
+                    uint output_width;
+                    uint output_height;
+                    uint factor;
+                };
+
+                PIXEL_BUFFER(0, image)
+                PIXEL_BUFFER(1, result)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= output_width * output_height) {
+                        return;
+                    }
+
+                    uint left = (idx % output_width) * factor;
+                    uint top = (idx / output_width) * factor;
+                    uint sum = 0u;
+                    for (uint y = top; y < top + factor; ++y) {
+                        for (uint x = left; x < left + factor; ++x) {
+                            sum += load_image(y * image_width + x);
+                        }
+                    }
+
+                    if (AVERAGE) {
+                        uint count = factor * factor;
+                        store_result(idx, (sum + count / 2u) / count);
+                    } else {
+                        store_result(idx, min(sum, 65535u));
+                    }
+                }
+            "
+    );
+}
+
+/// Sums or averages `factor` by `factor` blocks of a frame into one pixel each, giving a
+/// `(width / factor)` by `(height / factor)` frame. Rows and columns past the last whole
+/// block are cropped.
+pub struct BinningResources {
+    factor: u32,
+    mode: BinMode,
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+impl BinningResources {
+    /// A `factor` of 0 or above [`MAX_BIN_FACTOR`] is `MyError::InvalidParameter`.
+    pub fn new(
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        factor: u32,
+        mode: BinMode,
+    ) -> Result<Self, MyError> {
+        if factor == 0 || factor > MAX_BIN_FACTOR {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let pipeline = {
+            let cs = binning_shader::load(device.clone())
+                .unwrap()
+                .specialize(
+                    [(0, SpecializationConstant::Bool(mode == BinMode::Average))]
+                        .into_iter()
+                        .collect(),
+                )
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let pipeline_layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, pipeline_layout),
+            )
+            .unwrap()
+        };
+
+        Ok(BinningResources {
+            factor,
+            mode,
+            pipeline,
+            descriptor_set_allocator,
+        })
+    }
+
+    pub fn factor(&self) -> u32 {
+        self.factor
+    }
+
+    pub fn mode(&self) -> BinMode {
+        self.mode
+    }
+
+    /// Width and height of a binned `width` by `height` frame.
+    pub fn output_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        (width / self.factor, height / self.factor)
+    }
+
+    /// Writes the binned `image_buffer` to the start of `result_buffer`.
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+        result_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let (output_width, output_height) = self.output_dimensions(image_width, image_height);
+        let dispatch_size_x = (output_width * output_height + local_size_x - 1) / local_size_x;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, image_buffer),
+                WriteDescriptorSet::buffer(1, result_buffer),
+            ],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                binning_shader::Params {
+                    image_width,
+                    output_width,
+                    output_height,
+                    factor: self.factor,
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BinMode, BinningResources, MAX_BIN_FACTOR};
+    use crate::core::{error::MyError, test_utils::TestContext};
+
+    fn bin(image: Vec<u16>, width: u32, height: u32, factor: u32, mode: BinMode) -> Vec<u16> {
+        let context = TestContext::new();
+        let resources = BinningResources::new(
+            context.device.clone(),
+            context.descriptor_set_allocator.clone(),
+            factor,
+            mode,
+        )
+        .unwrap();
+        let (output_width, output_height) = resources.output_dimensions(width, height);
+
+        let image_buffer = context.host_buffer(image);
+        let result_buffer =
+            context.host_buffer(vec![0u16; (output_width * output_height) as usize]);
+        context.submit(|builder| {
+            resources.apply_pipeline(
+                builder,
+                width,
+                height,
+                image_buffer.clone(),
+                result_buffer.clone(),
+            )
+        });
+        let binned = result_buffer.read().unwrap().to_vec();
+        binned
+    }
+
+    #[test]
+    fn averaging_a_constant_image_keeps_the_constant() {
+        let binned = bin(vec![1234u16; 64 * 32], 64, 32, 2, BinMode::Average);
+        assert_eq!(binned, vec![1234u16; 32 * 16]);
+    }
+
+    #[test]
+    fn sums_saturate_and_remainders_are_cropped() {
+        // 5x3 with a 2x2 bin: the last column and row are cropped.
+        #[rustfmt::skip]
+        let image = vec![
+            1, 2, 3, 4, 99,
+            5, 6, 7, 8, 99,
+            99, 99, 99, 99, 99,
+        ];
+        assert_eq!(bin(image.clone(), 5, 3, 2, BinMode::Sum), [14, 22]);
+        assert_eq!(bin(image, 5, 3, 2, BinMode::Average), [4, 6]);
+
+        assert_eq!(bin(vec![40000u16; 16], 4, 4, 4, BinMode::Sum), [u16::MAX]);
+    }
+
+    #[test]
+    fn out_of_range_factor_is_rejected() {
+        let context = TestContext::new();
+        for factor in [0, MAX_BIN_FACTOR + 1] {
+            let result = BinningResources::new(
+                context.device.clone(),
+                context.descriptor_set_allocator.clone(),
+                factor,
+                BinMode::Sum,
+            );
+            assert!(matches!(result, Err(MyError::InvalidParameter)));
+        }
+    }
+}
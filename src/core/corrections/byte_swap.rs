@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::Subbuffer,
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+
+mod byte_swap_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                };
+
+                PIXEL_BUFFER(0, image)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    uint value = load_image(idx);
+                    store_image(idx, (value >> 8) | ((value & 0xFF) << 8));
+                }
+            "
+    );
+}
+
+/// Swaps the two bytes of every pixel, for consumers expecting the opposite byte order.
+pub struct ByteSwapResources {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+impl ByteSwapResources {
+    pub fn new(
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    ) -> Self {
+        let pipeline = {
+            let cs = byte_swap_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)
+                .unwrap();
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        ByteSwapResources {
+            pipeline,
+            descriptor_set_allocator,
+        }
+    }
+
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let pixel_count = image_width * image_height;
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [WriteDescriptorSet::buffer(0, image_buffer)],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                byte_swap_shader::Params { pixel_count },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ByteSwapResources;
+    use crate::core::test_utils::TestContext;
+
+    #[test]
+    fn swaps_bytes_of_each_pixel() {
+        let context = TestContext::new();
+        let image = vec![0x0000u16, 0x00FF, 0x1234, 0xFF00, 0xABCD];
+
+        let resources = ByteSwapResources::new(
+            context.device.clone(),
+            context.descriptor_set_allocator.clone(),
+        );
+
+        let image_buffer = context.host_buffer(image.clone());
+        context.submit(|builder| resources.apply_pipeline(builder, 5, 1, image_buffer.clone()));
+
+        let expected: Vec<u16> = image.iter().map(|pixel| pixel.swap_bytes()).collect();
+        assert_eq!(*image_buffer.read().unwrap(), expected[..]);
+    }
+}
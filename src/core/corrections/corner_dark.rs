@@ -0,0 +1,369 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    shader::EntryPoint,
+};
+
+use super::{pipeline_cache, EntryPointLookup, Rect, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+/// Most pixels the regions may cover together, so their sum fits the shader's u32
+/// accumulator.
+pub const MAX_CORNER_PIXELS: u32 = 1 << 16;
+
+mod region_sum_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint image_width;
+                    uint region_pixels;
+                };
+
+                // x, y, width and height of each region.
+                layout(set = 0, binding = 0) readonly buffer Regions {
+                    uvec4 regions[];
+                };
+                PIXEL_BUFFER(1, image)
+                layout(set = 0, binding = 2) buffer Sum {
+                    uint sum;
+                };
+
+                void main() {
+                    uint i = gl_GlobalInvocationID.x;
+                    if (i >= region_pixels) {
+                        return;
+                    }
+
+                    // The regions' pixels are numbered one region after another.
+                    uint r = 0u;
+                    while (i >= regions[r].z * regions[r].w) {
+                        i -= regions[r].z * regions[r].w;
+                        ++r;
+                    }
+                    uvec4 region = regions[r];
+                    uint x = region.x + i % region.z;
+                    uint y = region.y + i / region.z;
+                    atomicAdd(sum, load_image(y * image_width + x));
+                }
+            "
+    );
+}
+
+mod subtract_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                    uint region_pixels;
+                };
+
+                layout(set = 0, binding = 0) readonly buffer Sum {
+                    uint sum;
+                };
+                PIXEL_BUFFER(1, image)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    uint level = (sum + region_pixels / 2u) / region_pixels;
+                    uint value = load_image(idx);
+                    store_image(idx, value > level ? value - level : 0u);
+                }
+            "
+    );
+}
+
+/// Estimates the dark level of each frame as the mean of unexposed regions, typically its
+/// corners, and subtracts it from every pixel, saturating at zero. For detectors without a
+/// dark map, or to track drift on top of one.
+pub struct CornerDarkResources {
+    sum_pipeline: Arc<ComputePipeline>,
+    subtract_pipeline: Arc<ComputePipeline>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    regions_buffer: Subbuffer<[[u32; 4]]>,
+    region_pixels: u32,
+}
+
+impl CornerDarkResources {
+    /// No regions, an empty region, one that doesn't fit the image or regions covering
+    /// more than [`MAX_CORNER_PIXELS`] together are `MyError::InvalidParameter`.
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        regions: &[Rect],
+        image_height: u32,
+        image_width: u32,
+    ) -> Result<Self, MyError> {
+        if regions.is_empty()
+            || !regions
+                .iter()
+                .all(|region| region.fits(image_width, image_height))
+        {
+            return Err(MyError::InvalidParameter);
+        }
+        let region_pixels: u64 = regions
+            .iter()
+            .map(|region| region.pixel_count() as u64)
+            .sum();
+        if region_pixels > MAX_CORNER_PIXELS as u64 {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let regions_buffer = Buffer::from_iter(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            regions
+                .iter()
+                .map(|region| [region.x, region.y, region.w, region.h]),
+        )
+        .map_err(|e| MyError::AllocationError("corner dark regions", e.to_string()))?;
+
+        let create_pipeline = |cs: EntryPoint| {
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+        let sum_pipeline = create_pipeline(
+            region_sum_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?,
+        );
+        let subtract_pipeline = create_pipeline(
+            subtract_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?,
+        );
+
+        Ok(CornerDarkResources {
+            sum_pipeline,
+            subtract_pipeline,
+            memory_allocator,
+            descriptor_set_allocator,
+            regions_buffer,
+            region_pixels: region_pixels as u32,
+        })
+    }
+
+    /// Sums the regions of `image_buffer` into a buffer of the frame's own, so frames in
+    /// flight don't share it, then subtracts their mean from the whole frame.
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let sum = Buffer::from_data(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            0u32,
+        )
+        .unwrap();
+
+        let sum_set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            self.sum_pipeline.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::buffer(0, self.regions_buffer.clone()),
+                WriteDescriptorSet::buffer(1, image_buffer.clone()),
+                WriteDescriptorSet::buffer(2, sum.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.sum_pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.sum_pipeline.layout().clone(),
+                0,
+                sum_set,
+            )
+            .unwrap()
+            .push_constants(
+                self.sum_pipeline.layout().clone(),
+                0,
+                region_sum_shader::Params {
+                    image_width,
+                    region_pixels: self.region_pixels,
+                },
+            )
+            .unwrap()
+            .dispatch([self.region_pixels.div_ceil(local_size_x), 1, 1])
+            .unwrap();
+
+        let pixel_count = image_width * image_height;
+        let subtract_set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            self.subtract_pipeline.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::buffer(0, sum),
+                WriteDescriptorSet::buffer(1, image_buffer),
+            ],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.subtract_pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.subtract_pipeline.layout().clone(),
+                0,
+                subtract_set,
+            )
+            .unwrap()
+            .push_constants(
+                self.subtract_pipeline.layout().clone(),
+                0,
+                subtract_shader::Params {
+                    pixel_count,
+                    region_pixels: self.region_pixels,
+                },
+            )
+            .unwrap()
+            .dispatch([pixel_count.div_ceil(local_size_x), 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CornerDarkResources, MAX_CORNER_PIXELS};
+    use crate::core::{corrections::Rect, error::MyError, test_utils::TestContext};
+
+    fn resources(
+        context: &TestContext,
+        regions: &[Rect],
+        width: u32,
+        height: u32,
+    ) -> Result<CornerDarkResources, MyError> {
+        CornerDarkResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            regions,
+            height,
+            width,
+        )
+    }
+
+    #[test]
+    fn corner_level_is_subtracted_from_the_exposed_region() {
+        let context = TestContext::new();
+        let (width, height) = (64u32, 48u32);
+        let corner = |x, y| Rect { x, y, w: 8, h: 8 };
+        let regions = [corner(0, 0), corner(56, 0), corner(0, 40), corner(56, 40)];
+
+        // Corners alternate around a level of 500, the exposed region sits at 3000.
+        let image: Vec<u16> = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                let in_corner = (x < 8 || x >= 56) && (y < 8 || y >= 40);
+                match (in_corner, i % 2) {
+                    (true, 0) => 490,
+                    (true, _) => 510,
+                    (false, _) => 3000,
+                }
+            })
+            .collect();
+
+        let resources = resources(&context, &regions, width, height).unwrap();
+        let image_buffer = context.host_buffer(image.clone());
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, width, height, image_buffer.clone())
+        });
+
+        let corrected = image_buffer.read().unwrap();
+        for (i, (&before, &after)) in image.iter().zip(corrected.iter()).enumerate() {
+            let expected = before.saturating_sub(500);
+            assert_eq!(after, expected, "pixel {i}");
+        }
+    }
+
+    #[test]
+    fn invalid_regions_are_rejected() {
+        let context = TestContext::new();
+        let region = Rect {
+            x: 0,
+            y: 0,
+            w: 8,
+            h: 8,
+        };
+
+        for regions in [
+            vec![],
+            vec![region, Rect { w: 0, ..region }],
+            vec![Rect { x: 60, ..region }],
+            vec![Rect {
+                w: 64,
+                h: MAX_CORNER_PIXELS / 64 + 1,
+                ..region
+            }],
+        ] {
+            assert!(matches!(
+                resources(&context, &regions, 64, 2048),
+                Err(MyError::InvalidParameter)
+            ));
+        }
+    }
+}
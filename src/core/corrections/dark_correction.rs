@@ -1,9 +1,12 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc,
+};
 
 use vulkano::{
     buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
     command_buffer::{
-        allocator::StandardCommandBufferAllocator, CommandBufferUsage, PrimaryAutoCommandBuffer,
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, CopyBufferInfo,
         RecordingCommandBuffer,
     },
     descriptor_set::{
@@ -19,11 +22,73 @@ use vulkano::{
     sync::{self, GpuFuture},
 };
 
+use super::{frame_len, grid_stride_dispatch_size, local_size_entry_point, pipeline_cache, Rect};
+use crate::core::error::MyError;
+
+mod offset_correction_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+                #include <roi.glsl>
+
+                layout(local_size_x_id = 100, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint offset;
+                    uint clamp_result;
+                    uint pixel_count;
+                    uint items_per_invocation;
+                    uint image_width;
+                    uint roi_x;
+                    uint roi_y;
+                    uint roi_width;
+                    uint frame_pixels;
+                };
+
+                PIXEL_BUFFER(0, darkMap)
+                PIXEL_BUFFER(1, image)
+
+                void main() {
+                    // Frames of a batch follow each other in the image buffer, one per z,
+                    // and share the map.
+                    uint frame = gl_GlobalInvocationID.z * frame_pixels;
+
+                    // Grid-stride loop, an invocation's pixels lying a whole dispatch apart so
+                    // neighbouring invocations still access neighbouring pixels.
+                    uint stride = gl_NumWorkGroups.x * gl_WorkGroupSize.x;
+                    for (uint item = 0; item < items_per_invocation; ++item) {
+                        uint i = gl_GlobalInvocationID.x + item * stride;
+                        if (i >= pixel_count) {
+                            return;
+                        }
+
+                        uint idx = roi_pixel(i, roi_x, roi_y, roi_width, image_width);
+                        uint value = load_image(frame + idx);
+                        uint dark = load_darkMap(idx);
+                        if (clamp_result != 0) {
+                            int difference = max(int(value) - int(dark), 0);
+                            store_image(frame + idx, uint(min(difference + int(offset), 65535)));
+                        } else {
+                            // Wraps like u16 arithmetic, store_image keeps the low 16 bits.
+                            store_image(frame + idx, value - dark + offset);
+                        }
+                    }
+                }
+            "
+    );
+}
+
 pub struct DarkMapBufferResources {
     pipeline: Arc<ComputePipeline>,
     dark_map_buffer: Subbuffer<[u16]>,
     memory_allocator: Arc<StandardMemoryAllocator>,
     descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    /// Pushed as a constant on every dispatch so it can change between frames.
+    offset: AtomicU32,
+    /// Saturates `image - dark` at zero instead of letting it wrap around.
+    clamp: AtomicBool,
+    local_size: u32,
 }
 
 impl DarkMapBufferResources {
@@ -37,37 +102,54 @@ impl DarkMapBufferResources {
         offset: u32,
         image_height: u32,
         image_width: u32,
+        local_size: u32,
+    ) -> Result<Self, MyError> {
+        let dark_map_buffer = Buffer::new_slice(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            frame_len(image_width, image_height)?, /* number of elements, matching the image size */
+        )
+        .map_err(|e| MyError::AllocationError("dark map", e.to_string()))?;
+
+        dark_map_buffer.write().unwrap().copy_from_slice(dark_map);
+
+        Ok(Self::from_buffer(
+            device,
+            queue,
+            command_buffer_allocator,
+            memory_allocator,
+            descriptor_set_allocator,
+            dark_map_buffer,
+            offset,
+            local_size,
+        ))
+    }
+
+    pub fn from_buffer(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        dark_map_buffer: Subbuffer<[u16]>,
+        offset: u32,
+        local_size: u32,
     ) -> Self {
         let pipeline = {
-            mod offset_correction_shader {
-                vulkano_shaders::shader! {
-                    ty: "compute",
-                    src: r"
-                            #version 450
-                            #extension GL_EXT_shader_16bit_storage : require
-                            #extension GL_EXT_shader_explicit_arithmetic_types_int16 : require
-
-                            layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
-
-                            layout(set = 0, binding = 0) buffer DarkMapData {
-                                uint16_t darkMapData[];
-                            };
-                            layout(set = 0, binding = 1) buffer ImageData {
-                                uint16_t imageData[];
-                            };
-        
-                            void main() {
-                                uint idx = gl_GlobalInvocationID.x;
-                                imageData[idx] += (- darkMapData[idx] + uint16_t(300));
-                            }
-                        ",
-                }
-            }
-
-            let cs = offset_correction_shader::load(device.clone())
-                .unwrap()
-                .entry_point("main")
-                .unwrap();
+            let cs = local_size_entry_point(
+                offset_correction_shader::load(device.clone()).unwrap(),
+                local_size,
+                [],
+            )
+            .unwrap();
             let stage = PipelineShaderStageCreateInfo::new(cs);
             let layout = PipelineLayout::new(
                 device.clone(),
@@ -78,29 +160,12 @@ impl DarkMapBufferResources {
             .unwrap();
             ComputePipeline::new(
                 device.clone(),
-                None,
+                pipeline_cache(&device),
                 ComputePipelineCreateInfo::stage_layout(stage, layout),
             )
             .unwrap()
         };
 
-        let dark_map_buffer = Buffer::new_slice(
-            memory_allocator.clone(),
-            BufferCreateInfo {
-                usage: BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER,
-                ..Default::default()
-            },
-            AllocationCreateInfo {
-                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
-                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
-                ..Default::default()
-            },
-            (image_width * image_height) as u64, /* number of elements, matching the image size */
-        )
-        .unwrap();
-
-        dark_map_buffer.write().unwrap().copy_from_slice(dark_map);
-
         let builder = RecordingCommandBuffer::primary(
             command_buffer_allocator,
             queue.queue_family_index(),
@@ -123,19 +188,168 @@ impl DarkMapBufferResources {
             dark_map_buffer,
             memory_allocator,
             descriptor_set_allocator,
+            offset: AtomicU32::new(offset),
+            clamp: AtomicBool::new(true),
+            local_size,
         }
     }
 
-    pub fn apply_pipeline(
+    pub fn offset(&self) -> u32 {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    /// Takes effect from the next recorded dispatch.
+    pub fn set_offset(&self, offset: u32) {
+        self.offset.store(offset, Ordering::Relaxed);
+    }
+
+    pub fn clamp(&self) -> bool {
+        self.clamp.load(Ordering::Relaxed)
+    }
+
+    /// Selects saturating (the default) or wrapping u16 arithmetic for pixels darker than
+    /// the dark map. Takes effect from the next recorded dispatch.
+    pub fn set_clamp(&self, clamp: bool) {
+        self.clamp.store(clamp, Ordering::Relaxed);
+    }
+
+    pub fn dark_map_buffer(&self) -> Subbuffer<[u16]> {
+        self.dark_map_buffer.clone()
+    }
+
+    /// Rewrites the dark map in place through a staging copy, keeping the pipeline and
+    /// buffer, and blocks until the copy has finished. No frame reading the map may be on
+    /// the GPU meanwhile, see `Corrections::update_dark_map`.
+    ///
+    /// A map of another length is `MyError::InvalidTextureData`, and a buffer adopted with
+    /// [`DarkMapBufferResources::from_buffer`] that can't be copied into
+    /// `MyError::InvalidParameter`. A device lost during the copy is `MyError::DeviceLost`.
+    pub fn update_map(
         &self,
-        builder: &mut RecordingCommandBuffer<PrimaryAutoCommandBuffer>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        new_map: &[u16],
+    ) -> Result<(), MyError> {
+        if new_map.len() as u64 != self.dark_map_buffer.len() {
+            return Err(MyError::InvalidTextureData);
+        }
+        if !self
+            .dark_map_buffer
+            .buffer()
+            .usage()
+            .intersects(BufferUsage::TRANSFER_DST)
+        {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let staging_buffer = Buffer::from_iter(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            new_map.iter().copied(),
+        )
+        .map_err(|e| MyError::AllocationError("dark map staging buffer", e.to_string()))?;
+
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        builder.copy_buffer(CopyBufferInfo::buffers(
+            staging_buffer,
+            self.dark_map_buffer.clone(),
+        ))?;
+
+        sync::now(queue.device().clone())
+            .then_execute(queue.clone(), builder.end()?)
+            .map_err(|e| MyError::SubmissionError(e.to_string()))?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+
+        Ok(())
+    }
+
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        self.apply_pipeline_in(
+            builder,
+            image_width,
+            image_height,
+            Rect::full(image_width, image_height),
+            1,
+            image_buffer,
+        );
+    }
+
+    /// Like [`DarkMapBufferResources::apply_pipeline`], correcting only the pixels in
+    /// `roi`, each invocation correcting `items_per_invocation` of them.
+    pub fn apply_pipeline_in<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
         image_width: u32,
         image_height: u32,
+        roi: Rect,
+        items_per_invocation: u32,
         image_buffer: Subbuffer<[u16]>,
     ) {
-        let local_size_x = 64;
+        self.record(
+            builder,
+            image_width,
+            image_height,
+            roi,
+            items_per_invocation,
+            1,
+            image_buffer,
+        );
+    }
 
-        let dispatch_size_x = (image_width * image_height + local_size_x - 1) / local_size_x;
+    /// Like [`DarkMapBufferResources::apply_pipeline`], for `frame_count` frames lying one
+    /// after another in `image_buffer`, corrected in one dispatch.
+    pub fn apply_pipeline_frames<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        frame_count: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        self.record(
+            builder,
+            image_width,
+            image_height,
+            Rect::full(image_width, image_height),
+            1,
+            frame_count,
+            image_buffer,
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn record<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        roi: Rect,
+        items_per_invocation: u32,
+        frame_count: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let pixel_count = roi.pixel_count();
+        let dispatch_size_x =
+            grid_stride_dispatch_size(pixel_count, self.local_size, items_per_invocation);
 
         let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
         let set = DescriptorSet::new(
@@ -159,7 +373,217 @@ impl DarkMapBufferResources {
                 set,
             )
             .unwrap()
-            .dispatch([dispatch_size_x, 1, 1])
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                offset_correction_shader::Params {
+                    offset: self.offset(),
+                    clamp_result: self.clamp() as u32,
+                    pixel_count,
+                    items_per_invocation,
+                    image_width,
+                    roi_x: roi.x,
+                    roi_y: roi.y,
+                    roi_width: roi.w,
+                    frame_pixels: image_width * image_height,
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, frame_count])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::DarkMapBufferResources;
+    use crate::core::corrections::{Rect, DEFAULT_LOCAL_SIZE, LOCAL_SIZE_CANDIDATES};
+    use crate::core::{error::MyError, test_utils::TestContext};
+
+    #[test]
+    fn updated_map_replaces_the_old_one_in_place() {
+        let context = TestContext::new();
+        let (width, height) = (8u32, 8u32);
+        let size = (width * height) as usize;
+
+        let resources = DarkMapBufferResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &vec![100u16; size],
+            0,
+            height,
+            width,
+            DEFAULT_LOCAL_SIZE,
+        )
+        .unwrap();
+        let buffer = resources.dark_map_buffer();
+
+        let new_map: Vec<u16> = (0..size as u16).collect();
+        resources
+            .update_map(
+                context.queue.clone(),
+                context.command_buffer_allocator.clone(),
+                &new_map,
+            )
+            .unwrap();
+        // Same buffer, new contents.
+        assert!(Arc::ptr_eq(
+            resources.dark_map_buffer().buffer(),
+            buffer.buffer()
+        ));
+
+        let image_buffer = context.host_buffer(vec![1000u16; size]);
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, width, height, image_buffer.clone())
+        });
+        let expected: Vec<u16> = new_map.iter().map(|&dark| 1000 - dark).collect();
+        assert_eq!(*image_buffer.read().unwrap(), expected[..]);
+
+        assert!(matches!(
+            resources.update_map(
+                context.queue.clone(),
+                context.command_buffer_allocator.clone(),
+                &new_map[1..],
+            ),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+
+    #[test]
+    fn pixels_below_dark_map_saturate_at_offset() {
+        let context = TestContext::new();
+        let (width, height) = (8u32, 8u32);
+        let size = (width * height) as usize;
+        let offset = 300;
+
+        let resources = DarkMapBufferResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &vec![1000u16; size],
+            offset,
+            height,
+            width,
+            DEFAULT_LOCAL_SIZE,
+        )
+        .unwrap();
+
+        let image_buffer = context.host_buffer(vec![200u16; size]);
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, width, height, image_buffer.clone())
+        });
+        assert!(image_buffer
+            .read()
+            .unwrap()
+            .iter()
+            .all(|&pixel| pixel == offset as u16));
+
+        resources.set_clamp(false);
+        let image_buffer = context.host_buffer(vec![200u16; size]);
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, width, height, image_buffer.clone())
+        });
+        let wrapped = 200u16.wrapping_sub(1000).wrapping_add(offset as u16);
+        assert!(image_buffer
+            .read()
+            .unwrap()
+            .iter()
+            .all(|&pixel| pixel == wrapped));
+    }
+
+    #[test]
+    fn multiple_items_per_invocation_match_one() {
+        let context = TestContext::new();
+        // Not a multiple of any dispatch's invocation count, so the last items run past the
+        // end of the frame.
+        let (width, height) = (37u32, 29u32);
+        let size = (width * height) as usize;
+        let dark_map: Vec<u16> = (0..size).map(|i| (i % 97) as u16 * 3).collect();
+        let image: Vec<u16> = (0..size).map(|i| (i % 1013) as u16 + 100).collect();
+
+        let resources = DarkMapBufferResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &dark_map,
+            300,
+            height,
+            width,
+            DEFAULT_LOCAL_SIZE,
+        )
+        .unwrap();
+
+        let correct = |items_per_invocation| {
+            let image_buffer = context.host_buffer(image.clone());
+            context.submit(|builder| {
+                resources.apply_pipeline_in(
+                    builder,
+                    width,
+                    height,
+                    Rect::full(width, height),
+                    items_per_invocation,
+                    image_buffer.clone(),
+                )
+            });
+            let corrected = image_buffer.read().unwrap().to_vec();
+            corrected
+        };
+
+        let expected = correct(1);
+        for items_per_invocation in [2, 4, 8, 64] {
+            assert_eq!(correct(items_per_invocation), expected);
+        }
+    }
+
+    #[test]
+    fn every_local_size_candidate_matches_the_default() {
+        let context = TestContext::new();
+        let (width, height) = (37u32, 29u32);
+        let size = (width * height) as usize;
+        let dark_map: Vec<u16> = (0..size).map(|i| (i % 97) as u16 * 3).collect();
+        let image: Vec<u16> = (0..size).map(|i| (i % 1013) as u16 + 100).collect();
+
+        let correct = |local_size| {
+            let resources = DarkMapBufferResources::new(
+                context.device.clone(),
+                context.queue.clone(),
+                context.command_buffer_allocator.clone(),
+                context.memory_allocator.clone(),
+                context.descriptor_set_allocator.clone(),
+                &dark_map,
+                300,
+                height,
+                width,
+                local_size,
+            )
             .unwrap();
+            let image_buffer = context.host_buffer(image.clone());
+            context.submit(|builder| {
+                resources.apply_pipeline_in(
+                    builder,
+                    width,
+                    height,
+                    Rect::full(width, height),
+                    2,
+                    image_buffer.clone(),
+                )
+            });
+            let corrected = image_buffer.read().unwrap().to_vec();
+            corrected
+        };
+
+        let expected = correct(DEFAULT_LOCAL_SIZE);
+        for local_size in LOCAL_SIZE_CANDIDATES {
+            assert_eq!(correct(local_size), expected);
+        }
     }
 }
@@ -0,0 +1,237 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::Subbuffer,
+    command_buffer::{allocator::StandardCommandBufferAllocator, RecordingCommandBuffer},
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, Queue},
+    format::Format,
+    image::view::ImageView,
+    memory::allocator::StandardMemoryAllocator,
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::{core::create_image_texture, error::MyError};
+
+mod dark_correction_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint offset;
+                };
+
+                layout(set = 0, binding = 0, r16ui) uniform readonly uimage2D darkMap;
+                PIXEL_BUFFER(1, image)
+
+                void main() {
+                    ivec2 size = imageSize(darkMap);
+                    ivec2 pos = ivec2(gl_GlobalInvocationID.xy);
+
+                    if (pos.x >= size.x || pos.y >= size.y) {
+                        return;
+                    }
+
+                    uint idx = pos.y * size.x + pos.x;
+                    int difference = max(int(load_image(idx)) - int(imageLoad(darkMap, pos).r), 0);
+                    store_image(idx, uint(min(difference + int(offset), 65535)));
+                }
+            "
+    );
+}
+
+/// Dark correction reading the dark map from a 2D storage image, dispatched in 8x8 tiles,
+/// the counterpart of `DefectMapTextureResources`. Subtracts in place, saturating at zero
+/// like `DarkMapBufferResources` does by default.
+pub struct DarkMapTextureResources {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    dark_map_view: Arc<ImageView>,
+    offset: u32,
+}
+
+impl DarkMapTextureResources {
+    /// A `dark_map` that doesn't match the image size is `MyError::InvalidTextureData`.
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        dark_map: &[u16],
+        offset: u32,
+        image_height: u32,
+        image_width: u32,
+    ) -> Result<Self, MyError> {
+        let dark_map_image = create_image_texture(
+            queue,
+            command_buffer_allocator,
+            memory_allocator,
+            dark_map,
+            Format::R16_UINT,
+            image_width,
+            image_height,
+        )?;
+
+        let pipeline = {
+            let cs = dark_correction_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        Ok(DarkMapTextureResources {
+            pipeline,
+            descriptor_set_allocator,
+            dark_map_view: ImageView::new_default(dark_map_image).unwrap(),
+            offset,
+        })
+    }
+
+    /// Corrects `image_buffer` in place.
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size = 8;
+
+        let dispatch_size_x = (image_width + local_size - 1) / local_size;
+        let dispatch_size_y = (image_height + local_size - 1) / local_size;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(0, self.dark_map_view.clone()),
+                WriteDescriptorSet::buffer(1, image_buffer),
+            ],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                dark_correction_shader::Params {
+                    offset: self.offset,
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, dispatch_size_y, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DarkMapTextureResources;
+    use crate::core::{
+        corrections::{dark_correction::DarkMapBufferResources, DEFAULT_LOCAL_SIZE},
+        error::MyError,
+        test_utils::TestContext,
+    };
+
+    #[test]
+    fn texture_and_buffer_variants_match() {
+        let context = TestContext::new();
+        // Not a multiple of the tile size, so the edge tiles are partly idle.
+        let (width, height) = (100u32, 37u32);
+        let size = (width * height) as usize;
+
+        let image: Vec<u16> = (0..size).map(|i| (i % 4096) as u16).collect();
+        let dark_map: Vec<u16> = (0..size).map(|i| (i % 300) as u16).collect();
+
+        let buffer_resources = DarkMapBufferResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &dark_map,
+            100,
+            height,
+            width,
+            DEFAULT_LOCAL_SIZE,
+        )
+        .unwrap();
+        let texture_resources = DarkMapTextureResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &dark_map,
+            100,
+            height,
+            width,
+        )
+        .unwrap();
+
+        let buffer_image = context.host_buffer(image.clone());
+        let texture_image = context.host_buffer(image);
+        context.submit(|builder| {
+            buffer_resources.apply_pipeline(builder, width, height, buffer_image.clone());
+            texture_resources.apply_pipeline(builder, width, height, texture_image.clone());
+        });
+
+        assert_eq!(
+            *buffer_image.read().unwrap(),
+            *texture_image.read().unwrap()
+        );
+    }
+
+    #[test]
+    fn mismatched_dark_map_is_rejected() {
+        let context = TestContext::new();
+        let result = DarkMapTextureResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &[0; 15],
+            100,
+            4,
+            4,
+        );
+        assert!(matches!(result, Err(MyError::InvalidTextureData)));
+    }
+}
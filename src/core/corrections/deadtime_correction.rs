@@ -0,0 +1,247 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use vulkano::{
+    buffer::Subbuffer,
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    shader::SpecializationConstant,
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+/// Smallest denominator the shader divides by. Pixels at or past the saturation rate
+/// `1 / tau` are corrected as if they were just below it and so clamp to the u16 maximum.
+pub const MIN_LIVE_FRACTION: f32 = 1e-6;
+
+mod deadtime_correction_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(constant_id = 0) const float MIN_LIVE_FRACTION = 1e-6;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                    float tau;
+                };
+
+                PIXEL_BUFFER(0, image)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    float measured = float(load_image(idx));
+                    float live_fraction = max(1.0 - measured * tau, MIN_LIVE_FRACTION);
+                    float value = measured / live_fraction;
+                    store_image(idx, uint(clamp(round(value), 0.0, 65535.0)));
+                }
+            "
+    );
+}
+
+/// Non-paralyzable dead-time correction for photon-counting detectors,
+/// `measured / (1 - measured * tau)` with `tau` in frames per count, clamped to the u16
+/// range. The live fraction is clamped to [`MIN_LIVE_FRACTION`] so saturated pixels
+/// never divide by zero or a negative number.
+pub struct DeadtimeCorrectionResources {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    /// f32 bits, pushed on every dispatch so it can change between frames.
+    tau: AtomicU32,
+}
+
+impl DeadtimeCorrectionResources {
+    pub fn new(
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        tau: f32,
+    ) -> Result<Self, MyError> {
+        if !(tau.is_finite() && tau >= 0.0) {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let pipeline = {
+            let cs = deadtime_correction_shader::load(device.clone())
+                .unwrap()
+                .specialize(
+                    [(0, SpecializationConstant::F32(MIN_LIVE_FRACTION))]
+                        .into_iter()
+                        .collect(),
+                )
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        Ok(DeadtimeCorrectionResources {
+            pipeline,
+            descriptor_set_allocator,
+            tau: AtomicU32::new(tau.to_bits()),
+        })
+    }
+
+    pub fn tau(&self) -> f32 {
+        f32::from_bits(self.tau.load(Ordering::Relaxed))
+    }
+
+    /// Takes effect from the next recorded dispatch.
+    pub fn set_tau(&self, tau: f32) -> Result<(), MyError> {
+        if !(tau.is_finite() && tau >= 0.0) {
+            return Err(MyError::InvalidParameter);
+        }
+        self.tau.store(tau.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let pixel_count = image_width * image_height;
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [WriteDescriptorSet::buffer(0, image_buffer)],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                deadtime_correction_shader::Params {
+                    pixel_count,
+                    tau: self.tau(),
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeadtimeCorrectionResources, MIN_LIVE_FRACTION};
+    use crate::core::test_utils::TestContext;
+
+    #[test]
+    fn matches_analytic_deadtime_formula() {
+        let context = TestContext::new();
+        let tau = 1e-5f32;
+        // Dead-time losses from none up to about two thirds of the counts.
+        let image = vec![0u16, 1, 100, 1000, 10000, 30000, 60000, 65535];
+
+        let resources = DeadtimeCorrectionResources::new(
+            context.device.clone(),
+            context.descriptor_set_allocator.clone(),
+            tau,
+        )
+        .unwrap();
+
+        let image_buffer = context.host_buffer(image.clone());
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, image.len() as u32, 1, image_buffer.clone())
+        });
+
+        let result = image_buffer.read().unwrap();
+        for (&measured, &output) in image.iter().zip(result.iter()) {
+            let measured = measured as f32;
+            let live_fraction = (1.0 - measured * tau).max(MIN_LIVE_FRACTION);
+            let expected = (measured / live_fraction).clamp(0.0, 65535.0);
+            assert!(
+                (output as f32 - expected).abs() <= 1.0,
+                "{measured} -> {output}, expected {expected}"
+            );
+        }
+        assert_eq!(result[0], 0);
+        assert_eq!(result[4], 11111);
+    }
+
+    #[test]
+    fn saturated_pixels_clamp_instead_of_dividing_by_zero() {
+        let context = TestContext::new();
+        // 1 / tau is 1000 counts, so most of the image is past saturation.
+        let image = vec![999u16, 1000, 1001, 5000, 65535];
+
+        let resources = DeadtimeCorrectionResources::new(
+            context.device.clone(),
+            context.descriptor_set_allocator.clone(),
+            1e-3,
+        )
+        .unwrap();
+
+        let image_buffer = context.host_buffer(image.clone());
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, image.len() as u32, 1, image_buffer.clone())
+        });
+
+        assert!(image_buffer
+            .read()
+            .unwrap()
+            .iter()
+            .all(|&pixel| pixel == 65535));
+    }
+
+    #[test]
+    fn rejects_negative_tau() {
+        let context = TestContext::new();
+        for tau in [-1e-6, f32::NAN, f32::INFINITY] {
+            assert!(DeadtimeCorrectionResources::new(
+                context.device.clone(),
+                context.descriptor_set_allocator.clone(),
+                tau,
+            )
+            .is_err());
+        }
+    }
+}
@@ -3,8 +3,7 @@ use std::sync::Arc;
 use vulkano::{
     buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
     command_buffer::{
-        allocator::StandardCommandBufferAllocator, CommandBufferUsage, PrimaryAutoCommandBuffer,
-        RecordingCommandBuffer,
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
     },
     descriptor_set::{
         allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
@@ -16,19 +15,219 @@ use vulkano::{
         ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
         PipelineShaderStageCreateInfo,
     },
+    shader::SpecializationConstant,
     sync::{self, GpuFuture},
 };
 
+use super::{frame_len, local_size_entry_point, pipeline_cache, Rect};
+use crate::core::error::MyError;
+
+/// How the weighted neighbour sum of a defective pixel is normalised.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NormalizationPolicy {
+    /// Divide by the summed weight of the valid neighbours only.
+    #[default]
+    ValidNeighbours,
+    /// Divide by the summed weight of the whole kernel, so pixels near edges or other
+    /// defects are attenuated rather than renormalised.
+    FullKernel,
+}
+
+/// How a defective pixel is estimated from its valid neighbours.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DefectCorrectionMode {
+    /// Weighted mean over the kernel, normalised according to the [`NormalizationPolicy`].
+    #[default]
+    WeightedMean,
+    /// Median of the valid neighbours in the kernel window, more robust for clustered
+    /// defects. The normalisation policy doesn't apply.
+    Median,
+}
+
+/// Radius of the original 5x5 kernel.
+pub const DEFAULT_KERNEL_RADIUS: u32 = 2;
+
+/// Largest supported radius, a 15x15 window. The median mode keeps the whole window in
+/// registers, so much larger kernels would spill.
+pub const MAX_KERNEL_RADIUS: u32 = 7;
+
+/// Weights of a `(2 * radius + 1)` square kernel in row-major order, falling off linearly
+/// with the Manhattan distance from the centre, which itself has no weight. A radius of 2
+/// gives the original 5x5 table.
+pub fn kernel_weights(kernel_radius: u32) -> Vec<f32> {
+    let radius = kernel_radius as i32;
+    (-radius..=radius)
+        .flat_map(|y| (-radius..=radius).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            if x == 0 && y == 0 {
+                0.0
+            } else {
+                (2 * radius + 1 - x.abs() - y.abs()) as f32
+            }
+        })
+        .collect()
+}
+
+/// Checks a defect map covers the whole image. An empty map is accepted and stands for an
+/// image without defects.
+pub(crate) fn validate_defect_map(
+    defect_map: &[u16],
+    image_height: u32,
+    image_width: u32,
+) -> Result<(), MyError> {
+    if !defect_map.is_empty() && defect_map.len() as u64 != frame_len(image_width, image_height)? {
+        return Err(MyError::InvalidTextureData);
+    }
+    Ok(())
+}
+
+fn validate_kernel_radius(kernel_radius: u32) -> Result<(), MyError> {
+    if !(1..=MAX_KERNEL_RADIUS).contains(&kernel_radius) {
+        return Err(MyError::InvalidParameter);
+    }
+    Ok(())
+}
+
+mod defect_correction_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+                #include <roi.glsl>
+
+                layout(local_size_x_id = 100, local_size_y = 1, local_size_z = 1) in;
+
+                layout(constant_id = 0) const bool FULL_KERNEL_NORMALIZATION = false;
+                layout(constant_id = 1) const bool MEDIAN = false;
+                layout(constant_id = 2) const int KERNEL_RADIUS = 2;
+
+                #define KERNEL_SIZE (2 * KERNEL_RADIUS + 1)
+
+                layout(push_constant) uniform Params {
+                    uint image_width;
+                    uint image_height;
+                    uint roi_x;
+                    uint roi_y;
+                    uint roi_width;
+                    uint roi_height;
+                };
+
+                PIXEL_BUFFER(0, defectMap)
+
+                PIXEL_BUFFER(1, image)
+
+                PIXEL_BUFFER(2, result)
+
+                // KERNEL_SIZE x KERNEL_SIZE weights in row-major order.
+                layout(set = 0, binding = 3) readonly buffer WeightKernel {
+                    float weightKernel[];
+                };
+
+                float kernelWeight(int x, int y) {
+                    return weightKernel[(y + KERNEL_RADIUS) * KERNEL_SIZE + x + KERNEL_RADIUS];
+                }
+
+                void main() {
+                    uint i = gl_GlobalInvocationID.x;
+                    if (i >= roi_width * roi_height) {
+                        return;
+                    }
+
+                    // Neighbours outside the ROI are still read, uncorrected by the passes
+                    // before this one.
+                    uint idx = roi_pixel(i, roi_x, roi_y, roi_width, image_width);
+
+                    // Frames of a batch follow each other in the image and result buffers,
+                    // one per z, and share the map.
+                    uint frame = gl_GlobalInvocationID.z * image_width * image_height;
+
+                    float weightedSum = 0.0;
+                    float totalWeight = 0.0;
+                    float fullWeight = 0.0;
+
+                    if (load_defectMap(idx) == 1 && MEDIAN) {
+                        // Insertion sort of the valid neighbours as they are gathered.
+                        uint values[KERNEL_SIZE * KERNEL_SIZE - 1];
+                        uint count = 0;
+                        for (int y = -KERNEL_RADIUS; y <= KERNEL_RADIUS; ++y) {
+                            for (int x = -KERNEL_RADIUS; x <= KERNEL_RADIUS; ++x) {
+                                int pixelX = int(idx % image_width) + x;
+                                int pixelY = int(idx / image_width) + y;
+
+                                if (pixelX >= 0 && pixelX < image_width && pixelY >= 0 && pixelY < image_height) {
+                                    uint globalIndex = pixelY * image_width + pixelX;
+                                    if (load_defectMap(globalIndex) == 0) {
+                                        uint value = load_image(frame + globalIndex);
+                                        uint i = count;
+                                        while (i > 0 && values[i - 1] > value) {
+                                            values[i] = values[i - 1];
+                                            --i;
+                                        }
+                                        values[i] = value;
+                                        ++count;
+                                    }
+                                }
+                            }
+                        }
+
+                        if (count > 0) {
+                            store_result(frame + idx, (values[(count - 1) / 2] + values[count / 2]) / 2);
+                        } else {
+                            store_result(frame + idx, load_image(frame + idx));
+                        }
+                    } else if (load_defectMap(idx) == 1) {
+                        for (int y = -KERNEL_RADIUS; y <= KERNEL_RADIUS; ++y) {
+                            for (int x = -KERNEL_RADIUS; x <= KERNEL_RADIUS; ++x) {
+                                fullWeight += kernelWeight(x, y);
+
+                                int pixelX = int(idx % image_width) + x;
+                                int pixelY = int(idx / image_width) + y;
+
+                                if (pixelX >= 0 && pixelX < image_width && pixelY >= 0 && pixelY < image_height) {
+                                    uint globalIndex = pixelY * image_width + pixelX;
+                                    if (load_defectMap(globalIndex) == 0) {
+                                        weightedSum += float(load_image(frame + globalIndex)) * kernelWeight(x, y);
+                                        totalWeight += kernelWeight(x, y);
+                                    }
+                                }
+                            }
+                        }
+
+                        if (totalWeight > 0) {
+                            float weight = FULL_KERNEL_NORMALIZATION ? fullWeight : totalWeight;
+                            store_result(frame + idx, uint(weightedSum / weight));
+                        } else {
+                            store_result(frame + idx, load_image(frame + idx));
+                        }
+                    } else {
+                        store_result(frame + idx, load_image(frame + idx));
+                    }
+                }
+                "
+    );
+}
+
 pub struct DefectMapBufferResources {
     pipeline: Arc<ComputePipeline>,
     memory_allocator: Arc<StandardMemoryAllocator>,
     descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
-    kernel_buffer: Subbuffer<[u16]>,
+    /// [`kernel_weights`] of `kernel_radius`.
+    kernel_buffer: Subbuffer<[f32]>,
     defect_map_buffer: Subbuffer<[u16]>,
-    direction_buffer: Subbuffer<[i32; 1]>,
+    normalization: NormalizationPolicy,
+    mode: DefectCorrectionMode,
+    kernel_radius: u32,
+    local_size: u32,
 }
 
 impl DefectMapBufferResources {
+    /// Uploads `defect_map`, one value per pixel with 1 marking a defect. An empty map
+    /// means no defects; any other length that doesn't match the image is
+    /// `MyError::InvalidTextureData`.
+    ///
+    /// Neighbours are taken from a `(2 * kernel_radius + 1)` square window, so the kernel
+    /// size is always odd; a radius of 0 or above [`MAX_KERNEL_RADIUS`] is
+    /// `MyError::InvalidParameter`.
     pub fn new(
         device: Arc<Device>,
         queue: Arc<Queue>,
@@ -36,86 +235,88 @@ impl DefectMapBufferResources {
         memory_allocator: Arc<StandardMemoryAllocator>,
         descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
         defect_map: &[u16],
+        normalization: NormalizationPolicy,
+        mode: DefectCorrectionMode,
+        kernel_radius: u32,
         image_height: u32,
         image_width: u32,
-    ) -> Self {
-        let pipeline = {
-            mod offset_correction_shader {
-                vulkano_shaders::shader! {
-                    ty: "compute",
-                    src: r"
-                            #version 450
-                            #extension GL_EXT_shader_16bit_storage : require
-                            #extension GL_EXT_shader_explicit_arithmetic_types_int16 : require
-
-                            #define KERNEL_SIZE 5
-
-                            layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
-
-                            layout(set = 0, binding = 0) buffer DefectData {
-                                uint16_t defectMapData[];
-                            };
-
-                            layout(set = 0, binding = 1) buffer ImageData {
-                                uint16_t imageData[];
-                            };
-
-                            layout(set = 0, binding = 2) buffer ResultImage {
-                                uint16_t resultData[];
-                            };
-                   
-                            int kernel[5] = int[5](1, 2, 0, 2, 1);
-
-                            // Define the weight kernel as a constant 2D array
-                            const float weightKernel[KERNEL_SIZE][KERNEL_SIZE] = float[KERNEL_SIZE][KERNEL_SIZE](
-                                float[KERNEL_SIZE](1.0, 2.0, 3.0, 2.0, 1.0),
-                                float[KERNEL_SIZE](2.0, 3.0, 4.0, 3.0, 2.0),
-                                float[KERNEL_SIZE](3.0, 4.0, 0.0, 4.0, 3.0),
-                                float[KERNEL_SIZE](2.0, 3.0, 4.0, 3.0, 2.0),
-                                float[KERNEL_SIZE](1.0, 2.0, 3.0, 2.0, 1.0)
-                            );
-
-                            void main() {
-                                uint image_height = 5800;
-                                uint image_width = 4800;
-
-                                uint idx = gl_GlobalInvocationID.x;
-                                float weightedSum = 0.0;
-                                float totalWeight = 0.0;
-
-                                if (defectMapData[idx] == 1) {
-                                    for (int y = -KERNEL_SIZE / 2; y <= KERNEL_SIZE / 2; ++y) {
-                                        for (int x = -KERNEL_SIZE / 2; x <= KERNEL_SIZE / 2; ++x) {
-                                            int pixelX = int(idx % image_width) + x;
-                                            int pixelY = int(idx / image_width) + y;
-
-                                            if (pixelX >= 0 && pixelX < image_width && pixelY >= 0 && pixelY < image_height) {
-                                                uint globalIndex = pixelY * image_width + pixelX;
-                                                if (defectMapData[globalIndex] == 0) {
-                                                    weightedSum += imageData[globalIndex] * weightKernel[y + KERNEL_SIZE / 2][x + KERNEL_SIZE / 2];
-                                                    totalWeight += weightKernel[y + KERNEL_SIZE / 2][x + KERNEL_SIZE / 2];
-                                                }
-                                            }
-                                        }
-                                    }
+        local_size: u32,
+    ) -> Result<Self, MyError> {
+        validate_defect_map(defect_map, image_height, image_width)?;
+        validate_kernel_radius(kernel_radius)?;
 
-                                    if (totalWeight > 0) {
-                                        resultData[idx] = uint16_t(weightedSum / totalWeight);
-                                    } else {
-                                        resultData[idx] = imageData[idx];
-                                    }
-                                } else {
-                                    resultData[idx] = imageData[idx];
-                                }
-                            }
-                            ",
-                }
+        let defect_map_buffer = Buffer::new_slice(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            frame_len(image_width, image_height)?, /* number of elements, matching the image size */
+        )
+        .map_err(|e| MyError::AllocationError("defect map", e.to_string()))?;
+
+        {
+            let mut defect_map_write = defect_map_buffer.write().unwrap();
+            if defect_map.is_empty() {
+                defect_map_write.fill(0);
+            } else {
+                defect_map_write.copy_from_slice(defect_map);
             }
+        }
 
-            let cs = offset_correction_shader::load(device.clone())
-                .unwrap()
-                .entry_point("main")
-                .unwrap();
+        Self::from_buffer(
+            device,
+            queue,
+            command_buffer_allocator,
+            memory_allocator,
+            descriptor_set_allocator,
+            defect_map_buffer,
+            normalization,
+            mode,
+            kernel_radius,
+            local_size,
+        )
+    }
+
+    /// Adopts a device-resident defect map, validating `kernel_radius` as
+    /// [`DefectMapBufferResources::new`] does.
+    pub fn from_buffer(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        defect_map_buffer: Subbuffer<[u16]>,
+        normalization: NormalizationPolicy,
+        mode: DefectCorrectionMode,
+        kernel_radius: u32,
+        local_size: u32,
+    ) -> Result<Self, MyError> {
+        validate_kernel_radius(kernel_radius)?;
+
+        let pipeline = {
+            let cs = local_size_entry_point(
+                defect_correction_shader::load(device.clone()).unwrap(),
+                local_size,
+                [
+                    (
+                        0,
+                        SpecializationConstant::Bool(
+                            normalization == NormalizationPolicy::FullKernel,
+                        ),
+                    ),
+                    (
+                        1,
+                        SpecializationConstant::Bool(mode == DefectCorrectionMode::Median),
+                    ),
+                    (2, SpecializationConstant::I32(kernel_radius as i32)),
+                ],
+            )?;
             let stage = PipelineShaderStageCreateInfo::new(cs);
             let layout = PipelineLayout::new(
                 device.clone(),
@@ -127,29 +328,12 @@ impl DefectMapBufferResources {
 
             ComputePipeline::new(
                 device.clone(),
-                None,
+                pipeline_cache(&device),
                 ComputePipelineCreateInfo::stage_layout(stage, layout),
             )
             .unwrap()
         };
 
-        let defect_map_buffer = Buffer::new_slice(
-            memory_allocator.clone(),
-            BufferCreateInfo {
-                usage: BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER,
-                ..Default::default()
-            },
-            AllocationCreateInfo {
-                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
-                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
-                ..Default::default()
-            },
-            (image_height * image_width) as u64, /* number of elements, matching the image size */
-        )
-        .unwrap();
-
-        defect_map_buffer.write().unwrap().copy_from_slice(defect_map);
-
         let kernel_buffer = Buffer::from_iter(
             memory_allocator.clone(),
             BufferCreateInfo {
@@ -161,24 +345,9 @@ impl DefectMapBufferResources {
                     | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
                 ..Default::default()
             },
-            vec![1u16, 2, 3, 2, 1],
+            kernel_weights(kernel_radius),
         )
-        .unwrap();
-
-        let direction_buffer = Buffer::from_data(
-            memory_allocator.clone(),
-            BufferCreateInfo {
-                usage: BufferUsage::TRANSFER_DST | BufferUsage::UNIFORM_BUFFER,
-                ..Default::default()
-            },
-            AllocationCreateInfo {
-                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
-                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
-                ..Default::default()
-            },
-            [0], // 0 for horizontal, 1 for vertical
-        )
-        .unwrap();
+        .map_err(|e| MyError::AllocationError("defect kernel", e.to_string()))?;
 
         let builder = RecordingCommandBuffer::primary(
             command_buffer_allocator,
@@ -197,27 +366,113 @@ impl DefectMapBufferResources {
 
         future.wait(None).unwrap();
 
-        DefectMapBufferResources {
+        Ok(DefectMapBufferResources {
             pipeline,
             memory_allocator,
             descriptor_set_allocator,
             defect_map_buffer,
             kernel_buffer,
-            direction_buffer,
-        }
+            normalization,
+            mode,
+            kernel_radius,
+            local_size,
+        })
+    }
+
+    pub fn normalization(&self) -> NormalizationPolicy {
+        self.normalization
+    }
+
+    pub fn mode(&self) -> DefectCorrectionMode {
+        self.mode
     }
 
-    pub fn apply_pipeline(
+    pub fn kernel_radius(&self) -> u32 {
+        self.kernel_radius
+    }
+
+    pub fn local_size(&self) -> u32 {
+        self.local_size
+    }
+
+    pub fn defect_map_buffer(&self) -> Subbuffer<[u16]> {
+        self.defect_map_buffer.clone()
+    }
+
+    pub fn apply_pipeline<L>(
         &self,
-        builder: &mut RecordingCommandBuffer<PrimaryAutoCommandBuffer>,
+        builder: &mut RecordingCommandBuffer<L>,
         image_width: u32,
         image_height: u32,
         image_buffer: Subbuffer<[u16]>,
         result_buffer: Subbuffer<[u16]>,
     ) {
-        let local_size_x = 64;
+        self.apply_pipeline_in(
+            builder,
+            image_width,
+            image_height,
+            Rect::full(image_width, image_height),
+            image_buffer,
+            result_buffer,
+        );
+    }
 
-        let dispatch_size_x = (image_width * image_height + local_size_x - 1) / local_size_x;
+    /// Like [`DefectMapBufferResources::apply_pipeline`], writing only the pixels in `roi`
+    /// to `result_buffer`.
+    pub fn apply_pipeline_in<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        roi: Rect,
+        image_buffer: Subbuffer<[u16]>,
+        result_buffer: Subbuffer<[u16]>,
+    ) {
+        self.record(
+            builder,
+            image_width,
+            image_height,
+            roi,
+            1,
+            image_buffer,
+            result_buffer,
+        );
+    }
+
+    /// Like [`DefectMapBufferResources::apply_pipeline`], for `frame_count` frames lying one
+    /// after another in `image_buffer` and `result_buffer`, corrected in one dispatch.
+    pub fn apply_pipeline_frames<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        frame_count: u32,
+        image_buffer: Subbuffer<[u16]>,
+        result_buffer: Subbuffer<[u16]>,
+    ) {
+        self.record(
+            builder,
+            image_width,
+            image_height,
+            Rect::full(image_width, image_height),
+            frame_count,
+            image_buffer,
+            result_buffer,
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn record<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        roi: Rect,
+        frame_count: u32,
+        image_buffer: Subbuffer<[u16]>,
+        result_buffer: Subbuffer<[u16]>,
+    ) {
+        let dispatch_size_x = roi.pixel_count().div_ceil(self.local_size);
 
         let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
         let set = DescriptorSet::new(
@@ -227,7 +482,7 @@ impl DefectMapBufferResources {
                 WriteDescriptorSet::buffer(0, self.defect_map_buffer.clone()),
                 WriteDescriptorSet::buffer(1, image_buffer.clone()),
                 WriteDescriptorSet::buffer(2, result_buffer.clone()),
-                //WriteDescriptorSet::buffer(3, self.direction_buffer.clone()),
+                WriteDescriptorSet::buffer(3, self.kernel_buffer.clone()),
             ],
             [],
         )
@@ -243,11 +498,364 @@ impl DefectMapBufferResources {
                 set,
             )
             .unwrap()
-            .dispatch([dispatch_size_x, 1, 1])
-            .unwrap()
-            .update_buffer(self.direction_buffer.clone(), &[1])
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                defect_correction_shader::Params {
+                    image_width,
+                    image_height,
+                    roi_x: roi.x,
+                    roi_y: roi.y,
+                    roi_width: roi.w,
+                    roi_height: roi.h,
+                },
+            )
             .unwrap()
-            .dispatch([dispatch_size_x, 1, 1])
+            .dispatch([dispatch_size_x, 1, frame_count])
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        kernel_weights, DefectCorrectionMode, DefectMapBufferResources, NormalizationPolicy,
+        DEFAULT_KERNEL_RADIUS, MAX_KERNEL_RADIUS,
+    };
+    use crate::core::{corrections::DEFAULT_LOCAL_SIZE, error::MyError, test_utils::TestContext};
+
+    const WIDTH: u32 = 4800;
+    const HEIGHT: u32 = 5800;
+
+    const WEIGHTS: [[f32; 5]; 5] = [
+        [1.0, 2.0, 3.0, 2.0, 1.0],
+        [2.0, 3.0, 4.0, 3.0, 2.0],
+        [3.0, 4.0, 0.0, 4.0, 3.0],
+        [2.0, 3.0, 4.0, 3.0, 2.0],
+        [1.0, 2.0, 3.0, 2.0, 1.0],
+    ];
+
+    fn correct(
+        width: u32,
+        height: u32,
+        image: &[u16],
+        defect_map: &[u16],
+        normalization: NormalizationPolicy,
+    ) -> Vec<u16> {
+        correct_with(
+            width,
+            height,
+            image,
+            defect_map,
+            normalization,
+            DefectCorrectionMode::default(),
+            DEFAULT_KERNEL_RADIUS,
+        )
+    }
+
+    fn correct_with(
+        width: u32,
+        height: u32,
+        image: &[u16],
+        defect_map: &[u16],
+        normalization: NormalizationPolicy,
+        mode: DefectCorrectionMode,
+        kernel_radius: u32,
+    ) -> Vec<u16> {
+        let context = TestContext::new();
+        let resources = DefectMapBufferResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            defect_map,
+            normalization,
+            mode,
+            kernel_radius,
+            height,
+            width,
+            DEFAULT_LOCAL_SIZE,
+        )
+        .unwrap();
+
+        let image_buffer = context.host_buffer(image.to_vec());
+        let result_buffer = context.host_buffer(vec![0u16; image.len()]);
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, width, height, image_buffer, result_buffer.clone())
+        });
+
+        let result = result_buffer.read().unwrap().to_vec();
+        result
+    }
+
+    /// Weighted mean of the good pixels in the 5x5 neighbourhood of an interior pixel.
+    fn reference(width: u32, image: &[u16], defect_map: &[u16], x: i32, y: i32) -> u16 {
+        let (mut weighted_sum, mut total_weight) = (0.0, 0.0);
+        for ky in -2..=2 {
+            for kx in -2..=2 {
+                let idx = ((y + ky) * width as i32 + x + kx) as usize;
+                if defect_map[idx] == 0 {
+                    let weight = WEIGHTS[(ky + 2) as usize][(kx + 2) as usize];
+                    weighted_sum += image[idx] as f32 * weight;
+                    total_weight += weight;
+                }
+            }
+        }
+        (weighted_sum / total_weight) as u16
+    }
+
+    #[test]
+    fn single_pass_matches_full_kernel_reference() {
+        let size = (WIDTH * HEIGHT) as usize;
+        let image: Vec<u16> = (0..size as u32)
+            .map(|i| ((i % WIDTH) * 7 + (i / WIDTH) * 13) as u16)
+            .collect();
+        let mut defect_map = vec![0u16; size];
+        let (x, y) = (200i32, 300i32);
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1)] {
+            defect_map[((y + dy) * WIDTH as i32 + x + dx) as usize] = 1;
+        }
+
+        // One dispatch of the full 2D kernel, not separate horizontal and vertical passes.
+        let result = correct(
+            WIDTH,
+            HEIGHT,
+            &image,
+            &defect_map,
+            NormalizationPolicy::default(),
+        );
+        let centre = (y * WIDTH as i32 + x) as usize;
+        assert_eq!(result[centre], reference(WIDTH, &image, &defect_map, x, y));
+    }
+
+    #[test]
+    fn small_frame_addresses_its_own_neighbours() {
+        let (width, height) = (16u32, 16u32);
+        let size = (width * height) as usize;
+        // Every pixel distinct, so a wrong row stride picks up the wrong values.
+        let image: Vec<u16> = (0..size as u16).map(|i| i * 3).collect();
+        let mut defect_map = vec![0u16; size];
+        let (x, y) = (5i32, 7i32);
+        defect_map[(y * width as i32 + x) as usize] = 1;
+
+        let result = correct(
+            width,
+            height,
+            &image,
+            &defect_map,
+            NormalizationPolicy::default(),
+        );
+
+        let centre = (y * width as i32 + x) as usize;
+        assert_eq!(result[centre], reference(width, &image, &defect_map, x, y));
+        for (idx, (&corrected, &original)) in result.iter().zip(&image).enumerate() {
+            if idx != centre {
+                assert_eq!(corrected, original);
+            }
+        }
+    }
+
+    #[test]
+    fn normalization_policies_differ_at_edges() {
+        let size = (WIDTH * HEIGHT) as usize;
+        let image = vec![100u16; size];
+        let mut defect_map = vec![0u16; size];
+        let interior = (100 * WIDTH + 100) as usize;
+        defect_map[0] = 1;
+        defect_map[interior] = 1;
+
+        let valid = correct(
+            WIDTH,
+            HEIGHT,
+            &image,
+            &defect_map,
+            NormalizationPolicy::ValidNeighbours,
+        );
+        let full = correct(
+            WIDTH,
+            HEIGHT,
+            &image,
+            &defect_map,
+            NormalizationPolicy::FullKernel,
+        );
+
+        // Only 22 of the kernel's total weight of 60 lands inside the image at the corner.
+        assert_eq!(valid[0], 100);
+        assert_eq!(full[0], (100.0 * 22.0 / 60.0) as u16);
+
+        assert_eq!(valid[interior], 100);
+        assert_eq!(full[interior], 100);
+    }
+
+    #[test]
+    fn wrong_length_map_is_rejected() {
+        let context = TestContext::new();
+        let (width, height) = (16u32, 16u32);
+
+        for len in [
+            1,
+            (width * height) as usize - 1,
+            (width * height) as usize + 1,
+        ] {
+            let result = DefectMapBufferResources::new(
+                context.device.clone(),
+                context.queue.clone(),
+                context.command_buffer_allocator.clone(),
+                context.memory_allocator.clone(),
+                context.descriptor_set_allocator.clone(),
+                &vec![0u16; len],
+                NormalizationPolicy::default(),
+                DefectCorrectionMode::default(),
+                DEFAULT_KERNEL_RADIUS,
+                height,
+                width,
+                DEFAULT_LOCAL_SIZE,
+            );
+            assert!(matches!(result, Err(MyError::InvalidTextureData)));
+        }
+    }
+
+    #[test]
+    fn empty_map_means_no_defects() {
+        let (width, height) = (16u32, 16u32);
+        let image: Vec<u16> = (0..(width * height) as u16).map(|i| i * 3).collect();
+
+        let result = correct(width, height, &image, &[], NormalizationPolicy::default());
+        assert_eq!(result, image);
+    }
+
+    #[test]
+    fn median_mode_ignores_an_outlier_neighbour() {
+        let (width, height) = (16u32, 16u32);
+        let size = (width * height) as usize;
+        let mut image = vec![100u16; size];
+        let mut defect_map = vec![0u16; size];
+        let (x, y) = (8usize, 8usize);
+        defect_map[y * width as usize + x] = 1;
+        // A hot neighbour drags the weighted mean up but not the median.
+        image[y * width as usize + x + 1] = 60000;
+        // Some spread below and above, so the median isn't just the common value.
+        image[(y - 1) * width as usize + x] = 90;
+        image[(y + 1) * width as usize + x] = 110;
+
+        let median = correct_with(
+            width,
+            height,
+            &image,
+            &defect_map,
+            NormalizationPolicy::default(),
+            DefectCorrectionMode::Median,
+            DEFAULT_KERNEL_RADIUS,
+        );
+        let mean = correct(
+            width,
+            height,
+            &image,
+            &defect_map,
+            NormalizationPolicy::default(),
+        );
+
+        let centre = y * width as usize + x;
+        assert_eq!(median[centre], 100);
+        assert!(mean[centre] > 1000);
+    }
+
+    #[test]
+    fn median_mode_without_valid_neighbours_passes_through() {
+        let (width, height) = (3u32, 3u32);
+        let image = vec![7u16, 8, 9, 10, 11, 12, 13, 14, 15];
+
+        let result = correct_with(
+            width,
+            height,
+            &image,
+            &[1u16; 9],
+            NormalizationPolicy::default(),
+            DefectCorrectionMode::Median,
+            DEFAULT_KERNEL_RADIUS,
+        );
+        assert_eq!(result, image);
+    }
+
+    #[test]
+    fn default_radius_generates_the_original_kernel() {
+        let expected: Vec<f32> = WEIGHTS.iter().flatten().copied().collect();
+        assert_eq!(kernel_weights(DEFAULT_KERNEL_RADIUS), expected);
+        assert_eq!(kernel_weights(4).len(), 81);
+    }
+
+    #[test]
+    fn larger_kernel_reaches_past_a_defect_cluster() {
+        let (width, height) = (32u32, 32u32);
+        let size = (width * height) as usize;
+        let image: Vec<u16> = (0..size as u32)
+            .map(|i| ((i % width) * 7 + (i / width) * 13) as u16)
+            .collect();
+        // A 5x5 cluster leaves the centre pixel without valid neighbours in a 5x5 window.
+        let mut defect_map = vec![0u16; size];
+        let (x, y) = (16i32, 16i32);
+        for dy in -2..=2 {
+            for dx in -2..=2 {
+                defect_map[((y + dy) * width as i32 + x + dx) as usize] = 1;
+            }
+        }
+        let centre = (y * width as i32 + x) as usize;
+
+        let correct_with_radius = |kernel_radius| {
+            correct_with(
+                width,
+                height,
+                &image,
+                &defect_map,
+                NormalizationPolicy::default(),
+                DefectCorrectionMode::default(),
+                kernel_radius,
+            )
+        };
+        assert_eq!(correct_with_radius(2)[centre], image[centre]);
+
+        let kernel_radius = 4;
+        let weights = kernel_weights(kernel_radius);
+        let size_1d = 2 * kernel_radius as i32 + 1;
+        let (mut weighted_sum, mut total_weight) = (0.0, 0.0);
+        for ky in -4..=4 {
+            for kx in -4..=4 {
+                let idx = ((y + ky) * width as i32 + x + kx) as usize;
+                if defect_map[idx] == 0 {
+                    let weight = weights[((ky + 4) * size_1d + kx + 4) as usize];
+                    weighted_sum += image[idx] as f32 * weight;
+                    total_weight += weight;
+                }
+            }
+        }
+        assert_eq!(
+            correct_with_radius(kernel_radius)[centre],
+            (weighted_sum / total_weight) as u16
+        );
+    }
+
+    #[test]
+    fn out_of_range_kernel_radius_is_rejected() {
+        let context = TestContext::new();
+        let (width, height) = (16u32, 16u32);
+
+        for kernel_radius in [0, MAX_KERNEL_RADIUS + 1] {
+            let result = DefectMapBufferResources::new(
+                context.device.clone(),
+                context.queue.clone(),
+                context.command_buffer_allocator.clone(),
+                context.memory_allocator.clone(),
+                context.descriptor_set_allocator.clone(),
+                &[],
+                NormalizationPolicy::default(),
+                DefectCorrectionMode::default(),
+                kernel_radius,
+                height,
+                width,
+                DEFAULT_LOCAL_SIZE,
+            );
+            assert!(matches!(result, Err(MyError::InvalidParameter)));
+        }
+    }
+}
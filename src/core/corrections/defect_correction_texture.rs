@@ -3,32 +3,138 @@ use std::sync::Arc;
 use vulkano::{
     buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
     command_buffer::{
-        allocator::StandardCommandBufferAllocator, CommandBufferUsage, PrimaryAutoCommandBuffer,
-        RecordingCommandBuffer,
+        allocator::StandardCommandBufferAllocator, CopyBufferToImageInfo, RecordingCommandBuffer,
     },
     descriptor_set::{
         allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
     },
     device::{Device, Queue},
+    format::Format,
+    image::{view::ImageView, Image, ImageCreateInfo, ImageType, ImageUsage},
     memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
     pipeline::{
         compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
         ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
         PipelineShaderStageCreateInfo,
     },
-    sync::{self, GpuFuture},
+    shader::SpecializationConstant,
 };
 
-pub struct DefectMapBufferResources {
+use super::{
+    defect_correction::{validate_defect_map, NormalizationPolicy},
+    pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT,
+};
+use crate::core::{core::create_image_texture, error::MyError};
+
+/// Values of `direction` selecting the pass of the separable kernel.
+const HORIZONTAL_PASS: u32 = 0;
+const VERTICAL_PASS: u32 = 1;
+
+mod defect_correction_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                #define KERNEL_RADIUS 2
+
+                // See `HORIZONTAL_PASS` and `VERTICAL_PASS`.
+                #define HORIZONTAL_PASS 0u
+                #define VERTICAL_PASS 1u
+
+                layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+                layout(constant_id = 0) const bool FULL_KERNEL_NORMALIZATION = false;
+
+                layout(push_constant) uniform Params {
+                    uint direction;
+                };
+
+                layout(set = 0, binding = 0, r16ui) uniform readonly uimage2D defectMap;
+                layout(set = 0, binding = 1, r16ui) uniform readonly uimage2D image;
+
+                PIXEL_BUFFER(2, result)
+
+                // Weighted sum of each pixel's good row neighbours and their total weight,
+                // written by the horizontal pass for the vertical one.
+                layout(set = 0, binding = 3) buffer RowSums {
+                    vec2 rowSums[];
+                };
+
+                float kernelWeight(int offset) {
+                    return float(KERNEL_RADIUS + 1 - abs(offset));
+                }
+
+                void main() {
+                    ivec2 size = imageSize(image);
+                    ivec2 pos = ivec2(gl_GlobalInvocationID.xy);
+
+                    if (pos.x >= size.x || pos.y >= size.y) {
+                        return;
+                    }
+
+                    uint idx = pos.y * size.x + pos.x;
+
+                    if (direction == HORIZONTAL_PASS) {
+                        vec2 sums = vec2(0.0);
+                        for (int x = -KERNEL_RADIUS; x <= KERNEL_RADIUS; ++x) {
+                            ivec2 neighbour = pos + ivec2(x, 0);
+                            if (neighbour.x >= 0 && neighbour.x < size.x
+                                    && imageLoad(defectMap, neighbour).r == 0) {
+                                sums += kernelWeight(x) * vec2(imageLoad(image, neighbour).r, 1.0);
+                            }
+                        }
+                        rowSums[idx] = sums;
+                        return;
+                    }
+
+                    uint value = imageLoad(image, pos).r;
+
+                    if (imageLoad(defectMap, pos).r != 1) {
+                        store_result(idx, value);
+                        return;
+                    }
+
+                    vec2 sums = vec2(0.0);
+                    float rowWeight = 0.0;
+                    for (int y = -KERNEL_RADIUS; y <= KERNEL_RADIUS; ++y) {
+                        int neighbourY = pos.y + y;
+                        if (neighbourY >= 0 && neighbourY < size.y) {
+                            sums += kernelWeight(y) * rowSums[neighbourY * size.x + pos.x];
+                        }
+                        rowWeight += kernelWeight(y);
+                    }
+
+                    if (sums.y > 0) {
+                        // The centre of the kernel is the defect itself and never counts.
+                        float fullWeight = rowWeight * rowWeight - kernelWeight(0) * kernelWeight(0);
+                        float weight = FULL_KERNEL_NORMALIZATION ? fullWeight : sums.y;
+                        store_result(idx, uint(sums.x / weight));
+                    } else {
+                        store_result(idx, value);
+                    }
+                }
+            "
+    );
+}
+
+/// Defect correction that gathers the neighbourhood from 2D storage images rather than a
+/// flat buffer, trading an extra copy per frame for better cache locality. The kernel is
+/// separable: a horizontal pass sums every pixel's good row neighbours and a vertical pass
+/// combines those sums for the defective pixels, reading `2 * radius + 1` neighbours per
+/// pass instead of the whole square window.
+pub struct DefectMapTextureResources {
     pipeline: Arc<ComputePipeline>,
-    memory_allocator: Arc<StandardMemoryAllocator>,
     descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
-    kernel_buffer: Subbuffer<[u16]>,
-    defect_map_buffer: Subbuffer<[u16]>,
-    direction_buffer: Subbuffer<[i32; 1]>,
+    defect_map_view: Arc<ImageView>,
+    image: Arc<Image>,
+    image_view: Arc<ImageView>,
+    row_sums: Subbuffer<[[f32; 2]]>,
 }
 
-impl DefectMapBufferResources {
+impl DefectMapTextureResources {
+    /// Uploads `defect_map` like `DefectMapBufferResources::new`, an empty map meaning no
+    /// defects.
     pub fn new(
         device: Arc<Device>,
         queue: Arc<Queue>,
@@ -36,86 +142,27 @@ impl DefectMapBufferResources {
         memory_allocator: Arc<StandardMemoryAllocator>,
         descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
         defect_map: &[u16],
+        normalization: NormalizationPolicy,
         image_height: u32,
         image_width: u32,
-    ) -> Self {
-        let pipeline = {
-            mod offset_correction_shader {
-                vulkano_shaders::shader! {
-                    ty: "compute",
-                    src: r"
-                            #version 450
-                            #extension GL_EXT_shader_16bit_storage : require
-                            #extension GL_EXT_shader_explicit_arithmetic_types_int16 : require
-
-                            #define KERNEL_SIZE 5
-
-                            layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
-
-                            layout(set = 0, binding = 0) buffer DefectData {
-                                uint16_t defectMapData[];
-                            };
-
-                            layout(set = 0, binding = 1) buffer ImageData {
-                                uint16_t imageData[];
-                            };
-
-                            layout(set = 0, binding = 2) buffer ResultImage {
-                                uint16_t resultData[];
-                            };
-                   
-                            int kernel[5] = int[5](1, 2, 0, 2, 1);
-
-                            // Define the weight kernel as a constant 2D array
-                            const float weightKernel[KERNEL_SIZE][KERNEL_SIZE] = float[KERNEL_SIZE][KERNEL_SIZE](
-                                float[KERNEL_SIZE](1.0, 2.0, 3.0, 2.0, 1.0),
-                                float[KERNEL_SIZE](2.0, 3.0, 4.0, 3.0, 2.0),
-                                float[KERNEL_SIZE](3.0, 4.0, 0.0, 4.0, 3.0),
-                                float[KERNEL_SIZE](2.0, 3.0, 4.0, 3.0, 2.0),
-                                float[KERNEL_SIZE](1.0, 2.0, 3.0, 2.0, 1.0)
-                            );
-
-                            void main() {
-                                uint image_height = 5800;
-                                uint image_width = 4800;
-
-                                uint idx = gl_GlobalInvocationID.x;
-                                float weightedSum = 0.0;
-                                float totalWeight = 0.0;
-
-                                if (defectMapData[idx] == 1) {
-                                    for (int y = -KERNEL_SIZE / 2; y <= KERNEL_SIZE / 2; ++y) {
-                                        for (int x = -KERNEL_SIZE / 2; x <= KERNEL_SIZE / 2; ++x) {
-                                            int pixelX = int(idx % image_width) + x;
-                                            int pixelY = int(idx / image_width) + y;
-
-                                            if (pixelX >= 0 && pixelX < image_width && pixelY >= 0 && pixelY < image_height) {
-                                                uint globalIndex = pixelY * image_width + pixelX;
-                                                if (defectMapData[globalIndex] == 0) {
-                                                    weightedSum += imageData[globalIndex] * weightKernel[y + KERNEL_SIZE / 2][x + KERNEL_SIZE / 2];
-                                                    totalWeight += weightKernel[y + KERNEL_SIZE / 2][x + KERNEL_SIZE / 2];
-                                                }
-                                            }
-                                        }
-                                    }
-
-                                    if (totalWeight > 0) {
-                                        resultData[idx] = uint16_t(weightedSum / totalWeight);
-                                    } else {
-                                        resultData[idx] = imageData[idx];
-                                    }
-                                } else {
-                                    resultData[idx] = imageData[idx];
-                                }
-                            }
-                            ",
-                }
-            }
+    ) -> Result<Self, MyError> {
+        validate_defect_map(defect_map, image_height, image_width)?;
 
-            let cs = offset_correction_shader::load(device.clone())
+        let pipeline = {
+            let cs = defect_correction_shader::load(device.clone())
                 .unwrap()
-                .entry_point("main")
-                .unwrap();
+                .specialize(
+                    [(
+                        0,
+                        SpecializationConstant::Bool(
+                            normalization == NormalizationPolicy::FullKernel,
+                        ),
+                    )]
+                    .into_iter()
+                    .collect(),
+                )
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
             let stage = PipelineShaderStageCreateInfo::new(cs);
             let layout = PipelineLayout::new(
                 device.clone(),
@@ -127,113 +174,97 @@ impl DefectMapBufferResources {
 
             ComputePipeline::new(
                 device.clone(),
-                None,
+                pipeline_cache(&device),
                 ComputePipelineCreateInfo::stage_layout(stage, layout),
             )
             .unwrap()
         };
 
-        let defect_map_buffer = Buffer::new_slice(
+        let pixel_count = (image_height * image_width) as usize;
+        let defect_map: Vec<u16> = defect_map
+            .iter()
+            .copied()
+            .chain(std::iter::repeat(0))
+            .take(pixel_count)
+            .collect();
+        let defect_map_image = create_image_texture(
+            queue,
+            command_buffer_allocator,
             memory_allocator.clone(),
-            BufferCreateInfo {
-                usage: BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER,
-                ..Default::default()
-            },
-            AllocationCreateInfo {
-                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
-                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
-                ..Default::default()
-            },
-            (image_height * image_width) as u64, /* number of elements, matching the image size */
-        )
-        .unwrap();
-
-        defect_map_buffer.write().unwrap().copy_from_slice(defect_map);
-
-        let kernel_buffer = Buffer::from_iter(
+            &defect_map,
+            Format::R16_UINT,
+            image_width,
+            image_height,
+        )?;
+        let image = Image::new(
             memory_allocator.clone(),
-            BufferCreateInfo {
-                usage: BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER,
-                ..Default::default()
-            },
-            AllocationCreateInfo {
-                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
-                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+            ImageCreateInfo {
+                image_type: ImageType::Dim2d,
+                format: Format::R16_UINT,
+                extent: [image_width, image_height, 1],
+                usage: ImageUsage::STORAGE | ImageUsage::TRANSFER_DST,
                 ..Default::default()
             },
-            vec![1u16, 2, 3, 2, 1],
+            AllocationCreateInfo::default(),
         )
-        .unwrap();
-
-        let direction_buffer = Buffer::from_data(
-            memory_allocator.clone(),
+        .map_err(|_| MyError::TextureCreationError)?;
+        let row_sums = Buffer::new_slice::<[f32; 2]>(
+            memory_allocator,
             BufferCreateInfo {
-                usage: BufferUsage::TRANSFER_DST | BufferUsage::UNIFORM_BUFFER,
+                usage: BufferUsage::STORAGE_BUFFER,
                 ..Default::default()
             },
             AllocationCreateInfo {
-                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
-                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
                 ..Default::default()
             },
-            [0], // 0 for horizontal, 1 for vertical
+            pixel_count as u64,
         )
-        .unwrap();
+        .map_err(|e| MyError::AllocationError("defect row sums", e.to_string()))?;
 
-        let builder = RecordingCommandBuffer::primary(
-            command_buffer_allocator,
-            queue.queue_family_index(),
-            CommandBufferUsage::OneTimeSubmit,
-        )
-        .unwrap();
-
-        let command_buffer = builder.end().unwrap();
-
-        let future = sync::now(device)
-            .then_execute(queue, command_buffer)
-            .unwrap()
-            .then_signal_fence_and_flush()
-            .unwrap();
-
-        future.wait(None).unwrap();
-
-        DefectMapBufferResources {
+        Ok(DefectMapTextureResources {
             pipeline,
-            memory_allocator,
             descriptor_set_allocator,
-            defect_map_buffer,
-            kernel_buffer,
-            direction_buffer,
-        }
+            defect_map_view: ImageView::new_default(defect_map_image).unwrap(),
+            image_view: ImageView::new_default(image.clone()).unwrap(),
+            image,
+            row_sums,
+        })
     }
 
-    pub fn apply_pipeline(
+    pub fn apply_pipeline<L>(
         &self,
-        builder: &mut RecordingCommandBuffer<PrimaryAutoCommandBuffer>,
+        builder: &mut RecordingCommandBuffer<L>,
         image_width: u32,
         image_height: u32,
         image_buffer: Subbuffer<[u16]>,
         result_buffer: Subbuffer<[u16]>,
     ) {
-        let local_size_x = 64;
+        let local_size = 8;
 
-        let dispatch_size_x = (image_width * image_height + local_size_x - 1) / local_size_x;
+        let dispatch_size_x = (image_width + local_size - 1) / local_size;
+        let dispatch_size_y = (image_height + local_size - 1) / local_size;
 
         let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
         let set = DescriptorSet::new(
             self.descriptor_set_allocator.clone(),
             layout.clone(),
             [
-                WriteDescriptorSet::buffer(0, self.defect_map_buffer.clone()),
-                WriteDescriptorSet::buffer(1, image_buffer.clone()),
-                WriteDescriptorSet::buffer(2, result_buffer.clone()),
-                //WriteDescriptorSet::buffer(3, self.direction_buffer.clone()),
+                WriteDescriptorSet::image_view(0, self.defect_map_view.clone()),
+                WriteDescriptorSet::image_view(1, self.image_view.clone()),
+                WriteDescriptorSet::buffer(2, result_buffer),
+                WriteDescriptorSet::buffer(3, self.row_sums.clone()),
             ],
             [],
         )
         .unwrap();
 
         builder
+            .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+                image_buffer,
+                self.image.clone(),
+            ))
+            .unwrap()
             .bind_pipeline_compute(self.pipeline.clone())
             .unwrap()
             .bind_descriptor_sets(
@@ -242,12 +273,196 @@ impl DefectMapBufferResources {
                 0,
                 set,
             )
-            .unwrap()
-            .dispatch([dispatch_size_x, 1, 1])
-            .unwrap()
-            .update_buffer(self.direction_buffer.clone(), &[1])
-            .unwrap()
-            .dispatch([dispatch_size_x, 1, 1])
             .unwrap();
+
+        for direction in [HORIZONTAL_PASS, VERTICAL_PASS] {
+            builder
+                .push_constants(
+                    self.pipeline.layout().clone(),
+                    0,
+                    defect_correction_shader::Params { direction },
+                )
+                .unwrap()
+                .dispatch([dispatch_size_x, dispatch_size_y, 1])
+                .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DefectMapTextureResources;
+    use crate::core::{
+        corrections::{
+            defect_correction::{
+                DefectCorrectionMode, DefectMapBufferResources, NormalizationPolicy,
+                DEFAULT_KERNEL_RADIUS,
+            },
+            DEFAULT_LOCAL_SIZE,
+        },
+        test_utils::TestContext,
+    };
+
+    const WIDTH: u32 = 4800;
+    const HEIGHT: u32 = 5800;
+
+    fn correct(image: Vec<u16>, defect_map: &[u16], width: u32, height: u32) -> Vec<u16> {
+        let context = TestContext::new();
+        let resources = DefectMapTextureResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            defect_map,
+            NormalizationPolicy::default(),
+            height,
+            width,
+        )
+        .unwrap();
+
+        let size = image.len();
+        let image_buffer = context.host_buffer(image);
+        let result_buffer = context.host_buffer(vec![0u16; size]);
+        context.submit(|builder| {
+            resources.apply_pipeline(
+                builder,
+                width,
+                height,
+                image_buffer.clone(),
+                result_buffer.clone(),
+            );
+        });
+        let result = result_buffer.read().unwrap().to_vec();
+        result
+    }
+
+    /// The separable kernel over the whole window at once: weights `3 - |dx|` times
+    /// `3 - |dy|`, normalised over the good neighbours.
+    fn reference(image: &[u16], defect_map: &[u16], width: u32, height: u32) -> Vec<u16> {
+        let (width, height) = (width as i64, height as i64);
+        let weight = |offset: i64| (3 - offset.abs()) as f32;
+        (0..image.len())
+            .map(|idx| {
+                if defect_map[idx] != 1 {
+                    return image[idx];
+                }
+                let (x, y) = (idx as i64 % width, idx as i64 / width);
+                let (mut sum, mut total) = (0.0f32, 0.0f32);
+                for dy in -2..=2 {
+                    for dx in -2..=2 {
+                        let (nx, ny) = (x + dx, y + dy);
+                        if nx < 0 || ny < 0 || nx >= width || ny >= height {
+                            continue;
+                        }
+                        let neighbour = (ny * width + nx) as usize;
+                        if defect_map[neighbour] == 0 {
+                            sum += weight(dx) * weight(dy) * image[neighbour] as f32;
+                            total += weight(dx) * weight(dy);
+                        }
+                    }
+                }
+                if total > 0.0 {
+                    (sum / total) as u16
+                } else {
+                    image[idx]
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn separable_passes_match_the_full_kernel() {
+        let size = (WIDTH * HEIGHT) as usize;
+
+        let image: Vec<u16> = (0..size).map(|i| (i % 4096) as u16).collect();
+        // Clusters as well as isolated pixels, so some neighbourhoods lose neighbours.
+        let defect_map: Vec<u16> = (0..size)
+            .map(|i| (i % 997 == 0 || i % 997 == 1 || i % 4801 == 0) as u16)
+            .collect();
+
+        let expected = reference(&image, &defect_map, WIDTH, HEIGHT);
+        let result = correct(image, &defect_map, WIDTH, HEIGHT);
+        // The sums are exact, only the GPU's division may round the other way.
+        for (idx, (&result, &expected)) in result.iter().zip(&expected).enumerate() {
+            assert!(
+                result.abs_diff(expected) <= 1,
+                "pixel {idx}: {result} != {expected}"
+            );
+        }
+    }
+
+    /// The buffer variant's kernel falls off with the Manhattan distance instead, so the two
+    /// only agree where both weighted means are exact: isolated defects with their whole
+    /// window inside a linear ramp.
+    #[test]
+    fn texture_and_buffer_variants_match() {
+        let (width, height) = (256u32, 128u32);
+        let image: Vec<u16> = (0..width * height)
+            .map(|i| (1000 + 3 * (i % width) + 5 * (i / width)) as u16)
+            .collect();
+        let defect_map: Vec<u16> = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                (x % 7 == 3 && y % 7 == 3 && x < width - 2 && y < height - 2) as u16
+            })
+            .collect();
+
+        let context = TestContext::new();
+        let buffer_resources = DefectMapBufferResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &defect_map,
+            NormalizationPolicy::default(),
+            DefectCorrectionMode::default(),
+            DEFAULT_KERNEL_RADIUS,
+            height,
+            width,
+            DEFAULT_LOCAL_SIZE,
+        )
+        .unwrap();
+        let image_buffer = context.host_buffer(image.clone());
+        let buffer_result = context.host_buffer(vec![0u16; image.len()]);
+        context.submit(|builder| {
+            buffer_resources.apply_pipeline(
+                builder,
+                width,
+                height,
+                image_buffer.clone(),
+                buffer_result.clone(),
+            );
+        });
+
+        let texture_result = correct(image, &defect_map, width, height);
+        let buffer_result = buffer_result.read().unwrap();
+        // Same tolerance as against the full kernel, for the division's rounding.
+        for (idx, (&texture, &buffer)) in
+            texture_result.iter().zip(buffer_result.iter()).enumerate()
+        {
+            assert!(
+                texture.abs_diff(buffer) <= 1,
+                "pixel {idx}: {texture} != {buffer}"
+            );
+        }
+    }
+
+    #[test]
+    fn isolated_bad_column_is_filled() {
+        let (width, height) = (64u32, 16u32);
+        let bad_column = 20;
+        let mut image = vec![1000u16; (width * height) as usize];
+        let mut defect_map = vec![0u16; image.len()];
+        for y in 0..height as usize {
+            image[y * width as usize + bad_column] = 60000;
+            defect_map[y * width as usize + bad_column] = 1;
+        }
+
+        // None of the column's vertical neighbours are good, so only the horizontal pass
+        // can fill it.
+        let result = correct(image, &defect_map, width, height);
+        assert!(result.iter().all(|&pixel| pixel == 1000));
     }
 }
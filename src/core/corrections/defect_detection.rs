@@ -0,0 +1,390 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage},
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
+    },
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, Queue},
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    sync::{self, GpuFuture},
+};
+
+use super::{frame_len, pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+mod defect_detection_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                // See `MAX_DETECTION_RADIUS`.
+                #define MAX_DETECTION_RADIUS 3
+                #define MAX_WINDOW ((2 * MAX_DETECTION_RADIUS + 1) * (2 * MAX_DETECTION_RADIUS + 1))
+
+                layout(push_constant) uniform Params {
+                    uint image_width;
+                    uint image_height;
+                    uint radius;
+                    float threshold;
+                };
+
+                PIXEL_BUFFER(0, exposure)
+                PIXEL_BUFFER(1, defects)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= image_width * image_height) {
+                        return;
+                    }
+
+                    int x = int(idx % image_width);
+                    int y = int(idx / image_width);
+                    int r = int(radius);
+
+                    // Insertion sort of the window as it is read, clipped to the image.
+                    uint window[MAX_WINDOW];
+                    uint count = 0;
+                    for (int dy = -r; dy <= r; ++dy) {
+                        for (int dx = -r; dx <= r; ++dx) {
+                            int nx = x + dx;
+                            int ny = y + dy;
+                            if (nx < 0 || ny < 0 || nx >= int(image_width) || ny >= int(image_height)) {
+                                continue;
+                            }
+                            uint value = load_exposure(uint(ny) * image_width + uint(nx));
+                            uint i = count;
+                            while (i > 0 && window[i - 1] > value) {
+                                window[i] = window[i - 1];
+                                --i;
+                            }
+                            window[i] = value;
+                            ++count;
+                        }
+                    }
+                    float median = float(window[(count - 1) / 2] + window[count / 2]) * 0.5;
+
+                    float deviations[MAX_WINDOW];
+                    for (uint j = 0; j < count; ++j) {
+                        float deviation = abs(float(window[j]) - median);
+                        uint i = j;
+                        while (i > 0 && deviations[i - 1] > deviation) {
+                            deviations[i] = deviations[i - 1];
+                            --i;
+                        }
+                        deviations[i] = deviation;
+                    }
+                    float mad = (deviations[(count - 1) / 2] + deviations[count / 2]) * 0.5;
+
+                    // The MAD scaled to the standard deviation of Gaussian noise, floored at
+                    // one count so a noiseless flat doesn't flag every quantisation step.
+                    float sigma = max(1.4826 * mad, 1.0);
+                    float deviation = abs(float(load_exposure(idx)) - median);
+                    store_defects(idx, deviation > threshold * sigma ? 1u : 0u);
+                }
+            "
+    );
+}
+
+/// Neighbourhood radius [`DefectDetection::default`] takes each pixel's median over.
+pub const DEFAULT_DETECTION_RADIUS: u32 = 2;
+
+/// Largest [`DefectDetection::radius`], each invocation sorting its window in registers.
+pub const MAX_DETECTION_RADIUS: u32 = 3;
+
+/// How [`DefectDetectionResources`] tells defective pixels of a flat exposure from good
+/// ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DefectDetection {
+    /// Pixels each side of a pixel its local median is taken over, the window being
+    /// `(2 * radius + 1)` square and clipped at the edges of the frame. Between 1 and
+    /// [`MAX_DETECTION_RADIUS`].
+    pub radius: u32,
+    /// Deviations from the local median, in local standard deviations, past which a pixel
+    /// is flagged. The standard deviation is estimated from the window's median absolute
+    /// deviation, so clusters of bad pixels don't inflate it.
+    pub threshold: f32,
+}
+
+impl Default for DefectDetection {
+    fn default() -> Self {
+        DefectDetection {
+            radius: DEFAULT_DETECTION_RADIUS,
+            threshold: 5.0,
+        }
+    }
+}
+
+/// Builds a defect map from a flat exposure in a single dispatch, flagging pixels that
+/// deviate from the median of their neighbourhood.
+pub struct DefectDetectionResources {
+    pipeline: Arc<ComputePipeline>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+impl DefectDetectionResources {
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    ) -> Result<Self, MyError> {
+        let pipeline = {
+            let cs = defect_detection_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        Ok(DefectDetectionResources {
+            pipeline,
+            memory_allocator,
+            descriptor_set_allocator,
+        })
+    }
+
+    /// Flags the defective pixels of `flat`, a `width` by `height` exposure, blocking until
+    /// the map is read back. The map holds 1 for a defective pixel and 0 otherwise, ready
+    /// for `Corrections::enable_defect_correction`.
+    ///
+    /// A radius out of range or a threshold that isn't positive is
+    /// `MyError::InvalidParameter`, and a flat of another size `MyError::InvalidTextureData`.
+    pub fn detect(
+        &self,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        width: u32,
+        height: u32,
+        flat: &[u16],
+        detection: DefectDetection,
+    ) -> Result<Vec<u16>, MyError> {
+        if !(1..=MAX_DETECTION_RADIUS).contains(&detection.radius)
+            || !(detection.threshold.is_finite() && detection.threshold > 0.0)
+        {
+            return Err(MyError::InvalidParameter);
+        }
+        let pixel_count = frame_len(width, height)?;
+        if pixel_count == 0 || flat.len() as u64 != pixel_count {
+            return Err(MyError::InvalidTextureData);
+        }
+
+        let exposure = Buffer::from_iter(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            flat.iter().copied(),
+        )
+        .map_err(|e| MyError::AllocationError("flat exposure", e.to_string()))?;
+
+        let defects = Buffer::new_slice::<u16>(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            pixel_count,
+        )
+        .map_err(|e| MyError::AllocationError("detected defect map", e.to_string()))?;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, exposure),
+                WriteDescriptorSet::buffer(1, defects.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                defect_detection_shader::Params {
+                    image_width: width,
+                    image_height: height,
+                    radius: detection.radius,
+                    threshold: detection.threshold,
+                },
+            )
+            .unwrap()
+            .dispatch([(pixel_count as u32).div_ceil(64), 1, 1])
+            .unwrap();
+
+        sync::now(queue.device().clone())
+            .then_execute(queue.clone(), builder.end().unwrap())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        let defects = defects.read().unwrap().to_vec();
+        Ok(defects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefectDetection, DefectDetectionResources, MAX_DETECTION_RADIUS};
+    use crate::core::{error::MyError, test_utils::TestContext};
+
+    fn detect(
+        flat: &[u16],
+        width: u32,
+        height: u32,
+        detection: DefectDetection,
+    ) -> Result<Vec<u16>, MyError> {
+        let context = TestContext::new();
+        let resources = DefectDetectionResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+        )
+        .unwrap();
+
+        resources.detect(
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            width,
+            height,
+            flat,
+            detection,
+        )
+    }
+
+    /// A flat of around 1000 counts with a few counts of deterministic noise.
+    fn noisy_flat(width: u32, height: u32) -> Vec<u16> {
+        (0..width * height)
+            .map(|i| 1000 + (i * 7919 % 13) as u16)
+            .collect()
+    }
+
+    #[test]
+    fn hot_and_cold_pixels_are_flagged() {
+        let (width, height) = (37u32, 23u32);
+        let mut flat = noisy_flat(width, height);
+        let hot = 11 * width as usize + 5;
+        let cold = 4 * width as usize + 30;
+        // In the corner, where the window is clipped to a quarter.
+        let corner = 0;
+        flat[hot] = 4000;
+        flat[cold] = 100;
+        flat[corner] = 60000;
+
+        let defects = detect(&flat, width, height, DefectDetection::default()).unwrap();
+        for (idx, &defect) in defects.iter().enumerate() {
+            let expected = [hot, cold, corner].contains(&idx) as u16;
+            assert_eq!(defect, expected, "pixel {idx}");
+        }
+    }
+
+    #[test]
+    fn the_threshold_is_in_local_standard_deviations() {
+        let (width, height) = (16u32, 16u32);
+        // In a checkerboard of 1000 and 1010, the 3x3 window around a 1010 pixel has a
+        // median of 1010 and a median absolute deviation of 10.
+        let mut flat: Vec<u16> = (0..width * height)
+            .map(|i| 1000 + ((i + i / width) % 2 * 10) as u16)
+            .collect();
+        let target = 9 * width as usize + 8;
+        flat[target] = 1110;
+
+        // 100 counts off the median is about 6.7 standard deviations.
+        let loose = DefectDetection {
+            radius: 1,
+            threshold: 7.0,
+        };
+        let strict = DefectDetection {
+            radius: 1,
+            threshold: 6.5,
+        };
+        assert_eq!(detect(&flat, width, height, loose).unwrap()[target], 0);
+        assert_eq!(detect(&flat, width, height, strict).unwrap()[target], 1);
+    }
+
+    #[test]
+    fn invalid_detections_are_rejected() {
+        let flat = noisy_flat(8, 8);
+        for detection in [
+            DefectDetection {
+                radius: 0,
+                ..Default::default()
+            },
+            DefectDetection {
+                radius: MAX_DETECTION_RADIUS + 1,
+                ..Default::default()
+            },
+            DefectDetection {
+                threshold: 0.0,
+                ..Default::default()
+            },
+            DefectDetection {
+                threshold: f32::NAN,
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                detect(&flat, 8, 8, detection),
+                Err(MyError::InvalidParameter)
+            ));
+        }
+        assert!(matches!(
+            detect(&flat[1..], 8, 8, DefectDetection::default()),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+}
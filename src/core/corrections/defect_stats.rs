@@ -0,0 +1,363 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
+    },
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, Queue},
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    sync::{self, GpuFuture},
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+
+/// Propagation passes recorded per submission before checking for convergence.
+const PROPAGATION_PASSES_PER_SUBMIT: u32 = 16;
+
+const INIT_PASS: u32 = 0;
+const PROPAGATE_PASS: u32 = 1;
+const SIZES_PASS: u32 = 2;
+const SUMMARY_PASS: u32 = 3;
+
+mod defect_stats_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint width;
+                    uint height;
+                    uint pass_index;
+                };
+
+                PIXEL_BUFFER(0, defectMap)
+                layout(set = 0, binding = 1) buffer Labels {
+                    uint labels[];
+                };
+                layout(set = 0, binding = 2) buffer ClusterSizes {
+                    uint clusterSizes[];
+                };
+                layout(set = 0, binding = 3) buffer Stats {
+                    uint changed;
+                    uint defectCount;
+                    uint clusterCount;
+                    uint largestCluster;
+                };
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= width * height) {
+                        return;
+                    }
+
+                    // Labels are one-based pixel indices, zero marks a good pixel.
+                    if (pass_index == 0) {
+                        labels[idx] = load_defectMap(idx) == 1 ? idx + 1 : 0;
+                        clusterSizes[idx] = 0;
+                        return;
+                    }
+
+                    // Every defect takes the smallest label among its 8-connected defective
+                    // neighbours, then jumps to the label of that label's pixel. Labels only
+                    // ever decrease to those of the same cluster, so this converges on the
+                    // cluster's smallest pixel index.
+                    if (pass_index == 1) {
+                        uint label = labels[idx];
+                        if (label == 0) {
+                            return;
+                        }
+
+                        int x = int(idx % width);
+                        int y = int(idx / width);
+                        uint smallest = label;
+                        for (int dy = -1; dy <= 1; ++dy) {
+                            for (int dx = -1; dx <= 1; ++dx) {
+                                int nx = x + dx;
+                                int ny = y + dy;
+                                if (nx >= 0 && nx < int(width) && ny >= 0 && ny < int(height)) {
+                                    uint neighbour = labels[uint(ny) * width + uint(nx)];
+                                    if (neighbour != 0) {
+                                        smallest = min(smallest, neighbour);
+                                    }
+                                }
+                            }
+                        }
+                        smallest = min(smallest, labels[smallest - 1]);
+
+                        if (smallest < label) {
+                            atomicMin(labels[idx], smallest);
+                            changed = 1;
+                        }
+                        return;
+                    }
+
+                    if (pass_index == 2) {
+                        uint label = labels[idx];
+                        if (label != 0) {
+                            atomicAdd(clusterSizes[label - 1], 1);
+                            atomicAdd(defectCount, 1);
+                        }
+                        return;
+                    }
+
+                    uint size = clusterSizes[idx];
+                    if (size > 0) {
+                        atomicAdd(clusterCount, 1);
+                        atomicMax(largestCluster, size);
+                    }
+                }
+            "
+    );
+}
+
+/// Summary of the pixels flagged by a defect map. Clusters are 8-connected groups of
+/// defective pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DefectStats {
+    pub count: u32,
+    /// Fraction of the frame covered by defects.
+    pub fraction: f32,
+    /// Pixel count of the largest cluster.
+    pub largest_cluster: u32,
+    pub cluster_count: u32,
+}
+
+/// Labels the connected components of a defect map on the GPU and reduces them to
+/// [`DefectStats`].
+pub struct DefectStatsResources {
+    pipeline: Arc<ComputePipeline>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+impl DefectStatsResources {
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    ) -> Self {
+        let pipeline = {
+            let cs = defect_stats_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)
+                .unwrap();
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        DefectStatsResources {
+            pipeline,
+            memory_allocator,
+            descriptor_set_allocator,
+        }
+    }
+
+    /// Computes the statistics of `defect_map_buffer`, submitting propagation passes until
+    /// the cluster labels stop changing.
+    pub fn compute(
+        &self,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        image_width: u32,
+        image_height: u32,
+        defect_map_buffer: Subbuffer<[u16]>,
+    ) -> DefectStats {
+        let local_size_x = 64;
+
+        let pixel_count = image_width * image_height;
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        let device_buffer = || {
+            Buffer::new_slice::<u32>(
+                self.memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
+                    ..Default::default()
+                },
+                pixel_count as u64,
+            )
+            .unwrap()
+        };
+        let labels = device_buffer();
+        let cluster_sizes = device_buffer();
+
+        let stats = Buffer::from_iter(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            [0u32; 4],
+        )
+        .unwrap();
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, defect_map_buffer),
+                WriteDescriptorSet::buffer(1, labels),
+                WriteDescriptorSet::buffer(2, cluster_sizes),
+                WriteDescriptorSet::buffer(3, stats.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        let submit = |passes: &[u32]| {
+            let mut builder = RecordingCommandBuffer::primary(
+                command_buffer_allocator.clone(),
+                queue.queue_family_index(),
+                CommandBufferUsage::OneTimeSubmit,
+            )
+            .unwrap();
+
+            builder
+                .bind_pipeline_compute(self.pipeline.clone())
+                .unwrap()
+                .bind_descriptor_sets(
+                    PipelineBindPoint::Compute,
+                    self.pipeline.layout().clone(),
+                    0,
+                    set.clone(),
+                )
+                .unwrap();
+            for &pass_index in passes {
+                builder
+                    .push_constants(
+                        self.pipeline.layout().clone(),
+                        0,
+                        defect_stats_shader::Params {
+                            width: image_width,
+                            height: image_height,
+                            pass_index,
+                        },
+                    )
+                    .unwrap()
+                    .dispatch([dispatch_size_x, 1, 1])
+                    .unwrap();
+            }
+
+            let command_buffer = builder.end().unwrap();
+            sync::now(queue.device().clone())
+                .then_execute(queue.clone(), command_buffer)
+                .unwrap()
+                .then_signal_fence_and_flush()
+                .unwrap()
+                .wait(None)
+                .unwrap();
+        };
+
+        submit(&[INIT_PASS]);
+        loop {
+            stats.write().unwrap()[0] = 0;
+            submit(&[PROPAGATE_PASS; PROPAGATION_PASSES_PER_SUBMIT as usize]);
+            if stats.read().unwrap()[0] == 0 {
+                break;
+            }
+        }
+        submit(&[SIZES_PASS, SUMMARY_PASS]);
+
+        let stats = stats.read().unwrap();
+        DefectStats {
+            count: stats[1],
+            fraction: stats[1] as f32 / pixel_count as f32,
+            largest_cluster: stats[3],
+            cluster_count: stats[2],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefectStats, DefectStatsResources};
+    use crate::core::test_utils::TestContext;
+
+    #[test]
+    fn counts_isolated_and_clustered_defects() {
+        let context = TestContext::new();
+        let (width, height) = (32u32, 16u32);
+
+        let mut defect_map = vec![0u16; (width * height) as usize];
+        let mut flag = |x: u32, y: u32| defect_map[(y * width + x) as usize] = 1;
+        // Isolated pixel.
+        flag(1, 1);
+        // 2x2 block.
+        for (x, y) in [(10, 2), (11, 2), (10, 3), (11, 3)] {
+            flag(x, y);
+        }
+        // Diagonal line, connected through corners.
+        for i in 0..3 {
+            flag(20 + i, 5 + i);
+        }
+        // U shape whose arms only join at the bottom, touching the frame edge.
+        for (x, y) in [
+            (0, 10),
+            (0, 11),
+            (0, 12),
+            (1, 12),
+            (2, 12),
+            (2, 11),
+            (2, 10),
+        ] {
+            flag(x, y);
+        }
+
+        let resources = DefectStatsResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+        );
+        let stats = resources.compute(
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            width,
+            height,
+            context.host_buffer(defect_map),
+        );
+
+        assert_eq!(
+            stats,
+            DefectStats {
+                count: 15,
+                fraction: 15.0 / (width * height) as f32,
+                largest_cluster: 7,
+                cluster_count: 4,
+            }
+        );
+    }
+}
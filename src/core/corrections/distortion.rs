@@ -0,0 +1,280 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+mod distortion_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint image_width;
+                    uint image_height;
+                };
+
+                layout(set = 0, binding = 0) readonly buffer DisplacementX {
+                    float dx[];
+                };
+                layout(set = 0, binding = 1) readonly buffer DisplacementY {
+                    float dy[];
+                };
+                PIXEL_BUFFER(2, image)
+                PIXEL_BUFFER(3, result)
+
+                // Samples outside the frame take the value of the nearest edge pixel.
+                float sampleImage(int x, int y) {
+                    x = clamp(x, 0, int(image_width) - 1);
+                    y = clamp(y, 0, int(image_height) - 1);
+                    return float(load_image(uint(y) * image_width + uint(x)));
+                }
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= image_width * image_height) {
+                        return;
+                    }
+
+                    vec2 source = vec2(idx % image_width, idx / image_width) + vec2(dx[idx], dy[idx]);
+                    vec2 corner = floor(source);
+                    vec2 t = source - corner;
+                    int x0 = int(corner.x);
+                    int y0 = int(corner.y);
+
+                    float top = mix(sampleImage(x0, y0), sampleImage(x0 + 1, y0), t.x);
+                    float bottom = mix(sampleImage(x0, y0 + 1), sampleImage(x0 + 1, y0 + 1), t.x);
+                    float value = mix(top, bottom, t.y);
+                    store_result(idx, uint(clamp(round(value), 0.0, 65535.0)));
+                }
+            "
+    );
+}
+
+/// Undoes geometric distortion with a per-pixel displacement field: each output pixel
+/// `(x, y)` is the input bilinearly sampled at `(x + dx, y + dy)`. Samples past the edges
+/// of the frame are clamped to it. Can't work in place, so it writes to a separate result
+/// buffer like defect correction.
+pub struct DistortionResources {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    dx_buffer: Subbuffer<[f32]>,
+    dy_buffer: Subbuffer<[f32]>,
+}
+
+impl DistortionResources {
+    /// Fields that don't match the image size, or that hold non-finite displacements, are
+    /// `MyError::InvalidTextureData`.
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        dx: &[f32],
+        dy: &[f32],
+        image_height: u32,
+        image_width: u32,
+    ) -> Result<Self, MyError> {
+        let pixel_count = (image_width * image_height) as usize;
+        if dx.len() != pixel_count || dy.len() != pixel_count {
+            return Err(MyError::InvalidTextureData);
+        }
+        if !dx
+            .iter()
+            .chain(dy)
+            .all(|displacement| displacement.is_finite())
+        {
+            return Err(MyError::InvalidTextureData);
+        }
+
+        let upload = |field: &[f32], name: &'static str| {
+            Buffer::from_iter(
+                memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                        | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                    ..Default::default()
+                },
+                field.iter().copied(),
+            )
+            .map_err(|e| MyError::AllocationError(name, e.to_string()))
+        };
+        let dx_buffer = upload(dx, "distortion x displacement")?;
+        let dy_buffer = upload(dy, "distortion y displacement")?;
+
+        let pipeline = {
+            let cs = distortion_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        Ok(DistortionResources {
+            pipeline,
+            descriptor_set_allocator,
+            dx_buffer,
+            dy_buffer,
+        })
+    }
+
+    /// Writes the resampled `image_buffer` to `result_buffer`.
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+        result_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let dispatch_size_x = (image_width * image_height + local_size_x - 1) / local_size_x;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, self.dx_buffer.clone()),
+                WriteDescriptorSet::buffer(1, self.dy_buffer.clone()),
+                WriteDescriptorSet::buffer(2, image_buffer),
+                WriteDescriptorSet::buffer(3, result_buffer),
+            ],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                distortion_shader::Params {
+                    image_width,
+                    image_height,
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DistortionResources;
+    use crate::core::{error::MyError, test_utils::TestContext};
+
+    #[test]
+    fn uniform_shift_translates_with_interpolation() {
+        let context = TestContext::new();
+        let (width, height) = (16u32, 8u32);
+        let size = (width * height) as usize;
+        // A ramp along both axes, so interpolated values are exact.
+        let image: Vec<u16> = (0..size as u32)
+            .map(|i| (100 * (i % width) + 1000 * (i / width)) as u16)
+            .collect();
+
+        let resources = DistortionResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &vec![2.5; size],
+            &vec![-1.25; size],
+            height,
+            width,
+        )
+        .unwrap();
+
+        let image_buffer = context.host_buffer(image);
+        let result_buffer = context.host_buffer(vec![0u16; size]);
+        context.submit(|builder| {
+            resources.apply_pipeline(
+                builder,
+                width,
+                height,
+                image_buffer.clone(),
+                result_buffer.clone(),
+            )
+        });
+
+        let result = result_buffer.read().unwrap();
+        for y in 0..height {
+            for x in 0..width {
+                // Clamped to the frame, where the ramp stops.
+                let source_x = (x as f32 + 2.5).min((width - 1) as f32);
+                let source_y = (y as f32 - 1.25).max(0.0);
+                let expected = (100.0 * source_x + 1000.0 * source_y).round() as u16;
+                assert_eq!(result[(y * width + x) as usize], expected, "({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn mismatched_fields_are_rejected() {
+        let context = TestContext::new();
+        let create = |dx: &[f32], dy: &[f32]| {
+            DistortionResources::new(
+                context.device.clone(),
+                context.memory_allocator.clone(),
+                context.descriptor_set_allocator.clone(),
+                dx,
+                dy,
+                4,
+                4,
+            )
+        };
+
+        assert!(matches!(
+            create(&[0.0; 16], &[0.0; 15]),
+            Err(MyError::InvalidTextureData)
+        ));
+        let mut dx = [0.0; 16];
+        dx[3] = f32::NAN;
+        assert!(matches!(
+            create(&dx, &[0.0; 16]),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+}
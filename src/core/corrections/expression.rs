@@ -0,0 +1,516 @@
+use std::{
+    iter::Peekable,
+    str::Chars,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use vulkano::{
+    buffer::{BufferContents, Subbuffer},
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    shader::{ShaderModule, ShaderModuleCreateInfo},
+};
+
+use super::{pipeline_cache, uses_pixel_words, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+/// Largest number of distinct named uniforms an expression can use.
+pub const MAX_EXPRESSION_UNIFORMS: usize = 8;
+
+/// Built-in per-pixel variables: the pixel value and its column and row.
+const VARIABLES: [&str; 3] = ["pixel", "x", "y"];
+
+/// Functions an expression may call, with their argument counts.
+const FUNCTIONS: [(&str, usize); 10] = [
+    ("abs", 1),
+    ("sqrt", 1),
+    ("exp", 1),
+    ("log", 1),
+    ("floor", 1),
+    ("ceil", 1),
+    ("pow", 2),
+    ("min", 2),
+    ("max", 2),
+    ("clamp", 3),
+];
+
+/// Must match the push constant block of `SHADER_TEMPLATE`.
+#[derive(BufferContents)]
+#[repr(C)]
+struct ExpressionParams {
+    image_width: u32,
+    image_height: u32,
+    uniforms: [f32; MAX_EXPRESSION_UNIFORMS],
+}
+
+/// Spliced into `SHADER_TEMPLATE` in place of its include, which shaderc can't resolve
+/// without a callback.
+const PIXELS_GLSL: &str = include_str!("../shaders/pixels.glsl");
+
+const SHADER_TEMPLATE: &str = r"
+    #version 450
+    #include <pixels.glsl>
+
+    layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+    layout(push_constant) uniform Params {
+        uint image_width;
+        uint image_height;
+        float uniforms[8];
+    };
+
+    PIXEL_BUFFER(0, image)
+
+    void main() {
+        uint idx = gl_GlobalInvocationID.x;
+        if (idx >= image_width * image_height) {
+            return;
+        }
+
+        float pixel = float(load_image(idx));
+        float x = float(idx % image_width);
+        float y = float(idx / image_width);
+
+        float value = EXPRESSION;
+        store_image(idx, uint(clamp(round(value), 0.0, 65535.0)));
+    }
+";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f32),
+    Identifier(String),
+    Symbol(char),
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, MyError> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+
+    fn take_while(chars: &mut Peekable<Chars>, keep: impl Fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some(&c) = chars.peek() {
+            if !keep(c) {
+                break;
+            }
+            taken.push(c);
+            chars.next();
+        }
+        taken
+    }
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let literal = take_while(&mut chars, |c| c.is_ascii_digit() || c == '.');
+            let value = literal
+                .parse::<f32>()
+                .map_err(|_| MyError::InvalidExpression(format!("invalid number {literal}")))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let name = take_while(&mut chars, |c| c.is_ascii_alphanumeric() || c == '_');
+            tokens.push(Token::Identifier(name));
+        } else if "+-*/(),".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(MyError::InvalidExpression(format!(
+                "unsupported character '{c}'"
+            )));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent over the tokens, emitting the equivalent GLSL as it goes. Uniforms are
+/// numbered in order of first use.
+struct Translator {
+    tokens: Vec<Token>,
+    position: usize,
+    uniforms: Vec<String>,
+}
+
+impl Translator {
+    fn peek_symbol(&self) -> Option<char> {
+        match self.tokens.get(self.position) {
+            Some(Token::Symbol(symbol)) => Some(*symbol),
+            _ => None,
+        }
+    }
+
+    fn expect_symbol(&mut self, expected: char) -> Result<(), MyError> {
+        if self.peek_symbol() != Some(expected) {
+            return Err(MyError::InvalidExpression(format!("expected '{expected}'")));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    /// `sum := product (('+' | '-') product)*`
+    fn sum(&mut self) -> Result<String, MyError> {
+        let mut glsl = self.product()?;
+        while let Some(operator @ ('+' | '-')) = self.peek_symbol() {
+            self.position += 1;
+            glsl = format!("({glsl} {operator} {})", self.product()?);
+        }
+        Ok(glsl)
+    }
+
+    /// `product := unary (('*' | '/') unary)*`
+    fn product(&mut self) -> Result<String, MyError> {
+        let mut glsl = self.unary()?;
+        while let Some(operator @ ('*' | '/')) = self.peek_symbol() {
+            self.position += 1;
+            glsl = format!("({glsl} {operator} {})", self.unary()?);
+        }
+        Ok(glsl)
+    }
+
+    /// `unary := '-' unary | primary`
+    fn unary(&mut self) -> Result<String, MyError> {
+        if self.peek_symbol() == Some('-') {
+            self.position += 1;
+            return Ok(format!("(-{})", self.unary()?));
+        }
+        self.primary()
+    }
+
+    /// `primary := number | variable | uniform | function '(' sum (',' sum)* ')' | '(' sum ')'`
+    fn primary(&mut self) -> Result<String, MyError> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| MyError::InvalidExpression("unexpected end of expression".into()))?;
+        self.position += 1;
+
+        match token {
+            // Debug formatting always includes a decimal point or exponent, so GLSL parses
+            // the literal as a float.
+            Token::Number(value) => Ok(format!("{value:?}")),
+            Token::Symbol('(') => {
+                let glsl = self.sum()?;
+                self.expect_symbol(')')?;
+                Ok(glsl)
+            }
+            Token::Symbol(symbol) => {
+                Err(MyError::InvalidExpression(format!("unexpected '{symbol}'")))
+            }
+            Token::Identifier(name) => {
+                if self.peek_symbol() == Some('(') {
+                    return self.call(&name);
+                }
+                if VARIABLES.contains(&name.as_str()) {
+                    return Ok(name);
+                }
+                if FUNCTIONS.iter().any(|(function, _)| *function == name) {
+                    return Err(MyError::InvalidExpression(format!(
+                        "{name} must be called with arguments"
+                    )));
+                }
+
+                let index = match self.uniforms.iter().position(|uniform| *uniform == name) {
+                    Some(index) => index,
+                    None => {
+                        if self.uniforms.len() == MAX_EXPRESSION_UNIFORMS {
+                            return Err(MyError::InvalidExpression(format!(
+                                "more than {MAX_EXPRESSION_UNIFORMS} uniforms"
+                            )));
+                        }
+                        self.uniforms.push(name);
+                        self.uniforms.len() - 1
+                    }
+                };
+                Ok(format!("uniforms[{index}]"))
+            }
+        }
+    }
+
+    fn call(&mut self, name: &str) -> Result<String, MyError> {
+        let arity = FUNCTIONS
+            .iter()
+            .find(|(function, _)| *function == name)
+            .map(|(_, arity)| *arity)
+            .ok_or_else(|| MyError::InvalidExpression(format!("unsupported function {name}")))?;
+
+        self.expect_symbol('(')?;
+        let mut arguments = vec![self.sum()?];
+        while self.peek_symbol() == Some(',') {
+            self.position += 1;
+            arguments.push(self.sum()?);
+        }
+        self.expect_symbol(')')?;
+
+        if arguments.len() != arity {
+            return Err(MyError::InvalidExpression(format!(
+                "{name} takes {arity} arguments, got {}",
+                arguments.len()
+            )));
+        }
+        Ok(format!("{name}({})", arguments.join(", ")))
+    }
+}
+
+/// Translates `expression` to a GLSL float expression, returning it with the names of the
+/// uniforms it uses in the order they are indexed.
+fn translate(expression: &str) -> Result<(String, Vec<String>), MyError> {
+    let mut translator = Translator {
+        tokens: tokenize(expression)?,
+        position: 0,
+        uniforms: Vec::new(),
+    };
+
+    let glsl = translator.sum()?;
+    if let Some(token) = translator.tokens.get(translator.position) {
+        return Err(MyError::InvalidExpression(format!(
+            "unexpected {token:?} after the end of the expression"
+        )));
+    }
+
+    Ok((glsl, translator.uniforms))
+}
+
+/// Applies a user supplied per-pixel arithmetic expression, compiled to a compute shader at
+/// runtime. Expressions use `+ - * /`, parentheses, number literals, the variables `pixel`,
+/// `x` and `y`, the functions in `FUNCTIONS`, and any other identifier as a named uniform
+/// that starts at zero. The result is rounded and clamped to the u16 range.
+pub struct ExpressionResources {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    uniform_names: Vec<String>,
+    /// f32 bits, pushed as constants on every dispatch.
+    uniforms: [AtomicU32; MAX_EXPRESSION_UNIFORMS],
+}
+
+impl ExpressionResources {
+    pub fn new(
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        expression: &str,
+    ) -> Result<Self, MyError> {
+        let (glsl, uniform_names) = translate(expression)?;
+        let source = SHADER_TEMPLATE
+            .replace("#include <pixels.glsl>", PIXELS_GLSL)
+            .replace("EXPRESSION", &glsl);
+
+        let compiler = shaderc::Compiler::new().ok_or(MyError::ShaderCreationError)?;
+        let mut options = shaderc::CompileOptions::new().ok_or(MyError::ShaderCreationError)?;
+        if uses_pixel_words(&device) {
+            options.add_macro_definition("PIXEL_WORDS", None);
+        }
+        let spirv = compiler
+            .compile_into_spirv(
+                &source,
+                shaderc::ShaderKind::Compute,
+                "expression.comp",
+                "main",
+                Some(&options),
+            )
+            .map_err(|_| MyError::ShaderCreationError)?;
+
+        let pipeline = {
+            let module = unsafe {
+                ShaderModule::new(
+                    device.clone(),
+                    ShaderModuleCreateInfo::new(spirv.as_binary()),
+                )
+            }
+            .map_err(|_| MyError::ShaderCreationError)?;
+            let cs = module.required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        Ok(ExpressionResources {
+            pipeline,
+            descriptor_set_allocator,
+            uniform_names,
+            uniforms: Default::default(),
+        })
+    }
+
+    pub fn uniform(&self, name: &str) -> Option<f32> {
+        let index = self
+            .uniform_names
+            .iter()
+            .position(|uniform| uniform == name)?;
+        Some(f32::from_bits(self.uniforms[index].load(Ordering::Relaxed)))
+    }
+
+    /// Takes effect from the next recorded dispatch. `name` must appear in the expression.
+    pub fn set_uniform(&self, name: &str, value: f32) -> Result<(), MyError> {
+        let index = self
+            .uniform_names
+            .iter()
+            .position(|uniform| uniform == name)
+            .ok_or(MyError::InvalidParameter)?;
+        if !value.is_finite() {
+            return Err(MyError::InvalidParameter);
+        }
+        self.uniforms[index].store(value.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let dispatch_size_x = (image_width * image_height + local_size_x - 1) / local_size_x;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [WriteDescriptorSet::buffer(0, image_buffer)],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                ExpressionParams {
+                    image_width,
+                    image_height,
+                    uniforms: std::array::from_fn(|i| {
+                        f32::from_bits(self.uniforms[i].load(Ordering::Relaxed))
+                    }),
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{translate, ExpressionResources, MAX_EXPRESSION_UNIFORMS};
+    use crate::core::{error::MyError, test_utils::TestContext};
+
+    #[test]
+    fn translates_precedence_and_uniforms() {
+        let (glsl, uniforms) = translate("pixel * 2 + offset / -scale").unwrap();
+        assert_eq!(glsl, "((pixel * 2.0) + (uniforms[0] / (-uniforms[1])))");
+        assert_eq!(uniforms, ["offset", "scale"]);
+
+        let (glsl, uniforms) = translate("clamp(sqrt(pixel), x, max(y, k)) - k").unwrap();
+        assert_eq!(
+            glsl,
+            "(clamp(sqrt(pixel), x, max(y, uniforms[0])) - uniforms[0])"
+        );
+        assert_eq!(uniforms, ["k"]);
+    }
+
+    #[test]
+    fn rejects_unsupported_expressions() {
+        let too_many_uniforms = (0..=MAX_EXPRESSION_UNIFORMS)
+            .map(|i| format!("u{i}"))
+            .collect::<Vec<_>>()
+            .join(" + ");
+        for expression in [
+            "",
+            "pixel +",
+            "pixel; discard",
+            "pixel % 2",
+            "sin(pixel)",
+            "pow(pixel)",
+            "sqrt + 1",
+            "(pixel * 2",
+            "pixel 2",
+            "1.2.3",
+            too_many_uniforms.as_str(),
+        ] {
+            assert!(
+                matches!(translate(expression), Err(MyError::InvalidExpression(_))),
+                "{expression:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn applies_expression_to_every_pixel() {
+        let context = TestContext::new();
+        let (width, height) = (64u32, 4u32);
+        let image: Vec<u16> = (0..width * height).map(|i| i as u16).collect();
+
+        let resources = ExpressionResources::new(
+            context.device.clone(),
+            context.descriptor_set_allocator.clone(),
+            "pixel * 2 + 10",
+        )
+        .unwrap();
+
+        let image_buffer = context.host_buffer(image.clone());
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, width, height, image_buffer.clone())
+        });
+
+        let expected: Vec<u16> = image.iter().map(|&pixel| pixel * 2 + 10).collect();
+        assert_eq!(*image_buffer.read().unwrap(), expected[..]);
+    }
+
+    #[test]
+    fn uniforms_apply_from_the_next_dispatch() {
+        let context = TestContext::new();
+        let (width, height) = (64u32, 1u32);
+
+        let resources = ExpressionResources::new(
+            context.device.clone(),
+            context.descriptor_set_allocator.clone(),
+            "pixel + x * step",
+        )
+        .unwrap();
+        assert_eq!(resources.uniform("step"), Some(0.0));
+        resources.set_uniform("step", 3.0).unwrap();
+        assert!(resources.set_uniform("missing", 1.0).is_err());
+
+        let image_buffer = context.host_buffer(vec![100u16; width as usize]);
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, width, height, image_buffer.clone())
+        });
+
+        let expected: Vec<u16> = (0..width as u16).map(|x| 100 + x * 3).collect();
+        assert_eq!(*image_buffer.read().unwrap(), expected[..]);
+    }
+}
@@ -0,0 +1,273 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+};
+
+use super::{local_size_entry_point, pipeline_cache};
+use crate::core::error::MyError;
+
+mod flat_field_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x_id = 100, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                    float scale;
+                };
+
+                PIXEL_BUFFER(0, darkMap)
+                PIXEL_BUFFER(1, flatMap)
+                PIXEL_BUFFER(2, image)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    float dark = float(load_darkMap(idx));
+                    float response = float(load_flatMap(idx)) - dark;
+                    // A pixel whose flat is no brighter than its dark has no usable response,
+                    // so it is passed through uncorrected.
+                    if (response <= 0.0) {
+                        return;
+                    }
+
+                    float value = (float(load_image(idx)) - dark) / response * scale;
+                    store_image(idx, uint(clamp(round(value), 0.0, 65535.0)));
+                }
+            "
+    );
+}
+
+/// Dark subtraction and flat-field normalisation in one pass,
+/// `(raw - dark) / (flat - dark) * scale`, clamped to the u16 range. Saves the round trip
+/// through device memory of separate dark and gain passes. Pixels where `flat <= dark` are
+/// left as they are.
+pub struct FlatFieldBufferResources {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    dark_map_buffer: Subbuffer<[u16]>,
+    flat_map_buffer: Subbuffer<[u16]>,
+    /// f32 bits, pushed on every dispatch so it can change between frames.
+    scale: AtomicU32,
+    local_size: u32,
+}
+
+impl FlatFieldBufferResources {
+    /// `scale` starts at the mean `flat - dark` response of the usable pixels, so a frame
+    /// taken under the flat's illumination keeps its brightness. Maps that don't match the
+    /// image size are `MyError::InvalidTextureData`.
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        dark_map: &[u16],
+        flat_map: &[u16],
+        image_height: u32,
+        image_width: u32,
+        local_size: u32,
+    ) -> Result<Self, MyError> {
+        let pixel_count = (image_width * image_height) as usize;
+        if dark_map.len() != pixel_count || flat_map.len() != pixel_count {
+            return Err(MyError::InvalidTextureData);
+        }
+
+        let (response_sum, usable) = dark_map
+            .iter()
+            .zip(flat_map)
+            .filter(|(dark, flat)| flat > dark)
+            .fold((0u64, 0u64), |(sum, count), (&dark, &flat)| {
+                (sum + (flat - dark) as u64, count + 1)
+            });
+        let scale = if usable == 0 {
+            1.0
+        } else {
+            (response_sum as f64 / usable as f64) as f32
+        };
+
+        let upload = |map: &[u16], name: &'static str| {
+            Buffer::from_iter(
+                memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                        | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                    ..Default::default()
+                },
+                map.iter().copied(),
+            )
+            .map_err(|e| MyError::AllocationError(name, e.to_string()))
+        };
+        let dark_map_buffer = upload(dark_map, "flat-field dark map")?;
+        let flat_map_buffer = upload(flat_map, "flat-field flat map")?;
+
+        let pipeline = {
+            let cs = local_size_entry_point(
+                flat_field_shader::load(device.clone()).unwrap(),
+                local_size,
+                [],
+            )?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        Ok(FlatFieldBufferResources {
+            pipeline,
+            descriptor_set_allocator,
+            dark_map_buffer,
+            flat_map_buffer,
+            scale: AtomicU32::new(scale.to_bits()),
+            local_size,
+        })
+    }
+
+    pub fn scale(&self) -> f32 {
+        f32::from_bits(self.scale.load(Ordering::Relaxed))
+    }
+
+    /// Takes effect from the next recorded dispatch.
+    pub fn set_scale(&self, scale: f32) -> Result<(), MyError> {
+        if !(scale.is_finite() && scale > 0.0) {
+            return Err(MyError::InvalidParameter);
+        }
+        self.scale.store(scale.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let pixel_count = image_width * image_height;
+        let dispatch_size_x = pixel_count.div_ceil(self.local_size);
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, self.dark_map_buffer.clone()),
+                WriteDescriptorSet::buffer(1, self.flat_map_buffer.clone()),
+                WriteDescriptorSet::buffer(2, image_buffer),
+            ],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                flat_field_shader::Params {
+                    pixel_count,
+                    scale: self.scale(),
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FlatFieldBufferResources;
+    use crate::core::{corrections::DEFAULT_LOCAL_SIZE, error::MyError, test_utils::TestContext};
+
+    #[test]
+    fn normalises_by_flat_response() {
+        let context = TestContext::new();
+        let dark = vec![100u16, 100, 200, 200, 500, 500];
+        let flat = vec![1100u16, 2100, 1200, 700, 500, 400];
+        let image = vec![600u16, 1100, 1200, 450, 777, 888];
+
+        let resources = FlatFieldBufferResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &dark,
+            &flat,
+            1,
+            image.len() as u32,
+            DEFAULT_LOCAL_SIZE,
+        )
+        .unwrap();
+        // Mean response of the four usable pixels: (1000 + 2000 + 1000 + 500) / 4.
+        assert_eq!(resources.scale(), 1125.0);
+        resources.set_scale(1000.0).unwrap();
+
+        let image_buffer = context.host_buffer(image.clone());
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, image.len() as u32, 1, image_buffer.clone())
+        });
+
+        // The last two pixels have flat == dark and flat < dark and pass through.
+        assert_eq!(
+            *image_buffer.read().unwrap(),
+            [500, 500, 1000, 500, 777, 888]
+        );
+    }
+
+    #[test]
+    fn wrong_length_maps_are_rejected() {
+        let context = TestContext::new();
+        let result = FlatFieldBufferResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &[0; 16],
+            &[1; 15],
+            4,
+            4,
+            DEFAULT_LOCAL_SIZE,
+        );
+        assert!(matches!(result, Err(MyError::InvalidTextureData)));
+    }
+}
@@ -0,0 +1,346 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::Subbuffer,
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    shader::SpecializationConstant,
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+
+/// Element type of the frames a `Corrections` context is fed, chosen when it is created.
+/// The corrections themselves always run on 16-bit pixels: other formats are converted on
+/// the GPU on the way in and back on the way out, integers saturating and floats rounding
+/// to the nearest count.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PixelFormat {
+    U8,
+    #[default]
+    U16,
+    U32,
+    F32,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::U8 => 1,
+            PixelFormat::U16 => 2,
+            PixelFormat::U32 | PixelFormat::F32 => 4,
+        }
+    }
+
+    /// Number of u32 words a frame of `pixel_count` pixels occupies in this format.
+    pub fn word_count(&self, pixel_count: u32) -> u64 {
+        (pixel_count as u64 * self.bytes_per_pixel() as u64).div_ceil(4)
+    }
+
+    /// Value of the shader's `FORMAT` specialization constant.
+    fn shader_id(&self) -> u32 {
+        *self as u32
+    }
+}
+
+mod format_conversion_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                #define FORMAT_U8 0
+                #define FORMAT_U32 2
+                #define FORMAT_F32 3
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(constant_id = 0) const uint FORMAT = FORMAT_U8;
+                // Converts the working image to the raw frame instead of the other way.
+                layout(constant_id = 1) const bool PACK = false;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                };
+
+                layout(set = 0, binding = 0) buffer Raw {
+                    uint raw[];
+                };
+
+                PIXEL_BUFFER(1, image)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+
+                    if (FORMAT == FORMAT_U8) {
+                        // Four pixels share a word, so each invocation owns a whole word.
+                        uint first = idx * 4;
+                        if (first >= pixel_count) {
+                            return;
+                        }
+                        uint count = min(pixel_count - first, 4u);
+
+                        if (PACK) {
+                            uint word = 0u;
+                            for (uint i = 0; i < count; ++i) {
+                                word |= min(load_image(first + i), 255u) << (8 * i);
+                            }
+                            raw[idx] = word;
+                        } else {
+                            uint word = raw[idx];
+                            for (uint i = 0; i < count; ++i) {
+                                store_image(first + i, (word >> (8 * i)) & 0xFFu);
+                            }
+                        }
+                        return;
+                    }
+
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    if (PACK) {
+                        uint pixel = load_image(idx);
+                        raw[idx] = FORMAT == FORMAT_F32 ? floatBitsToUint(float(pixel)) : pixel;
+                    } else if (FORMAT == FORMAT_F32) {
+                        float value = uintBitsToFloat(raw[idx]);
+                        store_image(idx, uint(clamp(round(value), 0.0, 65535.0)));
+                    } else {
+                        store_image(idx, min(raw[idx], 65535u));
+                    }
+                }
+            "
+    );
+}
+
+/// Converts frames between a non-16-bit [`PixelFormat`], held as raw u32 words, and the
+/// 16-bit image buffers the corrections work on.
+pub struct FormatConversionResources {
+    format: PixelFormat,
+    unpack_pipeline: Arc<ComputePipeline>,
+    pack_pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+impl FormatConversionResources {
+    /// # Panics
+    ///
+    /// Panics for `PixelFormat::U16`, which needs no conversion.
+    pub fn new(
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        format: PixelFormat,
+    ) -> Self {
+        assert_ne!(format, PixelFormat::U16, "16-bit frames need no conversion");
+
+        let create_pipeline = |pack: bool| {
+            let cs = format_conversion_shader::load(device.clone())
+                .unwrap()
+                .specialize(
+                    [
+                        (0, SpecializationConstant::U32(format.shader_id())),
+                        (1, SpecializationConstant::Bool(pack)),
+                    ]
+                    .into_iter()
+                    .collect(),
+                )
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)
+                .unwrap();
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        FormatConversionResources {
+            format,
+            unpack_pipeline: create_pipeline(false),
+            pack_pipeline: create_pipeline(true),
+            descriptor_set_allocator,
+        }
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Converts the raw frame in `raw_buffer` into `image_buffer`.
+    pub fn unpack<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        raw_buffer: Subbuffer<[u32]>,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        self.dispatch(
+            &self.unpack_pipeline,
+            builder,
+            image_width * image_height,
+            raw_buffer,
+            image_buffer,
+        );
+    }
+
+    /// Converts `image_buffer` back into the raw frame in `raw_buffer`.
+    pub fn pack<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+        raw_buffer: Subbuffer<[u32]>,
+    ) {
+        self.dispatch(
+            &self.pack_pipeline,
+            builder,
+            image_width * image_height,
+            raw_buffer,
+            image_buffer,
+        );
+    }
+
+    fn dispatch<L>(
+        &self,
+        pipeline: &Arc<ComputePipeline>,
+        builder: &mut RecordingCommandBuffer<L>,
+        pixel_count: u32,
+        raw_buffer: Subbuffer<[u32]>,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let invocations = if self.format == PixelFormat::U8 {
+            pixel_count.div_ceil(4)
+        } else {
+            pixel_count
+        };
+        let dispatch_size_x = (invocations + local_size_x - 1) / local_size_x;
+
+        let layout = pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, raw_buffer),
+                WriteDescriptorSet::buffer(1, image_buffer),
+            ],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                pipeline.layout().clone(),
+                0,
+                format_conversion_shader::Params { pixel_count },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FormatConversionResources, PixelFormat};
+    use crate::core::test_utils::TestContext;
+
+    fn round_trip(format: PixelFormat, raw: Vec<u32>, pixel_count: u32) -> (Vec<u16>, Vec<u32>) {
+        let context = TestContext::new();
+        let resources = FormatConversionResources::new(
+            context.device.clone(),
+            context.descriptor_set_allocator.clone(),
+            format,
+        );
+
+        let raw_buffer = context.host_buffer(raw);
+        let image_buffer = context.host_buffer(vec![0u16; pixel_count as usize]);
+        context.submit(|builder| {
+            resources.unpack(
+                builder,
+                pixel_count,
+                1,
+                raw_buffer.clone(),
+                image_buffer.clone(),
+            )
+        });
+        let image = image_buffer.read().unwrap().to_vec();
+
+        let packed_buffer =
+            context.host_buffer(vec![0u32; format.word_count(pixel_count) as usize]);
+        context.submit(|builder| {
+            resources.pack(
+                builder,
+                pixel_count,
+                1,
+                image_buffer.clone(),
+                packed_buffer.clone(),
+            )
+        });
+        let packed = packed_buffer.read().unwrap().to_vec();
+
+        (image, packed)
+    }
+
+    #[test]
+    fn u8_frames_round_trip() {
+        // Five pixels, so the last word is only partly used.
+        let pixels = [0u8, 1, 127, 255, 42];
+        let mut bytes = pixels.to_vec();
+        bytes.resize(8, 0);
+        let raw: Vec<u32> = bytemuck::cast_slice(&bytes).to_vec();
+
+        let (image, packed) = round_trip(PixelFormat::U8, raw.clone(), pixels.len() as u32);
+        assert_eq!(image, [0, 1, 127, 255, 42]);
+        assert_eq!(packed, raw);
+    }
+
+    #[test]
+    fn u32_frames_saturate() {
+        let raw = vec![0u32, 1000, 65535, 65536, 4_000_000_000];
+
+        let (image, packed) = round_trip(PixelFormat::U32, raw, 5);
+        assert_eq!(image, [0, 1000, 65535, 65535, 65535]);
+        assert_eq!(packed, [0, 1000, 65535, 65535, 65535]);
+    }
+
+    #[test]
+    fn f32_frames_round_and_clamp() {
+        let values = [-5.0f32, 0.4, 1000.6, 70000.0, f32::NAN];
+        let raw = values.iter().map(|value| value.to_bits()).collect();
+
+        let (image, packed) = round_trip(PixelFormat::F32, raw, 5);
+        assert_eq!(&image[..4], [0, 0, 1001, 65535]);
+        let packed: Vec<f32> = packed.into_iter().map(f32::from_bits).collect();
+        assert_eq!(&packed[..4], [0.0, 0.0, 1001.0, 65535.0]);
+    }
+}
@@ -0,0 +1,307 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+
+mod frame_quality_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Levels {
+                    uint pixel_count;
+                    uint saturation_level;
+                    uint dark_level;
+                };
+
+                PIXEL_BUFFER(0, image)
+                layout(set = 0, binding = 1) buffer Counters {
+                    uint saturatedCount;
+                    uint darkCount;
+                };
+                layout(set = 0, binding = 2) buffer PartialSums {
+                    uint partialSums[];
+                };
+
+                shared uint localSums[64];
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    uint local = gl_LocalInvocationIndex;
+                    uint value = 0;
+
+                    if (idx < pixel_count) {
+                        value = load_image(idx);
+                        if (value >= saturation_level) {
+                            atomicAdd(saturatedCount, 1);
+                        }
+                        if (value <= dark_level) {
+                            atomicAdd(darkCount, 1);
+                        }
+                    }
+
+                    // A workgroup sum of 64 u16 values always fits in 32 bits, the host adds
+                    // up the per-workgroup partials.
+                    localSums[local] = value;
+                    barrier();
+                    for (uint stride = 32; stride > 0; stride >>= 1) {
+                        if (local < stride) {
+                            localSums[local] += localSums[local + stride];
+                        }
+                        barrier();
+                    }
+
+                    if (local == 0) {
+                        partialSums[gl_WorkGroupID.x] = localSums[0];
+                    }
+                }
+            "
+    );
+}
+
+/// Summary statistics of a corrected frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameQuality {
+    /// Fraction of pixels at or above the saturation level.
+    pub saturated_fraction: f32,
+    /// Fraction of pixels at or below the dark level.
+    pub dark_fraction: f32,
+    pub mean: f32,
+}
+
+/// Limits a frame must stay within to be considered usable.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QualityThresholds {
+    pub max_saturated_fraction: f32,
+    pub max_dark_fraction: f32,
+    pub min_mean: f32,
+    pub max_mean: f32,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        QualityThresholds {
+            max_saturated_fraction: 0.01,
+            max_dark_fraction: 0.5,
+            min_mean: 0.0,
+            max_mean: u16::MAX as f32,
+        }
+    }
+}
+
+impl FrameQuality {
+    pub fn is_good(&self, thresholds: &QualityThresholds) -> bool {
+        self.saturated_fraction <= thresholds.max_saturated_fraction
+            && self.dark_fraction <= thresholds.max_dark_fraction
+            && (thresholds.min_mean..=thresholds.max_mean).contains(&self.mean)
+    }
+}
+
+/// Per-frame output buffers of a quality pass, readable once the frame's fence signals.
+pub struct FrameQualityReadback {
+    counters: Subbuffer<[u32]>,
+    partial_sums: Subbuffer<[u32]>,
+    pixel_count: u32,
+}
+
+impl FrameQualityReadback {
+    pub fn read(&self) -> FrameQuality {
+        let counters = self.counters.read().unwrap();
+        let sum: u64 = self
+            .partial_sums
+            .read()
+            .unwrap()
+            .iter()
+            .map(|&partial| partial as u64)
+            .sum();
+        let pixel_count = self.pixel_count as f32;
+
+        FrameQuality {
+            saturated_fraction: counters[0] as f32 / pixel_count,
+            dark_fraction: counters[1] as f32 / pixel_count,
+            mean: (sum as f64 / self.pixel_count as f64) as f32,
+        }
+    }
+}
+
+pub struct FrameQualityResources {
+    pipeline: Arc<ComputePipeline>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    saturation_level: u16,
+    dark_level: u16,
+}
+
+impl FrameQualityResources {
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        saturation_level: u16,
+        dark_level: u16,
+    ) -> Self {
+        let pipeline = {
+            let cs = frame_quality_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)
+                .unwrap();
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        FrameQualityResources {
+            pipeline,
+            memory_allocator,
+            descriptor_set_allocator,
+            saturation_level,
+            dark_level,
+        }
+    }
+
+    /// Records the quality pass over `image_buffer`. Each call gets its own output buffers
+    /// so frames in flight don't share counters.
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) -> FrameQualityReadback {
+        let local_size_x = 64;
+
+        let pixel_count = image_width * image_height;
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        let host_buffer = |len: u32| {
+            Buffer::from_iter(
+                self.memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                        | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                    ..Default::default()
+                },
+                vec![0u32; len as usize],
+            )
+            .unwrap()
+        };
+        let counters = host_buffer(2);
+        let partial_sums = host_buffer(dispatch_size_x);
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, image_buffer),
+                WriteDescriptorSet::buffer(1, counters.clone()),
+                WriteDescriptorSet::buffer(2, partial_sums.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                frame_quality_shader::Levels {
+                    pixel_count,
+                    saturation_level: self.saturation_level as u32,
+                    dark_level: self.dark_level as u32,
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+
+        FrameQualityReadback {
+            counters,
+            partial_sums,
+            pixel_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameQualityResources, QualityThresholds};
+    use crate::core::test_utils::TestContext;
+
+    #[test]
+    fn saturated_frame_is_flagged() {
+        let context = TestContext::new();
+        let (width, height) = (100, 100);
+        let resources = FrameQualityResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            u16::MAX,
+            0,
+        );
+
+        let mut normal: Vec<u16> = (0..width * height).map(|i| (i % 2000) as u16 + 1).collect();
+        normal[0] = u16::MAX;
+        let normal_buffer = context.host_buffer(normal.clone());
+        let saturated_buffer = context.host_buffer(vec![u16::MAX; (width * height) as usize]);
+
+        let mut readbacks = Vec::new();
+        context.submit(|builder| {
+            readbacks.push(resources.apply_pipeline(builder, width, height, normal_buffer));
+            readbacks.push(resources.apply_pipeline(builder, width, height, saturated_buffer));
+        });
+        let normal_quality = readbacks[0].read();
+        let saturated_quality = readbacks[1].read();
+
+        let expected_mean = normal.iter().map(|&p| p as f64).sum::<f64>() / normal.len() as f64;
+        assert_eq!(normal_quality.saturated_fraction, 1.0 / 10000.0);
+        assert_eq!(normal_quality.dark_fraction, 0.0);
+        assert!((normal_quality.mean as f64 - expected_mean).abs() < 0.01);
+        assert!(normal_quality.is_good(&QualityThresholds::default()));
+
+        assert_eq!(saturated_quality.saturated_fraction, 1.0);
+        assert_eq!(saturated_quality.mean, u16::MAX as f32);
+        assert!(!saturated_quality.is_good(&QualityThresholds::default()));
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+};
+
+struct SequenceState {
+    /// Frame whose turn it is.
+    next_frame: u64,
+    /// Later frames whose turns were released without being taken, passed over when their
+    /// turn comes.
+    skipped: HashSet<u64>,
+}
+
+struct Shared {
+    state: Mutex<SequenceState>,
+    turn: Condvar,
+}
+
+/// Hands frames their turn at a stateful pass strictly in the order they were claimed,
+/// whichever task reaches the pass first. Passes folding every frame into state carried
+/// across frames, like lag correction and the temporal average, take their turn from one.
+pub struct FrameSequence {
+    frames_claimed: AtomicU64,
+    shared: Arc<Shared>,
+}
+
+/// A frame's place in a [`FrameSequence`], from [`FrameSequence::claim`]. Dropping it
+/// without taking it, as when the frame fails or its task is aborted first, gives up the
+/// turn so later frames aren't left waiting.
+pub struct FrameTurn {
+    shared: Arc<Shared>,
+    frame: u64,
+}
+
+impl FrameSequence {
+    pub fn new() -> Self {
+        FrameSequence {
+            frames_claimed: AtomicU64::new(0),
+            shared: Arc::new(Shared {
+                state: Mutex::new(SequenceState {
+                    next_frame: 0,
+                    skipped: HashSet::new(),
+                }),
+                turn: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Reserves the next position in the sequence. Call in submission order.
+    pub fn claim(&self) -> FrameTurn {
+        FrameTurn {
+            shared: self.shared.clone(),
+            frame: self.frames_claimed.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for FrameSequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameTurn {
+    /// Blocks until every earlier frame has taken or given up its turn, then runs `f` and
+    /// passes the turn on, even if `f` fails or panics.
+    pub fn take<R>(self, f: impl FnOnce() -> R) -> R {
+        drop(
+            self.shared
+                .turn
+                .wait_while(self.shared.state.lock().unwrap(), |state| {
+                    state.next_frame != self.frame
+                })
+                .unwrap(),
+        );
+        f()
+    }
+}
+
+impl Drop for FrameTurn {
+    fn drop(&mut self) {
+        // Taken even when poisoned, as every later frame depends on the turn moving on.
+        let mut state = self
+            .shared
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if state.next_frame == self.frame {
+            state.next_frame += 1;
+            while state.skipped.remove(&state.next_frame) {
+                state.next_frame += 1;
+            }
+            self.shared.turn.notify_all();
+        } else {
+            state.skipped.insert(self.frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, thread};
+
+    use super::FrameSequence;
+
+    #[test]
+    fn turns_follow_claim_order() {
+        let sequence = FrameSequence::new();
+        let taken = Mutex::new(Vec::new());
+
+        let first = sequence.claim();
+        let second = sequence.claim();
+        let third = sequence.claim();
+        let fourth = sequence.claim();
+        thread::scope(|scope| {
+            // Reaches its turn before the earlier frames and has to wait for them.
+            let late = scope.spawn(|| fourth.take(|| taken.lock().unwrap().push(4)));
+            // Released before its turn, like a frame whose task was aborted.
+            drop(second);
+            first.take(|| taken.lock().unwrap().push(1));
+            // Released on its turn, like a frame that failed before reaching the pass.
+            drop(third);
+            late.join().unwrap();
+        });
+        assert_eq!(*taken.lock().unwrap(), [1, 4]);
+
+        sequence.claim().take(|| taken.lock().unwrap().push(5));
+        assert_eq!(*taken.lock().unwrap(), [1, 4, 5]);
+    }
+}
@@ -1,10 +1,12 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
 
 use vulkano::{
     buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
     command_buffer::{
-        allocator::StandardCommandBufferAllocator, CommandBufferUsage, PrimaryAutoCommandBuffer,
-        RecordingCommandBuffer,
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
     },
     descriptor_set::{
         allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
@@ -16,14 +18,236 @@ use vulkano::{
         ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
         PipelineShaderStageCreateInfo,
     },
+    shader::SpecializationConstant,
     sync::{self, GpuFuture},
 };
 
+use super::{
+    frame_len, grid_stride_dispatch_size, local_size_entry_point, pipeline_cache, EntryPointLookup,
+    Rect, MAIN_ENTRY_POINT,
+};
+use crate::core::error::MyError;
+
+/// Fractional bits of fixed-point gain maps unless told otherwise, Q2.14: gains up to just
+/// under 4 in steps of 2^-14, so a full-scale pixel is off by at most 4 counts.
+pub const DEFAULT_GAIN_FRAC_BITS: u32 = 14;
+
+/// Most fractional bits a fixed-point gain can have, leaving no integer bits.
+pub const MAX_GAIN_FRAC_BITS: u32 = 16;
+
+mod gain_correction_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+                #include <roi.glsl>
+
+                layout(local_size_x_id = 100, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                    uint items_per_invocation;
+                    uint image_width;
+                    uint roi_x;
+                    uint roi_y;
+                    uint roi_width;
+                    uint frame_pixels;
+                    float scale;
+                };
+
+                layout(set = 0, binding = 0) buffer GainMapData {
+                    float gainMapData[];
+                };
+                PIXEL_BUFFER(1, image)
+
+                void main() {
+                    // One frame of a batch per z, see the dark correction shader.
+                    uint frame = gl_GlobalInvocationID.z * frame_pixels;
+
+                    // Grid-stride loop, see the dark correction shader.
+                    uint stride = gl_NumWorkGroups.x * gl_WorkGroupSize.x;
+                    for (uint item = 0; item < items_per_invocation; ++item) {
+                        uint i = gl_GlobalInvocationID.x + item * stride;
+                        if (i >= pixel_count) {
+                            return;
+                        }
+
+                        uint idx = roi_pixel(i, roi_x, roi_y, roi_width, image_width);
+                        store_image(frame + idx, uint(float(load_image(frame + idx)) * gainMapData[idx] * scale));
+                    }
+                }
+            "
+    );
+}
+
+mod fixed_gain_correction_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+                #include <roi.glsl>
+
+                layout(local_size_x_id = 100, local_size_y = 1, local_size_z = 1) in;
+
+                layout(constant_id = 0) const uint FRAC_BITS = 14;
+
+                // Laid out like the f32 shader's, so both record with the same constants.
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                    uint items_per_invocation;
+                    uint image_width;
+                    uint roi_x;
+                    uint roi_y;
+                    uint roi_width;
+                    uint frame_pixels;
+                    float scale;
+                };
+
+                PIXEL_BUFFER(0, gainMap)
+                PIXEL_BUFFER(1, image)
+
+                void main() {
+                    // One frame of a batch per z, see the dark correction shader.
+                    uint frame = gl_GlobalInvocationID.z * frame_pixels;
+
+                    // Grid-stride loop, see the dark correction shader.
+                    uint stride = gl_NumWorkGroups.x * gl_WorkGroupSize.x;
+                    for (uint item = 0; item < items_per_invocation; ++item) {
+                        uint i = gl_GlobalInvocationID.x + item * stride;
+                        if (i >= pixel_count) {
+                            return;
+                        }
+
+                        uint idx = roi_pixel(i, roi_x, roi_y, roi_width, image_width);
+                        // A product of two u16 values can't overflow 32 bits. Truncates like
+                        // the f32 shader. `scale` is always 1, fixed-point maps can't be
+                        // normalised.
+                        store_image(frame + idx, (load_image(frame + idx) * load_gainMap(idx)) >> FRAC_BITS);
+                    }
+                }
+            "
+    );
+}
+
+mod gain_minimum_shader {
+    pixel_shader!(
+        r"
+                #version 450
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                };
+
+                layout(set = 0, binding = 0) readonly buffer GainMapData {
+                    float gainMapData[];
+                };
+                layout(set = 0, binding = 1) buffer Minimum {
+                    uint minimumBits;
+                };
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    // Positive floats order like their bit patterns. Dead pixels without a
+                    // gain don't count.
+                    float gain = gainMapData[idx];
+                    if (gain > 0.0) {
+                        atomicMin(minimumBits, floatBitsToUint(gain));
+                    }
+                }
+            "
+    );
+}
+
+mod resample_shader {
+    pixel_shader!(
+        r"
+                #version 450
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint source_width;
+                    uint source_height;
+                    uint image_width;
+                    uint image_height;
+                };
+
+                layout(set = 0, binding = 0) readonly buffer Source {
+                    float source[];
+                };
+                layout(set = 0, binding = 1) writeonly buffer Resampled {
+                    float resampled[];
+                };
+
+                // Samples outside the map take the value of the nearest edge gain.
+                float sampleSource(int x, int y) {
+                    x = clamp(x, 0, int(source_width) - 1);
+                    y = clamp(y, 0, int(source_height) - 1);
+                    return source[uint(y) * source_width + uint(x)];
+                }
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= image_width * image_height) {
+                        return;
+                    }
+
+                    // Pixel centres of both grids line up.
+                    vec2 scale = vec2(source_width, source_height) / vec2(image_width, image_height);
+                    vec2 position = (vec2(idx % image_width, idx / image_width) + 0.5) * scale - 0.5;
+                    vec2 corner = floor(position);
+                    vec2 t = position - corner;
+                    int x0 = int(corner.x);
+                    int y0 = int(corner.y);
+
+                    float top = mix(sampleSource(x0, y0), sampleSource(x0 + 1, y0), t.x);
+                    float bottom = mix(sampleSource(x0, y0 + 1), sampleSource(x0 + 1, y0 + 1), t.x);
+                    resampled[idx] = mix(top, bottom, t.y);
+                }
+            "
+    );
+}
+
+/// Gain map resident on the device.
+enum GainMap {
+    Float(Subbuffer<[f32]>),
+    /// Unsigned fixed point with `frac_bits` fractional bits, half the size of `Float`.
+    Fixed {
+        buffer: Subbuffer<[u16]>,
+        frac_bits: u32,
+    },
+}
+
+/// Quantizes `gain_map` to the unsigned fixed point [`GainMapBufferResources::new_fixed`]
+/// takes, rounding to the nearest step of `2^-frac_bits`. Gains outside what `frac_bits`
+/// can represent saturate. More than [`MAX_GAIN_FRAC_BITS`] is
+/// `MyError::InvalidParameter`.
+pub fn quantize_gain_map(gain_map: &[f32], frac_bits: u32) -> Result<Vec<u16>, MyError> {
+    if frac_bits > MAX_GAIN_FRAC_BITS {
+        return Err(MyError::InvalidParameter);
+    }
+    let one = (1u32 << frac_bits) as f32;
+    Ok(gain_map
+        .iter()
+        .map(|&gain| (gain * one).round() as u16)
+        .collect())
+}
+
+/// Flat-field correction, multiplying every pixel by its gain in place.
 pub struct GainMapBufferResources {
     pipeline: Arc<ComputePipeline>,
-    gain_map_buffer: Subbuffer<[f32]>,
+    gain_map: GainMap,
     memory_allocator: Arc<StandardMemoryAllocator>,
     descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    local_size: u32,
+    /// Bits of the f32 every gain is multiplied by, 1 until `normalise_to_minimum`.
+    scale: AtomicU32,
 }
 
 impl GainMapBufferResources {
@@ -36,38 +260,125 @@ impl GainMapBufferResources {
         gain_map: &[f32],
         image_height: u32,
         image_width: u32,
+        local_size: u32,
+    ) -> Result<Self, MyError> {
+        let gain_map_buffer = Buffer::new_slice(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            frame_len(image_width, image_height)?, /* number of elements, matching the image size */
+        )
+        .map_err(|e| MyError::AllocationError("gain map", e.to_string()))?;
+
+        gain_map_buffer.write().unwrap().copy_from_slice(gain_map);
+
+        Ok(Self::from_buffer(
+            device,
+            queue,
+            command_buffer_allocator,
+            memory_allocator,
+            descriptor_set_allocator,
+            gain_map_buffer,
+            local_size,
+        ))
+    }
+
+    /// Uploads a gain map in unsigned fixed point with `frac_bits` fractional bits, see
+    /// [`quantize_gain_map`]. Half the memory and bandwidth of an f32 map, in exchange for
+    /// gains below `2^(16 - frac_bits)` in steps of `2^-frac_bits`: a pixel may come out
+    /// up to `value * 2^-frac_bits` counts off, [`DEFAULT_GAIN_FRAC_BITS`] being a good
+    /// balance for gains around 1. More than [`MAX_GAIN_FRAC_BITS`] is
+    /// `MyError::InvalidParameter`.
+    pub fn new_fixed(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        gain_map: &[u16],
+        frac_bits: u32,
+        local_size: u32,
+    ) -> Result<Self, MyError> {
+        if frac_bits > MAX_GAIN_FRAC_BITS {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let buffer = Buffer::from_iter(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            gain_map.iter().copied(),
+        )
+        .map_err(|e| MyError::AllocationError("fixed-point gain map", e.to_string()))?;
+
+        Ok(Self::with_map(
+            device,
+            queue,
+            command_buffer_allocator,
+            memory_allocator,
+            descriptor_set_allocator,
+            GainMap::Fixed { buffer, frac_bits },
+            local_size,
+        ))
+    }
+
+    pub fn from_buffer(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        gain_map_buffer: Subbuffer<[f32]>,
+        local_size: u32,
+    ) -> Self {
+        Self::with_map(
+            device,
+            queue,
+            command_buffer_allocator,
+            memory_allocator,
+            descriptor_set_allocator,
+            GainMap::Float(gain_map_buffer),
+            local_size,
+        )
+    }
+
+    fn with_map(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        gain_map: GainMap,
+        local_size: u32,
     ) -> Self {
         let pipeline = {
-            mod offset_correction_shader {
-                vulkano_shaders::shader! {
-                    ty: "compute",
-                    src: r"
-                            #version 450
-                            #extension GL_EXT_shader_16bit_storage : require
-                            #extension GL_EXT_shader_explicit_arithmetic_types_int16 : require
-        
-                            layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
-
-                            layout(set = 0, binding = 0) buffer GainMapData {
-                                float gainMapData[];
-                            };
-                            layout(set = 0, binding = 1) buffer ImageData {
-                                uint16_t imageData[];
-                            };
-        
-                            void main() {
-                                uint idx = gl_GlobalInvocationID.x;
-                                uint16_t new_val = uint16_t(float(imageData[idx]) * gainMapData[idx]);
-                                imageData[idx] = new_val;
-                            }
-                        ",
-                }
+            let cs = match gain_map {
+                GainMap::Float(_) => local_size_entry_point(
+                    gain_correction_shader::load(device.clone()).unwrap(),
+                    local_size,
+                    [],
+                ),
+                GainMap::Fixed { frac_bits, .. } => local_size_entry_point(
+                    fixed_gain_correction_shader::load(device.clone()).unwrap(),
+                    local_size,
+                    [(0, SpecializationConstant::U32(frac_bits))],
+                ),
             }
-
-            let cs = offset_correction_shader::load(device.clone())
-                .unwrap()
-                .entry_point("main")
-                .unwrap();
+            .unwrap();
             let stage = PipelineShaderStageCreateInfo::new(cs);
             let layout = PipelineLayout::new(
                 device.clone(),
@@ -78,29 +389,12 @@ impl GainMapBufferResources {
             .unwrap();
             ComputePipeline::new(
                 device.clone(),
-                None,
+                pipeline_cache(&device),
                 ComputePipelineCreateInfo::stage_layout(stage, layout),
             )
             .unwrap()
         };
 
-        let gain_map_buffer = Buffer::new_slice(
-            memory_allocator.clone(),
-            BufferCreateInfo {
-                usage: BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER,
-                ..Default::default()
-            },
-            AllocationCreateInfo {
-                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
-                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
-                ..Default::default()
-            },
-            (image_height * image_width) as u64, /* number of elements, matching the image size */
-        )
-        .unwrap();
-        
-        gain_map_buffer.write().unwrap().copy_from_slice(&gain_map);
-
         let builder = RecordingCommandBuffer::primary(
             command_buffer_allocator,
             queue.queue_family_index(),
@@ -120,30 +414,213 @@ impl GainMapBufferResources {
 
         GainMapBufferResources {
             pipeline,
-            gain_map_buffer,
+            gain_map,
             memory_allocator,
             descriptor_set_allocator,
+            local_size,
+            scale: AtomicU32::new(1.0f32.to_bits()),
+        }
+    }
+
+    /// Factor every gain is multiplied by, 1 unless normalised.
+    pub fn normalisation(&self) -> f32 {
+        f32::from_bits(self.scale.load(Ordering::Relaxed))
+    }
+
+    /// Finds the smallest positive gain of the map on the GPU and scales the map by its
+    /// inverse from the next recorded dispatch, so the least sensitive pixels keep their
+    /// value and every other pixel is raised to match. Returns the factor. Gains of zero or
+    /// less mark dead pixels and are left out.
+    ///
+    /// A fixed-point map, or one without a positive gain, is `MyError::InvalidParameter`.
+    pub fn normalise_to_minimum(
+        &self,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    ) -> Result<f32, MyError> {
+        let GainMap::Float(gain_map_buffer) = &self.gain_map else {
+            return Err(MyError::InvalidParameter);
+        };
+        let pixel_count = gain_map_buffer.len() as u32;
+
+        let device = queue.device().clone();
+        let pipeline = {
+            let cs = gain_minimum_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        let minimum = Buffer::from_data(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            // Infinity, so a map without a positive gain is told apart.
+            f32::INFINITY.to_bits(),
+        )
+        .map_err(|e| MyError::AllocationError("gain minimum", e.to_string()))?;
+
+        let layout = pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, gain_map_buffer.clone()),
+                WriteDescriptorSet::buffer(1, minimum.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        builder
+            .bind_pipeline_compute(pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                pipeline.layout().clone(),
+                0,
+                gain_minimum_shader::Params { pixel_count },
+            )
+            .unwrap()
+            .dispatch([pixel_count.div_ceil(64), 1, 1])
+            .unwrap();
+
+        sync::now(device)
+            .then_execute(queue, builder.end().unwrap())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        let minimum = f32::from_bits(*minimum.read().unwrap());
+        if !minimum.is_finite() {
+            return Err(MyError::InvalidParameter);
         }
+        let scale = 1.0 / minimum;
+        self.scale.store(scale.to_bits(), Ordering::Relaxed);
+        Ok(scale)
+    }
+
+    /// Corrects `image_buffer` in place. `result_buffer` is unused, it is taken so every
+    /// correction records with the same arguments.
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+        _result_buffer: Subbuffer<[u16]>,
+    ) {
+        self.apply_pipeline_in(
+            builder,
+            image_width,
+            image_height,
+            Rect::full(image_width, image_height),
+            1,
+            image_buffer,
+        );
+    }
+
+    /// Like [`GainMapBufferResources::apply_pipeline`], correcting only the pixels in
+    /// `roi`, each invocation correcting `items_per_invocation` of them.
+    pub fn apply_pipeline_in<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        roi: Rect,
+        items_per_invocation: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        self.record(
+            builder,
+            image_width,
+            image_height,
+            roi,
+            items_per_invocation,
+            1,
+            image_buffer,
+        );
     }
 
-    pub fn apply_pipeline(
+    /// Like [`GainMapBufferResources::apply_pipeline`], for `frame_count` frames lying one
+    /// after another in `image_buffer`, corrected in one dispatch.
+    pub fn apply_pipeline_frames<L>(
         &self,
-        builder: &mut RecordingCommandBuffer<PrimaryAutoCommandBuffer>,
+        builder: &mut RecordingCommandBuffer<L>,
         image_width: u32,
         image_height: u32,
+        frame_count: u32,
         image_buffer: Subbuffer<[u16]>,
-        result_buffer: Subbuffer<[u16]>,
     ) {
-        let local_size_x = 64;
+        self.record(
+            builder,
+            image_width,
+            image_height,
+            Rect::full(image_width, image_height),
+            1,
+            frame_count,
+            image_buffer,
+        );
+    }
 
-        let dispatch_size_x = (image_width * image_height + local_size_x - 1) / local_size_x;
+    #[allow(clippy::too_many_arguments)]
+    fn record<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        roi: Rect,
+        items_per_invocation: u32,
+        frame_count: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let pixel_count = roi.pixel_count();
+        let dispatch_size_x =
+            grid_stride_dispatch_size(pixel_count, self.local_size, items_per_invocation);
 
         let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
         let set = DescriptorSet::new(
             self.descriptor_set_allocator.clone(),
             layout.clone(),
             [
-                WriteDescriptorSet::buffer(0, self.gain_map_buffer.clone()),
+                match &self.gain_map {
+                    GainMap::Float(buffer) => WriteDescriptorSet::buffer(0, buffer.clone()),
+                    GainMap::Fixed { buffer, .. } => WriteDescriptorSet::buffer(0, buffer.clone()),
+                },
                 WriteDescriptorSet::buffer(1, image_buffer),
             ],
             [],
@@ -160,7 +637,343 @@ impl GainMapBufferResources {
                 set,
             )
             .unwrap()
-            .dispatch([dispatch_size_x, 1, 1])
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                gain_correction_shader::Params {
+                    pixel_count,
+                    items_per_invocation,
+                    image_width,
+                    roi_x: roi.x,
+                    roi_y: roi.y,
+                    roi_width: roi.w,
+                    frame_pixels: image_width * image_height,
+                    scale: self.normalisation(),
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, frame_count])
             .unwrap();
     }
 }
+
+/// Bilinearly resamples a gain map calibrated at `source_dimensions` to
+/// `image_dimensions` on the GPU, for maps taken at another resolution or binning. The
+/// result can be adopted with [`GainMapBufferResources::from_buffer`].
+///
+/// A zero source dimension is `MyError::InvalidParameter`, a map that doesn't hold
+/// `source_dimensions` gains `MyError::InvalidTextureData`.
+pub fn resample_gain_map(
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    gain_map: &[f32],
+    (source_width, source_height): (u32, u32),
+    (image_width, image_height): (u32, u32),
+) -> Result<Subbuffer<[f32]>, MyError> {
+    if source_width == 0 || source_height == 0 {
+        return Err(MyError::InvalidParameter);
+    }
+    if gain_map.len() as u64 != source_width as u64 * source_height as u64 {
+        return Err(MyError::InvalidTextureData);
+    }
+
+    let device = queue.device().clone();
+    let allocation_info = AllocationCreateInfo {
+        memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+            | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+        ..Default::default()
+    };
+    let source_buffer = Buffer::from_iter(
+        memory_allocator.clone(),
+        BufferCreateInfo {
+            usage: BufferUsage::STORAGE_BUFFER,
+            ..Default::default()
+        },
+        allocation_info.clone(),
+        gain_map.iter().copied(),
+    )
+    .map_err(|e| MyError::AllocationError("gain map source", e.to_string()))?;
+    let resampled_buffer = Buffer::new_slice(
+        memory_allocator,
+        BufferCreateInfo {
+            usage: BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER,
+            ..Default::default()
+        },
+        allocation_info,
+        frame_len(image_width, image_height)?,
+    )
+    .map_err(|e| MyError::AllocationError("resampled gain map", e.to_string()))?;
+
+    let pipeline = {
+        let cs = resample_shader::load(device.clone())
+            .unwrap()
+            .required_entry_point(MAIN_ENTRY_POINT)?;
+        let stage = PipelineShaderStageCreateInfo::new(cs);
+        let layout = PipelineLayout::new(
+            device.clone(),
+            PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                .into_pipeline_layout_create_info(device.clone())
+                .unwrap(),
+        )
+        .unwrap();
+        ComputePipeline::new(
+            device.clone(),
+            pipeline_cache(&device),
+            ComputePipelineCreateInfo::stage_layout(stage, layout),
+        )
+        .unwrap()
+    };
+
+    let local_size_x = 64;
+    let dispatch_size_x = (image_width * image_height + local_size_x - 1) / local_size_x;
+
+    let layout = pipeline.layout().set_layouts().get(0).unwrap();
+    let set = DescriptorSet::new(
+        descriptor_set_allocator,
+        layout.clone(),
+        [
+            WriteDescriptorSet::buffer(0, source_buffer),
+            WriteDescriptorSet::buffer(1, resampled_buffer.clone()),
+        ],
+        [],
+    )
+    .unwrap();
+
+    let mut builder = RecordingCommandBuffer::primary(
+        command_buffer_allocator,
+        queue.queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )
+    .unwrap();
+    builder
+        .bind_pipeline_compute(pipeline.clone())
+        .unwrap()
+        .bind_descriptor_sets(
+            PipelineBindPoint::Compute,
+            pipeline.layout().clone(),
+            0,
+            set,
+        )
+        .unwrap()
+        .push_constants(
+            pipeline.layout().clone(),
+            0,
+            resample_shader::Params {
+                source_width,
+                source_height,
+                image_width,
+                image_height,
+            },
+        )
+        .unwrap()
+        .dispatch([dispatch_size_x, 1, 1])
+        .unwrap();
+
+    sync::now(device)
+        .then_execute(queue, builder.end().unwrap())
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    Ok(resampled_buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        gain_correction_shader, quantize_gain_map, resample_gain_map, GainMapBufferResources,
+        MAX_GAIN_FRAC_BITS,
+    };
+    use crate::core::{
+        corrections::{EntryPointLookup, DEFAULT_LOCAL_SIZE, MAIN_ENTRY_POINT},
+        error::MyError,
+        test_utils::TestContext,
+    };
+
+    #[test]
+    fn pixels_are_scaled_by_their_gain() {
+        let context = TestContext::new();
+        // Not a multiple of the workgroup size, so the last workgroup is partly idle.
+        let (width, height) = (10u32, 7u32);
+        let size = (width * height) as usize;
+        let gain_map: Vec<f32> = (0..size).map(|i| 0.5 + (i % 4) as f32 * 0.25).collect();
+
+        let resources = GainMapBufferResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &gain_map,
+            height,
+            width,
+            DEFAULT_LOCAL_SIZE,
+        )
+        .unwrap();
+
+        let image_buffer = context.host_buffer(vec![1000u16; size]);
+        let result_buffer = context.host_buffer(vec![0u16; size]);
+        context.submit(|builder| {
+            resources.apply_pipeline(
+                builder,
+                width,
+                height,
+                image_buffer.clone(),
+                result_buffer.clone(),
+            )
+        });
+
+        let expected: Vec<u16> = gain_map.iter().map(|gain| (1000.0 * gain) as u16).collect();
+        assert_eq!(&*image_buffer.read().unwrap(), &expected[..]);
+    }
+
+    #[test]
+    fn gains_are_normalised_to_their_minimum() {
+        let context = TestContext::new();
+        let (width, height) = (10u32, 7u32);
+        let size = (width * height) as usize;
+        let mut gain_map: Vec<f32> = (0..size).map(|i| 0.5 + (i % 4) as f32 * 0.25).collect();
+        // A dead pixel doesn't count as the minimum.
+        gain_map[3] = 0.0;
+
+        let resources = GainMapBufferResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &gain_map,
+            height,
+            width,
+            DEFAULT_LOCAL_SIZE,
+        )
+        .unwrap();
+        assert_eq!(resources.normalisation(), 1.0);
+        let normalisation = resources
+            .normalise_to_minimum(
+                context.queue.clone(),
+                context.command_buffer_allocator.clone(),
+            )
+            .unwrap();
+        assert_eq!(normalisation, 2.0);
+        assert_eq!(resources.normalisation(), 2.0);
+
+        let image_buffer = context.host_buffer(vec![1000u16; size]);
+        let result_buffer = context.host_buffer(vec![0u16; size]);
+        context.submit(|builder| {
+            resources.apply_pipeline(
+                builder,
+                width,
+                height,
+                image_buffer.clone(),
+                result_buffer.clone(),
+            )
+        });
+
+        let expected: Vec<u16> = gain_map
+            .iter()
+            .map(|gain| (1000.0 * gain * 2.0) as u16)
+            .collect();
+        assert_eq!(&*image_buffer.read().unwrap(), &expected[..]);
+
+        let dead = GainMapBufferResources::new(
+            context.device.clone(),
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &vec![0.0; size],
+            height,
+            width,
+            DEFAULT_LOCAL_SIZE,
+        )
+        .unwrap();
+        assert!(matches!(
+            dead.normalise_to_minimum(
+                context.queue.clone(),
+                context.command_buffer_allocator.clone()
+            ),
+            Err(MyError::InvalidParameter)
+        ));
+    }
+
+    #[test]
+    fn quantized_gains_round_and_saturate() {
+        assert_eq!(
+            quantize_gain_map(&[1.0, 0.5, 1.00004, -1.0, 5.0], 14).unwrap(),
+            [16384, 8192, 16385, 0, u16::MAX]
+        );
+        assert!(matches!(
+            quantize_gain_map(&[1.0], MAX_GAIN_FRAC_BITS + 1),
+            Err(MyError::InvalidParameter)
+        ));
+    }
+
+    fn resample(
+        gain_map: &[f32],
+        source: (u32, u32),
+        image: (u32, u32),
+    ) -> Result<Vec<f32>, MyError> {
+        let context = TestContext::new();
+        let resampled = resample_gain_map(
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            gain_map,
+            source,
+            image,
+        )?;
+        let resampled = resampled.read().unwrap().to_vec();
+        Ok(resampled)
+    }
+
+    #[test]
+    fn gain_map_is_bilinearly_resampled() {
+        // Doubling the width puts the new pixel centres a quarter of a source pixel either
+        // side of the old ones, clamped at the edges.
+        assert_eq!(
+            resample(&[1.0, 2.0], (2, 1), (4, 1)).unwrap(),
+            [1.0, 1.25, 1.75, 2.0]
+        );
+        assert_eq!(
+            resample(&[1.0, 1.0, 3.0, 3.0], (2, 2), (2, 4)).unwrap(),
+            [1.0, 1.0, 1.5, 1.5, 2.5, 2.5, 3.0, 3.0]
+        );
+        // Downsampling by two averages the pairs.
+        assert_eq!(
+            resample(&[1.0, 2.0, 3.0, 4.0], (4, 1), (2, 1)).unwrap(),
+            [1.5, 3.5]
+        );
+    }
+
+    #[test]
+    fn resampling_needs_the_source_dimensions() {
+        assert!(matches!(
+            resample(&[], (0, 4), (4, 4)),
+            Err(MyError::InvalidParameter)
+        ));
+        assert!(matches!(
+            resample(&[1.0; 8], (3, 3), (4, 4)),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+
+    #[test]
+    fn missing_entry_point_is_reported_by_name() {
+        let context = TestContext::new();
+        let module = gain_correction_shader::load(context.device.clone()).unwrap();
+        assert!(module.required_entry_point(MAIN_ENTRY_POINT).is_ok());
+
+        let error = module.required_entry_point("correct_image").err().unwrap();
+        assert!(matches!(error, MyError::MissingEntryPoint("correct_image")));
+        assert_eq!(
+            error.to_string(),
+            "Shader module has no entry point named `correct_image`"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use std::{ops::RangeInclusive, sync::Arc};
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
+    },
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, Queue},
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    sync::{self, GpuFuture},
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+/// Most bins a histogram can have, one per u16 value. Also keeps the shader's bin
+/// arithmetic within 32 bits.
+pub const MAX_HISTOGRAM_BINS: u32 = 1 << 16;
+
+mod histogram_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                    uint bin_count;
+                    uint range_min;
+                    uint range_max;
+                };
+
+                PIXEL_BUFFER(0, image)
+                layout(set = 0, binding = 1) buffer Histogram {
+                    uint bins[];
+                };
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    // Values outside the range land in the first or last bin.
+                    uint value = clamp(load_image(idx), range_min, range_max);
+                    uint bin = (value - range_min) * bin_count / (range_max - range_min + 1u);
+                    atomicAdd(bins[bin], 1u);
+                }
+            "
+    );
+}
+
+/// Histogram of a frame's pixel values, for auto-windowing. Each invocation increments the
+/// bin of one pixel atomically.
+pub struct HistogramResources {
+    pipeline: Arc<ComputePipeline>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+impl HistogramResources {
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    ) -> Result<Self, MyError> {
+        let pipeline = {
+            let cs = histogram_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        Ok(HistogramResources {
+            pipeline,
+            memory_allocator,
+            descriptor_set_allocator,
+        })
+    }
+
+    /// Counts the first `pixel_count` values of `image_buffer` into `bin_count` bins of
+    /// equal width spanning `range`, blocking until the counts are read back. A bin count
+    /// of 0 or above [`MAX_HISTOGRAM_BINS`], or an empty range, is
+    /// `MyError::InvalidParameter`.
+    pub fn compute(
+        &self,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        pixel_count: u32,
+        image_buffer: Subbuffer<[u16]>,
+        bin_count: u32,
+        range: RangeInclusive<u16>,
+    ) -> Result<Vec<u32>, MyError> {
+        if bin_count == 0 || bin_count > MAX_HISTOGRAM_BINS || range.is_empty() {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let local_size_x = 64;
+
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        let bins = Buffer::from_iter(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            vec![0u32; bin_count as usize],
+        )
+        .map_err(|e| MyError::AllocationError("histogram bins", e.to_string()))?;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, image_buffer),
+                WriteDescriptorSet::buffer(1, bins.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                histogram_shader::Params {
+                    pixel_count,
+                    bin_count,
+                    range_min: *range.start() as u32,
+                    range_max: *range.end() as u32,
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+
+        sync::now(queue.device().clone())
+            .then_execute(queue.clone(), builder.end().unwrap())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        let counts = bins.read().unwrap().to_vec();
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HistogramResources, MAX_HISTOGRAM_BINS};
+    use crate::core::{error::MyError, test_utils::TestContext};
+
+    fn histogram(
+        image: Vec<u16>,
+        bin_count: u32,
+        range: std::ops::RangeInclusive<u16>,
+    ) -> Result<Vec<u32>, MyError> {
+        let context = TestContext::new();
+        let resources = HistogramResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+        )
+        .unwrap();
+
+        let pixel_count = image.len() as u32;
+        resources.compute(
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            pixel_count,
+            context.host_buffer(image),
+            bin_count,
+            range,
+        )
+    }
+
+    #[test]
+    fn values_are_counted_into_equal_bins() {
+        let image = vec![100, 199, 200, 250, 299, 300, 399];
+        assert_eq!(histogram(image, 3, 100..=399).unwrap(), [2, 3, 2]);
+    }
+
+    #[test]
+    fn out_of_range_values_clamp_to_the_end_bins() {
+        let image = vec![0, 99, 100, 150, 200, 65535];
+        assert_eq!(histogram(image, 2, 100..=199).unwrap(), [3, 3]);
+    }
+
+    #[test]
+    fn full_range_histogram_counts_every_pixel() {
+        let image: Vec<u16> = (0..4096).map(|i| (i * 16) as u16).collect();
+        let counts = histogram(image, 256, 0..=u16::MAX).unwrap();
+        assert!(counts.iter().all(|&count| count == 16));
+    }
+
+    #[test]
+    fn invalid_bins_or_range_are_rejected() {
+        for (bin_count, range) in [(0, 0..=10), (MAX_HISTOGRAM_BINS + 1, 0..=10)] {
+            assert!(matches!(
+                histogram(vec![0; 4], bin_count, range),
+                Err(MyError::InvalidParameter)
+            ));
+        }
+        #[allow(clippy::reversed_empty_ranges)]
+        let empty = 10..=0;
+        assert!(matches!(
+            histogram(vec![0; 4], 4, empty),
+            Err(MyError::InvalidParameter)
+        ));
+    }
+}
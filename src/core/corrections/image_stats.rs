@@ -0,0 +1,270 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
+    },
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, Queue},
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    sync::{self, GpuFuture},
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+mod image_stats_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                };
+
+                PIXEL_BUFFER(0, image)
+                layout(set = 0, binding = 1) buffer Stats {
+                    uint minValue;
+                    uint maxValue;
+                    uint sumLow;
+                    uint sumHigh;
+                };
+
+                shared uint localSums[64];
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    uint local = gl_LocalInvocationIndex;
+                    uint value = 0u;
+
+                    if (idx < pixel_count) {
+                        value = load_image(idx);
+                        atomicMin(minValue, value);
+                        atomicMax(maxValue, value);
+                    }
+
+                    // A workgroup sum of 64 u16 values fits in 32 bits, the total gets a
+                    // second word for the carry.
+                    localSums[local] = value;
+                    barrier();
+                    for (uint stride = 32u; stride > 0u; stride >>= 1) {
+                        if (local < stride) {
+                            localSums[local] += localSums[local + stride];
+                        }
+                        barrier();
+                    }
+
+                    if (local == 0u) {
+                        uint previous = atomicAdd(sumLow, localSums[0]);
+                        if (previous + localSums[0] < previous) {
+                            atomicAdd(sumHigh, 1u);
+                        }
+                    }
+                }
+            "
+    );
+}
+
+/// Range and mean of a frame's pixel values, for scaling display contrast.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageStats {
+    pub min: u16,
+    pub max: u16,
+    pub mean: f32,
+}
+
+/// Reduces a frame to [`ImageStats`] in a single dispatch, with atomic min, max and sum
+/// into one small host-readable buffer.
+pub struct ImageStatsResources {
+    pipeline: Arc<ComputePipeline>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+impl ImageStatsResources {
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    ) -> Result<Self, MyError> {
+        let pipeline = {
+            let cs = image_stats_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        Ok(ImageStatsResources {
+            pipeline,
+            memory_allocator,
+            descriptor_set_allocator,
+        })
+    }
+
+    /// Reduces the first `pixel_count` values of `image_buffer`, blocking until the result
+    /// is read back. An empty frame is `MyError::InvalidParameter`.
+    pub fn compute(
+        &self,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        pixel_count: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) -> Result<ImageStats, MyError> {
+        if pixel_count == 0 {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let local_size_x = 64;
+
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        // Min, max and the low and high words of the sum.
+        let stats = Buffer::from_iter(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            [u32::MAX, 0, 0, 0],
+        )
+        .map_err(|e| MyError::AllocationError("image stats", e.to_string()))?;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, image_buffer),
+                WriteDescriptorSet::buffer(1, stats.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                image_stats_shader::Params { pixel_count },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+
+        sync::now(queue.device().clone())
+            .then_execute(queue.clone(), builder.end().unwrap())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        let stats = stats.read().unwrap();
+        let sum = (stats[3] as u64) << 32 | stats[2] as u64;
+        Ok(ImageStats {
+            min: stats[0] as u16,
+            max: stats[1] as u16,
+            mean: (sum as f64 / pixel_count as f64) as f32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImageStats, ImageStatsResources};
+    use crate::core::{error::MyError, test_utils::TestContext};
+
+    fn stats(image: Vec<u16>, pixel_count: u32) -> Result<ImageStats, MyError> {
+        let context = TestContext::new();
+        let resources = ImageStatsResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+        )
+        .unwrap();
+
+        resources.compute(
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            pixel_count,
+            context.host_buffer(image),
+        )
+    }
+
+    #[test]
+    fn min_max_and_mean_match_the_frame() {
+        // Not a multiple of the workgroup size, so the last workgroup is partly idle.
+        let image: Vec<u16> = (0..1000).map(|i| 200 + (i * 37 % 500) as u16).collect();
+        let mean = image.iter().map(|&pixel| pixel as f64).sum::<f64>() / image.len() as f64;
+
+        let result = stats(image.clone(), image.len() as u32).unwrap();
+        assert_eq!(result.min, *image.iter().min().unwrap());
+        assert_eq!(result.max, *image.iter().max().unwrap());
+        assert!((result.mean as f64 - mean).abs() < 1e-3);
+    }
+
+    #[test]
+    fn sum_carries_past_32_bits() {
+        // 2^17 saturated pixels sum to just under 2^33.
+        let result = stats(vec![u16::MAX; 1 << 17], 1 << 17).unwrap();
+        assert_eq!(
+            result,
+            ImageStats {
+                min: u16::MAX,
+                max: u16::MAX,
+                mean: u16::MAX as f32,
+            }
+        );
+    }
+
+    #[test]
+    fn only_the_first_pixel_count_values_are_reduced() {
+        let result = stats(vec![10, 20, 30, 60000], 3).unwrap();
+        assert_eq!((result.min, result.max, result.mean), (10, 30, 20.0));
+        assert!(matches!(stats(vec![1], 0), Err(MyError::InvalidParameter)));
+    }
+}
@@ -0,0 +1,411 @@
+use std::sync::{Arc, Mutex};
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
+    },
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, Queue},
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    sync::{self, GpuFuture},
+};
+
+use super::{
+    frame_len,
+    frame_sequence::{FrameSequence, FrameTurn},
+    pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT,
+};
+use crate::core::error::MyError;
+
+/// Largest number of previous frames the afterglow model can weight.
+pub const MAX_LAG_TAPS: usize = 8;
+
+mod lag_correction_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                    uint tap_count;
+                    uint newest;
+                    uint available;
+                    float weights[8];
+                };
+
+                PIXEL_BUFFER(0, image)
+                // tap_count frames of pixel_count pixels, used as a ring.
+                PIXEL_BUFFER(1, history)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    // weights[0] applies to the previous frame, weights[1] to the one
+                    // before, and so on.
+                    float afterglow = 0.0;
+                    for (uint i = 0; i < available; ++i) {
+                        uint slot = (newest + tap_count - i) % tap_count;
+                        afterglow += weights[i] * float(load_history(slot * pixel_count + idx));
+                    }
+
+                    uint corrected = uint(
+                        clamp(round(float(load_image(idx)) - afterglow), 0.0, 65535.0)
+                    );
+                    store_image(idx, corrected);
+                    store_history(((newest + 1) % tap_count) * pixel_count + idx, corrected);
+                }
+            "
+    );
+}
+
+/// Position of the newest corrected frame in the history ring.
+struct LagHistory {
+    /// Ring slot of the most recent corrected frame.
+    newest: u32,
+    /// Corrected frames held in the ring, at most the tap count.
+    available: u32,
+}
+
+/// Removes multi-exponential detector afterglow with an N-tap model, `corrected = current -
+/// Σ wᵢ·prevᵢ`, where `prevᵢ` is the i-th previous corrected frame. The last N corrected
+/// frames stay resident on the device.
+///
+/// Every frame depends on the ones before it, so frames are corrected strictly in the order
+/// they were claimed with [`LagCorrectionResources::claim_frame`], whichever task finishes
+/// its other corrections first. A frame whose turn is given up never enters the history.
+pub struct LagCorrectionResources {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    history: Subbuffer<[u16]>,
+    weights: [f32; MAX_LAG_TAPS],
+    tap_count: u32,
+    sequence: FrameSequence,
+    ring: Mutex<LagHistory>,
+}
+
+impl LagCorrectionResources {
+    /// `weights` holds one weight per previous frame, most recent first. There must be
+    /// between one and [`MAX_LAG_TAPS`] of them, each finite and non-negative, summing to
+    /// less than one so the correction stays stable.
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        weights: &[f32],
+        image_height: u32,
+        image_width: u32,
+    ) -> Result<Self, MyError> {
+        if weights.is_empty() || weights.len() > MAX_LAG_TAPS {
+            return Err(MyError::InvalidParameter);
+        }
+        if !weights
+            .iter()
+            .all(|weight| weight.is_finite() && *weight >= 0.0)
+            || weights.iter().sum::<f32>() >= 1.0
+        {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let pipeline = {
+            let cs = lag_correction_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        let tap_count = weights.len() as u32;
+        // The history is indexed with 32-bit `uint`s too, so all its frames must fit them.
+        let history_len = frame_len(image_width, image_height)?
+            .checked_mul(tap_count as u64)
+            .filter(|&len| len <= u32::MAX as u64)
+            .ok_or_else(|| {
+                MyError::AllocationError(
+                    "lag history",
+                    format!("{tap_count} frames of {image_width}x{image_height} pixels overflow 32-bit pixel indices"),
+                )
+            })?;
+        let history = Buffer::new_slice::<u16>(
+            memory_allocator,
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
+                ..Default::default()
+            },
+            history_len,
+        )
+        .map_err(|e| MyError::AllocationError("lag history", e.to_string()))?;
+
+        let mut padded_weights = [0.0; MAX_LAG_TAPS];
+        padded_weights[..weights.len()].copy_from_slice(weights);
+
+        Ok(LagCorrectionResources {
+            pipeline,
+            descriptor_set_allocator,
+            history,
+            weights: padded_weights,
+            tap_count,
+            sequence: FrameSequence::new(),
+            ring: Mutex::new(LagHistory {
+                newest: tap_count - 1,
+                available: 0,
+            }),
+        })
+    }
+
+    /// Reserves the next position in the frame sequence. Call in submission order.
+    pub fn claim_frame(&self) -> FrameTurn {
+        self.sequence.claim()
+    }
+
+    /// Corrects `image_buffer` in place as the frame of `turn`, blocking until every earlier
+    /// frame has been corrected or given up its turn and then until the correction
+    /// completes. A frame that fails is left out of the history, and the turn passes on
+    /// either way.
+    pub fn apply(
+        &self,
+        turn: FrameTurn,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) -> Result<(), MyError> {
+        turn.take(|| {
+            let mut ring = self.ring.lock().unwrap();
+            let corrected = self.correct(
+                &ring,
+                queue,
+                command_buffer_allocator,
+                image_width,
+                image_height,
+                image_buffer,
+            );
+            if corrected.is_ok() {
+                ring.newest = (ring.newest + 1) % self.tap_count;
+                ring.available = (ring.available + 1).min(self.tap_count);
+            }
+            corrected
+        })
+    }
+
+    /// Records, submits and waits for the correction of the frame whose turn it is.
+    fn correct(
+        &self,
+        ring: &LagHistory,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) -> Result<(), MyError> {
+        let local_size_x = 64;
+
+        let pixel_count = image_width * image_height;
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, image_buffer),
+                WriteDescriptorSet::buffer(1, self.history.clone()),
+            ],
+            [],
+        )?;
+
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())?
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )?
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                lag_correction_shader::Params {
+                    pixel_count,
+                    tap_count: self.tap_count,
+                    newest: ring.newest,
+                    available: ring.available,
+                    weights: self.weights,
+                },
+            )?
+            .dispatch([dispatch_size_x, 1, 1])?;
+
+        sync::now(queue.device().clone())
+            .then_execute(queue, builder.end()?)
+            .map_err(|e| MyError::SubmissionError(e.to_string()))?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LagCorrectionResources, MAX_LAG_TAPS};
+    use crate::core::{
+        corrections::frame_sequence::FrameTurn, error::MyError, test_utils::TestContext,
+    };
+
+    #[test]
+    fn two_tap_model_removes_impulse_afterglow() {
+        let context = TestContext::new();
+        let (width, height) = (64u32, 4u32);
+        let size = (width * height) as usize;
+        let weights = [0.5, 0.25];
+
+        let resources = LagCorrectionResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &weights,
+            height,
+            width,
+        )
+        .unwrap();
+
+        // A single exposure of 1000 followed by the afterglow the model predicts.
+        let raw = [0u16, 1000, 500, 250, 0, 0];
+        let expected = [0u16, 1000, 0, 0, 0, 0];
+
+        for (&raw, &expected) in raw.iter().zip(&expected) {
+            let image_buffer = context.host_buffer(vec![raw; size]);
+            resources
+                .apply(
+                    resources.claim_frame(),
+                    context.queue.clone(),
+                    context.command_buffer_allocator.clone(),
+                    width,
+                    height,
+                    image_buffer.clone(),
+                )
+                .unwrap();
+            assert!(image_buffer
+                .read()
+                .unwrap()
+                .iter()
+                .all(|&pixel| pixel == expected));
+        }
+    }
+
+    #[test]
+    fn released_turns_pass_on_to_later_frames() {
+        let context = TestContext::new();
+        let (width, height) = (64u32, 4u32);
+        let size = (width * height) as usize;
+
+        let resources = LagCorrectionResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &[0.5],
+            height,
+            width,
+        )
+        .unwrap();
+        let apply = |turn: FrameTurn, raw: u16| {
+            let image_buffer = context.host_buffer(vec![raw; size]);
+            resources
+                .apply(
+                    turn,
+                    context.queue.clone(),
+                    context.command_buffer_allocator.clone(),
+                    width,
+                    height,
+                    image_buffer.clone(),
+                )
+                .unwrap();
+            let corrected = image_buffer.read().unwrap()[0];
+            corrected
+        };
+
+        let first = resources.claim_frame();
+        let second = resources.claim_frame();
+        let third = resources.claim_frame();
+        // Released before its turn comes, like a frame whose task was aborted.
+        drop(second);
+        assert_eq!(apply(first, 1000), 1000);
+        // Released on its turn, like a frame that failed before reaching lag correction.
+        drop(third);
+        // Would wait forever on the released turns, and only the first frame is history.
+        assert_eq!(apply(resources.claim_frame(), 600), 100);
+    }
+
+    #[test]
+    fn rejects_invalid_weights() {
+        let context = TestContext::new();
+        let too_many = [0.01; MAX_LAG_TAPS + 1];
+        for weights in [
+            &[][..],
+            &too_many[..],
+            &[0.5, 0.5][..],
+            &[-0.1][..],
+            &[f32::NAN][..],
+        ] {
+            assert!(LagCorrectionResources::new(
+                context.device.clone(),
+                context.memory_allocator.clone(),
+                context.descriptor_set_allocator.clone(),
+                weights,
+                4,
+                64,
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_histories_overflowing_32_bit_indices() {
+        let context = TestContext::new();
+        // Each frame fits in 2^31 pixels, but two of them don't fit a u32.
+        let result = LagCorrectionResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            &[0.5, 0.25],
+            32768,
+            65536,
+        );
+        assert!(matches!(result, Err(MyError::AllocationError(..))));
+    }
+}
@@ -0,0 +1,212 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use vulkano::{
+    buffer::Subbuffer,
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+mod linear_transform_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                    float a;
+                    float b;
+                };
+
+                PIXEL_BUFFER(0, image)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    float value = a * float(load_image(idx)) + b;
+                    store_image(idx, uint(clamp(round(value), 0.0, 65535.0)));
+                }
+            "
+    );
+}
+
+/// Remaps every pixel to `a * pixel + b`, clamped to the u16 range.
+pub struct LinearTransformResources {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    /// f32 bits, pushed on every dispatch so they can change between frames.
+    a: AtomicU32,
+    b: AtomicU32,
+}
+
+impl LinearTransformResources {
+    pub fn new(
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        a: f32,
+        b: f32,
+    ) -> Result<Self, MyError> {
+        if !(a.is_finite() && b.is_finite()) {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let pipeline = {
+            let cs = linear_transform_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        Ok(LinearTransformResources {
+            pipeline,
+            descriptor_set_allocator,
+            a: AtomicU32::new(a.to_bits()),
+            b: AtomicU32::new(b.to_bits()),
+        })
+    }
+
+    pub fn a(&self) -> f32 {
+        f32::from_bits(self.a.load(Ordering::Relaxed))
+    }
+
+    pub fn b(&self) -> f32 {
+        f32::from_bits(self.b.load(Ordering::Relaxed))
+    }
+
+    /// Takes effect from the next recorded dispatch.
+    pub fn set_a(&self, a: f32) -> Result<(), MyError> {
+        if !(a.is_finite()) {
+            return Err(MyError::InvalidParameter);
+        }
+        self.a.store(a.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Takes effect from the next recorded dispatch.
+    pub fn set_b(&self, b: f32) -> Result<(), MyError> {
+        if !(b.is_finite()) {
+            return Err(MyError::InvalidParameter);
+        }
+        self.b.store(b.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let pixel_count = image_width * image_height;
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [WriteDescriptorSet::buffer(0, image_buffer)],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                linear_transform_shader::Params {
+                    pixel_count,
+                    a: self.a(),
+                    b: self.b(),
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LinearTransformResources;
+    use crate::core::test_utils::TestContext;
+
+    #[test]
+    fn applies_scale_and_offset_with_clamping() {
+        let context = TestContext::new();
+        let image = vec![0u16, 10, 1000, 30000, 40000, 65535];
+
+        let resources = LinearTransformResources::new(
+            context.device.clone(),
+            context.descriptor_set_allocator.clone(),
+            2.0,
+            -15.0,
+        )
+        .unwrap();
+
+        let image_buffer = context.host_buffer(image);
+        context.submit(|builder| resources.apply_pipeline(builder, 6, 1, image_buffer.clone()));
+
+        assert_eq!(
+            *image_buffer.read().unwrap(),
+            [0, 5, 1985, 59985, 65535, 65535]
+        );
+    }
+
+    #[test]
+    fn rejects_non_finite_coefficients() {
+        let context = TestContext::new();
+        for (a, b) in [(f32::NAN, 0.0), (1.0, f32::INFINITY)] {
+            assert!(LinearTransformResources::new(
+                context.device.clone(),
+                context.descriptor_set_allocator.clone(),
+                a,
+                b,
+            )
+            .is_err());
+        }
+    }
+}
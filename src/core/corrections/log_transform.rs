@@ -0,0 +1,224 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use vulkano::{
+    buffer::Subbuffer,
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+mod log_transform_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                    float i0;
+                    float scale;
+                };
+
+                PIXEL_BUFFER(0, image)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    // Zero intensities are treated as one count so the log stays finite.
+                    float intensity = max(float(load_image(idx)), 1.0);
+                    float value = scale * -log(intensity / i0);
+                    store_image(idx, uint(clamp(round(value), 0.0, 65535.0)));
+                }
+            "
+    );
+}
+
+/// Converts transmitted intensity into absorption, `scale * -ln(I / I0)`, clamped to the
+/// u16 range. Pixels brighter than `I0` map to zero.
+pub struct LogTransformResources {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    /// f32 bits, pushed on every dispatch so they can change between frames.
+    i0: AtomicU32,
+    scale: AtomicU32,
+}
+
+impl LogTransformResources {
+    pub fn new(
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        i0: f32,
+        scale: f32,
+    ) -> Result<Self, MyError> {
+        if !(i0.is_finite() && i0 > 0.0 && scale.is_finite()) {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let pipeline = {
+            let cs = log_transform_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        Ok(LogTransformResources {
+            pipeline,
+            descriptor_set_allocator,
+            i0: AtomicU32::new(i0.to_bits()),
+            scale: AtomicU32::new(scale.to_bits()),
+        })
+    }
+
+    pub fn i0(&self) -> f32 {
+        f32::from_bits(self.i0.load(Ordering::Relaxed))
+    }
+
+    pub fn scale(&self) -> f32 {
+        f32::from_bits(self.scale.load(Ordering::Relaxed))
+    }
+
+    /// Takes effect from the next recorded dispatch.
+    pub fn set_i0(&self, i0: f32) -> Result<(), MyError> {
+        if !(i0.is_finite() && i0 > 0.0) {
+            return Err(MyError::InvalidParameter);
+        }
+        self.i0.store(i0.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Takes effect from the next recorded dispatch.
+    pub fn set_scale(&self, scale: f32) -> Result<(), MyError> {
+        if !(scale.is_finite()) {
+            return Err(MyError::InvalidParameter);
+        }
+        self.scale.store(scale.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let pixel_count = image_width * image_height;
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [WriteDescriptorSet::buffer(0, image_buffer)],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                log_transform_shader::Params {
+                    pixel_count,
+                    i0: self.i0(),
+                    scale: self.scale(),
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LogTransformResources;
+    use crate::core::test_utils::TestContext;
+
+    #[test]
+    fn matches_cpu_log_transform() {
+        let context = TestContext::new();
+        let (i0, scale) = (60000.0f32, 10000.0f32);
+        let image = vec![0u16, 1, 100, 1000, 30000, 60000, 65535];
+
+        let resources = LogTransformResources::new(
+            context.device.clone(),
+            context.descriptor_set_allocator.clone(),
+            i0,
+            scale,
+        )
+        .unwrap();
+
+        let image_buffer = context.host_buffer(image.clone());
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, image.len() as u32, 1, image_buffer.clone())
+        });
+
+        let result = image_buffer.read().unwrap();
+        for (&input, &output) in image.iter().zip(result.iter()) {
+            let expected = (scale * -((input.max(1) as f32) / i0).ln()).clamp(0.0, 65535.0);
+            assert!(
+                (output as f32 - expected).abs() <= 1.0,
+                "{input} -> {output}, expected {expected}"
+            );
+        }
+        assert_eq!(result[0], result[1]);
+        assert_eq!(*result.last().unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_non_positive_i0() {
+        let context = TestContext::new();
+        for i0 in [0.0, -1.0, f32::NAN] {
+            assert!(LogTransformResources::new(
+                context.device.clone(),
+                context.descriptor_set_allocator.clone(),
+                i0,
+                1.0,
+            )
+            .is_err());
+        }
+    }
+}
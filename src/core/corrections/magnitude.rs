@@ -0,0 +1,245 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::Subbuffer,
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    shader::SpecializationConstant,
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+/// Sample type of interleaved I/Q frames, each pixel being an I sample followed by its Q
+/// sample.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IqLayout {
+    #[default]
+    I16,
+    F32,
+}
+
+impl IqLayout {
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            IqLayout::I16 => 2,
+            IqLayout::F32 => 4,
+        }
+    }
+
+    /// Number of u32 words the I/Q pairs of `pixel_count` pixels occupy.
+    pub fn word_count(&self, pixel_count: u32) -> u64 {
+        (2 * pixel_count as u64 * self.bytes_per_sample() as u64).div_ceil(4)
+    }
+
+    /// Value of the shader's `LAYOUT` specialization constant.
+    fn shader_id(&self) -> u32 {
+        *self as u32
+    }
+}
+
+mod magnitude_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                #define LAYOUT_I16 0
+                #define LAYOUT_F32 1
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(constant_id = 0) const uint LAYOUT = LAYOUT_I16;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                };
+
+                layout(set = 0, binding = 0) readonly buffer Samples {
+                    uint samples[];
+                };
+
+                PIXEL_BUFFER(1, image)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    vec2 iq;
+                    if (LAYOUT == LAYOUT_I16) {
+                        // I in the low half of the word, Q in the high half, sign extended.
+                        uint word = samples[idx];
+                        iq = vec2(float(int(word << 16) >> 16), float(int(word) >> 16));
+                    } else {
+                        iq = vec2(uintBitsToFloat(samples[2 * idx]), uintBitsToFloat(samples[2 * idx + 1]));
+                    }
+
+                    store_image(idx, uint(clamp(round(length(iq)), 0.0, 65535.0)));
+                }
+            "
+    );
+}
+
+/// Turns a frame of interleaved I/Q samples, held as raw u32 words, into the 16-bit
+/// magnitude image `sqrt(I² + Q²)` the corrections work on, rounded and clamped to the u16
+/// range.
+pub struct MagnitudeResources {
+    layout: IqLayout,
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+impl MagnitudeResources {
+    pub fn new(
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        layout: IqLayout,
+    ) -> Result<Self, MyError> {
+        let pipeline = {
+            let cs = magnitude_shader::load(device.clone())
+                .unwrap()
+                .specialize(
+                    [(0, SpecializationConstant::U32(layout.shader_id()))]
+                        .into_iter()
+                        .collect(),
+                )
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let pipeline_layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, pipeline_layout),
+            )
+            .unwrap()
+        };
+
+        Ok(MagnitudeResources {
+            layout,
+            pipeline,
+            descriptor_set_allocator,
+        })
+    }
+
+    pub fn layout(&self) -> IqLayout {
+        self.layout
+    }
+
+    /// Writes the magnitude of each I/Q pair in `samples_buffer` to `image_buffer`, which
+    /// holds half as many values as there are samples.
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        samples_buffer: Subbuffer<[u32]>,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let pixel_count = image_width * image_height;
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, samples_buffer),
+                WriteDescriptorSet::buffer(1, image_buffer),
+            ],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                magnitude_shader::Params { pixel_count },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IqLayout, MagnitudeResources};
+    use crate::core::test_utils::TestContext;
+
+    fn magnitude(layout: IqLayout, samples: Vec<u32>, pixel_count: u32) -> Vec<u16> {
+        let context = TestContext::new();
+        let resources = MagnitudeResources::new(
+            context.device.clone(),
+            context.descriptor_set_allocator.clone(),
+            layout,
+        )
+        .unwrap();
+
+        let samples_buffer = context.host_buffer(samples);
+        let image_buffer = context.host_buffer(vec![0u16; pixel_count as usize]);
+        context.submit(|builder| {
+            resources.apply_pipeline(
+                builder,
+                pixel_count,
+                1,
+                samples_buffer.clone(),
+                image_buffer.clone(),
+            )
+        });
+        let magnitudes = image_buffer.read().unwrap().to_vec();
+        magnitudes
+    }
+
+    #[test]
+    fn i16_pairs_give_their_magnitude() {
+        let pairs: [(i16, i16); 5] = [(3, 4), (-3, -4), (0, 0), (i16::MIN, 0), (1, 1)];
+        // Little-endian, so each pair fills one word with I in its low half.
+        let words = pairs
+            .iter()
+            .map(|&(i, q)| i as u16 as u32 | (q as u16 as u32) << 16)
+            .collect();
+
+        assert_eq!(
+            magnitude(IqLayout::I16, words, pairs.len() as u32),
+            [5, 5, 0, 32768, 1]
+        );
+    }
+
+    #[test]
+    fn f32_pairs_round_and_clamp() {
+        let samples = [3.0f32, 4.0, 0.6, -0.8, 1e6, 0.0, -12.4, 0.0];
+        let words = samples.iter().map(|sample| sample.to_bits()).collect();
+
+        assert_eq!(magnitude(IqLayout::F32, words, 4), [5, 1, 65535, 13]);
+    }
+}
@@ -1,3 +1,256 @@
+use std::sync::{Arc, Mutex};
+
+use vulkano::{
+    device::{Device, DeviceOwned},
+    pipeline::cache::PipelineCache,
+    shader::{EntryPoint, ShaderModule, SpecializationConstant, SpecializedShaderModule},
+};
+
+use crate::core::error::MyError;
+
+/// Entry point of every correction shader.
+pub const MAIN_ENTRY_POINT: &str = "main";
+
+/// Most pixels one invocation of the grid-stride kernels corrects, see
+/// `Corrections::set_items_per_invocation`.
+pub const MAX_ITEMS_PER_INVOCATION: u32 = 64;
+
+/// Invocations per workgroup of the tunable correction shaders unless
+/// `Corrections::autotune` picked another size.
+pub const DEFAULT_LOCAL_SIZE: u32 = 64;
+
+/// Workgroup sizes `Corrections::autotune` times.
+pub const LOCAL_SIZE_CANDIDATES: [u32; 4] = [32, 64, 128, 256];
+
+/// Specialization constant the tunable shaders take their `local_size_x` from, declared in
+/// GLSL as `layout(local_size_x_id = 100) in`. Kept clear of the shaders' own constants.
+pub const LOCAL_SIZE_CONSTANT_ID: u32 = 100;
+
+/// Workgroups of `local_size` invocations needed for each to correct
+/// `items_per_invocation` of `pixel_count` pixels.
+pub fn grid_stride_dispatch_size(
+    pixel_count: u32,
+    local_size: u32,
+    items_per_invocation: u32,
+) -> u32 {
+    pixel_count.div_ceil(local_size * items_per_invocation)
+}
+
+/// Pixels in a frame of `width` by `height`, widened to `u64` for sizing buffers. Shaders
+/// index pixels with 32-bit `uint`s, so a frame of more than `u32::MAX` pixels is
+/// `MyError::AllocationError` rather than a silently wrapped size.
+pub fn frame_len(width: u32, height: u32) -> Result<u64, MyError> {
+    (width as u64)
+        .checked_mul(height as u64)
+        .filter(|&len| len <= u32::MAX as u64)
+        .ok_or_else(|| {
+            MyError::AllocationError(
+                "frame buffers",
+                format!("{width}x{height} pixels overflow 32-bit pixel indices"),
+            )
+        })
+}
+
+/// Returns `MyError::InvalidParameter` unless `device` can run workgroups of `local_size`
+/// invocations along x.
+pub fn validate_local_size(device: &Device, local_size: u32) -> Result<(), MyError> {
+    let properties = device.physical_device().properties();
+    if local_size == 0
+        || local_size > properties.max_compute_work_group_size[0]
+        || local_size > properties.max_compute_work_group_invocations
+    {
+        return Err(MyError::InvalidParameter);
+    }
+    Ok(())
+}
+
+/// Entry point of `module` with its workgroup size specialized to `local_size`, alongside
+/// the shader's own `constants`.
+pub fn local_size_entry_point(
+    module: Arc<ShaderModule>,
+    local_size: u32,
+    constants: impl IntoIterator<Item = (u32, SpecializationConstant)>,
+) -> Result<EntryPoint, MyError> {
+    validate_local_size(module.device(), local_size)?;
+    module
+        .specialize(
+            constants
+                .into_iter()
+                .chain([(
+                    LOCAL_SIZE_CONSTANT_ID,
+                    SpecializationConstant::U32(local_size),
+                )])
+                .collect(),
+        )
+        .unwrap()
+        .required_entry_point(MAIN_ENTRY_POINT)
+}
+
+/// Caches the correction pipelines are built through, at most one per device. See
+/// `Corrections::new_with_pipeline_cache`.
+static PIPELINE_CACHES: Mutex<Vec<Arc<PipelineCache>>> = Mutex::new(Vec::new());
+
+/// Builds the pipelines of the device of `cache` through it from now on, replacing any
+/// cache registered for the device before.
+pub fn register_pipeline_cache(cache: Arc<PipelineCache>) {
+    let mut caches = PIPELINE_CACHES.lock().unwrap();
+    caches.retain(|registered| registered.device() != cache.device());
+    caches.push(cache);
+}
+
+/// Stops building pipelines through `cache`, if it is still registered.
+pub fn unregister_pipeline_cache(cache: &Arc<PipelineCache>) {
+    PIPELINE_CACHES
+        .lock()
+        .unwrap()
+        .retain(|registered| !Arc::ptr_eq(registered, cache));
+}
+
+/// Cache registered for `device`, passed to every `ComputePipeline::new` of the corrections.
+pub fn pipeline_cache(device: &Device) -> Option<Arc<PipelineCache>> {
+    PIPELINE_CACHES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|cache| cache.device().as_ref() == device)
+        .cloned()
+}
+
+/// Region of a frame, in pixels from its top-left corner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl Rect {
+    /// The whole of a `width` by `height` frame.
+    pub fn full(width: u32, height: u32) -> Self {
+        Rect {
+            x: 0,
+            y: 0,
+            w: width,
+            h: height,
+        }
+    }
+
+    pub fn pixel_count(&self) -> u32 {
+        self.w * self.h
+    }
+
+    /// Whether the region is non-empty and lies within a `width` by `height` frame.
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        self.w > 0
+            && self.h > 0
+            && self
+                .x
+                .checked_add(self.w)
+                .is_some_and(|right| right <= width)
+            && self
+                .y
+                .checked_add(self.h)
+                .is_some_and(|bottom| bottom <= height)
+    }
+}
+
+/// Builds a correction shader twice from one source: `narrow` with 16-bit storage and
+/// `wide` with `PIXEL_WORDS` defined for devices without it (see
+/// `src/core/shaders/pixels.glsl`). The module's `load` picks the variant the device was
+/// created for; everything else is re-exported from `narrow`, the push constant layouts
+/// being identical.
+macro_rules! pixel_shader {
+    ($src:literal) => {
+        pub mod narrow {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                include: ["src/core/shaders"],
+                src: $src,
+            }
+        }
+
+        #[allow(dead_code)]
+        pub mod wide {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                include: ["src/core/shaders"],
+                define: [("PIXEL_WORDS", "")],
+                src: $src,
+            }
+        }
+
+        #[allow(unused_imports)]
+        pub use narrow::*;
+
+        pub fn load(
+            device: std::sync::Arc<vulkano::device::Device>,
+        ) -> Result<
+            std::sync::Arc<vulkano::shader::ShaderModule>,
+            vulkano::Validated<vulkano::VulkanError>,
+        > {
+            if $crate::core::corrections::uses_pixel_words(&device) {
+                wide::load(device)
+            } else {
+                narrow::load(device)
+            }
+        }
+    };
+}
+
+pub mod accumulate;
+pub mod auto_offset;
+pub mod binning;
+pub mod byte_swap;
+pub mod corner_dark;
 pub mod dark_correction;
+pub mod dark_correction_texture;
+pub mod deadtime_correction;
 pub mod defect_correction;
+pub mod defect_correction_texture;
+pub mod defect_detection;
+pub mod defect_stats;
+pub mod distortion;
+pub mod expression;
+pub mod flat_field;
+pub mod format_conversion;
+pub mod frame_quality;
+pub mod frame_sequence;
 pub mod gain_correction;
+pub mod histogram;
+pub mod image_stats;
+pub mod lag_correction;
+pub mod linear_transform;
+pub mod log_transform;
+pub mod magnitude;
+pub mod notch_filter;
+pub mod orientation;
+pub mod preview;
+pub mod temporal_ema;
+
+/// Whether shaders on `device` access pixels as packed u32 words, because it was created
+/// without 16-bit storage.
+pub fn uses_pixel_words(device: &Device) -> bool {
+    let features = device.enabled_features();
+    !(features.storage_buffer16_bit_access && features.shader_int16)
+}
+
+/// Entry point lookup that reports a missing entry point by name rather than as `None`.
+pub trait EntryPointLookup {
+    /// Returns `MyError::MissingEntryPoint` if the shader doesn't define `name`.
+    fn required_entry_point(&self, name: &'static str) -> Result<EntryPoint, MyError>;
+}
+
+impl EntryPointLookup for Arc<ShaderModule> {
+    fn required_entry_point(&self, name: &'static str) -> Result<EntryPoint, MyError> {
+        self.entry_point(name)
+            .ok_or(MyError::MissingEntryPoint(name))
+    }
+}
+
+impl EntryPointLookup for Arc<SpecializedShaderModule> {
+    fn required_entry_point(&self, name: &'static str) -> Result<EntryPoint, MyError> {
+        self.entry_point(name)
+            .ok_or(MyError::MissingEntryPoint(name))
+    }
+}
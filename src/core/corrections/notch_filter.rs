@@ -0,0 +1,349 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+mod coefficients_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint width;
+                    uint height;
+                    uint along_columns;
+                    uint notch_count;
+                };
+
+                PIXEL_BUFFER(0, image)
+                layout(set = 0, binding = 1) buffer Frequencies {
+                    uint frequencies[];
+                };
+                layout(set = 0, binding = 2) buffer Coefficients {
+                    vec2 coefficients[];
+                };
+
+                const float TAU = 6.28318530718;
+
+                // One invocation per (line, notch) evaluates that line's DFT bin.
+                void main() {
+                    uint line_count = along_columns == 1 ? width : height;
+                    uint line_length = along_columns == 1 ? height : width;
+
+                    uint id = gl_GlobalInvocationID.x;
+                    if (id >= line_count * notch_count) {
+                        return;
+                    }
+                    uint line = id / notch_count;
+                    uint frequency = frequencies[id % notch_count];
+
+                    vec2 sum = vec2(0.0);
+                    for (uint n = 0; n < line_length; ++n) {
+                        uint idx = along_columns == 1 ? n * width + line : line * width + n;
+                        float angle = TAU * float((frequency * n) % line_length) / float(line_length);
+                        sum += float(load_image(idx)) * vec2(cos(angle), -sin(angle));
+                    }
+                    coefficients[id] = sum;
+                }
+            "
+    );
+}
+
+mod notch_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint width;
+                    uint height;
+                    uint along_columns;
+                    uint notch_count;
+                };
+
+                PIXEL_BUFFER(0, image)
+                layout(set = 0, binding = 1) buffer Frequencies {
+                    uint frequencies[];
+                };
+                layout(set = 0, binding = 2) buffer Coefficients {
+                    vec2 coefficients[];
+                };
+
+                const float TAU = 6.28318530718;
+
+                // Subtracts the +/- frequency components of each notched bin, which is what
+                // zeroing those bins of the line's FFT and transforming back would leave.
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= width * height) {
+                        return;
+                    }
+
+                    uint x = idx % width;
+                    uint y = idx / width;
+                    uint line = along_columns == 1 ? x : y;
+                    uint n = along_columns == 1 ? y : x;
+                    uint line_length = along_columns == 1 ? height : width;
+
+                    float value = float(load_image(idx));
+                    for (uint i = 0; i < notch_count; ++i) {
+                        vec2 c = coefficients[line * notch_count + i];
+                        float angle = TAU * float((frequencies[i] * n) % line_length) / float(line_length);
+                        value -= 2.0 * (c.x * cos(angle) - c.y * sin(angle)) / float(line_length);
+                    }
+
+                    store_image(idx, uint(clamp(round(value), 0.0, 65535.0)));
+                }
+            "
+    );
+}
+
+/// Direction along which the periodic pattern varies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotchAxis {
+    /// Filter each row, removing vertical stripes.
+    Rows,
+    /// Filter each column, removing horizontal stripes.
+    Columns,
+}
+
+/// Removes fixed-pattern stripes by notching selected spatial frequencies out of every row
+/// or column. Only the notched bins are evaluated, a direct DFT per bin, since a handful of
+/// notches is far cheaper than a full forward and inverse FFT of every line.
+pub struct NotchFilterResources {
+    coefficients_pipeline: Arc<ComputePipeline>,
+    notch_pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    frequencies_buffer: Subbuffer<[u32]>,
+    coefficients_buffer: Subbuffer<[[f32; 2]]>,
+    axis: NotchAxis,
+    notch_count: u32,
+}
+
+impl NotchFilterResources {
+    /// `frequencies` are in cycles per line and must lie strictly between zero and the
+    /// Nyquist frequency of the filtered axis.
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        axis: NotchAxis,
+        frequencies: &[u32],
+        image_height: u32,
+        image_width: u32,
+    ) -> Result<Self, MyError> {
+        let (line_count, line_length) = match axis {
+            NotchAxis::Rows => (image_height, image_width),
+            NotchAxis::Columns => (image_width, image_height),
+        };
+        if frequencies.is_empty()
+            || frequencies
+                .iter()
+                .any(|&frequency| frequency == 0 || frequency * 2 >= line_length)
+        {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let create_pipeline = |cs| {
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+        let coefficients_pipeline = create_pipeline(
+            coefficients_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?,
+        );
+        let notch_pipeline = create_pipeline(
+            notch_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?,
+        );
+
+        let frequencies_buffer = Buffer::from_iter(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            frequencies.iter().copied(),
+        )
+        .unwrap();
+
+        let coefficients_buffer = Buffer::new_slice(
+            memory_allocator,
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
+                ..Default::default()
+            },
+            (line_count * frequencies.len() as u32) as u64,
+        )
+        .unwrap();
+
+        Ok(NotchFilterResources {
+            coefficients_pipeline,
+            notch_pipeline,
+            descriptor_set_allocator,
+            frequencies_buffer,
+            coefficients_buffer,
+            axis,
+            notch_count: frequencies.len() as u32,
+        })
+    }
+
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let line_count = match self.axis {
+            NotchAxis::Rows => image_height,
+            NotchAxis::Columns => image_width,
+        };
+        let params = coefficients_shader::Params {
+            width: image_width,
+            height: image_height,
+            along_columns: (self.axis == NotchAxis::Columns) as u32,
+            notch_count: self.notch_count,
+        };
+
+        for (pipeline, invocations) in [
+            (&self.coefficients_pipeline, line_count * self.notch_count),
+            (&self.notch_pipeline, image_width * image_height),
+        ] {
+            let layout = pipeline.layout().set_layouts().get(0).unwrap();
+            let set = DescriptorSet::new(
+                self.descriptor_set_allocator.clone(),
+                layout.clone(),
+                [
+                    WriteDescriptorSet::buffer(0, image_buffer.clone()),
+                    WriteDescriptorSet::buffer(1, self.frequencies_buffer.clone()),
+                    WriteDescriptorSet::buffer(2, self.coefficients_buffer.clone()),
+                ],
+                [],
+            )
+            .unwrap();
+
+            builder
+                .bind_pipeline_compute(pipeline.clone())
+                .unwrap()
+                .bind_descriptor_sets(
+                    PipelineBindPoint::Compute,
+                    pipeline.layout().clone(),
+                    0,
+                    set,
+                )
+                .unwrap()
+                .push_constants(pipeline.layout().clone(), 0, params)
+                .unwrap()
+                .dispatch([(invocations + local_size_x - 1) / local_size_x, 1, 1])
+                .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::{NotchAxis, NotchFilterResources};
+    use crate::core::test_utils::TestContext;
+
+    #[test]
+    fn removes_vertical_stripes() {
+        let context = TestContext::new();
+        let (width, height) = (256u32, 64u32);
+
+        let image: Vec<u16> = (0..width * height)
+            .map(|i| {
+                let x = (i % width) as f32;
+                (1000.0 + 50.0 * (TAU * 8.0 * x / width as f32).sin()).round() as u16
+            })
+            .collect();
+
+        let resources = NotchFilterResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            NotchAxis::Rows,
+            &[8],
+            height,
+            width,
+        )
+        .unwrap();
+
+        let image_buffer = context.host_buffer(image);
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, width, height, image_buffer.clone())
+        });
+
+        // The stripe amplitude of 50 is reduced to rounding noise around the mean.
+        assert!(image_buffer
+            .read()
+            .unwrap()
+            .iter()
+            .all(|&pixel| pixel.abs_diff(1000) <= 1));
+    }
+
+    #[test]
+    fn rejects_frequencies_outside_nyquist() {
+        let context = TestContext::new();
+        for frequencies in [&[][..], &[0], &[128]] {
+            assert!(NotchFilterResources::new(
+                context.device.clone(),
+                context.memory_allocator.clone(),
+                context.descriptor_set_allocator.clone(),
+                NotchAxis::Rows,
+                frequencies,
+                64,
+                256,
+            )
+            .is_err());
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::Subbuffer,
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::Device,
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    shader::SpecializationConstant,
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+/// Flip or clockwise rotation of the corrected frames, for detectors mounted rotated.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Orientation {
+    #[default]
+    None,
+    /// Mirrors each row.
+    FlipH,
+    /// Mirrors each column.
+    FlipV,
+    Rot90,
+    Rot180,
+    Rot270,
+}
+
+impl Orientation {
+    /// Width and height of a `width` by `height` frame once oriented.
+    pub fn output_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        match self {
+            Orientation::Rot90 | Orientation::Rot270 => (height, width),
+            _ => (width, height),
+        }
+    }
+
+    /// Value of the shader's `ORIENTATION` specialization constant.
+    fn shader_id(&self) -> u32 {
+        *self as u32
+    }
+}
+
+mod orientation_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                #define FLIP_H 1
+                #define FLIP_V 2
+                #define ROT_90 3
+                #define ROT_180 4
+                #define ROT_270 5
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(constant_id = 0) const uint ORIENTATION = 0;
+
+                layout(push_constant) uniform Params {
+                    uint image_width;
+                    uint image_height;
+                };
+
+                PIXEL_BUFFER(0, image)
+                PIXEL_BUFFER(1, result)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= image_width * image_height) {
+                        return;
+                    }
+
+                    uint x = idx % image_width;
+                    uint y = idx / image_width;
+                    uint last_x = image_width - 1u;
+                    uint last_y = image_height - 1u;
+
+                    // Destination of the source pixel, in a frame image_height wide for the
+                    // quarter turns.
+                    uint destination;
+                    if (ORIENTATION == FLIP_H) {
+                        destination = y * image_width + last_x - x;
+                    } else if (ORIENTATION == FLIP_V) {
+                        destination = (last_y - y) * image_width + x;
+                    } else if (ORIENTATION == ROT_90) {
+                        destination = x * image_height + last_y - y;
+                    } else if (ORIENTATION == ROT_180) {
+                        destination = (last_y - y) * image_width + last_x - x;
+                    } else if (ORIENTATION == ROT_270) {
+                        destination = (last_x - x) * image_height + y;
+                    } else {
+                        destination = idx;
+                    }
+
+                    store_result(destination, load_image(idx));
+                }
+            "
+    );
+}
+
+/// Writes `image_buffer` to a result buffer in the selected [`Orientation`], each
+/// invocation moving one source pixel to its remapped destination index.
+pub struct OrientationResources {
+    orientation: Orientation,
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+impl OrientationResources {
+    pub fn new(
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        orientation: Orientation,
+    ) -> Result<Self, MyError> {
+        let pipeline = {
+            let cs = orientation_shader::load(device.clone())
+                .unwrap()
+                .specialize(
+                    [(0, SpecializationConstant::U32(orientation.shader_id()))]
+                        .into_iter()
+                        .collect(),
+                )
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let pipeline_layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, pipeline_layout),
+            )
+            .unwrap()
+        };
+
+        Ok(OrientationResources {
+            orientation,
+            pipeline,
+            descriptor_set_allocator,
+        })
+    }
+
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    /// Writes the `image_width` by `image_height` frame in `image_buffer` to
+    /// `result_buffer`, oriented. The result is `image_height` pixels wide after a quarter
+    /// turn.
+    pub fn apply_pipeline<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+        result_buffer: Subbuffer<[u16]>,
+    ) {
+        let local_size_x = 64;
+
+        let dispatch_size_x = (image_width * image_height + local_size_x - 1) / local_size_x;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, image_buffer),
+                WriteDescriptorSet::buffer(1, result_buffer),
+            ],
+            [],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .unwrap()
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                orientation_shader::Params {
+                    image_width,
+                    image_height,
+                },
+            )
+            .unwrap()
+            .dispatch([dispatch_size_x, 1, 1])
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Orientation, OrientationResources};
+    use crate::core::test_utils::TestContext;
+
+    /// The 3x2 frame
+    ///
+    /// ```text
+    /// 1 2 3
+    /// 4 5 6
+    /// ```
+    fn orient(orientation: Orientation) -> Vec<u16> {
+        let context = TestContext::new();
+        let resources = OrientationResources::new(
+            context.device.clone(),
+            context.descriptor_set_allocator.clone(),
+            orientation,
+        )
+        .unwrap();
+
+        let image_buffer = context.host_buffer(vec![1u16, 2, 3, 4, 5, 6]);
+        let result_buffer = context.host_buffer(vec![0u16; 6]);
+        context.submit(|builder| {
+            resources.apply_pipeline(builder, 3, 2, image_buffer.clone(), result_buffer.clone())
+        });
+        let oriented = result_buffer.read().unwrap().to_vec();
+        oriented
+    }
+
+    #[test]
+    fn flips_and_rotations_remap_pixels() {
+        assert_eq!(orient(Orientation::None), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(orient(Orientation::FlipH), [3, 2, 1, 6, 5, 4]);
+        assert_eq!(orient(Orientation::FlipV), [4, 5, 6, 1, 2, 3]);
+        assert_eq!(orient(Orientation::Rot180), [6, 5, 4, 3, 2, 1]);
+        // 2 wide and 3 high after a quarter turn.
+        assert_eq!(orient(Orientation::Rot90), [4, 1, 5, 2, 6, 3]);
+        assert_eq!(orient(Orientation::Rot270), [3, 6, 2, 5, 1, 4]);
+    }
+
+    #[test]
+    fn quarter_turns_swap_dimensions() {
+        assert_eq!(Orientation::Rot90.output_dimensions(3, 2), (2, 3));
+        assert_eq!(Orientation::Rot270.output_dimensions(3, 2), (2, 3));
+        assert_eq!(Orientation::Rot180.output_dimensions(3, 2), (3, 2));
+        assert_eq!(Orientation::FlipH.output_dimensions(3, 2), (3, 2));
+    }
+}
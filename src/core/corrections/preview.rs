@@ -0,0 +1,399 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
+    },
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, Queue},
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    shader::ShaderModule,
+    sync::{self, GpuFuture},
+    Validated, VulkanError,
+};
+
+use super::{pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT};
+use crate::core::error::MyError;
+
+/// How a binned preview is scaled back up for display.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Interp {
+    /// Every binned pixel becomes a `scale` x `scale` block.
+    #[default]
+    Nearest,
+    /// Interpolates between the centres of neighbouring binned pixels, clamping at the
+    /// edges.
+    Bilinear,
+}
+
+/// A preview frame, see [`crate::core::core::Corrections::preview`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preview {
+    pub data: Vec<u16>,
+    pub width: u32,
+    pub height: u32,
+}
+
+mod bin_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint image_width;
+                    uint binned_width;
+                    uint binned_height;
+                    uint scale;
+                };
+
+                PIXEL_BUFFER(0, image)
+                PIXEL_BUFFER(1, binned)
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= binned_width * binned_height) {
+                        return;
+                    }
+
+                    uint x0 = (idx % binned_width) * scale;
+                    uint y0 = (idx / binned_width) * scale;
+                    uint sum = 0u;
+                    for (uint y = y0; y < y0 + scale; ++y) {
+                        for (uint x = x0; x < x0 + scale; ++x) {
+                            sum += load_image(y * image_width + x);
+                        }
+                    }
+                    uint count = scale * scale;
+                    store_binned(idx, (sum + count / 2) / count);
+                }
+            "
+    );
+}
+
+mod upscale_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint binned_width;
+                    uint binned_height;
+                    uint scale;
+                    uint bilinear;
+                };
+
+                PIXEL_BUFFER(0, binned)
+                PIXEL_BUFFER(1, preview)
+
+                void main() {
+                    uint width = binned_width * scale;
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= width * binned_height * scale) {
+                        return;
+                    }
+
+                    uint x = idx % width;
+                    uint y = idx / width;
+
+                    if (bilinear == 0u) {
+                        store_preview(idx, load_binned((y / scale) * binned_width + x / scale));
+                        return;
+                    }
+
+                    // Position in binned pixels, measured between pixel centres.
+                    vec2 source = (vec2(x, y) + 0.5) / float(scale) - 0.5;
+                    source = clamp(source, vec2(0.0), vec2(binned_width - 1, binned_height - 1));
+                    uvec2 p0 = uvec2(floor(source));
+                    uvec2 p1 = min(p0 + 1u, uvec2(binned_width - 1, binned_height - 1));
+                    vec2 t = source - vec2(p0);
+
+                    float top = mix(float(load_binned(p0.y * binned_width + p0.x)),
+                                    float(load_binned(p0.y * binned_width + p1.x)), t.x);
+                    float bottom = mix(float(load_binned(p1.y * binned_width + p0.x)),
+                                       float(load_binned(p1.y * binned_width + p1.x)), t.x);
+                    store_preview(idx, uint(round(mix(top, bottom, t.y))));
+                }
+            "
+    );
+}
+
+/// Bins a corrected frame down by an integer factor, averaging each block, then scales
+/// it back up for display. Any rows and columns that don't fill a whole block are dropped.
+pub struct PreviewResources {
+    bin_pipeline: Arc<ComputePipeline>,
+    upscale_pipeline: Arc<ComputePipeline>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+}
+
+fn compute_pipeline(
+    device: &Arc<Device>,
+    module: Result<Arc<ShaderModule>, Validated<VulkanError>>,
+) -> Arc<ComputePipeline> {
+    let cs = module
+        .unwrap()
+        .required_entry_point(MAIN_ENTRY_POINT)
+        .unwrap();
+    let stage = PipelineShaderStageCreateInfo::new(cs);
+    let layout = PipelineLayout::new(
+        device.clone(),
+        PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+            .into_pipeline_layout_create_info(device.clone())
+            .unwrap(),
+    )
+    .unwrap();
+    ComputePipeline::new(
+        device.clone(),
+        pipeline_cache(&device),
+        ComputePipelineCreateInfo::stage_layout(stage, layout),
+    )
+    .unwrap()
+}
+
+impl PreviewResources {
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    ) -> Self {
+        PreviewResources {
+            bin_pipeline: compute_pipeline(&device, bin_shader::load(device.clone())),
+            upscale_pipeline: compute_pipeline(&device, upscale_shader::load(device.clone())),
+            memory_allocator,
+            descriptor_set_allocator,
+        }
+    }
+
+    /// Bins `image_buffer` by `scale` and upscales the result with `interp`, blocking until
+    /// the preview is read back. The preview is `image_width / scale * scale` by
+    /// `image_height / scale * scale`. A `scale` of zero or larger than either dimension is
+    /// `MyError::InvalidParameter`.
+    pub fn compute(
+        &self,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+        scale: u32,
+        interp: Interp,
+    ) -> Result<Preview, MyError> {
+        if scale == 0 || scale > image_width || scale > image_height {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let local_size_x = 64;
+
+        let binned_width = image_width / scale;
+        let binned_height = image_height / scale;
+        let binned_count = binned_width * binned_height;
+        let preview_count = binned_count * scale * scale;
+
+        let binned = Buffer::new_slice::<u16>(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
+                ..Default::default()
+            },
+            binned_count as u64,
+        )
+        .map_err(|e| MyError::AllocationError("binned preview", e.to_string()))?;
+        let preview = Buffer::new_slice::<u16>(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            preview_count as u64,
+        )
+        .map_err(|e| MyError::AllocationError("preview", e.to_string()))?;
+
+        let bin_set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            self.bin_pipeline.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::buffer(0, image_buffer),
+                WriteDescriptorSet::buffer(1, binned.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+        let upscale_set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            self.upscale_pipeline.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::buffer(0, binned),
+                WriteDescriptorSet::buffer(1, preview.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_compute(self.bin_pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.bin_pipeline.layout().clone(),
+                0,
+                bin_set,
+            )
+            .unwrap()
+            .push_constants(
+                self.bin_pipeline.layout().clone(),
+                0,
+                bin_shader::Params {
+                    image_width,
+                    binned_width,
+                    binned_height,
+                    scale,
+                },
+            )
+            .unwrap()
+            .dispatch([(binned_count + local_size_x - 1) / local_size_x, 1, 1])
+            .unwrap()
+            .bind_pipeline_compute(self.upscale_pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.upscale_pipeline.layout().clone(),
+                0,
+                upscale_set,
+            )
+            .unwrap()
+            .push_constants(
+                self.upscale_pipeline.layout().clone(),
+                0,
+                upscale_shader::Params {
+                    binned_width,
+                    binned_height,
+                    scale,
+                    bilinear: (interp == Interp::Bilinear) as u32,
+                },
+            )
+            .unwrap()
+            .dispatch([(preview_count + local_size_x - 1) / local_size_x, 1, 1])
+            .unwrap();
+
+        sync::now(queue.device().clone())
+            .then_execute(queue, builder.end().unwrap())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        let data = preview.read().unwrap().to_vec();
+        Ok(Preview {
+            data,
+            width: binned_width * scale,
+            height: binned_height * scale,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Interp, PreviewResources};
+    use crate::core::{error::MyError, test_utils::TestContext};
+
+    const WIDTH: u32 = 9;
+    const HEIGHT: u32 = 4;
+
+    fn preview(image: Vec<u16>, scale: u32, interp: Interp) -> Result<super::Preview, MyError> {
+        let context = TestContext::new();
+        let resources = PreviewResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+        );
+        resources.compute(
+            context.queue.clone(),
+            context.command_buffer_allocator.clone(),
+            WIDTH,
+            HEIGHT,
+            context.host_buffer(image),
+            scale,
+            interp,
+        )
+    }
+
+    /// 2x2 blocks averaging to `1000 * bx + 4000 * by + 1`, plus an odd last column that
+    /// binning drops.
+    fn blocky_image() -> Vec<u16> {
+        (0..WIDTH * HEIGHT)
+            .map(|i| {
+                let (x, y) = (i % WIDTH, i / WIDTH);
+                (1000 * (x / 2) + 4000 * (y / 2) + (x % 2) * 2) as u16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nearest_repeats_binned_pixels() {
+        let result = preview(blocky_image(), 2, Interp::Nearest).unwrap();
+        assert_eq!((result.width, result.height), (8, 4));
+        assert_eq!(result.data.len(), 32);
+
+        for (i, &pixel) in result.data.iter().enumerate() {
+            let (x, y) = (i as u32 % 8, i as u32 / 8);
+            assert_eq!(pixel as u32, 1000 * (x / 2) + 4000 * (y / 2) + 1);
+        }
+    }
+
+    #[test]
+    fn bilinear_interpolates_between_centres() {
+        let result = preview(blocky_image(), 2, Interp::Bilinear).unwrap();
+        assert_eq!((result.width, result.height), (8, 4));
+
+        let at = |x: usize, y: usize| result.data[y * 8 + x];
+        // Clamped at the corner, a quarter and three quarters of the way to the next block.
+        assert_eq!(at(0, 0), 1);
+        assert_eq!(at(1, 0), 251);
+        assert_eq!(at(2, 0), 751);
+        assert_eq!(at(7, 0), 3001);
+        // Vertically between the two block rows, and both ways at once.
+        assert_eq!(at(0, 1), 1001);
+        assert_eq!(at(1, 2), 3251);
+    }
+
+    #[test]
+    fn scale_must_fit_the_frame() {
+        for scale in [0, HEIGHT + 1] {
+            assert!(matches!(
+                preview(blocky_image(), scale, Interp::Nearest),
+                Err(MyError::InvalidParameter)
+            ));
+        }
+    }
+}
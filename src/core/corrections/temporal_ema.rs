@@ -0,0 +1,349 @@
+use std::sync::{Arc, Mutex};
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
+    },
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, Queue},
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    sync::{self, GpuFuture},
+};
+
+use super::{
+    frame_len,
+    frame_sequence::{FrameSequence, FrameTurn},
+    pipeline_cache, EntryPointLookup, MAIN_ENTRY_POINT,
+};
+use crate::core::error::MyError;
+
+mod temporal_ema_shader {
+    pixel_shader!(
+        r"
+                #version 450
+                #include <pixels.glsl>
+
+                layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+                layout(push_constant) uniform Params {
+                    uint pixel_count;
+                    float alpha;
+                    uint first_frame;
+                };
+
+                PIXEL_BUFFER(0, image)
+                layout(set = 0, binding = 1) buffer Accumulator {
+                    float accumulator[];
+                };
+
+                void main() {
+                    uint idx = gl_GlobalInvocationID.x;
+                    if (idx >= pixel_count) {
+                        return;
+                    }
+
+                    float value = float(load_image(idx));
+                    accumulator[idx] = first_frame == 1
+                        ? value
+                        : alpha * value + (1.0 - alpha) * accumulator[idx];
+                }
+            "
+    );
+}
+
+/// Exponential moving average of the corrected frames, `acc = alpha * frame + (1 - alpha) *
+/// acc`, kept in an f32 accumulator. The first frame initialises the accumulator.
+///
+/// Frames are folded in strictly in the order they were claimed with
+/// [`TemporalEmaResources::claim_frame`], whichever task finishes its corrections first. A
+/// frame whose turn is given up is left out of the average.
+pub struct TemporalEmaResources {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    accumulator: Subbuffer<[f32]>,
+    alpha: f32,
+    sequence: FrameSequence,
+    /// Frames folded in so far.
+    frame_count: Mutex<u64>,
+}
+
+impl TemporalEmaResources {
+    pub fn new(
+        device: Arc<Device>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        alpha: f32,
+        image_height: u32,
+        image_width: u32,
+    ) -> Result<Self, MyError> {
+        if !(alpha > 0.0 && alpha <= 1.0) {
+            return Err(MyError::InvalidParameter);
+        }
+
+        let pipeline = {
+            let cs = temporal_ema_shader::load(device.clone())
+                .unwrap()
+                .required_entry_point(MAIN_ENTRY_POINT)?;
+            let stage = PipelineShaderStageCreateInfo::new(cs);
+            let layout = PipelineLayout::new(
+                device.clone(),
+                PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                    .into_pipeline_layout_create_info(device.clone())
+                    .unwrap(),
+            )
+            .unwrap();
+            ComputePipeline::new(
+                device.clone(),
+                pipeline_cache(&device),
+                ComputePipelineCreateInfo::stage_layout(stage, layout),
+            )
+            .unwrap()
+        };
+
+        let accumulator = Buffer::new_slice::<f32>(
+            memory_allocator,
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            frame_len(image_width, image_height)?,
+        )
+        .map_err(|e| MyError::AllocationError("temporal average", e.to_string()))?;
+
+        Ok(TemporalEmaResources {
+            pipeline,
+            descriptor_set_allocator,
+            accumulator,
+            alpha,
+            sequence: FrameSequence::new(),
+            frame_count: Mutex::new(0),
+        })
+    }
+
+    /// Reserves the next position in the frame sequence. Call in submission order.
+    pub fn claim_frame(&self) -> FrameTurn {
+        self.sequence.claim()
+    }
+
+    /// Folds the corrected frame in `image_buffer`, in sensor layout and native byte order,
+    /// into the average as the frame of `turn`. Blocks until every earlier frame has been
+    /// folded in or given up its turn and then until the update completes. A frame that
+    /// fails is left out of the average, and the turn passes on either way.
+    pub fn update(
+        &self,
+        turn: FrameTurn,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) -> Result<(), MyError> {
+        turn.take(|| {
+            let mut frame_count = self.frame_count.lock().unwrap();
+            self.fold(
+                *frame_count == 0,
+                queue,
+                command_buffer_allocator,
+                image_width,
+                image_height,
+                image_buffer,
+            )?;
+            *frame_count += 1;
+            Ok(())
+        })
+    }
+
+    /// Records, submits and waits for the update of the frame whose turn it is.
+    fn fold(
+        &self,
+        first_frame: bool,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        image_width: u32,
+        image_height: u32,
+        image_buffer: Subbuffer<[u16]>,
+    ) -> Result<(), MyError> {
+        let local_size_x = 64;
+
+        let pixel_count = image_width * image_height;
+        let dispatch_size_x = (pixel_count + local_size_x - 1) / local_size_x;
+
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = DescriptorSet::new(
+            self.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, image_buffer),
+                WriteDescriptorSet::buffer(1, self.accumulator.clone()),
+            ],
+            [],
+        )?;
+
+        let mut builder = RecordingCommandBuffer::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())?
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )?
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                temporal_ema_shader::Params {
+                    pixel_count,
+                    alpha: self.alpha,
+                    first_frame: first_frame as u32,
+                },
+            )?
+            .dispatch([dispatch_size_x, 1, 1])?;
+
+        sync::now(queue.device().clone())
+            .then_execute(queue, builder.end()?)
+            .map_err(|e| MyError::SubmissionError(e.to_string()))?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+        Ok(())
+    }
+
+    /// Current average rounded to u16 in sensor layout, `None` before the first frame.
+    pub fn read(&self) -> Option<Vec<u16>> {
+        let frame_count = self.frame_count.lock().unwrap();
+        if *frame_count == 0 {
+            return None;
+        }
+
+        let accumulator = self.accumulator.read().unwrap();
+        Some(
+            accumulator
+                .iter()
+                .map(|&value| value.round().clamp(0.0, u16::MAX as f32) as u16)
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::TemporalEmaResources;
+    use crate::core::{corrections::frame_sequence::FrameTurn, test_utils::TestContext};
+
+    #[test]
+    fn converges_on_step_change() {
+        let context = TestContext::new();
+        let (width, height) = (64u32, 4u32);
+        let size = (width * height) as usize;
+        let alpha = 0.5;
+
+        let resources = TemporalEmaResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            alpha,
+            height,
+            width,
+        )
+        .unwrap();
+        assert!(resources.read().is_none());
+
+        let update = |value: u16| {
+            resources
+                .update(
+                    resources.claim_frame(),
+                    context.queue.clone(),
+                    context.command_buffer_allocator.clone(),
+                    width,
+                    height,
+                    context.host_buffer(vec![value; size]),
+                )
+                .unwrap();
+            resources.read().unwrap()
+        };
+
+        // The first frame initialises the average instead of blending with zero.
+        assert!(update(200).iter().all(|&pixel| pixel == 200));
+
+        // After a step to 1000 the remaining gap shrinks by (1 - alpha) every frame.
+        for k in 1..=6 {
+            let expected = 1000.0 - 800.0 * (1.0f32 - alpha).powi(k);
+            assert!(update(1000)
+                .iter()
+                .all(|&pixel| pixel == expected.round() as u16));
+        }
+    }
+
+    #[test]
+    fn folds_frames_in_claim_order() {
+        let context = TestContext::new();
+        let (width, height) = (64u32, 4u32);
+        let size = (width * height) as usize;
+
+        let resources = TemporalEmaResources::new(
+            context.device.clone(),
+            context.memory_allocator.clone(),
+            context.descriptor_set_allocator.clone(),
+            0.5,
+            height,
+            width,
+        )
+        .unwrap();
+        let update = |turn: FrameTurn, value: u16| {
+            resources.update(
+                turn,
+                context.queue.clone(),
+                context.command_buffer_allocator.clone(),
+                width,
+                height,
+                context.host_buffer(vec![value; size]),
+            )
+        };
+
+        let first = resources.claim_frame();
+        let second = resources.claim_frame();
+        thread::scope(|scope| {
+            // Whichever thread gets there first, the earlier frame initialises the average,
+            // so the result is 0.5 * 1000 + 0.5 * 200 rather than 1000.
+            let late = scope.spawn(|| update(second, 1000));
+            update(first, 200).unwrap();
+            late.join().unwrap().unwrap();
+        });
+        assert!(resources.read().unwrap().iter().all(|&pixel| pixel == 600));
+    }
+
+    #[test]
+    fn rejects_alpha_outside_unit_interval() {
+        let context = TestContext::new();
+        for alpha in [0.0, -0.5, 1.5, f32::NAN] {
+            assert!(TemporalEmaResources::new(
+                context.device.clone(),
+                context.memory_allocator.clone(),
+                context.descriptor_set_allocator.clone(),
+                alpha,
+                4,
+                64,
+            )
+            .is_err());
+        }
+    }
+}
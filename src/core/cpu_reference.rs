@@ -0,0 +1,232 @@
+use super::{
+    corrections::defect_correction::{
+        kernel_weights, validate_defect_map, DefectCorrectionMode, NormalizationPolicy,
+        DEFAULT_KERNEL_RADIUS,
+    },
+    error::MyError,
+};
+
+/// Dark, gain and defect corrections in plain Rust, reproducing the arithmetic of the
+/// shaders, for machines without a usable Vulkan device. Passes run in the default stage
+/// order and on one thread, so expect it to be far slower than `Corrections`.
+pub struct CpuCorrections {
+    image_width: u32,
+    image_height: u32,
+    dark_map: Option<(Vec<u16>, u32)>,
+    gain_map: Option<Vec<f32>>,
+    defect_map: Option<DefectSettings>,
+}
+
+struct DefectSettings {
+    map: Vec<u16>,
+    normalization: NormalizationPolicy,
+    mode: DefectCorrectionMode,
+}
+
+impl CpuCorrections {
+    pub fn new(image_width: u32, image_height: u32) -> Self {
+        CpuCorrections {
+            image_width,
+            image_height,
+            dark_map: None,
+            gain_map: None,
+            defect_map: None,
+        }
+    }
+
+    fn validate_frame_len(&self, len: usize) -> Result<(), MyError> {
+        if len as u64 != self.image_width as u64 * self.image_height as u64 {
+            return Err(MyError::InvalidTextureData);
+        }
+        Ok(())
+    }
+
+    /// Like `Corrections::enable_dark_map_correction`, saturating at zero like the shader
+    /// does by default.
+    pub fn enable_dark_map_correction(
+        &mut self,
+        dark_map: &[u16],
+        offset: u32,
+    ) -> Result<(), MyError> {
+        self.validate_frame_len(dark_map.len())?;
+        self.dark_map = Some((dark_map.to_vec(), offset));
+        Ok(())
+    }
+
+    pub fn disable_dark_map_correction(&mut self) {
+        self.dark_map = None;
+    }
+
+    pub fn enable_gain_correction(&mut self, gain_map: &[f32]) -> Result<(), MyError> {
+        self.validate_frame_len(gain_map.len())?;
+        self.gain_map = Some(gain_map.to_vec());
+        Ok(())
+    }
+
+    pub fn disable_gain_correction(&mut self) {
+        self.gain_map = None;
+    }
+
+    /// Like `Corrections::enable_defect_correction_with_policy` and
+    /// `Corrections::enable_defect_correction_with_mode` combined, with the default kernel
+    /// radius. An empty map disables the pass.
+    pub fn enable_defect_correction_with(
+        &mut self,
+        defect_map: &[u16],
+        normalization: NormalizationPolicy,
+        mode: DefectCorrectionMode,
+    ) -> Result<(), MyError> {
+        validate_defect_map(defect_map, self.image_height, self.image_width)?;
+        self.defect_map = (!defect_map.is_empty()).then(|| DefectSettings {
+            map: defect_map.to_vec(),
+            normalization,
+            mode,
+        });
+        Ok(())
+    }
+
+    pub fn disable_defect_correction(&mut self) {
+        self.defect_map = None;
+    }
+
+    pub fn process_image_blocking(&mut self, input: &[u16]) -> Result<Vec<u16>, MyError> {
+        self.validate_frame_len(input.len())?;
+        let mut image = input.to_vec();
+
+        if let Some((dark_map, offset)) = &self.dark_map {
+            for (pixel, &dark) in image.iter_mut().zip(dark_map) {
+                let difference = (*pixel as i64 - dark as i64).max(0);
+                *pixel = (difference + *offset as i64).min(u16::MAX as i64) as u16;
+            }
+        }
+
+        if let Some(gain_map) = &self.gain_map {
+            for (pixel, &gain) in image.iter_mut().zip(gain_map) {
+                *pixel = (*pixel as f32 * gain) as u32 as u16;
+            }
+        }
+
+        if let Some(defects) = &self.defect_map {
+            image = self.correct_defects(&image, defects);
+        }
+
+        Ok(image)
+    }
+
+    fn correct_defects(&self, image: &[u16], defects: &DefectSettings) -> Vec<u16> {
+        let (width, height) = (self.image_width as i32, self.image_height as i32);
+        let radius = DEFAULT_KERNEL_RADIUS as i32;
+        let weights = kernel_weights(DEFAULT_KERNEL_RADIUS);
+        let full_weight: f32 = weights.iter().sum();
+
+        let mut result = image.to_vec();
+        for (idx, _) in defects.map.iter().enumerate().filter(|(_, &d)| d == 1) {
+            let (x, y) = (idx as i32 % width, idx as i32 / width);
+
+            let mut values = Vec::new();
+            let mut weighted_sum = 0.0f32;
+            let mut total_weight = 0.0f32;
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx < 0 || nx >= width || ny < 0 || ny >= height {
+                        continue;
+                    }
+                    let neighbour = (ny * width + nx) as usize;
+                    if defects.map[neighbour] != 0 {
+                        continue;
+                    }
+                    let weight = weights[((dy + radius) * (2 * radius + 1) + dx + radius) as usize];
+                    values.push(image[neighbour] as u32);
+                    weighted_sum += image[neighbour] as f32 * weight;
+                    total_weight += weight;
+                }
+            }
+
+            result[idx] = match defects.mode {
+                DefectCorrectionMode::Median if !values.is_empty() => {
+                    values.sort_unstable();
+                    let count = values.len();
+                    ((values[(count - 1) / 2] + values[count / 2]) / 2) as u16
+                }
+                DefectCorrectionMode::WeightedMean if total_weight > 0.0 => {
+                    let weight = match defects.normalization {
+                        NormalizationPolicy::ValidNeighbours => total_weight,
+                        NormalizationPolicy::FullKernel => full_weight,
+                    };
+                    (weighted_sum / weight) as u32 as u16
+                }
+                _ => image[idx],
+            };
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuCorrections;
+    use crate::core::{
+        corrections::defect_correction::{DefectCorrectionMode, NormalizationPolicy},
+        error::MyError,
+    };
+
+    #[test]
+    fn dark_and_gain_match_the_shader_arithmetic() {
+        let mut corrections = CpuCorrections::new(2, 2);
+        corrections
+            .enable_dark_map_correction(&[100, 100, 1000, 0], 300)
+            .unwrap();
+        corrections
+            .enable_gain_correction(&[1.0, 0.5, 1.0, 2.0])
+            .unwrap();
+
+        let result = corrections
+            .process_image_blocking(&[1000, 1001, 200, 40000])
+            .unwrap();
+        // The third pixel saturates at the offset, the fourth overflows the gain and keeps
+        // its low 16 bits.
+        assert_eq!(result, [1200, 600, 300, (80600u32 & 0xffff) as u16]);
+    }
+
+    #[test]
+    fn defects_are_replaced_from_valid_neighbours() {
+        let (width, height) = (5u32, 5u32);
+        let mut image = vec![100u16; 25];
+        let mut defect_map = vec![0u16; 25];
+        image[12] = 60000;
+        defect_map[12] = 1;
+        // A defective neighbour is ignored rather than averaged in.
+        image[13] = 50000;
+        defect_map[13] = 1;
+
+        for mode in [
+            DefectCorrectionMode::WeightedMean,
+            DefectCorrectionMode::Median,
+        ] {
+            let mut corrections = CpuCorrections::new(width, height);
+            corrections
+                .enable_defect_correction_with(&defect_map, NormalizationPolicy::default(), mode)
+                .unwrap();
+            let result = corrections.process_image_blocking(&image).unwrap();
+            assert_eq!(result[12], 100, "{mode:?}");
+            assert!(result
+                .iter()
+                .enumerate()
+                .all(|(i, &pixel)| defect_map[i] == 1 || pixel == 100));
+        }
+    }
+
+    #[test]
+    fn mismatched_frames_and_maps_are_rejected() {
+        let mut corrections = CpuCorrections::new(4, 4);
+        assert!(matches!(
+            corrections.enable_dark_map_correction(&[0; 15], 0),
+            Err(MyError::InvalidTextureData)
+        ));
+        assert!(matches!(
+            corrections.process_image_blocking(&[0; 8]),
+            Err(MyError::InvalidTextureData)
+        ));
+    }
+}
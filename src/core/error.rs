@@ -1,13 +1,65 @@
 use thiserror::Error;
+use vulkano::{Validated, ValidationError, VulkanError};
 
 #[derive(Error, Debug)]
 pub enum MyError {
     #[error("Failed to create shader module")]
     ShaderCreationError,
+    #[error("Shader module has no entry point named `{0}`")]
+    MissingEntryPoint(&'static str),
     #[error("Invalid texture dimensions or empty data")]
     InvalidTextureData,
     #[error("Failed to create texture")]
     TextureCreationError,
     #[error("Failed to create buffer")]
     BufferCreationError,
+    #[error("No frame has been uploaded for processing")]
+    NoInput,
+    #[error("Invalid correction parameter")]
+    InvalidParameter,
+    #[error("Unknown parameter {1} of stage {0}")]
+    UnknownParameter(&'static str, String),
+    #[error("No calibration set is registered as {0}")]
+    UnknownCalibration(String),
+    #[error("Processing is paused")]
+    Paused,
+    #[error("The GPU device was lost")]
+    DeviceLost,
+    #[error("Dark map correction is not enabled")]
+    DarkMapNotEnabled,
+    #[error("Defect correction is not enabled")]
+    DefectMapNotEnabled,
+    #[error("Input holds {0} frames, upload and process them one at a time")]
+    MultipleFrames(usize),
+    #[error("Invalid correction stage order: {0}")]
+    InvalidStageOrder(String),
+    #[error("Failed to allocate the {0}: {1}")]
+    AllocationError(&'static str, String),
+    #[error("Requested {requested} bytes of GPU memory but only {available} are available")]
+    InsufficientMemory { requested: u64, available: u64 },
+    #[error("Invalid expression: {0}")]
+    InvalidExpression(String),
+    #[error("No GPU matches the selection options")]
+    NoSuitableDevice,
+    #[error("Failed to initialise the GPU: {0}")]
+    GpuInitialisationError(String),
+    #[error("Failed to submit work to the GPU: {0}")]
+    SubmissionError(String),
+}
+
+/// A lost device is `MyError::DeviceLost`, any other failure to record, submit or wait on
+/// GPU work `MyError::SubmissionError`.
+impl From<Validated<VulkanError>> for MyError {
+    fn from(error: Validated<VulkanError>) -> Self {
+        match error {
+            Validated::Error(VulkanError::DeviceLost) => MyError::DeviceLost,
+            error => MyError::SubmissionError(error.to_string()),
+        }
+    }
+}
+
+impl From<Box<ValidationError>> for MyError {
+    fn from(error: Box<ValidationError>) -> Self {
+        MyError::SubmissionError(error.to_string())
+    }
 }
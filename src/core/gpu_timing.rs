@@ -0,0 +1,129 @@
+use std::{ops::Range, sync::Arc};
+
+use vulkano::{
+    command_buffer::RecordingCommandBuffer,
+    device::{Device, Queue},
+    query::{QueryPool, QueryPoolCreateInfo, QueryResultFlags, QueryType},
+    sync::PipelineStage,
+};
+
+/// GPU execution time of a frame's dark, gain and defect passes in microseconds, see
+/// [`crate::core::core::Corrections::last_frame_timings`]. Measured with timestamp queries
+/// around each dispatch, so host overhead and lock contention aren't included. `None` for a
+/// pass that didn't run.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CorrectionTimings {
+    pub dark_us: Option<f64>,
+    pub gain_us: Option<f64>,
+    pub defect_us: Option<f64>,
+}
+
+/// Passes with a start and end timestamp.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TimedPass {
+    Dark,
+    Gain,
+    Defect,
+}
+
+/// A start and end timestamp per [`TimedPass`].
+const QUERIES_PER_SLOT: u32 = 2 * 3;
+
+/// Timestamp queries of every frame slot, each slot having its own range so frames in
+/// flight don't overwrite each other's timestamps.
+pub(crate) struct TimestampQueries {
+    query_pool: Arc<QueryPool>,
+    /// Nanoseconds per timestamp tick.
+    timestamp_period: f32,
+}
+
+impl TimestampQueries {
+    /// Returns `None` if `queue`'s family can't write timestamps.
+    pub(crate) fn new(device: &Arc<Device>, queue: &Queue, slot_count: u32) -> Option<Self> {
+        let physical_device = device.physical_device();
+        physical_device.queue_family_properties()[queue.queue_family_index() as usize]
+            .timestamp_valid_bits?;
+
+        let query_pool = QueryPool::new(
+            device.clone(),
+            QueryPoolCreateInfo {
+                query_count: slot_count * QUERIES_PER_SLOT,
+                ..QueryPoolCreateInfo::query_type(QueryType::Timestamp)
+            },
+        )
+        .ok()?;
+
+        Some(TimestampQueries {
+            query_pool,
+            timestamp_period: physical_device.properties().timestamp_period,
+        })
+    }
+
+    fn queries(&self, slot: usize, pass: TimedPass) -> Range<u32> {
+        let start = slot as u32 * QUERIES_PER_SLOT + 2 * pass as u32;
+        start..start + 2
+    }
+
+    /// Resets the slot's queries, recorded before any of the frame's timed passes.
+    pub(crate) fn reset<L>(&self, builder: &mut RecordingCommandBuffer<L>, slot: usize) {
+        let first = slot as u32 * QUERIES_PER_SLOT;
+        // Safety: the slot's queries are only used by the frame being recorded, and aren't
+        // read until its fence has signalled.
+        unsafe {
+            builder
+                .reset_query_pool(self.query_pool.clone(), first..first + QUERIES_PER_SLOT)
+                .unwrap();
+        }
+    }
+
+    /// Records `record` between a start and end timestamp of `pass`.
+    pub(crate) fn time<L>(
+        &self,
+        builder: &mut RecordingCommandBuffer<L>,
+        slot: usize,
+        pass: TimedPass,
+        record: impl FnOnce(&mut RecordingCommandBuffer<L>),
+    ) {
+        let queries = self.queries(slot, pass);
+        // Safety: as for `reset`, and the queries were reset earlier in the frame.
+        unsafe {
+            builder
+                .write_timestamp(
+                    self.query_pool.clone(),
+                    queries.start,
+                    PipelineStage::AllCommands,
+                )
+                .unwrap();
+        }
+        record(builder);
+        unsafe {
+            builder
+                .write_timestamp(
+                    self.query_pool.clone(),
+                    queries.start + 1,
+                    PipelineStage::AllCommands,
+                )
+                .unwrap();
+        }
+    }
+
+    /// Reads the timings of the slot's `timed` passes. Call once the frame's fence has
+    /// signalled.
+    pub(crate) fn read(&self, slot: usize, timed: &[TimedPass]) -> CorrectionTimings {
+        let micros = |pass: TimedPass| {
+            timed.contains(&pass).then(|| {
+                let mut ticks = [0u64; 2];
+                self.query_pool
+                    .get_results(self.queries(slot, pass), &mut ticks, QueryResultFlags::WAIT)
+                    .unwrap();
+                ticks[1].wrapping_sub(ticks[0]) as f64 * self.timestamp_period as f64 / 1000.0
+            })
+        };
+
+        CorrectionTimings {
+            dark_us: micros(TimedPass::Dark),
+            gain_us: micros(TimedPass::Gain),
+            defect_us: micros(TimedPass::Defect),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{error, warn};
+use vulkano::{
+    buffer::Subbuffer,
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, RecordingCommandBuffer,
+    },
+    device::Queue,
+    sync::{self, GpuFuture},
+};
+
+use super::error::MyError;
+
+/// Background thread that periodically runs a probe and flags the device as lost when the
+/// probe reports `MyError::DeviceLost`. Stops when dropped.
+pub(crate) struct Heartbeat {
+    stop: Option<Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    pub fn start<F>(interval: Duration, device_lost: Arc<AtomicBool>, probe: F) -> Self
+    where
+        F: Fn() -> Result<(), MyError> + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+
+            match probe() {
+                Ok(()) => {}
+                Err(MyError::DeviceLost) => {
+                    error!("Heartbeat submission failed, device lost");
+                    device_lost.store(true, Ordering::Release);
+                    return;
+                }
+                Err(e) => warn!("Heartbeat submission failed: {e}"),
+            }
+        });
+
+        Heartbeat {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread immediately.
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Submits a trivial fill of `buffer` and waits for it to complete.
+pub(crate) fn submit_probe(
+    queue: &Arc<Queue>,
+    command_buffer_allocator: &Arc<StandardCommandBufferAllocator>,
+    buffer: &Subbuffer<[u32]>,
+) -> Result<(), MyError> {
+    let mut builder = RecordingCommandBuffer::primary(
+        command_buffer_allocator.clone(),
+        queue.queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )?;
+
+    builder.fill_buffer(buffer.clone(), 0)?;
+    let command_buffer = builder.end()?;
+
+    sync::now(queue.device().clone())
+        .then_execute(queue.clone(), command_buffer)
+        .map_err(|e| MyError::SubmissionError(e.to_string()))?
+        .then_signal_fence_and_flush()?
+        .wait(None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicU32, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::Heartbeat;
+    use crate::core::error::MyError;
+
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        false
+    }
+
+    #[test]
+    fn failing_probe_flags_device_lost() {
+        let device_lost = Arc::new(AtomicBool::new(false));
+        let _heartbeat = Heartbeat::start(Duration::from_millis(1), device_lost.clone(), || {
+            Err(MyError::DeviceLost)
+        });
+
+        assert!(wait_for(|| device_lost.load(Ordering::Acquire)));
+    }
+
+    #[test]
+    fn healthy_probe_keeps_running_until_dropped() {
+        let device_lost = Arc::new(AtomicBool::new(false));
+        let probes = Arc::new(AtomicU32::new(0));
+        let counter = probes.clone();
+        let heartbeat =
+            Heartbeat::start(Duration::from_millis(1), device_lost.clone(), move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            });
+
+        assert!(wait_for(|| probes.load(Ordering::Relaxed) >= 3));
+        drop(heartbeat);
+        let after_drop = probes.load(Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(probes.load(Ordering::Relaxed), after_drop);
+        assert!(!device_lost.load(Ordering::Acquire));
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Linear sub-buckets per power of two, bounding the relative error of a percentile to
+/// 1 / `SUB_BUCKETS`.
+const SUB_BUCKETS: u64 = 8;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+/// Enough to cover every microsecond count a `u64` can hold.
+const BUCKET_COUNT: usize = ((64 - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS) as usize;
+
+/// Per-frame latency percentiles, see [`crate::core::core::Corrections::latency_percentiles`].
+/// Percentiles are rounded up to their histogram bucket and so overestimate by at most
+/// 12.5%. All zero before any frame completes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Fixed-bucket histogram of latencies in microseconds. Recording is a couple of relaxed
+/// atomic increments, so it can be shared with the frame tasks without a lock.
+pub(crate) struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKET_COUNT],
+    max_micros: AtomicU64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            max_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Values below `SUB_BUCKETS` get a bucket each, above that every power of two is split
+/// into `SUB_BUCKETS` equal buckets.
+fn bucket_index(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros();
+    let sub_bucket = (micros >> (exponent - SUB_BUCKET_BITS)) & (SUB_BUCKETS - 1);
+    ((exponent - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS + sub_bucket) as usize
+}
+
+/// Largest value that falls into bucket `index`.
+fn bucket_upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let lower = (SUB_BUCKETS + index % SUB_BUCKETS) << shift;
+    lower + ((1u64 << shift) - 1)
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        self.buckets[bucket_index(micros)].fetch_add(1, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.max_micros.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> LatencyStats {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        let max_micros = self.max_micros.load(Ordering::Relaxed);
+        if total == 0 {
+            return LatencyStats::default();
+        }
+
+        let percentile = |fraction: f64| {
+            let rank = ((fraction * total as f64).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (index, count) in counts.iter().enumerate() {
+                seen += count;
+                if seen >= rank {
+                    return Duration::from_micros(bucket_upper_bound(index).min(max_micros));
+                }
+            }
+            Duration::from_micros(max_micros)
+        };
+
+        LatencyStats {
+            p50: percentile(0.50),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            max: Duration::from_micros(max_micros),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{bucket_index, bucket_upper_bound, LatencyHistogram, LatencyStats, BUCKET_COUNT};
+
+    #[test]
+    fn buckets_cover_their_values() {
+        for micros in (0..100_000).chain([u64::MAX / 3, u64::MAX]) {
+            let index = bucket_index(micros);
+            assert!(index < BUCKET_COUNT);
+            assert!(bucket_upper_bound(index) >= micros);
+            assert!(index == 0 || bucket_upper_bound(index - 1) < micros);
+        }
+    }
+
+    #[test]
+    fn percentiles_of_simulated_latencies() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.stats(), LatencyStats::default());
+
+        // 1 to 100 ms, so the nth percentile is n ms.
+        for millis in 1..=100 {
+            histogram.record(Duration::from_millis(millis));
+        }
+
+        let stats = histogram.stats();
+        for (reported, exact) in [(stats.p50, 50), (stats.p95, 95), (stats.p99, 99)] {
+            let exact = Duration::from_millis(exact);
+            assert!(reported >= exact && reported <= exact.mul_f64(1.125));
+        }
+        assert_eq!(stats.max, Duration::from_millis(100));
+
+        // A single outlier only moves the tail.
+        histogram.record(Duration::from_secs(5));
+        let stats = histogram.stats();
+        assert!(stats.p50 <= Duration::from_millis(57));
+        assert_eq!(stats.max, Duration::from_secs(5));
+
+        histogram.reset();
+        assert_eq!(histogram.stats(), LatencyStats::default());
+    }
+}
@@ -1,3 +1,11 @@
+pub mod backend;
 pub mod core;
 pub mod corrections;
+pub mod cpu_reference;
 pub mod error;
+pub mod gpu_timing;
+pub(crate) mod heartbeat;
+pub mod latency;
+
+#[cfg(test)]
+pub(crate) mod test_utils;
@@ -0,0 +1,40 @@
+// Included by every correction shader touching u16 pixel buffers, right after `#version`.
+//
+// PIXEL_BUFFER(BINDING, NAME) declares the storage buffer at BINDING together with
+// `uint load_NAME(uint index)` and `void store_NAME(uint index, uint value)`. Devices
+// without 16-bit storage get PIXEL_WORDS defined: the buffers are then read as u32 words
+// holding two pixels each, and a store only ever changes its own half of the word so
+// neighbouring invocations can't clobber each other.
+
+#ifdef PIXEL_WORDS
+
+#define PIXEL_BUFFER(BINDING, NAME)                                                  \
+    layout(set = 0, binding = BINDING) buffer NAME##Pixels {                         \
+        uint NAME##Words[];                                                          \
+    };                                                                               \
+    uint load_##NAME(uint index) {                                                   \
+        return (NAME##Words[index >> 1] >> ((index & 1u) * 16u)) & 0xFFFFu;          \
+    }                                                                                \
+    void store_##NAME(uint index, uint value) {                                      \
+        uint shift = (index & 1u) * 16u;                                             \
+        atomicAnd(NAME##Words[index >> 1], ~(0xFFFFu << shift));                     \
+        atomicOr(NAME##Words[index >> 1], (value & 0xFFFFu) << shift);               \
+    }
+
+#else
+
+#extension GL_EXT_shader_16bit_storage : require
+#extension GL_EXT_shader_explicit_arithmetic_types_int16 : require
+
+#define PIXEL_BUFFER(BINDING, NAME)                                                  \
+    layout(set = 0, binding = BINDING) buffer NAME##Pixels {                         \
+        uint16_t NAME##Data[];                                                       \
+    };                                                                               \
+    uint load_##NAME(uint index) {                                                   \
+        return uint(NAME##Data[index]);                                              \
+    }                                                                                \
+    void store_##NAME(uint index, uint value) {                                      \
+        NAME##Data[index] = uint16_t(value);                                         \
+    }
+
+#endif
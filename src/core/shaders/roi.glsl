@@ -0,0 +1,9 @@
+// Included by the correction shaders that can be limited to a region of interest, see
+// `Corrections::set_roi`. Invocations are dispatched over the ROI only, the `i`th one
+// correcting the `i`th pixel of the ROI in row-major order.
+
+// Index in a frame `image_width` pixels wide of the `i`th pixel of the ROI starting at
+// (roi_x, roi_y) and `roi_width` pixels wide.
+uint roi_pixel(uint i, uint roi_x, uint roi_y, uint roi_width, uint image_width) {
+    return (roi_y + i / roi_width) * image_width + roi_x + i % roi_width;
+}
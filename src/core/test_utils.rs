@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use bytemuck::Pod;
+use vulkano::{
+    buffer::{Buffer, BufferContents, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{
+        allocator::StandardCommandBufferAllocator, CommandBufferUsage, PrimaryAutoCommandBuffer,
+        RecordingCommandBuffer,
+    },
+    descriptor_set::allocator::StandardDescriptorSetAllocator,
+    device::{Device, Queue},
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    sync::{self, GpuFuture},
+};
+
+use super::core::initialise_gpu_resources;
+
+/// Device, queue and allocators for exercising a single correction resource in isolation.
+pub(crate) struct TestContext {
+    pub device: Arc<Device>,
+    pub queue: Arc<Queue>,
+    pub memory_allocator: Arc<StandardMemoryAllocator>,
+    pub descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    pub command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+}
+
+impl TestContext {
+    pub fn new() -> Self {
+        let (queue, device) = initialise_gpu_resources();
+
+        TestContext {
+            memory_allocator: Arc::new(StandardMemoryAllocator::new_default(device.clone())),
+            descriptor_set_allocator: Arc::new(StandardDescriptorSetAllocator::new(
+                device.clone(),
+                Default::default(),
+            )),
+            command_buffer_allocator: Arc::new(StandardCommandBufferAllocator::new(
+                device.clone(),
+                Default::default(),
+            )),
+            device,
+            queue,
+        }
+    }
+
+    /// Host-visible storage buffer initialised with `data`.
+    pub fn host_buffer<T>(&self, data: Vec<T>) -> Subbuffer<[T]>
+    where
+        T: BufferContents + Pod,
+    {
+        Buffer::from_iter(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER
+                    | BufferUsage::TRANSFER_SRC
+                    | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            data,
+        )
+        .unwrap()
+    }
+
+    /// Records commands with `record`, submits them and waits for completion.
+    pub fn submit(
+        &self,
+        record: impl FnOnce(&mut RecordingCommandBuffer<PrimaryAutoCommandBuffer>),
+    ) {
+        let mut builder = RecordingCommandBuffer::primary(
+            self.command_buffer_allocator.clone(),
+            self.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+
+        record(&mut builder);
+
+        sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), builder.end().unwrap())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+    }
+}
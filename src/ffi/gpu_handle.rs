@@ -1,127 +1,822 @@
-use std::{ptr::NonNull, time::Instant};
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_void, CString},
+    ptr::{self, NonNull},
+    sync::Arc,
+};
 
-use crate::core::core::{initialise_gpu_resources, Corrections};
+use log::error;
+use tokio::runtime::Runtime;
+use vulkano::buffer::Subbuffer;
 
-#[repr(C)]
+use super::power_preference::CPowerPreference;
+use crate::core::{
+    backend::{create_corrector, Backend, ImageCorrector},
+    core::{
+        initialise_gpu_resources_with, CorrectionMetrics, Corrections, DeviceInfo,
+        GpuSelectionOptions, SharedGpuResources,
+    },
+    corrections::{format_conversion::PixelFormat, frame_len, orientation::Orientation},
+    error::MyError,
+};
+
+/// Result of an FFI call, 0 on success and negative on failure. Failures leave a
+/// description for `gpu_last_error_message`.
+#[repr(i32)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum GpuStatus {
+    Ok = 0,
+    NullPointer = -1,
+    /// A frame or map doesn't match the frame size.
+    InvalidData = -2,
+    NoInput = -3,
+    Paused = -4,
+    DeviceLost = -5,
+    InvalidParameter = -6,
+    UnknownParameter = -7,
+    UnknownCalibration = -8,
+    DarkMapNotEnabled = -9,
+    DefectMapNotEnabled = -10,
+    MultipleFrames = -11,
+    InvalidStageOrder = -12,
+    InvalidExpression = -13,
+    /// A buffer, image or other device allocation failed.
+    AllocationFailed = -14,
+    InsufficientMemory = -15,
+    NoSuitableDevice = -16,
+    GpuInitialisationFailed = -17,
+    /// A shader failed to load, a bug rather than a caller error.
+    ShaderFailure = -18,
+    /// The handle's backend lacks the feature, see `create_gpu_handle_with_backend`.
+    Unsupported = -19,
+    /// Work failed to record, submit or complete on the GPU for a reason other than the
+    /// device being lost.
+    SubmissionFailed = -20,
+}
+
+thread_local! {
+    /// Description of the last failure on this thread, see `gpu_last_error_message`.
+    static LAST_ERROR: RefCell<CString> = RefCell::default();
+}
+
+fn set_last_error(message: &str) {
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+}
+
+impl GpuStatus {
+    fn null_pointer() -> Self {
+        set_last_error("a required pointer argument was null");
+        GpuStatus::NullPointer
+    }
+
+    fn unsupported() -> Self {
+        set_last_error("not supported by the CPU reference backend");
+        GpuStatus::Unsupported
+    }
+}
+
+/// Records the error's message for `gpu_last_error_message`.
+impl From<MyError> for GpuStatus {
+    fn from(error: MyError) -> Self {
+        set_last_error(&error.to_string());
+        match error {
+            MyError::InvalidTextureData => GpuStatus::InvalidData,
+            MyError::NoInput => GpuStatus::NoInput,
+            MyError::Paused => GpuStatus::Paused,
+            MyError::DeviceLost => GpuStatus::DeviceLost,
+            MyError::InvalidParameter => GpuStatus::InvalidParameter,
+            MyError::UnknownParameter(..) => GpuStatus::UnknownParameter,
+            MyError::UnknownCalibration(_) => GpuStatus::UnknownCalibration,
+            MyError::DarkMapNotEnabled => GpuStatus::DarkMapNotEnabled,
+            MyError::DefectMapNotEnabled => GpuStatus::DefectMapNotEnabled,
+            MyError::MultipleFrames(_) => GpuStatus::MultipleFrames,
+            MyError::InvalidStageOrder(_) => GpuStatus::InvalidStageOrder,
+            MyError::InvalidExpression(_) => GpuStatus::InvalidExpression,
+            MyError::TextureCreationError
+            | MyError::BufferCreationError
+            | MyError::AllocationError(..) => GpuStatus::AllocationFailed,
+            MyError::InsufficientMemory { .. } => GpuStatus::InsufficientMemory,
+            MyError::NoSuitableDevice => GpuStatus::NoSuitableDevice,
+            MyError::GpuInitialisationError(_) => GpuStatus::GpuInitialisationFailed,
+            MyError::ShaderCreationError | MyError::MissingEntryPoint(_) => {
+                GpuStatus::ShaderFailure
+            }
+            MyError::SubmissionError(_) => GpuStatus::SubmissionFailed,
+        }
+    }
+}
+
+/// Describes the last failure of an FFI call on the calling thread, as a NUL-terminated
+/// UTF-8 string, empty if none has failed yet. The string belongs to the library and stays
+/// valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn gpu_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ptr())
+}
+
+/// Opaque to C callers, who only ever hold a pointer to it.
 pub struct GPUHandle {
-    correction_context: NonNull<Corrections>,
+    corrector: Box<dyn ImageCorrector>,
+    runtime: NonNull<Runtime>,
 }
 
+/// The vulkano context behind `corrector`, `GpuStatus::Unsupported` for the CPU reference
+/// backend, which only provides the dark, gain and defect corrections.
+fn gpu_context(corrector: &mut dyn ImageCorrector) -> Result<&mut Corrections, GpuStatus> {
+    corrector.as_gpu().ok_or_else(GpuStatus::unsupported)
+}
+
+fn gpu_context_ref(corrector: &dyn ImageCorrector) -> Result<&Corrections, GpuStatus> {
+    corrector.as_gpu_ref().ok_or_else(GpuStatus::unsupported)
+}
+
+/// Pixels in a `width * height` frame, checked before a caller's buffer is sliced to that
+/// length. A count overflowing the shaders' 32-bit indices is `GpuStatus::InvalidData`.
+fn pixel_count(width: u32, height: u32) -> Result<usize, GpuStatus> {
+    frame_len(width, height)
+        .map(|len| len as usize)
+        .map_err(|error| {
+            set_last_error(&error.to_string());
+            GpuStatus::InvalidData
+        })
+}
+
+/// Device and allocators shared by the handles created from it, see
+/// `create_gpu_context`.
+pub struct GpuContext {
+    resources: SharedGpuResources,
+}
+
+/// Corrected frame resident on the device, see `gpu_process_to_gpu`.
+pub struct GpuBuffer {
+    buffer: Subbuffer<[u16]>,
+}
+
+#[repr(C)]
+pub struct GpuBufferHandle {
+    buffer: *mut GpuBuffer,
+    /// Number of pixels in the buffer.
+    len: u64,
+}
+
+/// Returns null if no GPU can be initialised or the context's buffers can't be allocated,
+/// see `gpu_last_error_message`.
 #[no_mangle]
 pub extern "C" fn create_gpu_handle(width: u32, height: u32, buffer_count: u32) -> *mut GPUHandle {
-    // Allocate GPUResources and check for errors
-    let gpu_resources = initialise_gpu_resources();
+    create_gpu_handle_with_format(width, height, buffer_count, PixelFormat::U16)
+}
+
+/// Like `create_gpu_handle`, for frames of `pixel_format` passed to `process_image_u8` or
+/// `process_image_u32`. 16-bit frames can still be passed to `process_image`.
+#[no_mangle]
+pub extern "C" fn create_gpu_handle_with_format(
+    width: u32,
+    height: u32,
+    buffer_count: u32,
+    pixel_format: PixelFormat,
+) -> *mut GPUHandle {
+    into_handle(
+        initialise_gpu_resources_with(GpuSelectionOptions::default())
+            .and_then(|(queue, device)| {
+                Corrections::new_with_pixel_format(
+                    device,
+                    queue,
+                    width,
+                    height,
+                    buffer_count,
+                    pixel_format,
+                )
+            })
+            .map(|corrections| Box::new(corrections) as Box<dyn ImageCorrector>),
+    )
+}
+
+/// Like `create_gpu_handle`, correcting frames on `backend`. `Backend::Gpu` falls back to
+/// the CPU reference backend if no GPU can be initialised, rather than returning null. CPU
+/// handles only support the dark, gain and defect maps and `process_image`; the other
+/// functions return `GpuStatus::Unsupported` for them.
+#[no_mangle]
+pub extern "C" fn create_gpu_handle_with_backend(
+    width: u32,
+    height: u32,
+    buffer_count: u32,
+    backend: Backend,
+) -> *mut GPUHandle {
+    into_handle(create_corrector(backend, width, height, buffer_count))
+}
 
-    let correction_context = Box::new(Corrections::new(
-        gpu_resources.1.clone(),
-        gpu_resources.0.clone(),
-        width,
-        height,
-        buffer_count,
-    ));
+/// Like `create_gpu_handle`, picking an integrated GPU over a discrete one if `preference`
+/// is `LowPower`.
+#[no_mangle]
+pub extern "C" fn create_gpu_handle_with_preference(
+    width: u32,
+    height: u32,
+    buffer_count: u32,
+    preference: CPowerPreference,
+) -> *mut GPUHandle {
+    let options = GpuSelectionOptions {
+        device_types: preference.device_types(),
+        ..Default::default()
+    };
+    into_handle(
+        initialise_gpu_resources_with(options)
+            .and_then(|(queue, device)| {
+                Corrections::new(device, queue, width, height, buffer_count)
+            })
+            .map(|corrections| Box::new(corrections) as Box<dyn ImageCorrector>),
+    )
+}
+
+/// Initialises a GPU for several handles to share through
+/// `create_gpu_handle_from_context`, rather than each creating its own device. Returns
+/// null if no GPU can be initialised, see `gpu_last_error_message`.
+#[no_mangle]
+pub extern "C" fn create_gpu_context() -> *mut GpuContext {
+    match initialise_gpu_resources_with(GpuSelectionOptions::default()) {
+        Ok((queue, device)) => Arc::into_raw(Arc::new(GpuContext {
+            resources: SharedGpuResources::new(device, queue),
+        }))
+        .cast_mut(),
+        Err(error) => {
+            error!("Failed to initialise GPU context: {error}");
+            set_last_error(&error.to_string());
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Like `create_gpu_handle`, on the device of `context`. The handle keeps the device
+/// alive, so `context` can be freed before it.
+#[no_mangle]
+pub extern "C" fn create_gpu_handle_from_context(
+    context: *mut GpuContext,
+    width: u32,
+    height: u32,
+    buffer_count: u32,
+) -> *mut GPUHandle {
+    if context.is_null() {
+        set_last_error("the GPU context was null");
+        return std::ptr::null_mut();
+    }
+
+    let context = unsafe { &*context };
+    into_handle(
+        Corrections::with_shared_resources(
+            context.resources.clone(),
+            width,
+            height,
+            buffer_count,
+            PixelFormat::U16,
+        )
+        .map(|corrections| Box::new(corrections) as Box<dyn ImageCorrector>),
+    )
+}
+
+/// Releases the caller's reference to `context`. Handles created from it keep working.
+#[no_mangle]
+pub extern "C" fn free_gpu_context(context: *mut GpuContext) {
+    if !context.is_null() {
+        drop(unsafe { Arc::from_raw(context.cast_const()) });
+    }
+}
+
+fn into_handle(corrector: Result<Box<dyn ImageCorrector>, MyError>) -> *mut GPUHandle {
+    let corrector = match corrector {
+        Ok(corrector) => corrector,
+        Err(error) => {
+            error!("Failed to create correction context: {error}");
+            set_last_error(&error.to_string());
+            return std::ptr::null_mut();
+        }
+    };
+
+    // C callers have no async runtime of their own for the correction tasks to run on.
+    let runtime = Box::new(Runtime::new().unwrap());
 
     let handle = Box::new(GPUHandle {
-        correction_context: NonNull::new(Box::into_raw(correction_context)).unwrap(),
+        corrector,
+        runtime: NonNull::new(Box::into_raw(runtime)).unwrap(),
     });
 
     Box::into_raw(handle)
 }
 
+/// Enables dark correction with the `width * height` map in `dark_map_data`, adding
+/// `offset` to every corrected pixel. A map that doesn't match the handle's frame size is
+/// `GpuStatus::InvalidData`.
 #[no_mangle]
 pub extern "C" fn set_dark_map(
     gpu_handle: *mut GPUHandle,
     dark_map_data: *mut u16,
     width: u32,
     height: u32,
-) {
+    offset: u32,
+) -> GpuStatus {
     if gpu_handle.is_null() || dark_map_data.is_null() {
-        return;
+        return GpuStatus::null_pointer();
     }
 
     let gpu_handle = unsafe { &mut *gpu_handle };
-    let dark_map = unsafe { std::slice::from_raw_parts(dark_map_data, (width * height) as usize) };
-    unsafe {
-        gpu_handle
-            .correction_context
-            .as_mut()
-            .enable_dark_map_correction(&dark_map, 300);
+    let len = match pixel_count(width, height) {
+        Ok(len) => len,
+        Err(status) => return status,
     };
+    let dark_map = unsafe { std::slice::from_raw_parts(dark_map_data, len) };
+    let result = gpu_handle.corrector.enable_dark(dark_map, offset);
+    match result {
+        Ok(()) => GpuStatus::Ok,
+        Err(error) => error.into(),
+    }
 }
 
+/// Enables gain correction with the `width * height` map in `gain_map_data`. A map that
+/// doesn't match the handle's frame size is `GpuStatus::InvalidData`.
 #[no_mangle]
 pub extern "C" fn set_gain_map(
     gpu_handle: *mut GPUHandle,
     gain_map_data: *mut f32,
     width: u32,
     height: u32,
-) {
+) -> GpuStatus {
     if gpu_handle.is_null() || gain_map_data.is_null() {
-        return;
+        return GpuStatus::null_pointer();
     }
 
     let gpu_handle: &mut GPUHandle = unsafe { &mut *gpu_handle };
-    let size = (width * height) as usize;
-    let gain_map = unsafe { std::slice::from_raw_parts(gain_map_data, (width * height) as usize) };
-    unsafe {
-        gpu_handle
-            .correction_context
-            .as_mut()
-            .enable_gain_correction(gain_map);
+    let len = match pixel_count(width, height) {
+        Ok(len) => len,
+        Err(status) => return status,
     };
+    let gain_map = unsafe { std::slice::from_raw_parts(gain_map_data, len) };
+    let result = gpu_handle.corrector.enable_gain(gain_map);
+    match result {
+        Ok(()) => GpuStatus::Ok,
+        Err(error) => error.into(),
+    }
 }
 
+/// Enables defect correction with the `width * height` map in `defect_map_data`. A map
+/// that doesn't match the handle's frame size is `GpuStatus::InvalidData`.
 #[no_mangle]
 pub extern "C" fn set_defect_map(
     gpu_handle: *mut GPUHandle,
     defect_map_data: *mut u16,
     width: u32,
     height: u32,
-) {
+) -> GpuStatus {
     if gpu_handle.is_null() || defect_map_data.is_null() {
-        return;
+        return GpuStatus::null_pointer();
     }
 
     let gpu_handle = unsafe { &mut *gpu_handle };
-    let defect_map =
-        unsafe { std::slice::from_raw_parts(defect_map_data, (width * height) as usize) };
-    unsafe {
-        gpu_handle
-            .correction_context
-            .as_mut()
-            .enable_defect_correction(defect_map);
+    let len = match pixel_count(width, height) {
+        Ok(len) => len,
+        Err(status) => return status,
     };
+    let defect_map = unsafe { std::slice::from_raw_parts(defect_map_data, len) };
+    let result = gpu_handle.corrector.enable_defect(defect_map);
+    match result {
+        Ok(()) => GpuStatus::Ok,
+        Err(error) => error.into(),
+    }
 }
 
+/// Turns dark correction back off. Frames already being corrected finish with the map.
+#[no_mangle]
+pub extern "C" fn clear_dark_map(gpu_handle: *mut GPUHandle) -> GpuStatus {
+    if gpu_handle.is_null() {
+        return GpuStatus::null_pointer();
+    }
+
+    let gpu_handle = unsafe { &mut *gpu_handle };
+    gpu_handle.corrector.disable_dark();
+    GpuStatus::Ok
+}
+
+/// Turns gain correction back off. Frames already being corrected finish with the map.
+#[no_mangle]
+pub extern "C" fn clear_gain_map(gpu_handle: *mut GPUHandle) -> GpuStatus {
+    if gpu_handle.is_null() {
+        return GpuStatus::null_pointer();
+    }
+
+    let gpu_handle = unsafe { &mut *gpu_handle };
+    gpu_handle.corrector.disable_gain();
+    GpuStatus::Ok
+}
+
+/// Turns defect correction back off. Frames already being corrected finish with the map.
+#[no_mangle]
+pub extern "C" fn clear_defect_map(gpu_handle: *mut GPUHandle) -> GpuStatus {
+    if gpu_handle.is_null() {
+        return GpuStatus::null_pointer();
+    }
+
+    let gpu_handle = unsafe { &mut *gpu_handle };
+    gpu_handle.corrector.disable_defect();
+    GpuStatus::Ok
+}
+
+/// Corrects the `width * height` frame in `data` in place, returning once the corrected
+/// pixels have been written back. Frames whose dimensions differ from the previous frame
+/// switch the handle to that size's buffers and maps.
 #[no_mangle]
 pub extern "C" fn process_image(
     gpu_handle: *mut GPUHandle,
     data: *mut u16,
     width: u32,
     height: u32,
-) {
-    let time = Instant::now();
-    if gpu_handle.is_null() {
+) -> GpuStatus {
+    if gpu_handle.is_null() || data.is_null() {
+        return GpuStatus::null_pointer();
+    }
+    let gpu_handle = unsafe { &mut *gpu_handle };
+    let _runtime_guard = unsafe { gpu_handle.runtime.as_ref() }.enter();
+
+    let len = match pixel_count(width, height) {
+        Ok(len) => len,
+        Err(status) => return status,
+    };
+    let image = unsafe { std::slice::from_raw_parts_mut(data, len) };
+    let result = match gpu_handle.corrector.as_gpu() {
+        Some(correction_context) => correction_context
+            .set_frame_dimensions(width, height)
+            .and_then(|()| correction_context.process_image_in_place(image)),
+        None => gpu_handle
+            .corrector
+            .process(image)
+            .map(|corrected| image.copy_from_slice(&corrected)),
+    };
+    match result {
+        Ok(()) => GpuStatus::Ok,
+        Err(error) => error.into(),
+    }
+}
+
+/// Corrects the `width * height` 8-bit frame in `data` in place, for handles created with
+/// `PixelFormat::U8`. Otherwise like `process_image`.
+#[no_mangle]
+pub extern "C" fn process_image_u8(
+    gpu_handle: *mut GPUHandle,
+    data: *mut u8,
+    width: u32,
+    height: u32,
+) -> GpuStatus {
+    if gpu_handle.is_null() || data.is_null() {
+        return GpuStatus::null_pointer();
+    }
+    let gpu_handle = unsafe { &mut *gpu_handle };
+    let correction_context = match gpu_context(&mut *gpu_handle.corrector) {
+        Ok(correction_context) => correction_context,
+        Err(status) => return status,
+    };
+
+    let len = match pixel_count(width, height) {
+        Ok(len) => len,
+        Err(status) => return status,
+    };
+    let image = unsafe { std::slice::from_raw_parts_mut(data, len) };
+    match correction_context
+        .set_frame_dimensions(width, height)
+        .and_then(|()| correction_context.process_image_u8(image))
+    {
+        Ok(()) => GpuStatus::Ok,
+        Err(error) => error.into(),
+    }
+}
+
+/// Corrects the `width * height` 32-bit frame in `data` in place, for handles created with
+/// `PixelFormat::U32`. Otherwise like `process_image`.
+#[no_mangle]
+pub extern "C" fn process_image_u32(
+    gpu_handle: *mut GPUHandle,
+    data: *mut u32,
+    width: u32,
+    height: u32,
+) -> GpuStatus {
+    if gpu_handle.is_null() || data.is_null() {
+        return GpuStatus::null_pointer();
+    }
+    let gpu_handle = unsafe { &mut *gpu_handle };
+    let correction_context = match gpu_context(&mut *gpu_handle.corrector) {
+        Ok(correction_context) => correction_context,
+        Err(status) => return status,
+    };
+
+    let len = match pixel_count(width, height) {
+        Ok(len) => len,
+        Err(status) => return status,
+    };
+    let image = unsafe { std::slice::from_raw_parts_mut(data, len) };
+    match correction_context
+        .set_frame_dimensions(width, height)
+        .and_then(|()| correction_context.process_image_u32(image))
+    {
+        Ok(()) => GpuStatus::Ok,
+        Err(error) => error.into(),
+    }
+}
+
+/// Called with the corrected pixels, their count and the `user_data` given to
+/// `process_image_async`, or with a null pointer and a count of 0 if the frame failed on
+/// the GPU.
+pub type CompletionCallback = extern "C" fn(data: *mut u16, len: usize, user_data: *mut c_void);
+
+/// `user_data` is only ever handed back to the host's callback, never dereferenced here.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// Copies the `width * height` frame in `data` and returns as soon as it is submitted.
+/// `callback` is invoked on a worker thread once the GPU has finished, with the corrected
+/// pixels and `user_data`.
+///
+/// The pixel pointer is owned by the handle and only valid until the callback returns; copy
+/// the pixels out to keep them. Callbacks of different frames may run concurrently and out
+/// of submission order. Frames still in flight complete and call back before
+/// `free_gpu_handle` returns.
+#[no_mangle]
+pub extern "C" fn process_image_async(
+    gpu_handle: *mut GPUHandle,
+    data: *const u16,
+    width: u32,
+    height: u32,
+    callback: CompletionCallback,
+    user_data: *mut c_void,
+) -> GpuStatus {
+    if gpu_handle.is_null() || data.is_null() {
+        return GpuStatus::null_pointer();
+    }
+    let gpu_handle = unsafe { &mut *gpu_handle };
+    let _runtime_guard = unsafe { gpu_handle.runtime.as_ref() }.enter();
+    let correction_context = match gpu_context(&mut *gpu_handle.corrector) {
+        Ok(correction_context) => correction_context,
+        Err(status) => return status,
+    };
+
+    let len = match pixel_count(width, height) {
+        Ok(len) => len,
+        Err(status) => return status,
+    };
+    let image = unsafe { std::slice::from_raw_parts(data, len) };
+    let user_data = UserData(user_data);
+    let result = correction_context
+        .set_frame_dimensions(width, height)
+        .and_then(|()| correction_context.upload_image(image))
+        .and_then(|()| {
+            correction_context.process_image_then(move |frame| {
+                let user_data = user_data;
+                match frame {
+                    Ok(mut frame) => {
+                        callback(frame.data.as_mut_ptr(), frame.data.len(), user_data.0)
+                    }
+                    Err(error) => {
+                        error!("asynchronous frame failed: {error}");
+                        callback(ptr::null_mut(), 0, user_data.0)
+                    }
+                }
+            })
+        });
+    match result {
+        Ok(()) => GpuStatus::Ok,
+        Err(error) => error.into(),
+    }
+}
+
+/// Corrects the `width * height` frame in `input` and leaves the result on the device,
+/// filling `result` with a handle to it instead of copying the pixels back. The handle stays
+/// valid across later calls until released with `gpu_buffer_free`.
+#[no_mangle]
+pub extern "C" fn gpu_process_to_gpu(
+    gpu_handle: *mut GPUHandle,
+    input: *const u16,
+    width: u32,
+    height: u32,
+    result: *mut GpuBufferHandle,
+) -> GpuStatus {
+    if gpu_handle.is_null() || input.is_null() || result.is_null() {
+        return GpuStatus::null_pointer();
+    }
+    let gpu_handle = unsafe { &mut *gpu_handle };
+    let _runtime_guard = unsafe { gpu_handle.runtime.as_ref() }.enter();
+    let correction_context = match gpu_context(&mut *gpu_handle.corrector) {
+        Ok(correction_context) => correction_context,
+        Err(status) => return status,
+    };
+
+    let len = match pixel_count(width, height) {
+        Ok(len) => len,
+        Err(status) => return status,
+    };
+    let image = unsafe { std::slice::from_raw_parts(input, len) };
+    match correction_context
+        .set_frame_dimensions(width, height)
+        .and_then(|()| correction_context.process_image_to_buffer(image))
+    {
+        Ok(buffer) => {
+            unsafe {
+                *result = GpuBufferHandle {
+                    len: buffer.len(),
+                    buffer: Box::into_raw(Box::new(GpuBuffer { buffer })),
+                }
+            };
+            GpuStatus::Ok
+        }
+        Err(error) => error.into(),
+    }
+}
+
+/// Copies the `len` pixels of a buffer returned by `gpu_process_to_gpu` into `data`.
+#[no_mangle]
+pub extern "C" fn gpu_buffer_read(
+    buffer_handle: *const GpuBufferHandle,
+    data: *mut u16,
+) -> GpuStatus {
+    if buffer_handle.is_null() || data.is_null() {
+        return GpuStatus::null_pointer();
+    }
+    let buffer_handle = unsafe { &*buffer_handle };
+    if buffer_handle.buffer.is_null() {
+        return GpuStatus::null_pointer();
+    }
+
+    let buffer = unsafe { &(*buffer_handle.buffer).buffer };
+    let data = unsafe { std::slice::from_raw_parts_mut(data, buffer_handle.len as usize) };
+    match buffer.read() {
+        Ok(pixels) => {
+            data.copy_from_slice(&pixels);
+            GpuStatus::Ok
+        }
+        Err(error) => {
+            set_last_error(&format!("failed to read the device buffer: {error}"));
+            GpuStatus::InvalidData
+        }
+    }
+}
+
+/// Releases the device buffer behind `buffer_handle` and clears the handle.
+#[no_mangle]
+pub extern "C" fn gpu_buffer_free(buffer_handle: *mut GpuBufferHandle) {
+    if buffer_handle.is_null() {
         return;
     }
-    let image = unsafe { std::slice::from_raw_parts_mut(data, (width * height) as usize) };
-    unsafe { (*gpu_handle).correction_context.as_mut().process_image() };
-    println!("Total time in RUST: {:?}", time.elapsed());
+    let buffer_handle = unsafe { &mut *buffer_handle };
+    if !buffer_handle.buffer.is_null() {
+        unsafe { drop(Box::from_raw(buffer_handle.buffer)) };
+    }
+    buffer_handle.buffer = std::ptr::null_mut();
+    buffer_handle.len = 0;
 }
 
+/// Makes `process_image` write big-endian pixels when `big_endian` is set, native-endian
+/// ones otherwise. Native is the default.
+#[no_mangle]
+pub extern "C" fn gpu_set_output_endianness(
+    gpu_handle: *mut GPUHandle,
+    big_endian: bool,
+) -> GpuStatus {
+    if gpu_handle.is_null() {
+        return GpuStatus::null_pointer();
+    }
+
+    let gpu_handle = unsafe { &mut *gpu_handle };
+    match gpu_context(&mut *gpu_handle.corrector) {
+        Ok(correction_context) => {
+            correction_context.set_output_big_endian(big_endian);
+            GpuStatus::Ok
+        }
+        Err(status) => status,
+    }
+}
+
+/// Flips or rotates the frames `process_image` writes back. After a quarter turn they are
+/// `height` pixels wide, see `gpu_output_dimensions`.
+#[no_mangle]
+pub extern "C" fn gpu_set_orientation(
+    gpu_handle: *mut GPUHandle,
+    orientation: Orientation,
+) -> GpuStatus {
+    if gpu_handle.is_null() {
+        return GpuStatus::null_pointer();
+    }
+
+    let gpu_handle = unsafe { &mut *gpu_handle };
+    match gpu_context(&mut *gpu_handle.corrector) {
+        Ok(correction_context) => match correction_context.set_orientation(orientation) {
+            Ok(()) => GpuStatus::Ok,
+            Err(error) => error.into(),
+        },
+        Err(status) => status,
+    }
+}
+
+/// Writes the width and height of the frames `process_image` writes back, which differ from
+/// the frame dimensions after a quarter turn.
+#[no_mangle]
+pub extern "C" fn gpu_output_dimensions(
+    gpu_handle: *mut GPUHandle,
+    width: *mut u32,
+    height: *mut u32,
+) -> GpuStatus {
+    if gpu_handle.is_null() || width.is_null() || height.is_null() {
+        return GpuStatus::null_pointer();
+    }
+
+    let gpu_handle = unsafe { &*gpu_handle };
+    let (output_width, output_height) = match gpu_context_ref(&*gpu_handle.corrector) {
+        Ok(correction_context) => correction_context.output_dimensions(),
+        Err(status) => return status,
+    };
+    unsafe {
+        *width = output_width;
+        *height = output_height;
+    }
+    GpuStatus::Ok
+}
+
+/// Writes the handle's throughput counters into `metrics`.
+#[no_mangle]
+pub extern "C" fn get_metrics(
+    gpu_handle: *mut GPUHandle,
+    metrics: *mut CorrectionMetrics,
+) -> GpuStatus {
+    if gpu_handle.is_null() || metrics.is_null() {
+        return GpuStatus::null_pointer();
+    }
+
+    let gpu_handle = unsafe { &*gpu_handle };
+    match gpu_context_ref(&*gpu_handle.corrector) {
+        Ok(correction_context) => {
+            unsafe { *metrics = correction_context.metrics() };
+            GpuStatus::Ok
+        }
+        Err(status) => status,
+    }
+}
+
+/// Writes which GPU the handle runs on into `info`.
+#[no_mangle]
+pub extern "C" fn gpu_get_device_info(
+    gpu_handle: *mut GPUHandle,
+    info: *mut DeviceInfo,
+) -> GpuStatus {
+    if gpu_handle.is_null() || info.is_null() {
+        return GpuStatus::null_pointer();
+    }
+
+    let gpu_handle = unsafe { &*gpu_handle };
+    match gpu_context_ref(&*gpu_handle.corrector) {
+        Ok(correction_context) => {
+            unsafe { *info = correction_context.device_info() };
+            GpuStatus::Ok
+        }
+        Err(status) => status,
+    }
+}
+
+/// Frees the handle, blocking until the frames still in flight have completed, their
+/// callbacks included, and the GPU is idle.
 #[no_mangle]
 pub extern "C" fn free_gpu_handle(handle: *mut GPUHandle) {
     if !handle.is_null() {
         // Convert the raw pointer back to a Box to ensure proper deallocation
-        let _handle = unsafe { Box::from_raw(handle) };
-        // GPUResources will be dropped here
+        let handle = unsafe { Box::from_raw(handle) };
+        let runtime = handle.runtime;
+        // The corrector waits for its frames in flight, which run on the runtime.
+        drop(handle);
+        unsafe { drop(Box::from_raw(runtime.as_ptr())) };
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Instant;
+    use std::{
+        ffi::{c_void, CStr},
+        mem::MaybeUninit,
+        sync::mpsc::{self, Sender},
+        time::{Duration, Instant},
+    };
 
-    use super::{create_gpu_handle, process_image, set_dark_map, GPUHandle};
+    use super::{
+        clear_dark_map, clear_defect_map, clear_gain_map, create_gpu_context, create_gpu_handle,
+        create_gpu_handle_from_context, create_gpu_handle_with_backend,
+        create_gpu_handle_with_format, create_gpu_handle_with_preference, free_gpu_context,
+        free_gpu_handle, gpu_buffer_free, gpu_buffer_read, gpu_get_device_info,
+        gpu_last_error_message, gpu_output_dimensions, gpu_process_to_gpu, gpu_set_orientation,
+        gpu_set_output_endianness, process_image, process_image_async, process_image_u32,
+        process_image_u8, set_dark_map, set_gain_map, GPUHandle, GpuBufferHandle, GpuStatus,
+    };
+    use crate::{
+        core::{
+            backend::Backend,
+            core::DeviceInfo,
+            corrections::{format_conversion::PixelFormat, orientation::Orientation},
+            error::MyError,
+        },
+        ffi::power_preference::CPowerPreference,
+    };
+    use vulkano::{Validated, VulkanError};
 
     #[test]
     fn test() {
@@ -137,6 +832,452 @@ mod tests {
             image_width as u32,
             image_height as u32,
         );
-        //set_dark_map(handle, data.as_mut_ptr(), image_width, image_height);
+        //set_dark_map(handle, data.as_mut_ptr(), image_width, image_height, offset);
+    }
+
+    #[test]
+    fn process_image_corrects_in_place() {
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let handle = create_gpu_handle(image_width, image_height, 2);
+        let mut dark_map = vec![100u16; size];
+        let status = set_dark_map(
+            handle,
+            dark_map.as_mut_ptr(),
+            image_width,
+            image_height,
+            300,
+        );
+        assert_eq!(status, GpuStatus::Ok);
+
+        // A map for another resolution is rejected, leaving the one above in place.
+        let mut gain_map = vec![2.0f32; size / 4];
+        let status = set_gain_map(
+            handle,
+            gain_map.as_mut_ptr(),
+            image_width / 2,
+            image_height / 2,
+        );
+        assert_eq!(status, GpuStatus::InvalidData);
+        let status = set_dark_map(
+            handle,
+            dark_map.as_mut_ptr(),
+            image_width,
+            image_height / 2,
+            300,
+        );
+        assert_eq!(status, GpuStatus::InvalidData);
+
+        let mut data = vec![1000u16; size];
+        let status = process_image(handle, data.as_mut_ptr(), image_width, image_height);
+        assert_eq!(status, GpuStatus::Ok);
+        assert!(data.iter().all(|&pixel| pixel == 1000 - 100 + 300));
+
+        let status = process_image(handle, data.as_mut_ptr(), 0, image_height);
+        assert_eq!(status, GpuStatus::InvalidData);
+
+        free_gpu_handle(handle);
+    }
+
+    #[test]
+    fn dark_offset_is_the_callers() {
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let handle = create_gpu_handle(image_width, image_height, 1);
+        let mut dark_map = vec![100u16; size];
+        for offset in [0, 1000] {
+            let status = set_dark_map(
+                handle,
+                dark_map.as_mut_ptr(),
+                image_width,
+                image_height,
+                offset,
+            );
+            assert_eq!(status, GpuStatus::Ok);
+
+            let mut data = vec![1000u16; size];
+            let status = process_image(handle, data.as_mut_ptr(), image_width, image_height);
+            assert_eq!(status, GpuStatus::Ok);
+            assert!(data
+                .iter()
+                .all(|&pixel| pixel == 1000 - 100 + offset as u16));
+        }
+
+        free_gpu_handle(handle);
+    }
+
+    #[test]
+    fn cleared_maps_stop_correcting() {
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let handle = create_gpu_handle(image_width, image_height, 1);
+        let mut dark_map = vec![100u16; size];
+        let status = set_dark_map(handle, dark_map.as_mut_ptr(), image_width, image_height, 0);
+        assert_eq!(status, GpuStatus::Ok);
+        let mut gain_map = vec![2.0f32; size];
+        let status = set_gain_map(handle, gain_map.as_mut_ptr(), image_width, image_height);
+        assert_eq!(status, GpuStatus::Ok);
+
+        assert_eq!(clear_dark_map(handle), GpuStatus::Ok);
+        let mut data = vec![1000u16; size];
+        let status = process_image(handle, data.as_mut_ptr(), image_width, image_height);
+        assert_eq!(status, GpuStatus::Ok);
+        assert!(data.iter().all(|&pixel| pixel == 2000));
+
+        assert_eq!(clear_gain_map(handle), GpuStatus::Ok);
+        assert_eq!(clear_defect_map(handle), GpuStatus::Ok);
+        let mut data = vec![1000u16; size];
+        let status = process_image(handle, data.as_mut_ptr(), image_width, image_height);
+        assert_eq!(status, GpuStatus::Ok);
+        assert!(data.iter().all(|&pixel| pixel == 1000));
+
+        assert_eq!(clear_dark_map(std::ptr::null_mut()), GpuStatus::NullPointer);
+
+        free_gpu_handle(handle);
+    }
+
+    #[test]
+    fn handles_share_a_context() {
+        let context = create_gpu_context();
+        assert!(!context.is_null());
+        let first = create_gpu_handle_from_context(context, 64, 64, 1);
+        let second = create_gpu_handle_from_context(context, 32, 32, 2);
+        assert!(!first.is_null() && !second.is_null());
+        // The handles keep the device alive.
+        free_gpu_context(context);
+
+        for (handle, side, offset) in [(first, 64u32, 300), (second, 32, 0)] {
+            let size = (side * side) as usize;
+            let mut dark_map = vec![100u16; size];
+            set_dark_map(handle, dark_map.as_mut_ptr(), side, side, offset);
+
+            let mut data = vec![1000u16; size];
+            let status = process_image(handle, data.as_mut_ptr(), side, side);
+            assert_eq!(status, GpuStatus::Ok);
+            assert!(data
+                .iter()
+                .all(|&pixel| pixel == 1000 - 100 + offset as u16));
+        }
+
+        free_gpu_handle(first);
+        free_gpu_handle(second);
+        assert!(create_gpu_handle_from_context(std::ptr::null_mut(), 64, 64, 1).is_null());
+    }
+
+    #[test]
+    fn device_info_names_the_gpu() {
+        let handle = create_gpu_handle(64, 64, 1);
+        let mut info = MaybeUninit::<DeviceInfo>::uninit();
+        assert_eq!(
+            gpu_get_device_info(handle, info.as_mut_ptr()),
+            GpuStatus::Ok
+        );
+        let info = unsafe { info.assume_init() };
+
+        let name = unsafe { CStr::from_ptr(info.name.as_ptr()) };
+        assert!(!name.to_str().unwrap().is_empty());
+        assert!(info.vram_bytes > 0);
+        assert_eq!(
+            gpu_get_device_info(handle, std::ptr::null_mut()),
+            GpuStatus::NullPointer
+        );
+
+        free_gpu_handle(handle);
+    }
+
+    #[test]
+    fn either_power_preference_finds_a_gpu() {
+        for preference in [
+            CPowerPreference::HighPerformance,
+            CPowerPreference::LowPower,
+        ] {
+            let handle = create_gpu_handle_with_preference(64, 64, 1, preference);
+            assert!(!handle.is_null());
+
+            let mut data = vec![1000u16; 64 * 64];
+            let status = process_image(handle, data.as_mut_ptr(), 64, 64);
+            assert_eq!(status, GpuStatus::Ok);
+
+            free_gpu_handle(handle);
+        }
+    }
+
+    #[test]
+    fn cpu_reference_handles_correct_frames() {
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let handle =
+            create_gpu_handle_with_backend(image_width, image_height, 1, Backend::CpuReference);
+        let mut dark_map = vec![100u16; size];
+        let status = set_dark_map(
+            handle,
+            dark_map.as_mut_ptr(),
+            image_width,
+            image_height,
+            300,
+        );
+        assert_eq!(status, GpuStatus::Ok);
+
+        let mut data = vec![1000u16; size];
+        let status = process_image(handle, data.as_mut_ptr(), image_width, image_height);
+        assert_eq!(status, GpuStatus::Ok);
+        assert!(data.iter().all(|&pixel| pixel == 1000 - 100 + 300));
+
+        // Features beyond the dark, gain and defect maps need a GPU.
+        assert_eq!(
+            gpu_set_orientation(handle, Orientation::Rot90),
+            GpuStatus::Unsupported
+        );
+
+        free_gpu_handle(handle);
+    }
+
+    #[test]
+    fn u32_frames_are_corrected_in_place() {
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let handle = create_gpu_handle_with_format(image_width, image_height, 1, PixelFormat::U32);
+        let mut dark_map = vec![100u16; size];
+        set_dark_map(
+            handle,
+            dark_map.as_mut_ptr(),
+            image_width,
+            image_height,
+            300,
+        );
+
+        let mut data = vec![1000u32; size];
+        let status = process_image_u32(handle, data.as_mut_ptr(), image_width, image_height);
+        assert_eq!(status, GpuStatus::Ok);
+        assert!(data.iter().all(|&pixel| pixel == 1000 - 100 + 300));
+
+        // The handle was created for u32 frames.
+        let mut data = vec![0u8; size];
+        let status = process_image_u8(handle, data.as_mut_ptr(), image_width, image_height);
+        assert_eq!(status, GpuStatus::InvalidParameter);
+
+        free_gpu_handle(handle);
+    }
+
+    #[test]
+    fn failures_leave_a_message() {
+        let message = || {
+            unsafe { CStr::from_ptr(gpu_last_error_message()) }
+                .to_str()
+                .unwrap()
+                .to_owned()
+        };
+
+        let status = set_dark_map(std::ptr::null_mut(), std::ptr::null_mut(), 64, 64, 300);
+        assert_eq!(status, GpuStatus::NullPointer);
+        assert!(message().contains("null"));
+
+        let handle = create_gpu_handle(64, 64, 1);
+        let mut dark_map = vec![100u16; 32 * 32];
+        let status = set_dark_map(handle, dark_map.as_mut_ptr(), 32, 32, 300);
+        assert_eq!(status, GpuStatus::InvalidData);
+        assert_eq!(message(), MyError::InvalidTextureData.to_string());
+
+        // Other threads keep their own message.
+        let other_thread_message = std::thread::spawn(message).join().unwrap();
+        assert_eq!(other_thread_message, "");
+
+        free_gpu_handle(handle);
+    }
+
+    #[test]
+    fn overflowing_dimensions_are_invalid_data() {
+        let handle = create_gpu_handle(64, 64, 1);
+        // Never read: 65536 * 65536 pixels overflow before the buffer is sliced.
+        let mut pixels = vec![0u16; 64 * 64];
+
+        let status = process_image(handle, pixels.as_mut_ptr(), 65536, 65536);
+        assert_eq!(status, GpuStatus::InvalidData);
+        let status = set_dark_map(handle, pixels.as_mut_ptr(), 65536, 65536, 300);
+        assert_eq!(status, GpuStatus::InvalidData);
+
+        free_gpu_handle(handle);
+    }
+
+    #[test]
+    fn failed_submissions_keep_lost_devices_apart() {
+        let lost = MyError::from(Validated::Error(VulkanError::DeviceLost));
+        assert_eq!(GpuStatus::from(lost), GpuStatus::DeviceLost);
+
+        let failed = MyError::from(Validated::Error(VulkanError::OutOfDeviceMemory));
+        assert_eq!(GpuStatus::from(failed), GpuStatus::SubmissionFailed);
+    }
+
+    extern "C" fn send_frame(data: *mut u16, len: usize, user_data: *mut c_void) {
+        let sender = unsafe { &*(user_data as *const Sender<Vec<u16>>) };
+        let pixels = unsafe { std::slice::from_raw_parts(data, len) };
+        sender.send(pixels.to_vec()).unwrap();
+    }
+
+    #[test]
+    fn async_processing_calls_back_with_result() {
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let handle = create_gpu_handle(image_width, image_height, 2);
+        let mut dark_map = vec![100u16; size];
+        set_dark_map(
+            handle,
+            dark_map.as_mut_ptr(),
+            image_width,
+            image_height,
+            300,
+        );
+
+        let (sender, receiver) = mpsc::channel();
+        let user_data = &sender as *const Sender<Vec<u16>> as *mut c_void;
+        let data = vec![1000u16; size];
+        let status = process_image_async(
+            handle,
+            data.as_ptr(),
+            image_width,
+            image_height,
+            send_frame,
+            user_data,
+        );
+        assert_eq!(status, GpuStatus::Ok);
+
+        let result = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(result.len(), size);
+        assert!(result.iter().all(|&pixel| pixel == 1000 - 100 + 300));
+
+        let status = process_image_async(
+            handle,
+            std::ptr::null(),
+            image_width,
+            image_height,
+            send_frame,
+            user_data,
+        );
+        assert_eq!(status, GpuStatus::NullPointer);
+
+        free_gpu_handle(handle);
+    }
+
+    #[test]
+    fn failed_allocation_returns_null() {
+        // Zero-sized buffers are rejected by Vulkan.
+        assert!(create_gpu_handle(0, 64, 2).is_null());
+    }
+
+    #[test]
+    fn gpu_result_outlives_later_frames() {
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+
+        let handle = create_gpu_handle(image_width, image_height, 2);
+        let mut dark_map = vec![100u16; size];
+        set_dark_map(
+            handle,
+            dark_map.as_mut_ptr(),
+            image_width,
+            image_height,
+            300,
+        );
+
+        let input = vec![1000u16; size];
+        let mut result = GpuBufferHandle {
+            buffer: std::ptr::null_mut(),
+            len: 0,
+        };
+        let status = gpu_process_to_gpu(
+            handle,
+            input.as_ptr(),
+            image_width,
+            image_height,
+            &mut result,
+        );
+        assert_eq!(status, GpuStatus::Ok);
+        assert_eq!(result.len, size as u64);
+
+        // Reuse every slot so the result can't be aliasing one of them.
+        for _ in 0..2 {
+            let mut data = vec![5000u16; size];
+            process_image(handle, data.as_mut_ptr(), image_width, image_height);
+        }
+
+        let mut pixels = vec![0u16; size];
+        assert_eq!(gpu_buffer_read(&result, pixels.as_mut_ptr()), GpuStatus::Ok);
+        assert!(pixels.iter().all(|&pixel| pixel == 1000 - 100 + 300));
+
+        gpu_buffer_free(&mut result);
+        assert!(result.buffer.is_null());
+        assert_eq!(
+            gpu_buffer_read(&result, pixels.as_mut_ptr()),
+            GpuStatus::NullPointer
+        );
+
+        free_gpu_handle(handle);
+    }
+
+    #[test]
+    fn big_endian_output_swaps_bytes() {
+        let image_width: u32 = 64;
+        let image_height: u32 = 64;
+        let size = (image_width * image_height) as usize;
+        let image: Vec<u16> = (0..size).map(|i| 0x0102 + i as u16).collect();
+
+        let handle = create_gpu_handle(image_width, image_height, 2);
+
+        let mut native = image.clone();
+        let status = process_image(handle, native.as_mut_ptr(), image_width, image_height);
+        assert_eq!(status, GpuStatus::Ok);
+
+        assert_eq!(gpu_set_output_endianness(handle, true), GpuStatus::Ok);
+        let mut big_endian = image.clone();
+        let status = process_image(handle, big_endian.as_mut_ptr(), image_width, image_height);
+        assert_eq!(status, GpuStatus::Ok);
+
+        for (native, big_endian) in native.iter().zip(&big_endian) {
+            assert_eq!(big_endian.to_ne_bytes(), native.to_be_bytes());
+        }
+
+        free_gpu_handle(handle);
+    }
+
+    #[test]
+    fn rotated_output_reports_swapped_dimensions() {
+        let (image_width, image_height) = (64u32, 16u32);
+        let size = (image_width * image_height) as usize;
+        let image: Vec<u16> = (0..size as u16).collect();
+
+        let handle = create_gpu_handle(image_width, image_height, 2);
+        assert_eq!(
+            gpu_set_orientation(handle, Orientation::Rot270),
+            GpuStatus::Ok
+        );
+
+        let mut data = image.clone();
+        let status = process_image(handle, data.as_mut_ptr(), image_width, image_height);
+        assert_eq!(status, GpuStatus::Ok);
+
+        let (mut width, mut height) = (0, 0);
+        assert_eq!(
+            gpu_output_dimensions(handle, &mut width, &mut height),
+            GpuStatus::Ok
+        );
+        assert_eq!((width, height), (image_height, image_width));
+        // Counter-clockwise, so the source's last column becomes the output's first row.
+        assert_eq!(data[0], image[image_width as usize - 1]);
+        assert_eq!(data[1], image[2 * image_width as usize - 1]);
+
+        free_gpu_handle(handle);
     }
 }
@@ -1 +1,2 @@
 mod gpu_handle;
+mod power_preference;
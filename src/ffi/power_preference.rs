@@ -0,0 +1,64 @@
+use vulkano::device::physical::PhysicalDeviceType;
+
+/// Which kind of GPU `create_gpu_handle_with_preference` favours when several are present.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CPowerPreference {
+    /// Discrete GPUs first, as `create_gpu_handle` picks.
+    #[default]
+    HighPerformance,
+    /// Integrated GPUs first, sparing the power a discrete GPU draws.
+    LowPower,
+}
+
+impl CPowerPreference {
+    /// Device types in order of preference, for `GpuSelectionOptions::device_types`.
+    pub fn device_types(self) -> Vec<PhysicalDeviceType> {
+        let (first, second) = match self {
+            CPowerPreference::HighPerformance => (
+                PhysicalDeviceType::DiscreteGpu,
+                PhysicalDeviceType::IntegratedGpu,
+            ),
+            CPowerPreference::LowPower => (
+                PhysicalDeviceType::IntegratedGpu,
+                PhysicalDeviceType::DiscreteGpu,
+            ),
+        };
+        vec![
+            first,
+            second,
+            PhysicalDeviceType::VirtualGpu,
+            PhysicalDeviceType::Cpu,
+            PhysicalDeviceType::Other,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vulkano::device::physical::PhysicalDeviceType;
+
+    use super::CPowerPreference;
+    use crate::core::core::GpuSelectionOptions;
+
+    #[test]
+    fn preferences_only_reorder_the_device_types() {
+        let high_performance = CPowerPreference::HighPerformance.device_types();
+        let low_power = CPowerPreference::LowPower.device_types();
+
+        assert_eq!(
+            high_performance,
+            GpuSelectionOptions::default().device_types
+        );
+        assert_eq!(
+            low_power[..2],
+            high_performance[..2]
+                .iter()
+                .rev()
+                .copied()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(low_power[2..], high_performance[2..]);
+        assert_eq!(low_power[0], PhysicalDeviceType::IntegratedGpu);
+    }
+}
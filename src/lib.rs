@@ -1,2 +1,2 @@
 pub mod core;
-pub mod ffi;
\ No newline at end of file
+pub mod ffi;
@@ -4,27 +4,275 @@
 #include <ostream>
 #include <new>
 
-struct Corrections;
+/// Where [`create_corrector`] runs the corrections.
+enum class Backend {
+  /// A Vulkan device, falling back to `CpuReference` if none can be initialised.
+  Gpu,
+  /// `CpuCorrections`, giving the same results without a GPU, only slower.
+  CpuReference,
+};
+
+/// Which kind of GPU `create_gpu_handle_with_preference` favours when several are present.
+enum class CPowerPreference {
+  /// Discrete GPUs first, as `create_gpu_handle` picks.
+  HighPerformance,
+  /// Integrated GPUs first, sparing the power a discrete GPU draws.
+  LowPower,
+};
+
+/// Result of an FFI call, 0 on success and negative on failure. Failures leave a
+/// description for `gpu_last_error_message`.
+enum class GpuStatus : int32_t {
+  Ok = 0,
+  NullPointer = -1,
+  /// A frame or map doesn't match the frame size.
+  InvalidData = -2,
+  NoInput = -3,
+  Paused = -4,
+  DeviceLost = -5,
+  InvalidParameter = -6,
+  UnknownParameter = -7,
+  UnknownCalibration = -8,
+  DarkMapNotEnabled = -9,
+  DefectMapNotEnabled = -10,
+  MultipleFrames = -11,
+  InvalidStageOrder = -12,
+  InvalidExpression = -13,
+  /// A buffer, image or other device allocation failed.
+  AllocationFailed = -14,
+  InsufficientMemory = -15,
+  NoSuitableDevice = -16,
+  GpuInitialisationFailed = -17,
+  /// A shader failed to load, a bug rather than a caller error.
+  ShaderFailure = -18,
+  /// The handle's backend lacks the feature, see `create_gpu_handle_with_backend`.
+  Unsupported = -19,
+  /// Work failed to record, submit or complete on the GPU for a reason other than the
+  /// device being lost.
+  SubmissionFailed = -20,
+};
+
+/// Element type of the frames a `Corrections` context is fed, chosen when it is created.
+/// The corrections themselves always run on 16-bit pixels: other formats are converted on
+/// the GPU on the way in and back on the way out, integers saturating and floats rounding
+/// to the nearest count.
+enum class PixelFormat {
+  U8,
+  U16,
+  U32,
+  F32,
+};
+
+/// Kind of GPU a context runs on, see [`DeviceInfo`].
+enum class GpuDeviceType {
+  Other,
+  IntegratedGpu,
+  DiscreteGpu,
+  VirtualGpu,
+  Cpu,
+};
+
+/// Flip or clockwise rotation of the corrected frames, for detectors mounted rotated.
+enum class Orientation {
+  None,
+  /// Mirrors each row.
+  FlipH,
+  /// Mirrors each column.
+  FlipV,
+  Rot90,
+  Rot180,
+  Rot270,
+};
+
+/// Corrected frame resident on the device, see `gpu_process_to_gpu`.
+struct GpuBuffer;
+
+/// Device and allocators shared by the handles created from it, see
+/// `create_gpu_context`.
+struct GpuContext;
+
+/// Opaque to C callers, who only ever hold a pointer to it.
+struct GPUHandle;
+
+struct GpuBufferHandle {
+  GpuBuffer *buffer;
+  /// Number of pixels in the buffer.
+  uint64_t len;
+};
+
+/// Called with the corrected pixels, their count and the `user_data` given to
+/// `process_image_async`, or with a null pointer and a count of 0 if the frame failed on
+/// the GPU.
+using CompletionCallback = void(*)(uint16_t *data, uintptr_t len, void *user_data);
 
-struct GPUHandle {
-  Corrections *correction_context;
+/// Snapshot of the throughput counters of the asynchronous processing path.
+struct CorrectionMetrics {
+  uint64_t frames_submitted;
+  uint64_t frames_completed;
+  /// Frames rejected because processing was paused.
+  uint64_t frames_dropped;
+  /// Largest number of submitted but not yet completed frames seen at submission.
+  uint64_t max_in_flight;
+};
+
+/// The GPU a context runs on, for hosts to log which one was picked.
+struct DeviceInfo {
+  /// NUL-terminated UTF-8, truncated to fit.
+  char name[256];
+  GpuDeviceType device_type;
+  /// Total size of the device-local heaps.
+  uint64_t vram_bytes;
+  /// Encoded as the vendor's driver chooses.
+  uint32_t driver_version;
 };
 
 extern "C" {
 
+/// Describes the last failure of an FFI call on the calling thread, as a NUL-terminated
+/// UTF-8 string, empty if none has failed yet. The string belongs to the library and stays
+/// valid until the next failing call on the same thread.
+const char *gpu_last_error_message();
+
+/// Returns null if no GPU can be initialised or the context's buffers can't be allocated,
+/// see `gpu_last_error_message`.
 GPUHandle *create_gpu_handle(uint32_t width, uint32_t height, uint32_t buffer_count);
 
-void set_dark_map(GPUHandle *gpu_handle, uint16_t *dark_map_data, uint32_t width, uint32_t height);
+/// Like `create_gpu_handle`, for frames of `pixel_format` passed to `process_image_u8` or
+/// `process_image_u32`. 16-bit frames can still be passed to `process_image`.
+GPUHandle *create_gpu_handle_with_format(uint32_t width,
+                                         uint32_t height,
+                                         uint32_t buffer_count,
+                                         PixelFormat pixel_format);
+
+/// Like `create_gpu_handle`, correcting frames on `backend`. `Backend::Gpu` falls back to
+/// the CPU reference backend if no GPU can be initialised, rather than returning null. CPU
+/// handles only support the dark, gain and defect maps and `process_image`; the other
+/// functions return `GpuStatus::Unsupported` for them.
+GPUHandle *create_gpu_handle_with_backend(uint32_t width,
+                                          uint32_t height,
+                                          uint32_t buffer_count,
+                                          Backend backend);
+
+/// Like `create_gpu_handle`, picking an integrated GPU over a discrete one if `preference`
+/// is `LowPower`.
+GPUHandle *create_gpu_handle_with_preference(uint32_t width,
+                                             uint32_t height,
+                                             uint32_t buffer_count,
+                                             CPowerPreference preference);
+
+/// Initialises a GPU for several handles to share through
+/// `create_gpu_handle_from_context`, rather than each creating its own device. Returns
+/// null if no GPU can be initialised, see `gpu_last_error_message`.
+GpuContext *create_gpu_context();
+
+/// Like `create_gpu_handle`, on the device of `context`. The handle keeps the device
+/// alive, so `context` can be freed before it.
+GPUHandle *create_gpu_handle_from_context(GpuContext *context,
+                                          uint32_t width,
+                                          uint32_t height,
+                                          uint32_t buffer_count);
+
+/// Releases the caller's reference to `context`. Handles created from it keep working.
+void free_gpu_context(GpuContext *context);
+
+/// Enables dark correction with the `width * height` map in `dark_map_data`, adding
+/// `offset` to every corrected pixel. A map that doesn't match the handle's frame size is
+/// `GpuStatus::InvalidData`.
+GpuStatus set_dark_map(GPUHandle *gpu_handle,
+                       uint16_t *dark_map_data,
+                       uint32_t width,
+                       uint32_t height,
+                       uint32_t offset);
+
+/// Enables gain correction with the `width * height` map in `gain_map_data`. A map that
+/// doesn't match the handle's frame size is `GpuStatus::InvalidData`.
+GpuStatus set_gain_map(GPUHandle *gpu_handle,
+                       float *gain_map_data,
+                       uint32_t width,
+                       uint32_t height);
+
+/// Enables defect correction with the `width * height` map in `defect_map_data`. A map
+/// that doesn't match the handle's frame size is `GpuStatus::InvalidData`.
+GpuStatus set_defect_map(GPUHandle *gpu_handle,
+                         uint16_t *defect_map_data,
+                         uint32_t width,
+                         uint32_t height);
+
+/// Turns dark correction back off. Frames already being corrected finish with the map.
+GpuStatus clear_dark_map(GPUHandle *gpu_handle);
+
+/// Turns gain correction back off. Frames already being corrected finish with the map.
+GpuStatus clear_gain_map(GPUHandle *gpu_handle);
+
+/// Turns defect correction back off. Frames already being corrected finish with the map.
+GpuStatus clear_defect_map(GPUHandle *gpu_handle);
+
+/// Corrects the `width * height` frame in `data` in place, returning once the corrected
+/// pixels have been written back. Frames whose dimensions differ from the previous frame
+/// switch the handle to that size's buffers and maps.
+GpuStatus process_image(GPUHandle *gpu_handle, uint16_t *data, uint32_t width, uint32_t height);
+
+/// Corrects the `width * height` 8-bit frame in `data` in place, for handles created with
+/// `PixelFormat::U8`. Otherwise like `process_image`.
+GpuStatus process_image_u8(GPUHandle *gpu_handle, uint8_t *data, uint32_t width, uint32_t height);
+
+/// Corrects the `width * height` 32-bit frame in `data` in place, for handles created with
+/// `PixelFormat::U32`. Otherwise like `process_image`.
+GpuStatus process_image_u32(GPUHandle *gpu_handle,
+                            uint32_t *data,
+                            uint32_t width,
+                            uint32_t height);
+
+/// Copies the `width * height` frame in `data` and returns as soon as it is submitted.
+/// `callback` is invoked on a worker thread once the GPU has finished, with the corrected
+/// pixels and `user_data`.
+///
+/// The pixel pointer is owned by the handle and only valid until the callback returns; copy
+/// the pixels out to keep them. Callbacks of different frames may run concurrently and out
+/// of submission order. Frames still in flight complete and call back before
+/// `free_gpu_handle` returns.
+GpuStatus process_image_async(GPUHandle *gpu_handle,
+                              const uint16_t *data,
+                              uint32_t width,
+                              uint32_t height,
+                              CompletionCallback callback,
+                              void *user_data);
+
+/// Corrects the `width * height` frame in `input` and leaves the result on the device,
+/// filling `result` with a handle to it instead of copying the pixels back. The handle stays
+/// valid across later calls until released with `gpu_buffer_free`.
+GpuStatus gpu_process_to_gpu(GPUHandle *gpu_handle,
+                             const uint16_t *input,
+                             uint32_t width,
+                             uint32_t height,
+                             GpuBufferHandle *result);
+
+/// Copies the `len` pixels of a buffer returned by `gpu_process_to_gpu` into `data`.
+GpuStatus gpu_buffer_read(const GpuBufferHandle *buffer_handle, uint16_t *data);
+
+/// Releases the device buffer behind `buffer_handle` and clears the handle.
+void gpu_buffer_free(GpuBufferHandle *buffer_handle);
+
+/// Makes `process_image` write big-endian pixels when `big_endian` is set, native-endian
+/// ones otherwise. Native is the default.
+GpuStatus gpu_set_output_endianness(GPUHandle *gpu_handle, bool big_endian);
+
+/// Flips or rotates the frames `process_image` writes back. After a quarter turn they are
+/// `height` pixels wide, see `gpu_output_dimensions`.
+GpuStatus gpu_set_orientation(GPUHandle *gpu_handle, Orientation orientation);
 
-void set_gain_map(GPUHandle *gpu_handle, float *gain_map_data, uint32_t width, uint32_t height);
+/// Writes the width and height of the frames `process_image` writes back, which differ from
+/// the frame dimensions after a quarter turn.
+GpuStatus gpu_output_dimensions(GPUHandle *gpu_handle, uint32_t *width, uint32_t *height);
 
-void set_defect_map(GPUHandle *gpu_handle,
-                    uint16_t *defect_map_data,
-                    uint32_t width,
-                    uint32_t height);
+/// Writes the handle's throughput counters into `metrics`.
+GpuStatus get_metrics(GPUHandle *gpu_handle, CorrectionMetrics *metrics);
 
-void process_image(GPUHandle *gpu_handle, uint16_t *data, uint32_t width, uint32_t height);
+/// Writes which GPU the handle runs on into `info`.
+GpuStatus gpu_get_device_info(GPUHandle *gpu_handle, DeviceInfo *info);
 
+/// Frees the handle, blocking until the frames still in flight have completed, their
+/// callbacks included, and the GPU is idle.
 void free_gpu_handle(GPUHandle *handle);
 
 } // extern "C"
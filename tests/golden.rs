@@ -0,0 +1,154 @@
+//! Runs every fixture under `tests/golden` through the corrections and compares the result
+//! with the fixture's `expected.raw` byte for byte, so any change to the shader math shows
+//! up here. After an intended change, rerun with `UPDATE_GOLDEN=1` to rewrite the expected
+//! outputs from the current implementation and review the diff.
+//!
+//! A fixture is a directory of little-endian raw files: `input.raw` (u16), and whichever of
+//! `dark_map.raw` (u16), `gain_map.raw` (f32) and `defect_map.raw` (u16) enable the
+//! matching correction.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use gpu_processing::core::core::{
+    initialise_gpu_resources, process_single, CorrectionMaps, Corrections,
+};
+
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 16;
+const DARK_OFFSET: u32 = 300;
+
+fn fixture_dir(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(name)
+}
+
+fn read_u16(path: &Path) -> Option<Vec<u16>> {
+    let bytes = fs::read(path).ok()?;
+    Some(
+        bytes
+            .chunks_exact(2)
+            .map(|pixel| u16::from_le_bytes([pixel[0], pixel[1]]))
+            .collect(),
+    )
+}
+
+fn read_f32(path: &Path) -> Option<Vec<f32>> {
+    let bytes = fs::read(path).ok()?;
+    Some(
+        bytes
+            .chunks_exact(4)
+            .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+            .collect(),
+    )
+}
+
+fn check_fixture(name: &str) {
+    let dir = fixture_dir(name);
+    let input = read_u16(&dir.join("input.raw")).expect("fixture has no input.raw");
+    assert_eq!(input.len(), (WIDTH * HEIGHT) as usize);
+
+    let (queue, device) = initialise_gpu_resources();
+    let mut correction_context = Corrections::new(device, queue, WIDTH, HEIGHT, 1).unwrap();
+    if let Some(dark_map) = read_u16(&dir.join("dark_map.raw")) {
+        correction_context
+            .enable_dark_map_correction(&dark_map, DARK_OFFSET)
+            .unwrap();
+    }
+    if let Some(gain_map) = read_f32(&dir.join("gain_map.raw")) {
+        correction_context
+            .enable_gain_correction(&gain_map)
+            .unwrap();
+    }
+    if let Some(defect_map) = read_u16(&dir.join("defect_map.raw")) {
+        correction_context
+            .enable_defect_correction(&defect_map)
+            .unwrap();
+    }
+
+    let mut output = input;
+    correction_context
+        .process_image_in_place(&mut output)
+        .unwrap();
+
+    let expected_path = dir.join("expected.raw");
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        let bytes: Vec<u8> = output
+            .iter()
+            .flat_map(|pixel| pixel.to_le_bytes())
+            .collect();
+        fs::write(&expected_path, bytes).unwrap();
+        return;
+    }
+
+    let expected = read_u16(&expected_path).expect("fixture has no expected.raw");
+    assert_eq!(expected.len(), output.len());
+    if let Some(index) = (0..output.len()).find(|&i| output[i] != expected[i]) {
+        let mismatches = (0..output.len())
+            .filter(|&i| output[i] != expected[i])
+            .count();
+        panic!(
+            "{name}: {mismatches} pixels differ from the golden output, first at ({}, {}): \
+             got {}, expected {}",
+            index as u32 % WIDTH,
+            index as u32 / WIDTH,
+            output[index],
+            expected[index]
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dark_only() {
+    check_fixture("dark");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn gain_only() {
+    check_fixture("gain");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn defect_only() {
+    check_fixture("defect");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dark_gain_and_defect() {
+    check_fixture("combined");
+}
+
+#[test]
+fn single_call_matches_a_configured_context() {
+    let dir = fixture_dir("combined");
+    let input = read_u16(&dir.join("input.raw")).unwrap();
+    let dark_map = read_u16(&dir.join("dark_map.raw")).unwrap();
+    let defect_map = read_u16(&dir.join("defect_map.raw")).unwrap();
+
+    let (queue, device) = initialise_gpu_resources();
+    let mut correction_context = Corrections::new(device, queue, WIDTH, HEIGHT, 1).unwrap();
+    correction_context
+        .enable_dark_map_correction(&dark_map, DARK_OFFSET)
+        .unwrap();
+    correction_context
+        .enable_defect_correction(&defect_map)
+        .unwrap();
+    let reference = correction_context.process_image_blocking(&input).unwrap();
+
+    let output = process_single(
+        &input,
+        WIDTH,
+        HEIGHT,
+        CorrectionMaps {
+            dark_map: Some((&dark_map, DARK_OFFSET)),
+            defect_map: Some(&defect_map),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(output, reference);
+}